@@ -1,52 +1,404 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 extern crate num_bigint;
 extern crate num_traits;
 
 use num_bigint::{BigUint, ToBigUint};
 use num_traits::{One, Zero};
+use safe_math::safe_div_u64;
+use wasm_utils::{read_bytes, write_bytes};
 
-// Safe ops for u64
-fn safe_add_u64(a: u64, b: u64) -> u64 {
-    a.checked_add(b).unwrap_or(u64::MAX)
-}
-fn safe_sub_u64(a: u64, b: u64) -> u64 {
-    a.checked_sub(b).unwrap_or(0)
-}
-fn safe_mul_u64(a: u64, b: u64) -> u64 {
-    a.checked_mul(b).unwrap_or(u64::MAX)
+// Data provenance logic:
+// For each product, we track environment flags (u32) and a 64-bit quality score,
+// plus a 32-bit "certification bitmask" representing e.g. GMP, ISO standards, etc.
+
+// We define multiple complexity steps:
+
+/// Bits that must be set in a valid environment flag of a `lineage_chain` hop (bits 0 and
+/// 2). `main` takes its mask from the caller instead.
+const REQUIRED_ENVIRONMENT_MASK: u32 = 0b101;
+
+/// Required environment bits `partial_fallback` may waive: bit 2 and humidity control
+/// (bit 4) are monitoring a lot can do without at a cost. Bit 0 and refrigeration are hard.
+const SOFT_ENVIRONMENT_MASK: u32 = 1 << 2 | HUMIDITY_CONTROLLED_ENVIRONMENT_BIT;
+const FALLBACK_COMPENSATING_QUALITY: u64 = 8_000;
+const FALLBACK_WAIVER_PENALTY_PERCENT: u64 = 25;
+
+/// `main`'s STATUS_* flags, STATUS_BITS of them, which go under the score (see
+/// SCORE_SHIFT). Products that go through `partial_fallback` say why, and how it dealt
+/// with their environment goes in the 2 bits from STATUS_FALLBACK_REASON_SHIFT, GAVE_UP
+/// with a digest of 0. How many set certification
+/// bits were revoked goes in the status bits from STATUS_REVOCATION_COUNT_SHIFT, and
+/// CERTIFICATION_VOID says it was all of them. On the fallback path, how many required
+/// environment bits were missing goes in the 3 bits from STATUS_MISSING_ENVIRONMENT_SHIFT,
+/// saturating at 7. How many certifications were dropped for a missing prerequisite goes
+/// in the 3 bits from STATUS_DEPENDENCY_VIOLATION_SHIFT. The chain digests of
+/// `lineage_chain` and `check_cold_chain` keep their low STATUS_SHIFT bits, with their own
+/// flags and counts above them.
+const STATUS_BITS: u32 = 29;
+const STATUS_SHIFT: u32 = 36;
+const DIGEST_MASK: u64 = (1 << STATUS_SHIFT) - 1;
+const STATUS_ENVIRONMENT_FALLBACK: u64 = 1 << 0;
+const STATUS_CERTIFICATION_EXPIRED: u64 = 1 << 1;
+const STATUS_INVALID_TIMESTAMPS: u64 = 1 << 2;
+const STATUS_CERTIFICATION_VOID: u64 = 1 << 3;
+const STATUS_LOW_TRUST: u64 = 1 << 4;
+const STATUS_ROUTE_VIOLATION: u64 = 1 << 5;
+const STATUS_SANCTIONED_REGION: u64 = 1 << 6;
+const STATUS_INVALID_MODE: u64 = 1 << 7;
+const STATUS_MISSING_ATTESTATION: u64 = 1 << 20;
+const STATUS_UNKNOWN_MANUFACTURER: u64 = 1 << 21;
+const STATUS_INVALID_PRODUCT_ID: u64 = 1 << 22;
+const STATUS_PRODUCT_EXPIRED: u64 = 1 << 23;
+const STATUS_SUSPECT: u64 = 1 << 24;
+const STATUS_UNKNOWN_INDUSTRY: u64 = 1 << 25;
+const STATUS_UNKNOWN_TRANSPORT: u64 = 1 << 28;
+const STATUS_REVOCATION_COUNT_SHIFT: u32 = 8;
+const STATUS_MISSING_ENVIRONMENT_SHIFT: u32 = 14;
+const STATUS_MISSING_ENVIRONMENT_MAX: u32 = 7;
+const STATUS_DEPENDENCY_VIOLATION_SHIFT: u32 = 17;
+const STATUS_FALLBACK_REASON_SHIFT: u32 = 26;
+const STATUS_FALLBACK_OTHER_CHECK: u64 = 0;
+const STATUS_FALLBACK_COMPENSATED: u64 = 1 << STATUS_FALLBACK_REASON_SHIFT;
+const STATUS_FALLBACK_WAIVED: u64 = 2 << STATUS_FALLBACK_REASON_SHIFT;
+const STATUS_FALLBACK_GAVE_UP: u64 = 3 << STATUS_FALLBACK_REASON_SHIFT;
+
+/// With an expected digest `main` verifies instead: 1 in the top bit if the result matches,
+/// the popcount of the XOR between them in the 7 bits below, and the low 56 bits of the
+/// computed result under those.
+const VERIFY_MATCH_BIT: u64 = 1 << 63;
+const VERIFY_DIFFERENCE_SHIFT: u32 = 56;
+const VERIFY_RESULT_MASK: u64 = (1 << VERIFY_DIFFERENCE_SHIFT) - 1;
+
+/// Provenance confidence from 0 to 100: ENVIRONMENT points for a valid environment, up to
+/// CERTIFICATION for certification breadth (all 32 bits for the lot) and up to QUALITY for
+/// quality up to SCORE_QUALITY_REFERENCE, less HOP_FAILURE for every failed check.
+const SCORE_MAX: u32 = 100;
+const SCORE_ENVIRONMENT_POINTS: u32 = 40;
+const SCORE_CERTIFICATION_POINTS: u32 = 30;
+const SCORE_QUALITY_POINTS: u32 = 30;
+const SCORE_QUALITY_REFERENCE: u64 = 10_000;
+const SCORE_HOP_FAILURE_PENALTY: u32 = 10;
+
+/// Unless asked for the legacy layout, `main` puts the score in its top 8 bits and moves
+/// the status bits down under it, leaving the digest the bits below SCORED_STATUS_SHIFT.
+/// The full digest is XOR-folded into those bits rather than cut off, see `fold_digest`.
+const SCORE_SHIFT: u32 = 56;
+const SCORED_STATUS_SHIFT: u32 = SCORE_SHIFT - STATUS_BITS;
+const SCORED_DIGEST_MASK: u64 = (1 << SCORED_STATUS_SHIFT) - 1;
+
+/// Certification classes by bitmask bits: bits 0-7 expire a year after issue, bits 8-15
+/// after two, and bits 16 and up never do.
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+const CERT_ONE_YEAR_MASK: u32 = 0x0000_00FF;
+const CERT_TWO_YEAR_MASK: u32 = 0x0000_FF00;
+
+/// A product's quality decays linearly to half over its shelf life, then quadratically to 0
+/// over a second shelf life, starting twice as steep, after which it has expired. A shelf
+/// life of 0 means it doesn't age.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const NO_SHELF_LIFE: u32 = 0;
+
+/// Certifications that are only valid alongside a prerequisite, as (dependent bit,
+/// prerequisite bit), e.g. organic processing (bit 5) needs organic raw material (bit 1).
+/// Every prerequisite is a lower bit than its dependent and the rules are sorted by
+/// dependent, so there can't be a cycle and one pass in order settles chains. There are
+/// no more rules than the 3 violation bits at STATUS_DEPENDENCY_VIOLATION_SHIFT can count.
+const CERT_DEPENDENCIES: [(u32, u32); 7] =
+    [(5, 1), (6, 5), (9, 8), (12, 3), (17, 16), (20, 9), (24, 17)];
+
+/// What a product is: a single unit, or a batch of `unit_count` units (at least 1) where
+/// `product_id` is the batch id and `quality_score` the mean over its units.
+const GRANULARITY_UNIT: u32 = 0;
+const GRANULARITY_BATCH: u32 = 1;
+
+/// Sign-offs on a record: producers attest in the low 16 bits of the sign-off mask and
+/// independent inspectors in the high 16. Without enough of both the record falls back
+/// with STATUS_MISSING_ATTESTATION. The whole mask, which fixes both counts, is folded
+/// into the final combination, so records signed off by different parties hash
+/// differently. V1 XORs it into the low 32 bits of the digest.
+const SIGNOFF_PRODUCER_MASK: u32 = 0xFFFF;
+const SIGNOFF_INSPECTOR_SHIFT: u32 = 16;
+const SIGNOFF_MIN_PRODUCERS: u32 = 2;
+const SIGNOFF_MIN_INSPECTORS: u32 = 1;
+
+/// How `main` hashes, see HashVersion. V1 is the original scheme and stays the default.
+const HASH_V1: u32 = 0;
+const HASH_V2: u32 = 1;
+
+/// V2 lineage rounds: multiply in an input and the prime, add the round's constant, then
+/// rotate the low limb, so the order of the inputs matters.
+const HASH_V2_ROUND_CONSTANTS: [u64; 3] = [
+    0x243F_6A88_85A3_08D3,
+    0x1319_8A2E_0370_7344,
+    0xA409_3822_299F_31D0,
+];
+const HASH_V2_ROTATION: u32 = 29;
+
+/// V2 folds the final values FNV-1a style instead of XORing them together.
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+#[derive(Clone, Copy)]
+enum HashVersion {
+    V1,
+    V2,
 }
-fn safe_div_u64(a: u64, b: u64) -> u64 {
-    if b == 0 {
-        0
-    } else {
-        a / b
+
+impl HashVersion {
+    fn from_u32(hash_version: u32) -> Option<Self> {
+        match hash_version {
+            HASH_V1 => Some(HashVersion::V1),
+            HASH_V2 => Some(HashVersion::V2),
+            _ => None,
+        }
     }
-}
 
-// Safe ops for u32
-fn safe_add_u32(a: u32, b: u32) -> u32 {
-    a.checked_add(b).unwrap_or(u32::MAX)
-}
-fn safe_sub_u32(a: u32, b: u32) -> u32 {
-    a.checked_sub(b).unwrap_or(0)
-}
-fn safe_mul_u32(a: u32, b: u32) -> u32 {
-    a.checked_mul(b).unwrap_or(u32::MAX)
-}
-fn safe_div_u32(a: u32, b: u32) -> u32 {
-    if b == 0 {
-        0
-    } else {
-        a / b
+    fn lineage_hash(self, product_id: u64, environment_flag: u32, quality_score: u64) -> BigUint {
+        match self {
+            HashVersion::V1 => {
+                compute_data_lineage_hash(product_id, environment_flag, quality_score)
+            }
+            HashVersion::V2 => {
+                compute_data_lineage_hash_v2(product_id, environment_flag, quality_score)
+            }
+        }
+    }
+
+    fn combine(
+        self,
+        lineage_hash: &BigUint,
+        env_valid: bool,
+        cert_transform: u32,
+        quality_score: u64,
+        attestations: u64,
+    ) -> u64 {
+        match self {
+            HashVersion::V1 => combine_final(
+                lineage_hash,
+                env_valid,
+                cert_transform,
+                quality_score,
+                attestations,
+            ),
+            HashVersion::V2 => combine_final_v2(
+                lineage_hash,
+                env_valid,
+                cert_transform,
+                quality_score,
+                attestations,
+            ),
+        }
     }
 }
 
-// Data provenance logic:
-// For each product, we track environment flags (u32) and a 64-bit quality score,
-// plus a 32-bit "certification bitmask" representing e.g. GMP, ISO standards, etc.
+/// Industries read the environment flag and certifications differently. A profile adds its
+/// required environment bits to the caller's mask, keeps only the certifications that
+/// matter to it, and weighs the quality score and certification transform in the final
+/// combination. Unknown industries get the generic profile, which changes nothing, and
+/// STATUS_UNKNOWN_INDUSTRY.
+struct IndustryProfile {
+    required_mask: u32,
+    relevant_certifications: u32,
+    quality_weight: u64,
+    certification_weight: u32,
+}
 
-// We define multiple complexity steps:
+const INDUSTRY_FOOD: u32 = 0;
+const INDUSTRY_PHARMA: u32 = 1;
+const INDUSTRY_ELECTRONICS: u32 = 2;
+const INDUSTRY_GENERIC: u32 = 3;
+const INDUSTRY_PROFILES: [IndustryProfile; 4] = [
+    // Food has to stay refrigerated, and only its expiring certifications count
+    IndustryProfile {
+        required_mask: REFRIGERATED_ENVIRONMENT_BIT,
+        relevant_certifications: CERT_ONE_YEAR_MASK | CERT_TWO_YEAR_MASK,
+        quality_weight: 1,
+        certification_weight: 1,
+    },
+    // Pharma also needs humidity control, and quality weighs three times as much
+    IndustryProfile {
+        required_mask: REFRIGERATED_ENVIRONMENT_BIT | HUMIDITY_CONTROLLED_ENVIRONMENT_BIT,
+        relevant_certifications: u32::MAX,
+        quality_weight: 3,
+        certification_weight: 1,
+    },
+    // Electronics only count permanent certifications, but they weigh twice as much
+    IndustryProfile {
+        required_mask: 0,
+        relevant_certifications: !(CERT_ONE_YEAR_MASK | CERT_TWO_YEAR_MASK),
+        quality_weight: 1,
+        certification_weight: 2,
+    },
+    IndustryProfile {
+        required_mask: 0,
+        relevant_certifications: u32::MAX,
+        quality_weight: 1,
+        certification_weight: 1,
+    },
+];
+
+/// Product ids carry the manufacturer code in their top 16 bits and the unit serial below.
+/// Code 0 isn't a manufacturer. A manufacturer's trust weight scales its products' quality,
+/// in percent, and products of an unknown one lose MANUFACTURER_UNKNOWN_PENALTY_PERCENT.
+const MANUFACTURER_SHIFT: u32 = 48;
+const SERIAL_MASK: u64 = (1 << MANUFACTURER_SHIFT) - 1;
+const MANUFACTURER_INVALID: u16 = 0;
+const MANUFACTURERS: [(u16, u64); 5] = [
+    (0x0001, 100),
+    (0x0002, 100),
+    (0x00A1, 95),
+    (0x0B07, 90),
+    (0x1F00, 85),
+];
+const MANUFACTURER_UNKNOWN_PENALTY_PERCENT: u64 = 20;
+/// Counterfeit red flags and what each adds to a 0-100 likelihood: certifications in the low
+/// bits whose matching environment bit is clear, a quality improbably high for a product
+/// with few certifications, and the 0xFFFF manufacturer code. Records scoring above
+/// COUNTERFEIT_SUSPECT_THRESHOLD fall back with STATUS_SUSPECT.
+const COUNTERFEIT_MATCHED_BITS: u32 = 0xFF;
+const COUNTERFEIT_UNMATCHED_CERT_WEIGHT: u32 = 40;
+const COUNTERFEIT_IMPROBABLE_QUALITY: u64 = 1_000_000;
+const COUNTERFEIT_MIN_CERTIFICATIONS: u32 = 2;
+const COUNTERFEIT_IMPROBABLE_QUALITY_WEIGHT: u32 = 30;
+const COUNTERFEIT_MANUFACTURER: u16 = 0xFFFF;
+const COUNTERFEIT_MANUFACTURER_WEIGHT: u32 = 30;
+const COUNTERFEIT_SUSPECT_THRESHOLD: u32 = 60;
+
+/// The lineage hash adds the manufacturer code times this prime to the serial's product,
+/// so a serial hashes differently under every manufacturer.
+const MANUFACTURER_MULTIPLIER: u64 = 1_000_003;
+
+/// Supplier trust, one byte per supply chain tier with 0 for a tier that isn't used, and
+/// what the aggregate on a 0-100 scale does: below LOW it forces the fallback, up to
+/// REDUCED it halves the quality score the lineage is hashed with. Without any supplier
+/// there's nothing to distrust, so the aggregate is the full 100.
+const TRUST_TIERS: u32 = 4;
+const TRUST_SCORE_MAX: u32 = 255;
+const TRUST_FULL: u32 = 100;
+const TRUST_LOW_THRESHOLD: u32 = 40;
+const TRUST_REDUCED_THRESHOLD: u32 = 70;
+
+/// Region codes are ISO 3166-1 numeric, with 0 for an unknown region. Shipping between
+/// them goes by ALLOWED_ROUTES, as (from, to) pairs; staying within a known region is always
+/// allowed. Any other route is a violation that costs a quarter of the quality score the
+/// lineage is hashed with. The user-assigned codes in SANCTIONED_REGIONS stand in for
+/// regions no product may come from or go to at all.
+const REGION_UNKNOWN: u32 = 0;
+const ALLOWED_ROUTES: [(u32, u32); 14] = [
+    (840, 124), // United States -> Canada
+    (124, 840), // Canada -> United States
+    (840, 484), // United States -> Mexico
+    (484, 840), // Mexico -> United States
+    (276, 250), // Germany -> France
+    (250, 276), // France -> Germany
+    (276, 380), // Germany -> Italy
+    (250, 724), // France -> Spain
+    (724, 250), // Spain -> France
+    (826, 250), // United Kingdom -> France
+    (392, 410), // Japan -> South Korea
+    (410, 392), // South Korea -> Japan
+    (36, 554),  // Australia -> New Zealand
+    (554, 36),  // New Zealand -> Australia
+];
+const SANCTIONED_REGIONS: [u32; 3] = [900, 901, 902];
+
+/// Quality lost in transit, in basis points of the original score: each hop costs 2% and
+/// each day 0.1%, half that with the REFRIGERATED environment bit set. The score never drops
+/// below 10% of the original, nor to 0 from a nonzero one.
+const BPS_DENOMINATOR: u64 = 10_000;
+const TRANSIT_HOP_LOSS_BPS: u64 = 200;
+const TRANSIT_DAY_LOSS_BPS: u64 = 10;
+const TRANSIT_FLOOR_BPS: u64 = 1_000;
+const REFRIGERATED_ENVIRONMENT_BIT: u32 = 1 << 3;
+const HUMIDITY_CONTROLLED_ENVIRONMENT_BIT: u32 = 1 << 4;
+
+/// How a product shipped, see TransportMode. Road is neutral and unknown modes count as
+/// road with STATUS_UNKNOWN_TRANSPORT. Rail earns a bonus that never takes quality above
+/// what was claimed, sea freight without humidity control loses a share, and air freight
+/// adds a fixed emissions surcharge to the lineage hash.
+const TRANSPORT_ROAD: u32 = 0;
+const TRANSPORT_RAIL: u32 = 1;
+const TRANSPORT_SEA: u32 = 2;
+const TRANSPORT_AIR: u32 = 3;
+const TRANSPORT_RAIL_BONUS_BPS: u64 = 200;
+const TRANSPORT_SEA_PENALTY_BPS: u64 = 1_500;
+const TRANSPORT_AIR_EMISSIONS_SURCHARGE: u64 = 7_919;
+
+/// A custody hop in linear memory: environment_flag (u32), certification_bitmask (u32) and
+/// quality_score (u64), all little-endian.
+const LINEAGE_HOP_SIZE: usize = 16;
+
+/// Returned by `lineage_chain` for a chain without hops.
+const LINEAGE_EMPTY_CHAIN: u64 = u64::MAX;
+
+/// The running lineage hash is multiplied by this prime before each hop's hash is added,
+/// keeping the low 128 bits, so a hop's weight depends on its position in the chain.
+const LINEAGE_CHAIN_MULTIPLIER: u64 = 104729;
+const LINEAGE_CHAIN_BITS: u64 = 128;
+
+/// `lineage_chain` keeps the digest in the low STATUS_SHIFT bits and, above the counts
+/// `cold_chain_result` adds, sets bit (index % LINEAGE_FAILURE_BITS) of the mask from
+/// LINEAGE_FAILURE_SHIFT for every hop that fails `validate_environment`, and
+/// LINEAGE_ANY_FAILURE_BIT if any hop did.
+const LINEAGE_FAILURE_SHIFT: u32 = COLD_CHAIN_RUN_SHIFT + 8;
+const LINEAGE_FAILURE_BITS: u32 = 10;
+const LINEAGE_ANY_FAILURE_BIT: u64 = 1 << 62;
+
+/// A product in a Merkle batch: product_id (u64), environment_flag (u32) and quality_score
+/// (u64), packed little-endian.
+const MERKLE_PRODUCT_SIZE: usize = 20;
+
+/// A Merkle inclusion proof step: the sibling hash (u64) and its side (u64), 0 if the sibling
+/// is the right-hand node and 1 if it's the left-hand one, both little-endian.
+const MERKLE_PATH_STEP_SIZE: usize = 16;
+const MERKLE_SIBLING_LEFT: u64 = 1;
+
+/// Internal Merkle nodes mix their children as (left rotl 23 ^ right) * multiplier.
+const MERKLE_ROTATION: u32 = 23;
+const MERKLE_MULTIPLIER: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// A split lot becomes two children: child 0 takes the split ratio (in basis points, see
+/// BPS_DENOMINATOR) of the parent and child 1 the rest. Each child's digest mixes the
+/// parent's with its index and share, and a merge mixes each lot's digest with its share
+/// of the merged quantity.
+const LOT_SPLIT_CHILDREN: u32 = 2;
+const LOT_CHILD_INDEX_SHIFT: u32 = 32;
+
+/// `trace_recall` result: bit 63 is set if the affected product is in the lot range, bit 62
+/// if the range spans more than RECALL_MAX_LOTS lots (nothing is counted then), and the low
+/// 32 bits hold the number of other lots sharing the affected product's hash low byte.
+const RECALL_IN_RANGE_BIT: u64 = 1 << 63;
+const RECALL_RANGE_TOO_LARGE_BIT: u64 = 1 << 62;
+const RECALL_MAX_LOTS: u64 = 10_000;
+/// Lots are hashed without a quality score, only their id and environment are known.
+const RECALL_QUALITY_SCORE: u64 = 0;
+
+/// Temperature readings are u16s, little-endian, offset-encoded: the value minus
+/// TEMPERATURE_OFFSET is tenths of a degree. The band limits use the same encoding.
+const TEMPERATURE_READING_SIZE: usize = 2;
+const TEMPERATURE_OFFSET: i64 = 1000;
+/// Readings outside the band are excursions. More than MAX_EXCURSIONS of them, or a run of
+/// more than MAX_EXCURSION_RUN in a row, voids the cold-chain certification.
+const COLD_CHAIN_MAX_EXCURSIONS: u32 = 3;
+const COLD_CHAIN_MAX_EXCURSION_RUN: u32 = 5;
+const CERT_COLD_CHAIN_BIT: u32 = 1 << 7;
+
+/// `check_cold_chain` result: the product's digest in the low bits (see DIGEST_MASK), the
+/// excursion count and the longest excursion run above it, 8 bits each and saturating, and
+/// COLD_CHAIN_VOID_BIT if the log voided the cold-chain certification.
+const COLD_CHAIN_EXCURSIONS_SHIFT: u32 = STATUS_SHIFT;
+const COLD_CHAIN_RUN_SHIFT: u32 = STATUS_SHIFT + 8;
+const COLD_CHAIN_COUNT_MAX: u32 = 0xFF;
+const COLD_CHAIN_VOID_BIT: u64 = 1 << 63;
+
+/// `provenance_record` output, all little-endian: the lineage hash's low and high 64 bits,
+/// the digest combined from them, the effective quality score and the STATUS_* flags as
+/// u64s, then the certification transform, environment validity (0 or 1), provenance score
+/// and effective certifications as u32s. The record takes no transit, transport or shelf
+/// life inputs, so its effective quality only has the manufacturer weight applied: the
+/// quality `main` hashes for a product that was made just now and hasn't shipped.
+const PROVENANCE_RECORD_SIZE: usize = 56;
 
 /// 1) Validate an environment flag using bitwise checks:
 ///    We require that certain bits in environment_flag are set (bitmask check).
@@ -58,22 +410,170 @@ fn validate_environment(environment_flag: u32, required_mask: u32) -> bool {
 
 /// 2) Compute a "data lineage hash" using BigUint for complexity:
 ///    We'll mix the product_id, environment_flag, quality_score, and a prime factor in a large product.
+///    The manufacturer code is added in as its own term, see MANUFACTURER_MULTIPLIER.
 fn compute_data_lineage_hash(
     product_id: u64,
     environment_flag: u32,
     quality_score: u64,
 ) -> BigUint {
-    let product_id_big = product_id.to_biguint().unwrap_or(BigUint::zero());
+    let (manufacturer, serial) = split_product_id(product_id);
+    let serial_big = serial.to_biguint().unwrap_or(BigUint::zero());
+    let manufacturer_big = BigUint::from(manufacturer) * MANUFACTURER_MULTIPLIER;
     let env_big = environment_flag.to_biguint().unwrap_or(BigUint::zero());
     let quality_big = quality_score.to_biguint().unwrap_or(BigUint::zero());
     let prime_thing = 104729u64.to_biguint().unwrap(); // 104729 is a known prime
 
-    // lineage_hash = (product_id_big << 3) * (env_big + 1) * (quality_big + 1) * prime
+    // lineage_hash = ((serial_big << 3) * (env_big + 1) * (quality_big + 1) + manufacturer) * prime
     // Using shifts, additions for complexity.
-    let shifted = &product_id_big << 3;
+    let shifted = &serial_big << 3;
     let mixed_env = &env_big + 1u32;
     let mixed_quality = &quality_big + 1u32;
-    &shifted * &mixed_env * &mixed_quality * &prime_thing
+    (&shifted * &mixed_env * &mixed_quality + &manufacturer_big) * &prime_thing
+}
+
+/// A product id's manufacturer code and unit serial, see MANUFACTURER_SHIFT.
+fn split_product_id(product_id: u64) -> (u16, u64) {
+    (
+        (product_id >> MANUFACTURER_SHIFT) as u16,
+        product_id & SERIAL_MASK,
+    )
+}
+
+/// A manufacturer's trust weight in percent, or None if it isn't registered.
+fn manufacturer_weight(manufacturer: u16) -> Option<u64> {
+    MANUFACTURERS
+        .iter()
+        .find(|&&(code, _)| code == manufacturer)
+        .map(|&(_, weight)| weight)
+}
+
+/// How likely a record is to be counterfeit, from 0 to 100, see COUNTERFEIT_MATCHED_BITS.
+fn counterfeit_score(
+    product_id: u64,
+    environment_flag: u32,
+    certification_bitmask: u32,
+    quality_score: u64,
+) -> u32 {
+    let mut score = 0;
+    if certification_bitmask & !environment_flag & COUNTERFEIT_MATCHED_BITS != 0 {
+        score += COUNTERFEIT_UNMATCHED_CERT_WEIGHT;
+    }
+    if quality_score > COUNTERFEIT_IMPROBABLE_QUALITY
+        && certification_bitmask.count_ones() < COUNTERFEIT_MIN_CERTIFICATIONS
+    {
+        score += COUNTERFEIT_IMPROBABLE_QUALITY_WEIGHT;
+    }
+    if split_product_id(product_id).0 == COUNTERFEIT_MANUFACTURER {
+        score += COUNTERFEIT_MANUFACTURER_WEIGHT;
+    }
+    score
+}
+
+impl IndustryProfile {
+    /// The final combination with this profile's weights, see HashVersion::combine.
+    fn combine(
+        &self,
+        hash_version: HashVersion,
+        lineage_hash: &BigUint,
+        env_valid: bool,
+        cert_transform: u32,
+        quality_score: u64,
+        attestations: u64,
+    ) -> u64 {
+        hash_version.combine(
+            lineage_hash,
+            env_valid,
+            cert_transform.wrapping_mul(self.certification_weight),
+            quality_score.wrapping_mul(self.quality_weight),
+            attestations,
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TransportMode {
+    Road,
+    Rail,
+    Sea,
+    Air,
+}
+
+impl TransportMode {
+    fn from_u32(transport_mode: u32) -> Option<Self> {
+        match transport_mode {
+            TRANSPORT_ROAD => Some(TransportMode::Road),
+            TRANSPORT_RAIL => Some(TransportMode::Rail),
+            TRANSPORT_SEA => Some(TransportMode::Sea),
+            TRANSPORT_AIR => Some(TransportMode::Air),
+            _ => None,
+        }
+    }
+
+    /// The quality score left after shipping this way, capped at `claimed_quality` for rail.
+    fn adjust_quality(
+        self,
+        quality_score: u64,
+        claimed_quality: u64,
+        environment_flag: u32,
+    ) -> u64 {
+        let quality = u128::from(quality_score);
+        let denominator = u128::from(BPS_DENOMINATOR);
+        match self {
+            TransportMode::Rail => {
+                let bonus =
+                    quality * u128::from(BPS_DENOMINATOR + TRANSPORT_RAIL_BONUS_BPS) / denominator;
+                bonus.min(u128::from(claimed_quality.max(quality_score))) as u64
+            }
+            TransportMode::Sea if environment_flag & HUMIDITY_CONTROLLED_ENVIRONMENT_BIT == 0 => {
+                (quality * u128::from(BPS_DENOMINATOR - TRANSPORT_SEA_PENALTY_BPS) / denominator)
+                    as u64
+            }
+            _ => quality_score,
+        }
+    }
+
+    fn emissions_surcharge(self) -> u64 {
+        match self {
+            TransportMode::Air => TRANSPORT_AIR_EMISSIONS_SURCHARGE,
+            _ => 0,
+        }
+    }
+}
+
+/// The lineage hash of a unit, or of a batch of `batch_units` units: a batch's hash is
+/// also multiplied by (unit count + 1), so batches with the same mean but a different
+/// size don't collide. The transport's emissions surcharge is added on top.
+fn granular_lineage_hash(
+    product_id: u64,
+    environment_flag: u32,
+    quality_score: u64,
+    batch_units: Option<u64>,
+    hash_version: HashVersion,
+    transport: TransportMode,
+) -> BigUint {
+    let lineage_hash = hash_version.lineage_hash(product_id, environment_flag, quality_score);
+    let lineage_hash = match batch_units {
+        Some(unit_count) => lineage_hash * (BigUint::from(unit_count) + 1u32),
+        None => lineage_hash,
+    };
+    lineage_hash + transport.emissions_surcharge()
+}
+
+/// 2b) The V2 lineage hash, see HASH_V2_ROUND_CONSTANTS. Unlike the plain product,
+///     moving a factor from one input to another changes it.
+fn compute_data_lineage_hash_v2(
+    product_id: u64,
+    environment_flag: u32,
+    quality_score: u64,
+) -> BigUint {
+    let inputs = [product_id, u64::from(environment_flag), quality_score];
+    let mut running = BigUint::one();
+    for (input, round_constant) in inputs.iter().zip(HASH_V2_ROUND_CONSTANTS) {
+        running = running * (BigUint::from(*input) + 1u32) * 104729u32 + round_constant;
+        let low = lower_64(&running);
+        running = (running >> 64u32 << 64u32) + low.rotate_left(HASH_V2_ROTATION);
+    }
+    running
 }
 
 /// 3) Further transformations on the certification bitmask:
@@ -91,6 +591,116 @@ fn transform_certification_bitmask(cert_mask: u32) -> u32 {
     combined ^ pop
 }
 
+/// Clear the certifications whose prerequisite is missing, see CERT_DEPENDENCIES.
+/// Returns the effective mask and how many certifications were cleared.
+fn enforce_cert_dependencies(cert_mask: u32) -> (u32, u32) {
+    let mut effective = cert_mask;
+    let mut violations = 0;
+    for &(dependent, prerequisite) in CERT_DEPENDENCIES.iter() {
+        if effective & 1 << dependent != 0 && effective & 1 << prerequisite == 0 {
+            effective &= !(1 << dependent);
+            violations += 1;
+        }
+    }
+    (effective, violations)
+}
+
+/// The lower 64 bits of a lineage hash.
+fn lower_64(lineage_hash: &BigUint) -> u64 {
+    let digits = lineage_hash.to_u64_digits();
+    if !digits.is_empty() {
+        digits[0]
+    } else {
+        0
+    }
+}
+
+/// The lowest two 64-bit limbs of a lineage hash.
+fn lower_128(lineage_hash: &BigUint) -> (u64, u64) {
+    let digits = lineage_hash.to_u64_digits();
+    (
+        digits.first().copied().unwrap_or(0),
+        digits.get(1).copied().unwrap_or(0),
+    )
+}
+
+/// The certification bits still valid `age` seconds after issue, see SECONDS_PER_YEAR.
+fn unexpired_certifications(cert_mask: u32, age: u64) -> u32 {
+    let mut expired = 0;
+    if age >= SECONDS_PER_YEAR {
+        expired |= CERT_ONE_YEAR_MASK;
+    }
+    if age >= 2 * SECONDS_PER_YEAR {
+        expired |= CERT_TWO_YEAR_MASK;
+    }
+    cert_mask & !expired
+}
+
+/// Weakest-link trust: the mean of the used tiers' scores with the lowest one counted
+/// twice, scaled to 0-100, see TRUST_TIERS.
+fn aggregate_trust(scores: u32) -> u32 {
+    let used = (0..TRUST_TIERS)
+        .map(|tier| (scores >> (8 * tier)) & 0xFF)
+        .filter(|&score| score != 0);
+    let (count, sum, min) = used.fold((0, 0, TRUST_SCORE_MAX), |(count, sum, min), score| {
+        (count + 1, sum + score, min.min(score))
+    });
+    if count == 0 {
+        return TRUST_FULL;
+    }
+    (sum + min) * TRUST_FULL / ((count + 1) * TRUST_SCORE_MAX)
+}
+
+/// Whether goods may ship from `origin` to `destination`, see ALLOWED_ROUTES.
+fn is_allowed_route(origin: u32, destination: u32) -> bool {
+    if origin == REGION_UNKNOWN || destination == REGION_UNKNOWN {
+        return false;
+    }
+    origin == destination || ALLOWED_ROUTES.contains(&(origin, destination))
+}
+
+fn is_sanctioned_route(origin: u32, destination: u32) -> bool {
+    SANCTIONED_REGIONS.contains(&origin) || SANCTIONED_REGIONS.contains(&destination)
+}
+
+/// The quality score left after `hops` transfers and `days` on the road, see
+/// TRANSIT_HOP_LOSS_BPS.
+fn degrade_quality(quality_score: u64, hops: u32, days: u32, environment_flag: u32) -> u64 {
+    let day_loss_bps = if environment_flag & REFRIGERATED_ENVIRONMENT_BIT != 0 {
+        TRANSIT_DAY_LOSS_BPS / 2
+    } else {
+        TRANSIT_DAY_LOSS_BPS
+    };
+    let loss_bps = u64::from(hops) * TRANSIT_HOP_LOSS_BPS + u64::from(days) * day_loss_bps;
+    let kept_bps = BPS_DENOMINATOR
+        .saturating_sub(loss_bps)
+        .max(TRANSIT_FLOOR_BPS);
+
+    // Multiply before dividing, in 128 bits so the full score range fits
+    let degraded = u128::from(quality_score) * u128::from(kept_bps) / u128::from(BPS_DENOMINATOR);
+    (degraded as u64).max(quality_score.min(1))
+}
+
+/// The quality score left `age_days` into a product's shelf life, see SECONDS_PER_DAY.
+fn decay_quality_by_age(quality_score: u64, age_days: u64, shelf_life_days: u32) -> u64 {
+    if shelf_life_days == NO_SHELF_LIFE {
+        return quality_score;
+    }
+    let shelf_life = u128::from(shelf_life_days);
+    let quality = u128::from(quality_score);
+    let age = u128::from(age_days);
+    // Multiply before dividing so short shelf lives keep their slope
+    let decayed = if age <= shelf_life {
+        quality * (2 * shelf_life - age) / (2 * shelf_life)
+    } else if age <= 2 * shelf_life {
+        let remaining = 2 * shelf_life - age;
+        quality * remaining * remaining / (2 * shelf_life * shelf_life)
+    } else {
+        0
+    };
+    decayed as u64
+}
+
 /// 4) Combine results (like a final integrity check) using bitwise manipulations
 ///    For demonstration, let's create a final 64-bit value mixing lineage hash and partial fallback logic.
 fn combine_final(
@@ -98,10 +708,10 @@ fn combine_final(
     env_valid: bool,
     cert_transform: u32,
     quality_score: u64,
+    attestations: u64,
 ) -> u64 {
     // Convert BigUint hash to u64 by taking the lower 64 bits
-    let lower_64 = lineage_hash.to_u64_digits();
-    let lineage_lo = if !lower_64.is_empty() { lower_64[0] } else { 0 };
+    let lineage_lo = lower_64(lineage_hash);
 
     // Some bitwise manipulations:
     let validity_bit = if env_valid { 1u64 } else { 0u64 };
@@ -113,76 +723,2723 @@ fn combine_final(
     // Then combine everything via XOR
     let x = rotate_left_7 ^ validity_bit;
     let y = masked_cert ^ quality_score;
-    x ^ y
+    x ^ y ^ attestations
+}
+
+/// 4b) The V2 final combination: the same values as `combine_final`, folded FNV-1a style so
+///     equal values don't cancel out.
+fn combine_final_v2(
+    lineage_hash: &BigUint,
+    env_valid: bool,
+    cert_transform: u32,
+    quality_score: u64,
+    attestations: u64,
+) -> u64 {
+    [
+        lower_64(lineage_hash),
+        u64::from(env_valid),
+        u64::from(cert_transform),
+        quality_score,
+        attestations,
+    ]
+    .iter()
+    .fold(FNV_OFFSET_BASIS, |hash, &value| {
+        (hash ^ value).wrapping_mul(FNV_PRIME)
+    })
 }
 
-// Partial fallback: if environment isn't valid, we attempt to shift environment flags or reduce quality
-// until valid or we run out of tries.
+/// Producer and inspector sign-offs in a sign-off mask, see SIGNOFF_PRODUCER_MASK.
+fn attestation_counts(signoff_mask: u32) -> (u32, u32) {
+    let producers = (signoff_mask & SIGNOFF_PRODUCER_MASK).count_ones();
+    let inspectors = (signoff_mask >> SIGNOFF_INSPECTOR_SHIFT).count_ones();
+    (producers, inspectors)
+}
+
+/// Partial fallback for a record that failed a check. An environment missing only soft
+/// bits is still accepted: one missing soft bit is covered by quality of at least
+/// FALLBACK_COMPENSATING_QUALITY, otherwise each missing soft bit costs the hashed quality
+/// FALLBACK_WAIVER_PENALTY_PERCENT. Any missing hard bit gives up with a digest of 0. A
+/// record that failed on something other than its environment is hashed as it is. Returns
+/// the digest and the STATUS_FALLBACK_* reason.
+#[allow(clippy::too_many_arguments)]
 fn partial_fallback(
     environment_flag: u32,
     quality_score: u64,
     product_id: u64,
     certification_bitmask: u32,
-    attempts: u32,
-) -> u64 {
-    if attempts == 0 {
+    required_mask: u32,
+    batch_units: Option<u64>,
+    hash_version: HashVersion,
+    attestations: u64,
+    profile: &IndustryProfile,
+    transport: TransportMode,
+) -> (u64, u64) {
+    let missing = required_mask & !environment_flag;
+    let (quality_score, reason) = if missing == 0 {
+        (quality_score, STATUS_FALLBACK_OTHER_CHECK)
+    } else if missing & !SOFT_ENVIRONMENT_MASK != 0 {
+        return (0, STATUS_FALLBACK_GAVE_UP);
+    } else if missing.count_ones() == 1 && quality_score >= FALLBACK_COMPENSATING_QUALITY {
+        (quality_score, STATUS_FALLBACK_COMPENSATED)
+    } else {
+        // At most 50% with two soft bits, so the penalty never wipes the score out
+        let penalty = u64::from(missing.count_ones()) * FALLBACK_WAIVER_PENALTY_PERCENT;
+        let kept = (u128::from(quality_score) * u128::from(100 - penalty) / 100) as u64;
+        (kept, STATUS_FALLBACK_WAIVED)
+    };
+
+    // For a batch the score is the mean, so a penalty costs every unit the same share a lone
+    // unit would lose.
+    let lineage_hash = granular_lineage_hash(
+        product_id,
+        environment_flag,
+        quality_score,
+        batch_units,
+        hash_version,
+        transport,
+    );
+    let transform = transform_certification_bitmask(certification_bitmask);
+    let digest = profile.combine(
+        hash_version,
+        &lineage_hash,
+        true,
+        transform,
+        quality_score,
+        attestations,
+    );
+    (digest, reason)
+}
+
+/// One custody point along a product's chain, see LINEAGE_HOP_SIZE.
+struct LineageHop {
+    environment_flag: u32,
+    certification_bitmask: u32,
+    quality_score: u64,
+}
+
+impl LineageHop {
+    fn from_le_bytes(bytes: &[u8]) -> LineageHop {
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut quality = [0u8; 8];
+        quality.copy_from_slice(&bytes[8..16]);
+        LineageHop {
+            environment_flag: u32_at(0),
+            certification_bitmask: u32_at(4),
+            quality_score: u64::from_le_bytes(quality),
+        }
+    }
+}
+
+/// 5) Fold a whole chain of custody into one digest: the running lineage hash takes each
+///    hop's hash in order, and the final mix uses the XOR of every hop's certification
+///    transform and quality score, after CERT_DEPENDENCIES. Hops that fail
+///    `validate_environment` still count, but are flagged above the digest, see
+///    LINEAGE_FAILURE_SHIFT. Hops carry no sign-offs.
+fn lineage_chain_digest(product_id: u64, hops: &[LineageHop]) -> u64 {
+    if hops.is_empty() {
+        return LINEAGE_EMPTY_CHAIN;
+    }
+    let multiplier = LINEAGE_CHAIN_MULTIPLIER
+        .to_biguint()
+        .unwrap_or(BigUint::one());
+    let chain_mask = (BigUint::one() << LINEAGE_CHAIN_BITS) - 1u32;
+
+    let mut running_hash = BigUint::zero();
+    let mut cert_transform = 0u32;
+    let mut quality_score = 0u64;
+    let mut failures = 0u64;
+    let mut any_failure = false;
+    for (i, hop) in hops.iter().enumerate() {
+        if !validate_environment(hop.environment_flag, REQUIRED_ENVIRONMENT_MASK) {
+            failures |= 1 << (i % LINEAGE_FAILURE_BITS as usize);
+            any_failure = true;
+        }
+        let hop_hash =
+            compute_data_lineage_hash(product_id, hop.environment_flag, hop.quality_score);
+        running_hash = (&running_hash * &multiplier + hop_hash) & &chain_mask;
+        let (certification, _) = enforce_cert_dependencies(hop.certification_bitmask);
+        cert_transform ^= transform_certification_bitmask(certification);
+        quality_score ^= hop.quality_score;
+    }
+
+    let digest = combine_final(
+        &running_hash,
+        !any_failure,
+        cert_transform,
+        quality_score,
+        0,
+    );
+    let any_failure_bit = if any_failure {
+        LINEAGE_ANY_FAILURE_BIT
+    } else {
+        0
+    };
+    digest & DIGEST_MASK | failures << LINEAGE_FAILURE_SHIFT | any_failure_bit
+}
+
+/// Digest a chain of `hop_count` custody hops at `hops_ptr`, see `lineage_chain_digest`.
+/// Returns LINEAGE_EMPTY_CHAIN for no hops, or 0 if the hops are out of memory range.
+///
+/// # Safety
+///
+/// `hops_ptr` must be 0 or point to `hop_count` readable hops.
+#[no_mangle]
+pub unsafe fn lineage_chain(product_id: u64, hops_ptr: u32, hop_count: u32) -> u64 {
+    if hop_count == 0 {
+        return LINEAGE_EMPTY_CHAIN;
+    }
+    let Some(len) = (hop_count as usize).checked_mul(LINEAGE_HOP_SIZE) else {
+        return 0;
+    };
+    let Some(bytes) = read_bytes(hops_ptr, len) else {
+        return 0;
+    };
+    let hops: Vec<LineageHop> = bytes
+        .chunks_exact(LINEAGE_HOP_SIZE)
+        .map(LineageHop::from_le_bytes)
+        .collect();
+    lineage_chain_digest(product_id, &hops)
+}
+
+/// Mix two Merkle children into their parent, see MERKLE_ROTATION.
+fn merkle_mix(left: u64, right: u64) -> u64 {
+    (left.rotate_left(MERKLE_ROTATION) ^ right).wrapping_mul(MERKLE_MULTIPLIER)
+}
+
+/// 6) Merkle root over the leaves, pairing them level by level and duplicating the last
+///    node of an odd level. A lone leaf is still mixed with its duplicate, so the root
+///    never equals a leaf. Returns 0 for no leaves.
+fn merkle_root(leaves: &[u64]) -> u64 {
+    let mut level = leaves.to_vec();
+    while let Some(&last) = level.last() {
+        if level.len() % 2 == 1 {
+            level.push(last);
+        }
+        level = level
+            .chunks_exact(2)
+            .map(|pair| merkle_mix(pair[0], pair[1]))
+            .collect();
+        if level.len() == 1 {
+            return level[0];
+        }
+    }
+    0
+}
+
+/// The root an inclusion proof leads to from `leaf`, one (sibling, side) step per level.
+fn merkle_path_root(leaf: u64, path: &[(u64, u64)]) -> u64 {
+    path.iter().fold(leaf, |node, &(sibling, side)| {
+        if side == MERKLE_SIBLING_LEFT {
+            merkle_mix(sibling, node)
+        } else {
+            merkle_mix(node, sibling)
+        }
+    })
+}
+
+/// Commit to `count` products at `products_ptr` (see MERKLE_PRODUCT_SIZE) at once: each
+/// leaf is the lower 64 bits of the product's lineage hash. Returns the Merkle root, or 0
+/// for no products or if they are out of memory range.
+///
+/// # Safety
+///
+/// `products_ptr` must be 0 or point to `count` readable products.
+#[no_mangle]
+pub unsafe fn batch_merkle_root(products_ptr: u32, count: u32) -> u64 {
+    let Some(len) = (count as usize).checked_mul(MERKLE_PRODUCT_SIZE) else {
+        return 0;
+    };
+    if len == 0 {
         return 0;
     }
+    let Some(bytes) = read_bytes(products_ptr, len) else {
+        return 0;
+    };
+    let leaves: Vec<u64> = bytes
+        .chunks_exact(MERKLE_PRODUCT_SIZE)
+        .map(|product| {
+            let mut product_id = [0u8; 8];
+            let mut environment_flag = [0u8; 4];
+            let mut quality_score = [0u8; 8];
+            product_id.copy_from_slice(&product[..8]);
+            environment_flag.copy_from_slice(&product[8..12]);
+            quality_score.copy_from_slice(&product[12..20]);
+            lower_64(&compute_data_lineage_hash(
+                u64::from_le_bytes(product_id),
+                u32::from_le_bytes(environment_flag),
+                u64::from_le_bytes(quality_score),
+            ))
+        })
+        .collect();
+    merkle_root(&leaves)
+}
 
-    // Try shifting environment_flag left by 1 to set new bits:
-    let new_env = environment_flag << 1;
-    if validate_environment(new_env, 0b101) {
-        let lineage_hash = compute_data_lineage_hash(product_id, new_env, quality_score);
-        let transform = transform_certification_bitmask(certification_bitmask);
-        return combine_final(&lineage_hash, true, transform, quality_score);
+/// Check an inclusion proof of `path_len` steps at `path_ptr` (see MERKLE_PATH_STEP_SIZE):
+/// returns 1 if it leads from `leaf` to `root`, 0 if not or if it's out of memory range.
+///
+/// # Safety
+///
+/// `path_ptr` must be 0 or point to `path_len` readable steps.
+#[no_mangle]
+pub unsafe fn verify_merkle_path(leaf: u64, path_ptr: u32, path_len: u32, root: u64) -> u64 {
+    let Some(len) = (path_len as usize).checked_mul(MERKLE_PATH_STEP_SIZE) else {
+        return 0;
+    };
+    let bytes = if len == 0 {
+        Vec::new()
     } else {
-        // Attempt halving the quality score
-        let half_quality = safe_div_u64(quality_score, 2);
-        if validate_environment(environment_flag, 0b101) && half_quality > 0 {
-            let lineage_hash =
-                compute_data_lineage_hash(product_id, environment_flag, half_quality);
-            let transform = transform_certification_bitmask(certification_bitmask);
-            return combine_final(&lineage_hash, true, transform, half_quality);
-        }
-        // Recurse with attempts - 1
-        partial_fallback(
-            new_env,
-            quality_score,
-            product_id,
-            certification_bitmask,
-            attempts - 1,
-        )
+        match read_bytes(path_ptr, len) {
+            Some(bytes) => bytes,
+            None => return 0,
+        }
+    };
+    let path: Vec<(u64, u64)> = bytes
+        .chunks_exact(MERKLE_PATH_STEP_SIZE)
+        .map(|step| {
+            let mut sibling = [0u8; 8];
+            let mut side = [0u8; 8];
+            sibling.copy_from_slice(&step[..8]);
+            side.copy_from_slice(&step[8..]);
+            (u64::from_le_bytes(sibling), u64::from_le_bytes(side))
+        })
+        .collect();
+    (merkle_path_root(leaf, &path) == root) as u64
+}
+
+/// The digest of child `child_index` of a lot split at `split_ratio_bps`, see
+/// LOT_SPLIT_CHILDREN. Returns 0 for a ratio that leaves a child empty or an unknown child.
+#[no_mangle]
+pub fn split_lineage(parent_hash_lo: u64, split_ratio_bps: u32, child_index: u32) -> u64 {
+    let ratio = u64::from(split_ratio_bps);
+    if ratio == 0 || ratio >= BPS_DENOMINATOR || child_index >= LOT_SPLIT_CHILDREN {
+        return 0;
     }
+    let share = if child_index == 0 {
+        ratio
+    } else {
+        BPS_DENOMINATOR - ratio
+    };
+    merkle_mix(
+        parent_hash_lo,
+        u64::from(child_index) << LOT_CHILD_INDEX_SHIFT | share,
+    )
 }
 
+/// The digest of two lots merged into one, weighted by their quantities, see
+/// LOT_SPLIT_CHILDREN. The lots are sorted first, so their order doesn't matter. Returns 0
+/// if both quantities are 0.
 #[no_mangle]
-pub fn main(
+pub fn merge_lineage(hash_a: u64, hash_b: u64, qty_a: u64, qty_b: u64) -> u64 {
+    let total = u128::from(qty_a) + u128::from(qty_b);
+    if total == 0 {
+        return 0;
+    }
+    let share = |qty: u64| (u128::from(qty) * u128::from(BPS_DENOMINATOR) / total) as u64;
+    let mut lots = [(hash_a, share(qty_a)), (hash_b, share(qty_b))];
+    lots.sort_unstable();
+    merkle_mix(
+        merkle_mix(lots[0].0, lots[0].1),
+        merkle_mix(lots[1].0, lots[1].1),
+    )
+}
+
+/// Write the audit record of a product to `out_ptr`, see PROVENANCE_RECORD_SIZE. Returns the
+/// bytes written, or 0 if memory is out of range.
+///
+/// # Safety
+///
+/// `out_ptr` must be 0 or point to PROVENANCE_RECORD_SIZE writable bytes.
+#[no_mangle]
+pub unsafe fn provenance_record(
     product_id: u64,
     environment_flag: u32,
     quality_score: u64,
     certification_bitmask: u32,
-) -> u64 {
-    // Step 1: Validate environment
-    let required_mask = 0b101; // bits 0 and 2 must be set
-    let env_valid = validate_environment(environment_flag, required_mask);
+    out_ptr: u32,
+) -> u32 {
+    let record = build_provenance_record(
+        product_id,
+        environment_flag,
+        quality_score,
+        certification_bitmask,
+    );
+    if write_bytes(out_ptr, &record) {
+        record.len() as u32
+    } else {
+        0
+    }
+}
+
+/// The checks `main` makes on a product alone, against REQUIRED_ENVIRONMENT_MASK, with the
+/// full 128 bits of its lineage hash. Only the status is set for an invalid product id.
+/// Transit and shelf-life degradation don't apply, see PROVENANCE_RECORD_SIZE.
+fn build_provenance_record(
+    product_id: u64,
+    environment_flag: u32,
+    quality_score: u64,
+    certification_bitmask: u32,
+) -> [u8; PROVENANCE_RECORD_SIZE] {
+    let mut record = [0u8; PROVENANCE_RECORD_SIZE];
+    let (manufacturer, _) = split_product_id(product_id);
+    if manufacturer == MANUFACTURER_INVALID {
+        record[32..40].copy_from_slice(&STATUS_INVALID_PRODUCT_ID.to_le_bytes());
+        return record;
+    }
+
+    let mut status = 0;
+    let weight = manufacturer_weight(manufacturer).unwrap_or_else(|| {
+        status |= STATUS_UNKNOWN_MANUFACTURER;
+        100 - MANUFACTURER_UNKNOWN_PENALTY_PERCENT
+    });
+    let effective_quality = (u128::from(quality_score) * u128::from(weight) / 100) as u64;
 
+    let env_valid = validate_environment(environment_flag, REQUIRED_ENVIRONMENT_MASK);
     if !env_valid {
-        // Attempt partial fallback
-        return partial_fallback(
-            environment_flag,
-            quality_score,
-            product_id,
-            certification_bitmask,
-            3,
-        );
+        let missing = (REQUIRED_ENVIRONMENT_MASK & !environment_flag)
+            .count_ones()
+            .min(STATUS_MISSING_ENVIRONMENT_MAX);
+        status |=
+            STATUS_ENVIRONMENT_FALLBACK | u64::from(missing) << STATUS_MISSING_ENVIRONMENT_SHIFT;
+    }
+    let (effective_certification, violations) = enforce_cert_dependencies(certification_bitmask);
+    status |= u64::from(violations) << STATUS_DEPENDENCY_VIOLATION_SHIFT;
+    let suspect = counterfeit_score(
+        product_id,
+        environment_flag,
+        certification_bitmask,
+        quality_score,
+    ) > COUNTERFEIT_SUSPECT_THRESHOLD;
+    if suspect {
+        status |= STATUS_SUSPECT;
     }
 
-    // Step 2: Compute data lineage hash
-    let lineage_hash = compute_data_lineage_hash(product_id, environment_flag, quality_score);
+    let lineage_hash = compute_data_lineage_hash(product_id, environment_flag, effective_quality);
+    let (hash_lo, hash_hi) = lower_128(&lineage_hash);
+    let cert_transform = transform_certification_bitmask(effective_certification);
+    let digest = combine_final(
+        &lineage_hash,
+        env_valid,
+        cert_transform,
+        effective_quality,
+        0,
+    );
+    let score = compute_provenance_score(
+        env_valid,
+        effective_certification.count_ones(),
+        effective_quality,
+        u32::from(suspect),
+    );
 
-    // Step 3: Transform certification bitmask
-    let cert_transform = transform_certification_bitmask(certification_bitmask);
+    record[..8].copy_from_slice(&hash_lo.to_le_bytes());
+    record[8..16].copy_from_slice(&hash_hi.to_le_bytes());
+    record[16..24].copy_from_slice(&digest.to_le_bytes());
+    record[24..32].copy_from_slice(&effective_quality.to_le_bytes());
+    record[32..40].copy_from_slice(&status.to_le_bytes());
+    record[40..44].copy_from_slice(&cert_transform.to_le_bytes());
+    record[44..48].copy_from_slice(&u32::from(env_valid).to_le_bytes());
+    record[48..52].copy_from_slice(&score.to_le_bytes());
+    record[52..].copy_from_slice(&effective_certification.to_le_bytes());
+    record
+}
 
-    // Step 4: Combine final
-    combine_final(&lineage_hash, env_valid, cert_transform, quality_score)
+/// Count the readings outside `min_temp..=max_temp`, all offset-encoded (see
+/// TEMPERATURE_OFFSET), and the longest run of them in a row. A reading right on a limit
+/// is inside the band.
+fn temperature_excursions(readings: &[u16], min_temp: u32, max_temp: u32) -> (u32, u32) {
+    let min_temp = i64::from(min_temp) - TEMPERATURE_OFFSET;
+    let max_temp = i64::from(max_temp) - TEMPERATURE_OFFSET;
+    let mut excursions = 0u32;
+    let mut run = 0u32;
+    let mut longest_run = 0u32;
+    for &reading in readings {
+        let temperature = i64::from(reading) - TEMPERATURE_OFFSET;
+        if temperature < min_temp || temperature > max_temp {
+            excursions += 1;
+            run += 1;
+            longest_run = longest_run.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    (excursions, longest_run)
+}
+
+/// Check a cold-chain product's temperature readings against the band, voiding
+/// CERT_COLD_CHAIN_BIT if there were too many excursions, then digest the product as one
+/// refrigerated `lineage_chain` hop with the certifications left and the in-band reading
+/// count as its quality. See COLD_CHAIN_VOID_BIT for the result.
+fn cold_chain_result(
+    readings: &[u16],
+    min_temp: u32,
+    max_temp: u32,
+    product_id: u64,
+    cert_mask: u32,
+) -> u64 {
+    let (excursions, longest_run) = temperature_excursions(readings, min_temp, max_temp);
+    let voided =
+        excursions > COLD_CHAIN_MAX_EXCURSIONS || longest_run > COLD_CHAIN_MAX_EXCURSION_RUN;
+    let hop = LineageHop {
+        environment_flag: REQUIRED_ENVIRONMENT_MASK | REFRIGERATED_ENVIRONMENT_BIT,
+        certification_bitmask: if voided {
+            cert_mask & !CERT_COLD_CHAIN_BIT
+        } else {
+            cert_mask
+        },
+        quality_score: (readings.len() as u64) - u64::from(excursions),
+    };
+    let digest = lineage_chain_digest(product_id, &[hop]);
+    let void_bit = if voided { COLD_CHAIN_VOID_BIT } else { 0 };
+    digest
+        | u64::from(excursions.min(COLD_CHAIN_COUNT_MAX)) << COLD_CHAIN_EXCURSIONS_SHIFT
+        | u64::from(longest_run.min(COLD_CHAIN_COUNT_MAX)) << COLD_CHAIN_RUN_SHIFT
+        | void_bit
+}
+
+/// Check a cold-chain product's `count` temperature readings at `readings_ptr` (see
+/// TEMPERATURE_READING_SIZE), see `cold_chain_result`. Returns 0 if the readings are out
+/// of memory range.
+///
+/// # Safety
+///
+/// `readings_ptr` must be 0 or point to `count` readable readings.
+#[no_mangle]
+pub unsafe fn check_cold_chain(
+    readings_ptr: u32,
+    count: u32,
+    min_temp: u32,
+    max_temp: u32,
+    product_id: u64,
+    cert_mask: u32,
+) -> u64 {
+    let Some(len) = (count as usize).checked_mul(TEMPERATURE_READING_SIZE) else {
+        return 0;
+    };
+    let bytes = if len == 0 {
+        Vec::new()
+    } else {
+        match read_bytes(readings_ptr, len) {
+            Some(bytes) => bytes,
+            None => return 0,
+        }
+    };
+    let readings: Vec<u16> = bytes
+        .chunks_exact(TEMPERATURE_READING_SIZE)
+        .map(|reading| u16::from_le_bytes([reading[0], reading[1]]))
+        .collect();
+    cold_chain_result(&readings, min_temp, max_temp, product_id, cert_mask)
+}
+
+/// The low byte of a lot's lineage hash, used to group lots for a recall.
+fn recall_bucket(lot_id: u64, environment_flag: u32) -> u64 {
+    lower_64(&compute_data_lineage_hash(
+        lot_id,
+        environment_flag,
+        RECALL_QUALITY_SCORE,
+    )) & 0xFF
+}
+
+/// Estimate a recall's blast radius, see RECALL_IN_RANGE_BIT. Returns 0 if the affected
+/// product is outside `lot_start..=lot_end`, which is empty if `lot_start > lot_end`.
+#[no_mangle]
+pub fn trace_recall(
+    lot_start: u64,
+    lot_end: u64,
+    affected_product_id: u64,
+    environment_flag: u32,
+) -> u64 {
+    if !(lot_start..=lot_end).contains(&affected_product_id) {
+        return 0;
+    }
+    if lot_end - lot_start >= RECALL_MAX_LOTS {
+        return RECALL_IN_RANGE_BIT | RECALL_RANGE_TOO_LARGE_BIT;
+    }
+    let bucket = recall_bucket(affected_product_id, environment_flag);
+    let neighbors = (lot_start..=lot_end)
+        .filter(|&lot| lot != affected_product_id)
+        .filter(|&lot| recall_bucket(lot, environment_flag) == bucket)
+        .count() as u64;
+    RECALL_IN_RANGE_BIT | neighbors
+}
+
+/// How far a record can be trusted, see SCORE_MAX. `hop_failures` counts the checks it
+/// failed besides its environment.
+fn compute_provenance_score(
+    env_valid: bool,
+    cert_popcount: u32,
+    quality_score: u64,
+    hop_failures: u32,
+) -> u32 {
+    let environment = if env_valid {
+        SCORE_ENVIRONMENT_POINTS
+    } else {
+        0
+    };
+    let certification = cert_popcount.min(u32::BITS) * SCORE_CERTIFICATION_POINTS / u32::BITS;
+    let quality = quality_score.min(SCORE_QUALITY_REFERENCE) * u64::from(SCORE_QUALITY_POINTS)
+        / SCORE_QUALITY_REFERENCE;
+    (environment + certification + quality as u32)
+        .saturating_sub(hop_failures.saturating_mul(SCORE_HOP_FAILURE_PENALTY))
+        .min(SCORE_MAX)
+}
+
+/// XOR every SCORED_STATUS_SHIFT-wide chunk of a digest together, so each of its bits
+/// still counts in the scored layout's shorter digest.
+fn fold_digest(digest: u64) -> u64 {
+    let mut folded = 0;
+    let mut rest = digest;
+    while rest != 0 {
+        folded ^= rest & SCORED_DIGEST_MASK;
+        rest >>= SCORED_STATUS_SHIFT;
+    }
+    folded
+}
+
+/// Pack a digest, its STATUS_* flags and its score into `main`'s default result, see
+/// SCORE_SHIFT.
+fn pack_scored(digest: u64, status: u64, score: u32) -> u64 {
+    fold_digest(digest) | status << SCORED_STATUS_SHIFT | u64::from(score) << SCORE_SHIFT
+}
+
+/// `main`'s result in the legacy layout: the record digested the way `main` did before it
+/// took any other input, the full 64 bits of `combine_final` over the whole product id with
+/// environment bits 0 and 2 required. An invalid environment gives 0: the original
+/// fallback only ever shifted the environment left, which can't bring bit 0 back.
+fn legacy_digest(
+    product_id: u64,
+    environment_flag: u32,
+    quality_score: u64,
+    certification_bitmask: u32,
+) -> u64 {
+    if !validate_environment(environment_flag, REQUIRED_ENVIRONMENT_MASK) {
+        return 0;
+    }
+    let lineage_hash = (BigUint::from(product_id) << 3u32)
+        * (BigUint::from(environment_flag) + 1u32)
+        * (BigUint::from(quality_score) + 1u32)
+        * 104729u32;
+    let cert_transform = transform_certification_bitmask(certification_bitmask);
+    combine_final(&lineage_hash, true, cert_transform, quality_score, 0)
+}
+
+/// Compare a computed result against the expected one, see VERIFY_MATCH_BIT.
+fn verify_digest(computed: u64, expected: u64) -> u64 {
+    let matched = if computed == expected {
+        VERIFY_MATCH_BIT
+    } else {
+        0
+    };
+    let difference = u64::from((computed ^ expected).count_ones());
+    matched | difference << VERIFY_DIFFERENCE_SHIFT | computed & VERIFY_RESULT_MASK
+}
+
+#[cfg_attr(not(test), no_mangle)]
+#[allow(clippy::too_many_arguments)]
+pub fn main(
+    product_id: u64,
+    environment_flag: u32,
+    quality_score: u64,
+    certification_bitmask: u32,
+    issued_at: u64,          // epoch seconds the certification was issued at
+    current_time: u64,       // epoch seconds now, not before issued_at
+    revoked_mask: u32,       // certification bits pulled since issue
+    supplier_scores: u32,    // trust per supply chain tier, see TRUST_TIERS
+    expected_digest: u64,    // result of an earlier run to verify against, 0 to just compute
+    origin_region: u32,      // where the product ships from, see REGION_UNKNOWN
+    destination_region: u32, // where it ships to
+    transit_hops: u32,       // transfers between carriers on the way
+    transit_days: u32,       // days on the road
+    required_mask: u32,      // bits environment_flag must have, 0 for no requirement
+    granularity: u32,        // see GRANULARITY_UNIT
+    unit_count: u64,         // units in a batch, ignored for a unit
+    hash_version: u32,       // see HASH_V1
+    signoff_mask: u32,       // see SIGNOFF_PRODUCER_MASK
+    legacy_layout: u32,      // nonzero for the original digest alone, see `legacy_digest`
+    produced_at: u64,        // epoch seconds the product was made, not after current_time
+    shelf_life_days: u32,    // see NO_SHELF_LIFE
+    industry: u32,           // see INDUSTRY_GENERIC
+    transport_mode: u32,     // see TRANSPORT_ROAD
+) -> u64 {
+    let result = if legacy_layout != 0 {
+        legacy_digest(
+            product_id,
+            environment_flag,
+            quality_score,
+            certification_bitmask,
+        )
+    } else {
+        let (digest, status, score) = provenance_result(
+            product_id,
+            environment_flag,
+            quality_score,
+            certification_bitmask,
+            issued_at,
+            current_time,
+            revoked_mask,
+            supplier_scores,
+            origin_region,
+            destination_region,
+            transit_hops,
+            transit_days,
+            required_mask,
+            granularity,
+            unit_count,
+            hash_version,
+            signoff_mask,
+            produced_at,
+            shelf_life_days,
+            industry,
+            transport_mode,
+        );
+        pack_scored(digest, status, score)
+    };
+    if expected_digest == 0 {
+        return result;
+    }
+    verify_digest(result, expected_digest)
+}
+
+/// The record's full digest, its STATUS_* flags and its score, for `main` to pack, see
+/// SCORE_SHIFT.
+#[allow(clippy::too_many_arguments)]
+fn provenance_result(
+    product_id: u64,
+    environment_flag: u32,
+    quality_score: u64,
+    certification_bitmask: u32,
+    issued_at: u64,
+    current_time: u64,
+    revoked_mask: u32,
+    supplier_scores: u32,
+    origin_region: u32,
+    destination_region: u32,
+    transit_hops: u32,
+    transit_days: u32,
+    required_mask: u32,
+    granularity: u32,
+    unit_count: u64,
+    hash_version: u32,
+    signoff_mask: u32,
+    produced_at: u64,
+    shelf_life_days: u32,
+    industry: u32,
+    transport_mode: u32,
+) -> (u64, u64, u32) {
+    if current_time < issued_at || current_time < produced_at {
+        return (0, STATUS_INVALID_TIMESTAMPS, 0);
+    }
+    let batch_units = match granularity {
+        GRANULARITY_UNIT => None,
+        GRANULARITY_BATCH if unit_count > 0 => Some(unit_count),
+        _ => return (0, STATUS_INVALID_MODE, 0),
+    };
+    let Some(hash_version) = HashVersion::from_u32(hash_version) else {
+        return (0, STATUS_INVALID_MODE, 0);
+    };
+    let (manufacturer, _) = split_product_id(product_id);
+    if manufacturer == MANUFACTURER_INVALID {
+        return (0, STATUS_INVALID_PRODUCT_ID, 0);
+    }
+    let mut status = 0;
+    let profile = match industry {
+        INDUSTRY_FOOD | INDUSTRY_PHARMA | INDUSTRY_ELECTRONICS | INDUSTRY_GENERIC => {
+            &INDUSTRY_PROFILES[industry as usize]
+        }
+        _ => {
+            status |= STATUS_UNKNOWN_INDUSTRY;
+            &INDUSTRY_PROFILES[INDUSTRY_GENERIC as usize]
+        }
+    };
+    let required_mask = required_mask | profile.required_mask;
+    let weight = manufacturer_weight(manufacturer).unwrap_or_else(|| {
+        status |= STATUS_UNKNOWN_MANUFACTURER;
+        100 - MANUFACTURER_UNKNOWN_PENALTY_PERCENT
+    });
+    let original_quality = quality_score;
+    let quality_score = (u128::from(quality_score) * u128::from(weight) / 100) as u64;
+    let quality_score =
+        degrade_quality(quality_score, transit_hops, transit_days, environment_flag);
+    let transport = TransportMode::from_u32(transport_mode).unwrap_or_else(|| {
+        status |= STATUS_UNKNOWN_TRANSPORT;
+        TransportMode::Road
+    });
+    let quality_score = transport.adjust_quality(quality_score, original_quality, environment_flag);
+
+    // Expired products still get a lineage hash at quality 0, the one recalls look for
+    let age_days = (current_time - produced_at) / SECONDS_PER_DAY;
+    if shelf_life_days != NO_SHELF_LIFE && age_days > 2 * u64::from(shelf_life_days) {
+        let lineage_hash = granular_lineage_hash(
+            product_id,
+            environment_flag,
+            0,
+            batch_units,
+            hash_version,
+            transport,
+        );
+        return (lower_64(&lineage_hash), status | STATUS_PRODUCT_EXPIRED, 0);
+    }
+    let quality_score = decay_quality_by_age(quality_score, age_days, shelf_life_days);
+
+    // Revoking a bit that isn't set changes nothing
+    let revoked = certification_bitmask & revoked_mask;
+    let active_certification = certification_bitmask & !revoked;
+    status |= u64::from(revoked.count_ones()) << STATUS_REVOCATION_COUNT_SHIFT;
+    if certification_bitmask != 0 && active_certification == 0 {
+        status |= STATUS_CERTIFICATION_VOID;
+    }
+    let live_certification =
+        unexpired_certifications(active_certification, current_time - issued_at);
+    let (effective_certification, violations) = enforce_cert_dependencies(live_certification);
+    status |= u64::from(violations) << STATUS_DEPENDENCY_VIOLATION_SHIFT;
+    let effective_certification = effective_certification & profile.relevant_certifications;
+
+    // Step 1: Validate environment
+    let env_valid = validate_environment(environment_flag, required_mask);
+    let fully_expired = active_certification != 0 && live_certification == 0;
+
+    // Weak suppliers make the lineage less trustworthy, and the weakest ones rule it out
+    let trust = aggregate_trust(supplier_scores);
+    let low_trust = trust < TRUST_LOW_THRESHOLD;
+    let mut hash_quality = if trust <= TRUST_REDUCED_THRESHOLD {
+        safe_div_u64(quality_score, 2)
+    } else {
+        quality_score
+    };
+
+    // Off-route shipments lose a quarter of their quality, sanctioned ones don't ship at all
+    let off_route = !is_allowed_route(origin_region, destination_region);
+    if off_route {
+        status |= STATUS_ROUTE_VIOLATION;
+        hash_quality -= safe_div_u64(hash_quality, 4);
+    }
+    let sanctioned = is_sanctioned_route(origin_region, destination_region);
+
+    // Records need sign-off from both sides of the trade
+    let (producers, inspectors) = attestation_counts(signoff_mask);
+    let missing_attestation =
+        producers < SIGNOFF_MIN_PRODUCERS || inspectors < SIGNOFF_MIN_INSPECTORS;
+    let attestations = u64::from(signoff_mask);
+
+    // Judged on the record as it was claimed, even if the environment checks out
+    let suspect = counterfeit_score(
+        product_id,
+        environment_flag,
+        certification_bitmask,
+        original_quality,
+    ) > COUNTERFEIT_SUSPECT_THRESHOLD;
+
+    let failed_checks = [
+        fully_expired,
+        low_trust,
+        off_route,
+        sanctioned,
+        missing_attestation,
+        suspect,
+    ]
+    .iter()
+    .filter(|&&failed| failed)
+    .count() as u32;
+    let score = compute_provenance_score(
+        env_valid,
+        effective_certification.count_ones(),
+        quality_score,
+        failed_checks,
+    );
+
+    if !env_valid || fully_expired || low_trust || sanctioned || missing_attestation || suspect {
+        if !env_valid {
+            status |= STATUS_ENVIRONMENT_FALLBACK;
+        }
+        let missing = (required_mask & !environment_flag)
+            .count_ones()
+            .min(STATUS_MISSING_ENVIRONMENT_MAX);
+        status |= u64::from(missing) << STATUS_MISSING_ENVIRONMENT_SHIFT;
+        if fully_expired {
+            status |= STATUS_CERTIFICATION_EXPIRED;
+        }
+        if low_trust {
+            status |= STATUS_LOW_TRUST;
+        }
+        if sanctioned {
+            status |= STATUS_SANCTIONED_REGION;
+        }
+        if missing_attestation {
+            status |= STATUS_MISSING_ATTESTATION;
+        }
+        if suspect {
+            status |= STATUS_SUSPECT;
+        }
+        let (fallback, reason) = partial_fallback(
+            environment_flag,
+            hash_quality,
+            product_id,
+            effective_certification,
+            required_mask,
+            batch_units,
+            hash_version,
+            attestations,
+            profile,
+            transport,
+        );
+        return (fallback, status | reason, score);
+    }
+
+    // Step 2: Compute data lineage hash
+    let lineage_hash = granular_lineage_hash(
+        product_id,
+        environment_flag,
+        hash_quality,
+        batch_units,
+        hash_version,
+        transport,
+    );
+
+    // Step 3: Transform certification bitmask, leaving out revoked, expired and unsupported
+    // certifications
+    let cert_transform = transform_certification_bitmask(effective_certification);
+
+    // Step 4: Combine final
+    let digest = profile.combine(
+        hash_version,
+        &lineage_hash,
+        env_valid,
+        cert_transform,
+        quality_score,
+        attestations,
+    );
+    (digest, status, score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two producers and one inspector, just enough for the sign-off quorum.
+    const QUORUM: u32 = 0b11 | 1 << SIGNOFF_INSPECTOR_SHIFT;
+
+    /// Every `main` input, defaulting to a fresh, domestic, road-shipped unit from manufacturer
+    /// 1 with quality 77, one permanent certification, the sign-off quorum and environment
+    /// bits 0 and 2, which it needs, in the scored layout.
+    struct MainArgs {
+        product_id: u64,
+        environment_flag: u32,
+        quality_score: u64,
+        certification_bitmask: u32,
+        issued_at: u64,
+        current_time: u64,
+        revoked_mask: u32,
+        supplier_scores: u32,
+        expected_digest: u64,
+        origin_region: u32,
+        destination_region: u32,
+        transit_hops: u32,
+        transit_days: u32,
+        required_mask: u32,
+        granularity: u32,
+        unit_count: u64,
+        hash_version: u32,
+        signoff_mask: u32,
+        legacy_layout: u32,
+        produced_at: u64,
+        shelf_life_days: u32,
+        industry: u32,
+        transport_mode: u32,
+    }
+
+    impl Default for MainArgs {
+        fn default() -> Self {
+            MainArgs {
+                product_id: 1 << MANUFACTURER_SHIFT | 9,
+                environment_flag: 0b101,
+                quality_score: 77,
+                certification_bitmask: 1 << 18,
+                issued_at: 0,
+                current_time: 0,
+                revoked_mask: 0,
+                supplier_scores: 0,
+                expected_digest: 0,
+                origin_region: 840,
+                destination_region: 840,
+                transit_hops: 0,
+                transit_days: 0,
+                required_mask: 0b101,
+                granularity: GRANULARITY_UNIT,
+                unit_count: 0,
+                hash_version: HASH_V1,
+                signoff_mask: QUORUM,
+                legacy_layout: 0,
+                produced_at: 0,
+                shelf_life_days: NO_SHELF_LIFE,
+                industry: INDUSTRY_GENERIC,
+                transport_mode: TRANSPORT_ROAD,
+            }
+        }
+    }
+
+    impl MainArgs {
+        fn run(&self) -> u64 {
+            main(
+                self.product_id,
+                self.environment_flag,
+                self.quality_score,
+                self.certification_bitmask,
+                self.issued_at,
+                self.current_time,
+                self.revoked_mask,
+                self.supplier_scores,
+                self.expected_digest,
+                self.origin_region,
+                self.destination_region,
+                self.transit_hops,
+                self.transit_days,
+                self.required_mask,
+                self.granularity,
+                self.unit_count,
+                self.hash_version,
+                self.signoff_mask,
+                self.legacy_layout,
+                self.produced_at,
+                self.shelf_life_days,
+                self.industry,
+                self.transport_mode,
+            )
+        }
+
+        /// The digest, status and score `main` packs, see `provenance_result`.
+        fn result(&self) -> (u64, u64, u32) {
+            provenance_result(
+                self.product_id,
+                self.environment_flag,
+                self.quality_score,
+                self.certification_bitmask,
+                self.issued_at,
+                self.current_time,
+                self.revoked_mask,
+                self.supplier_scores,
+                self.origin_region,
+                self.destination_region,
+                self.transit_hops,
+                self.transit_days,
+                self.required_mask,
+                self.granularity,
+                self.unit_count,
+                self.hash_version,
+                self.signoff_mask,
+                self.produced_at,
+                self.shelf_life_days,
+                self.industry,
+                self.transport_mode,
+            )
+        }
+    }
+
+    /// The digest and STATUS_* flags of the default record with these sign-offs.
+    fn signed_off(environment_flag: u32, hash_version: u32, signoff_mask: u32) -> (u64, u64) {
+        let (digest, status, _) = MainArgs {
+            environment_flag,
+            hash_version,
+            signoff_mask,
+            ..MainArgs::default()
+        }
+        .result();
+        (digest, status)
+    }
+
+    /// `main` for the default record, in either layout.
+    fn main_result(
+        environment_flag: u32,
+        quality_score: u64,
+        certification_bitmask: u32,
+        legacy_layout: u32,
+    ) -> u64 {
+        MainArgs {
+            environment_flag,
+            quality_score,
+            certification_bitmask,
+            legacy_layout,
+            ..MainArgs::default()
+        }
+        .run()
+    }
+
+    /// The STATUS_* flags of a result in the scored layout.
+    fn scored_status(result: u64) -> u64 {
+        (result >> SCORED_STATUS_SHIFT) & ((1 << (SCORE_SHIFT - SCORED_STATUS_SHIFT)) - 1)
+    }
+
+    #[test]
+    fn maximal_record_scores_100() {
+        assert_eq!(compute_provenance_score(true, 32, 10_000, 0), SCORE_MAX);
+        assert_eq!(compute_provenance_score(true, 32, u64::MAX, 0), SCORE_MAX);
+        assert_eq!(compute_provenance_score(true, 16, 5_000, 0), 70);
+        // Multiplied before dividing, so just short of the reference still earns 29 points
+        assert_eq!(compute_provenance_score(true, 0, 9_999, 0), 40 + 29);
+    }
+
+    #[test]
+    fn invalid_environment_caps_at_60() {
+        for cert_popcount in 0..=32 {
+            for quality_score in [0, 10_000, u64::MAX] {
+                assert!(compute_provenance_score(false, cert_popcount, quality_score, 0) <= 60);
+            }
+        }
+        assert_eq!(compute_provenance_score(false, 32, 10_000, 0), 60);
+        assert_eq!(main_result(0, 10_000, 0, 0) >> SCORE_SHIFT, 30);
+    }
+
+    #[test]
+    fn hop_failures_clamp_at_0() {
+        assert_eq!(compute_provenance_score(true, 32, 10_000, 3), 70);
+        assert_eq!(compute_provenance_score(true, 32, 10_000, 10), 0);
+        assert_eq!(compute_provenance_score(true, 32, 10_000, u32::MAX), 0);
+    }
+
+    #[test]
+    fn scored_layout_round_trips() {
+        let digest = 0xDEAD_BEEF_0123_4567;
+        let status = STATUS_LOW_TRUST | STATUS_UNKNOWN_TRANSPORT;
+        let packed = pack_scored(digest, status, 87);
+        assert_eq!(packed >> SCORE_SHIFT, 87);
+        assert_eq!(scored_status(packed), status);
+        assert_eq!(packed & SCORED_DIGEST_MASK, fold_digest(digest));
+        assert_eq!(pack_scored(0, 0, 0), 0);
+        // Every digest bit still counts, including the ones above the scored digest
+        for bit in 0..64 {
+            assert_ne!(pack_scored(digest ^ 1 << bit, status, 87), packed);
+        }
+        // main packs the record's own status and score
+        for environment_flag in [0, 0b101] {
+            for certification in [0, 0xFF, 1 << 5] {
+                let args = MainArgs {
+                    environment_flag,
+                    certification_bitmask: certification,
+                    ..MainArgs::default()
+                };
+                let (digest, status, score) = args.result();
+                assert_eq!(args.run(), pack_scored(digest, status, score));
+                assert_eq!(scored_status(args.run()), status);
+                assert!(score <= SCORE_MAX);
+            }
+        }
+    }
+
+    /// The little-endian field at `range` of a provenance record.
+    fn record_field(record: &[u8], range: core::ops::Range<usize>) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes[..range.len()].copy_from_slice(&record[range]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn provenance_record_fields_match_the_checks() {
+        let product_id = 1 << MANUFACTURER_SHIFT | 0xFFFF_FFFF_FFFF;
+        let (environment_flag, quality_score, certification) = (0b111, u64::MAX / 3, 0b10_0010);
+        let record =
+            build_provenance_record(product_id, environment_flag, quality_score, certification);
+
+        // Manufacturer 1 carries full weight
+        let effective_quality = quality_score;
+        let lineage_hash =
+            compute_data_lineage_hash(product_id, environment_flag, effective_quality);
+        let (hash_lo, hash_hi) = lower_128(&lineage_hash);
+        assert_eq!(record_field(&record, 0..8), hash_lo);
+        assert_eq!(record_field(&record, 8..16), hash_hi);
+        assert_ne!(hash_hi, 0);
+
+        let (effective_certification, violations) = enforce_cert_dependencies(certification);
+        let env_valid = validate_environment(environment_flag, REQUIRED_ENVIRONMENT_MASK);
+        let cert_transform = transform_certification_bitmask(effective_certification);
+        let suspect = counterfeit_score(product_id, environment_flag, certification, quality_score)
+            > COUNTERFEIT_SUSPECT_THRESHOLD;
+        let mut status = u64::from(violations) << STATUS_DEPENDENCY_VIOLATION_SHIFT;
+        if suspect {
+            status |= STATUS_SUSPECT;
+        }
+        assert_eq!(
+            record_field(&record, 16..24),
+            combine_final(
+                &lineage_hash,
+                env_valid,
+                cert_transform,
+                effective_quality,
+                0
+            )
+        );
+        assert_eq!(record_field(&record, 24..32), effective_quality);
+        assert_eq!(record_field(&record, 32..40), status);
+        assert_eq!(record_field(&record, 40..44), u64::from(cert_transform));
+        assert_eq!(record_field(&record, 44..48), u64::from(env_valid));
+        assert_eq!(
+            record_field(&record, 48..52),
+            u64::from(compute_provenance_score(
+                env_valid,
+                effective_certification.count_ones(),
+                effective_quality,
+                u32::from(suspect)
+            ))
+        );
+        assert_eq!(
+            record_field(&record, 52..56),
+            u64::from(effective_certification)
+        );
+
+        // Nowhere to write it natively
+        // Safety: a null out_ptr is never written to
+        assert_eq!(
+            unsafe {
+                provenance_record(
+                    product_id,
+                    environment_flag,
+                    quality_score,
+                    certification,
+                    0,
+                )
+            },
+            0
+        );
+    }
+
+    #[test]
+    fn provenance_record_weighs_unknown_manufacturers() {
+        let record = build_provenance_record(0x7777 << MANUFACTURER_SHIFT | 5, 0b101, 1000, 0);
+        assert_eq!(record_field(&record, 24..32), 800);
+        assert_ne!(
+            record_field(&record, 32..40) & STATUS_UNKNOWN_MANUFACTURER,
+            0
+        );
+        // A small lineage fits in one limb
+        let record = build_provenance_record(1 << MANUFACTURER_SHIFT | 1, 0, 1, 0);
+        assert_eq!(record_field(&record, 8..16), 0);
+        // An invalid product id only gets its status
+        let record = build_provenance_record(5, 0b101, 1000, 0);
+        assert_eq!(record_field(&record, 32..40), STATUS_INVALID_PRODUCT_ID);
+        assert!(record[..32]
+            .iter()
+            .chain(&record[40..])
+            .all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn signoff_quorum_met() {
+        assert_eq!(attestation_counts(QUORUM), (2, 1));
+        for hash_version in [HASH_V1, HASH_V2] {
+            assert_eq!(signed_off(0b101, hash_version, QUORUM).1, 0);
+            assert_eq!(signed_off(0b101, hash_version, u32::MAX).1, 0);
+        }
+    }
+
+    #[test]
+    fn missing_inspector_falls_back() {
+        assert_eq!(attestation_counts(0xFFFF), (16, 0));
+        assert_eq!(
+            signed_off(0b101, HASH_V1, 0xFFFF).1,
+            STATUS_MISSING_ATTESTATION
+        );
+        // Distinct from a failed environment, which adds its own flags
+        assert_eq!(
+            signed_off(0b001, HASH_V1, 0xFFFF).1,
+            STATUS_MISSING_ATTESTATION
+                | STATUS_ENVIRONMENT_FALLBACK
+                | 1 << STATUS_MISSING_ENVIRONMENT_SHIFT
+                | STATUS_FALLBACK_WAIVED
+        );
+    }
+
+    #[test]
+    fn missing_producer_falls_back() {
+        for signoff_mask in [0xFFFF_0001, 0xFFFF_0000, 0] {
+            assert_eq!(
+                signed_off(0b101, HASH_V1, signoff_mask).1,
+                STATUS_MISSING_ATTESTATION
+            );
+        }
+    }
+
+    #[test]
+    fn digest_depends_on_who_signed_off() {
+        for hash_version in [HASH_V1, HASH_V2] {
+            let base = signed_off(0b101, hash_version, QUORUM).0;
+            // Same counts, different parties
+            for signoff_mask in [
+                0b110 | 1 << 16,
+                0b11 | 1 << 17,
+                0b11 | 1 << 31,
+                1 << 15 | 1 | 1 << 16,
+            ] {
+                assert_ne!(base, signed_off(0b101, hash_version, signoff_mask).0);
+            }
+            // More of them
+            assert_ne!(base, signed_off(0b101, hash_version, 0b111 | 1 << 16).0);
+            assert_ne!(base, signed_off(0b101, hash_version, 0b11 | 0b11 << 16).0);
+        }
+        // V1 keeps every bit of the mask in the digest
+        let base = signed_off(0b101, HASH_V1, QUORUM).0;
+        for bit in 2..32 {
+            let other = signed_off(0b101, HASH_V1, QUORUM ^ 1 << bit).0;
+            assert_eq!(base ^ other, 1 << bit);
+        }
+    }
+
+    /// A custody hop with the required environment bits, or without bit 2 if not `valid`.
+    fn hop(valid: bool, certification_bitmask: u32, quality_score: u64) -> LineageHop {
+        LineageHop {
+            environment_flag: if valid { 0b101 } else { 0b001 },
+            certification_bitmask,
+            quality_score,
+        }
+    }
+
+    #[test]
+    fn lineage_chain_depends_on_hop_order() {
+        let pid = MainArgs::default().product_id;
+        let forward = lineage_chain_digest(pid, &[hop(true, 1 << 18, 77), hop(true, 0, 500)]);
+        let backward = lineage_chain_digest(pid, &[hop(true, 0, 500), hop(true, 1 << 18, 77)]);
+        assert_ne!(forward, backward);
+    }
+
+    #[test]
+    fn invalid_hop_is_recorded_not_fatal() {
+        let pid = MainArgs::default().product_id;
+        let valid = [hop(true, 0, 10), hop(true, 0, 20), hop(true, 0, 30)];
+        let one_invalid = [hop(true, 0, 10), hop(false, 0, 20), hop(true, 0, 30)];
+        let digest = lineage_chain_digest(pid, &one_invalid);
+        assert_ne!(digest, 0);
+        assert_ne!(digest, lineage_chain_digest(pid, &valid));
+        // The digest is left as it is, with hop 1 flagged above it
+        let running = [0b101, 0b001, 0b101].iter().zip([10, 20, 30]).fold(
+            BigUint::zero(),
+            |running, (&environment_flag, quality)| {
+                let multiplier = LINEAGE_CHAIN_MULTIPLIER.to_biguint().unwrap();
+                let mask = (BigUint::one() << LINEAGE_CHAIN_BITS) - 1u32;
+                (running * multiplier + compute_data_lineage_hash(pid, environment_flag, quality))
+                    & mask
+            },
+        );
+        let unmasked = combine_final(
+            &running,
+            false,
+            transform_certification_bitmask(0),
+            10 ^ 20 ^ 30,
+            0,
+        );
+        assert_eq!(
+            digest,
+            unmasked & DIGEST_MASK | 0b10 << LINEAGE_FAILURE_SHIFT | LINEAGE_ANY_FAILURE_BIT
+        );
+        assert_eq!(lineage_chain_digest(pid, &valid) >> STATUS_SHIFT, 0);
+    }
+
+    /// The hop indices set in a `lineage_chain_digest` result's failure mask.
+    fn failing_hop_bits(result: u64) -> Vec<u32> {
+        let mask = result >> LINEAGE_FAILURE_SHIFT & ((1 << LINEAGE_FAILURE_BITS) - 1);
+        (0..LINEAGE_FAILURE_BITS)
+            .filter(|bit| mask & 1 << bit != 0)
+            .collect()
+    }
+
+    #[test]
+    fn long_chain_failures_never_cancel() {
+        let pid = MainArgs::default().product_id;
+        let chain = |failing: &[usize]| {
+            let hops: Vec<_> = (0..40)
+                .map(|i| hop(!failing.contains(&i), 0, i as u64))
+                .collect();
+            lineage_chain_digest(pid, &hops)
+        };
+        // Hops 36 apart, and ones sharing a mask bit, used to cancel each other out
+        for failing in [[0, 36], [3, 13], [5, 35]] {
+            let result = chain(&failing);
+            assert_ne!(result & LINEAGE_ANY_FAILURE_BIT, 0, "{failing:?}");
+            assert!(!failing_hop_bits(result).is_empty(), "{failing:?}");
+            assert_ne!(result & DIGEST_MASK, chain(&[]) & DIGEST_MASK);
+        }
+        assert_eq!(chain(&[]) >> STATUS_SHIFT, 0);
+    }
+
+    #[test]
+    fn failing_hop_index_decodes_from_the_result() {
+        let pid = MainArgs::default().product_id;
+        let hops: Vec<_> = (0..45).map(|i| hop(i != 37, 0, 100 + i)).collect();
+        let result = lineage_chain_digest(pid, &hops);
+        assert_eq!(failing_hop_bits(result), [37 % LINEAGE_FAILURE_BITS]);
+        assert_ne!(result & LINEAGE_ANY_FAILURE_BIT, 0);
+        // Nothing the cold chain check adds overlaps the failure flags
+        let cold_chain_fields = 0xFFFF << COLD_CHAIN_EXCURSIONS_SHIFT | COLD_CHAIN_VOID_BIT;
+        assert_eq!(result & cold_chain_fields, 0);
+    }
+
+    #[test]
+    fn empty_lineage_chain_is_the_sentinel() {
+        assert_eq!(lineage_chain_digest(9, &[]), LINEAGE_EMPTY_CHAIN);
+        // Safety: null pointers are never read
+        unsafe {
+            assert_eq!(lineage_chain(9, 0, 0), LINEAGE_EMPTY_CHAIN);
+            // Hops that can't be read aren't mistaken for an empty chain
+            assert_eq!(lineage_chain(9, 0, 1), 0);
+        }
+    }
+
+    #[test]
+    fn one_hop_chain_matches_main() {
+        // Hops carry no sign-offs, so compare against a record without any
+        let args = MainArgs {
+            signoff_mask: 0,
+            ..MainArgs::default()
+        };
+        let chain = lineage_chain_digest(
+            args.product_id,
+            &[hop(true, args.certification_bitmask, args.quality_score)],
+        );
+        assert_eq!(chain, args.result().0 & DIGEST_MASK);
+    }
+
+    #[test]
+    fn lone_leaf_root_is_its_mix() {
+        assert_eq!(merkle_root(&[1]), merkle_mix(1, 1));
+        assert_eq!(merkle_root(&[1]), 0x7af7_1ef7_89ca_7c15);
+        assert_eq!(merkle_root(&[]), 0);
+    }
+
+    #[test]
+    fn merkle_roots_are_pinned() {
+        // The odd level of three leaves pairs the last one with itself
+        assert_eq!(
+            merkle_root(&[1, 2, 3]),
+            merkle_mix(merkle_mix(1, 2), merkle_mix(3, 3))
+        );
+        assert_eq!(merkle_root(&[1, 2, 3]), 0x691c_33e6_9d4b_5c6f);
+        assert_eq!(
+            merkle_root(&[1, 2, 3, 4]),
+            merkle_mix(merkle_mix(1, 2), merkle_mix(3, 4))
+        );
+        assert_eq!(merkle_root(&[1, 2, 3, 4]), 0x1947_e305_9d71_14f8);
+    }
+
+    #[test]
+    fn inclusion_proofs_check_against_the_root() {
+        let root = merkle_root(&[1, 2, 3, 4]);
+        // Leaf 3 is a left child, its parent a right one
+        let path = [(4, 0), (merkle_mix(1, 2), MERKLE_SIBLING_LEFT)];
+        assert_eq!(merkle_path_root(3, &path), root);
+        assert_ne!(merkle_path_root(4, &path), root);
+        let swapped = [
+            (4, MERKLE_SIBLING_LEFT),
+            (merkle_mix(1, 2), MERKLE_SIBLING_LEFT),
+        ];
+        assert_ne!(merkle_path_root(3, &swapped), root);
+        // An empty proof only holds for a leaf that is the root itself
+        // Safety: an empty path is never read
+        assert_eq!(unsafe { verify_merkle_path(root, 0, 0, root) }, 1);
+        assert_eq!(unsafe { verify_merkle_path(3, 0, 0, root) }, 0);
+    }
+
+    /// One certification from every expiry class, see SECONDS_PER_YEAR.
+    const EVERY_CERT_CLASS: u32 = 1 | 1 << 8 | 1 << 18;
+
+    /// `result` for a record certified with `certification_bitmask` at 0 and checked at
+    /// `current_time`.
+    fn certified_at(certification_bitmask: u32, current_time: u64) -> (u64, u64, u32) {
+        MainArgs {
+            certification_bitmask,
+            current_time,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn unexpired_certifications_keep_their_digest() {
+        let fresh = certified_at(EVERY_CERT_CLASS, 0);
+        assert_eq!(certified_at(EVERY_CERT_CLASS, SECONDS_PER_YEAR - 1), fresh);
+        assert_eq!(fresh.1 & STATUS_CERTIFICATION_EXPIRED, 0);
+    }
+
+    #[test]
+    fn one_year_class_expires_alone() {
+        assert_eq!(
+            unexpired_certifications(EVERY_CERT_CLASS, SECONDS_PER_YEAR),
+            1 << 8 | 1 << 18
+        );
+        let (digest, status, _) = certified_at(EVERY_CERT_CLASS, SECONDS_PER_YEAR);
+        assert_eq!(digest, certified_at(1 << 8 | 1 << 18, 0).0);
+        assert_ne!(digest, certified_at(EVERY_CERT_CLASS, 0).0);
+        assert_eq!(status & STATUS_CERTIFICATION_EXPIRED, 0);
+    }
+
+    #[test]
+    fn fully_expired_certification_falls_back() {
+        let expiring = 1 | 1 << 8;
+        assert_eq!(unexpired_certifications(expiring, 2 * SECONDS_PER_YEAR), 0);
+        let (_, status, _) = certified_at(expiring, 2 * SECONDS_PER_YEAR);
+        assert_ne!(status & STATUS_CERTIFICATION_EXPIRED, 0);
+        // The environment is fine, so it isn't reported as its failure
+        assert_eq!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        let (_, status, _) = MainArgs {
+            environment_flag: 0b001,
+            ..MainArgs::default()
+        }
+        .result();
+        assert_eq!(status & STATUS_CERTIFICATION_EXPIRED, 0);
+        assert_ne!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        // Certifications that never expire keep a record going however old it is
+        let (_, status, _) = certified_at(1 << 18, 50 * SECONDS_PER_YEAR);
+        assert_eq!(status & STATUS_CERTIFICATION_EXPIRED, 0);
+    }
+
+    #[test]
+    fn time_travel_is_rejected() {
+        let args = MainArgs {
+            issued_at: 10,
+            current_time: 5,
+            ..MainArgs::default()
+        };
+        assert_eq!(args.result(), (0, STATUS_INVALID_TIMESTAMPS, 0));
+        assert_eq!(args.run(), STATUS_INVALID_TIMESTAMPS << SCORED_STATUS_SHIFT);
+    }
+
+    /// `result` for the default record with these supplier trust scores.
+    fn trusted(supplier_scores: u32) -> (u64, u64, u32) {
+        MainArgs {
+            supplier_scores,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn no_suppliers_are_fully_trusted() {
+        assert_eq!(aggregate_trust(0), TRUST_FULL);
+        assert_eq!(trusted(0), MainArgs::default().result());
+    }
+
+    #[test]
+    fn one_weak_supplier_forces_the_fallback() {
+        // (255 + 10 + 10) * 100 / (3 * 255)
+        assert_eq!(aggregate_trust(0xFF_0A), 35);
+        let (_, status, _) = trusted(0xFF_0A);
+        assert_ne!(status & STATUS_LOW_TRUST, 0);
+        assert_eq!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        // The weak link counts double: three strong tiers only bring it up to reduced trust
+        assert_eq!(aggregate_trust(0xFFFF_FF0A), 61);
+        let (digest, status, _) = trusted(0xFFFF_FF0A);
+        assert_eq!(status & STATUS_LOW_TRUST, 0);
+        assert_ne!(digest, trusted(0).0);
+    }
+
+    #[test]
+    fn strong_suppliers_change_nothing() {
+        assert_eq!(aggregate_trust(0xFFFF_FFFF), TRUST_FULL);
+        assert_eq!(trusted(0xFFFF_FFFF), trusted(0));
+        // Just over the reduced-trust threshold: (3 * 200 + 2 * 170) * 100 / (5 * 255)
+        assert_eq!(aggregate_trust(0xC8C8_C8AA), 73);
+        assert_eq!(trusted(0xC8C8_C8AA), trusted(0));
+    }
+
+    #[test]
+    fn unused_tiers_are_ignored() {
+        assert_eq!(aggregate_trust(0x00_FF), TRUST_FULL);
+        assert_eq!(aggregate_trust(0xFF_00_00_FF), TRUST_FULL);
+        assert_eq!(aggregate_trust(0x0A_00), aggregate_trust(0x0A));
+        assert_eq!(trusted(0x00_00_FF_00), trusted(0));
+    }
+
+    #[test]
+    fn matching_digest_verifies() {
+        let computed = MainArgs::default().run();
+        let verified = MainArgs {
+            expected_digest: computed,
+            ..MainArgs::default()
+        }
+        .run();
+        assert_eq!(verified, VERIFY_MATCH_BIT | computed & VERIFY_RESULT_MASK);
+    }
+
+    #[test]
+    fn one_input_bit_flips_many_digest_bits() {
+        let tampered = MainArgs {
+            quality_score: 77 ^ 1,
+            ..MainArgs::default()
+        }
+        .run();
+        let computed = MainArgs::default().run();
+        let verified = MainArgs {
+            expected_digest: tampered,
+            ..MainArgs::default()
+        }
+        .run();
+        assert_eq!(verified & VERIFY_MATCH_BIT, 0);
+        let difference = (verified & !VERIFY_MATCH_BIT) >> VERIFY_DIFFERENCE_SHIFT;
+        assert_eq!(difference, u64::from((computed ^ tampered).count_ones()));
+        // About half of the 27 bits the digest folds into, the status and score being equal
+        assert_eq!(difference, 15);
+        assert_eq!(verified & VERIFY_RESULT_MASK, computed & VERIFY_RESULT_MASK);
+    }
+
+    #[test]
+    fn zero_expected_digest_passes_through() {
+        let args = MainArgs::default();
+        let (digest, status, score) = args.result();
+        assert_eq!(args.run(), pack_scored(digest, status, score));
+        let legacy = MainArgs {
+            legacy_layout: 1,
+            ..MainArgs::default()
+        };
+        assert_eq!(
+            legacy.run(),
+            legacy_digest(legacy.product_id, 0b101, 77, 1 << 18)
+        );
+    }
+
+    /// `result` for the default record shipped at quality 80.
+    fn shipped(origin_region: u32, destination_region: u32) -> (u64, u64, u32) {
+        MainArgs {
+            quality_score: 80,
+            origin_region,
+            destination_region,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn allowed_routes_keep_their_quality() {
+        assert!(is_allowed_route(840, 124));
+        let (digest, status, _) = shipped(840, 124);
+        assert_eq!(
+            status & (STATUS_ROUTE_VIOLATION | STATUS_SANCTIONED_REGION),
+            0
+        );
+        assert_eq!(digest, shipped(840, 840).0);
+    }
+
+    #[test]
+    fn disallowed_route_loses_a_quarter_of_its_quality() {
+        // Germany -> Italy is allowed, the way back isn't
+        assert!(!is_allowed_route(380, 276));
+        let (digest, status, _) = shipped(380, 276);
+        assert_eq!(
+            status & (STATUS_ROUTE_VIOLATION | STATUS_SANCTIONED_REGION),
+            STATUS_ROUTE_VIOLATION
+        );
+        // The lineage is hashed at 80 - 80 / 4, the final combination still sees 80
+        let lineage_hash = compute_data_lineage_hash(MainArgs::default().product_id, 0b101, 60);
+        let cert_transform = transform_certification_bitmask(1 << 18);
+        assert_eq!(
+            digest,
+            combine_final(&lineage_hash, true, cert_transform, 80, u64::from(QUORUM))
+        );
+    }
+
+    #[test]
+    fn sanctioned_origin_forces_the_fallback() {
+        assert!(is_sanctioned_route(900, 840));
+        let (_, status, _) = shipped(900, 840);
+        assert_ne!(status & STATUS_SANCTIONED_REGION, 0);
+        // The environment is fine, it's another check that sent it down the fallback path
+        assert_eq!(
+            status & STATUS_FALLBACK_GAVE_UP,
+            STATUS_FALLBACK_OTHER_CHECK
+        );
+        assert_ne!(shipped(900, 840).0, shipped(380, 276).0);
+    }
+
+    #[test]
+    fn unknown_region_is_a_violation_but_not_sanctioned() {
+        assert!(!is_allowed_route(REGION_UNKNOWN, 840));
+        assert!(!is_allowed_route(REGION_UNKNOWN, REGION_UNKNOWN));
+        assert!(!is_sanctioned_route(REGION_UNKNOWN, 840));
+        let (digest, status, _) = shipped(REGION_UNKNOWN, 840);
+        assert_eq!(
+            status & (STATUS_ROUTE_VIOLATION | STATUS_SANCTIONED_REGION),
+            STATUS_ROUTE_VIOLATION
+        );
+        // Hashed the same as any other off-route shipment
+        assert_eq!(digest, shipped(380, 276).0);
+    }
+
+    #[test]
+    fn no_transit_keeps_the_quality() {
+        assert_eq!(degrade_quality(12_345, 0, 0, 0), 12_345);
+        assert_eq!(
+            degrade_quality(u64::MAX, 0, 0, REFRIGERATED_ENVIRONMENT_BIT),
+            u64::MAX
+        );
+        // Five hops cost 10%, and the lineage is hashed as if the score had been that low
+        assert_eq!(degrade_quality(10_000, 5, 0, 0), 9_000);
+        let shipped = MainArgs {
+            quality_score: 10_000,
+            transit_hops: 5,
+            ..MainArgs::default()
+        };
+        let degraded = MainArgs {
+            quality_score: 9_000,
+            ..MainArgs::default()
+        };
+        assert_eq!(shipped.result().0, degraded.result().0);
+    }
+
+    #[test]
+    fn long_journey_hits_the_floor() {
+        assert_eq!(degrade_quality(10_000, 100, 0, 0), 1_000);
+        assert_eq!(degrade_quality(10_000, u32::MAX, u32::MAX, 0), 1_000);
+        // Too small a score to keep a tenth of still keeps 1
+        assert_eq!(degrade_quality(5, 100, 0, 0), 1);
+        assert_eq!(degrade_quality(0, 100, 0, 0), 0);
+    }
+
+    #[test]
+    fn refrigeration_halves_the_daily_loss() {
+        assert_eq!(degrade_quality(10_000, 0, 100, 0), 9_000);
+        assert_eq!(
+            degrade_quality(10_000, 0, 100, REFRIGERATED_ENVIRONMENT_BIT),
+            9_500
+        );
+        // Hops cost the same either way
+        assert_eq!(
+            degrade_quality(10_000, 5, 100, REFRIGERATED_ENVIRONMENT_BIT),
+            8_500
+        );
+    }
+
+    #[test]
+    fn degradation_floors_at_a_tenth() {
+        assert_eq!(degrade_quality(10_000, 44, 0, 0), 1_200);
+        assert_eq!(degrade_quality(10_000, 45, 0, 0), 1_000);
+        assert_eq!(degrade_quality(10_000, 45, 1, 0), 1_000);
+        assert_eq!(degrade_quality(10_000, 44, 19, 0), 1_010);
+        assert_eq!(degrade_quality(10_000, 44, 20, 0), 1_000);
+    }
+
+    #[test]
+    fn product_outside_the_range_isnt_traced() {
+        assert_eq!(trace_recall(10, 20, 9, 0), 0);
+        assert_eq!(trace_recall(10, 20, 21, 0), 0);
+        assert_ne!(trace_recall(10, 20, 10, 0), 0);
+        assert_ne!(trace_recall(10, 20, 20, 0), 0);
+    }
+
+    #[test]
+    fn recall_counts_lots_in_the_same_bucket() {
+        // Serial s of manufacturer 0 at environment 0 hashes to s * 8 * 104729, whose low
+        // byte is s * 200 mod 256, so lots share a bucket when their serials agree mod 32
+        assert_eq!(recall_bucket(5, 0), 5 * 200 % 256);
+        assert_eq!(trace_recall(1, 100, 5, 0), RECALL_IN_RANGE_BIT | 2);
+        assert_eq!(trace_recall(1, 68, 5, 0), RECALL_IN_RANGE_BIT | 1);
+        assert_eq!(trace_recall(5, 5, 5, 0), RECALL_IN_RANGE_BIT);
+    }
+
+    #[test]
+    fn oversized_recall_range_is_rejected() {
+        assert_eq!(
+            trace_recall(0, RECALL_MAX_LOTS, 7, 0),
+            RECALL_IN_RANGE_BIT | RECALL_RANGE_TOO_LARGE_BIT
+        );
+        assert_eq!(
+            trace_recall(0, u64::MAX, 7, 0),
+            RECALL_IN_RANGE_BIT | RECALL_RANGE_TOO_LARGE_BIT
+        );
+        // Exactly RECALL_MAX_LOTS lots still get counted
+        assert_eq!(
+            trace_recall(0, RECALL_MAX_LOTS - 1, 7, 0) & RECALL_RANGE_TOO_LARGE_BIT,
+            0
+        );
+    }
+
+    #[test]
+    fn reversed_recall_range_is_empty() {
+        assert_eq!(trace_recall(20, 10, 15, 0), 0);
+        assert_eq!(trace_recall(20, 10, 20, 0), 0);
+        assert_eq!(trace_recall(20, 10, 10, 0), 0);
+    }
+
+    /// `result` for the default record with this environment against `required_mask`.
+    fn required(environment_flag: u32, required_mask: u32) -> (u64, u64, u32) {
+        MainArgs {
+            environment_flag,
+            required_mask,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    /// The missing-environment count in a status.
+    fn missing_environment(status: u64) -> u64 {
+        status >> STATUS_MISSING_ENVIRONMENT_SHIFT & u64::from(STATUS_MISSING_ENVIRONMENT_MAX)
+    }
+
+    #[test]
+    fn old_required_mask_behaves_as_before() {
+        let (_, status, _) = required(0b101, 0b101);
+        assert_eq!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        // Bit 2 can be waived, bit 0 can't
+        let (digest, status, _) = required(0b001, 0b101);
+        assert_ne!(digest, 0);
+        assert_ne!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        assert_eq!(missing_environment(status), 1);
+        let (digest, status, _) = required(0b100, 0b101);
+        assert_eq!(digest, 0);
+        assert_eq!(status & STATUS_FALLBACK_GAVE_UP, STATUS_FALLBACK_GAVE_UP);
+        assert_eq!(missing_environment(status), 1);
+        // The old constant is still what `main` was called with
+        assert_eq!(
+            required(0b001, 0b101),
+            required(0b001, REQUIRED_ENVIRONMENT_MASK)
+        );
+    }
+
+    #[test]
+    fn stricter_required_mask_counts_every_missing_bit() {
+        let (_, status, _) = required(0b1111, 0b1111);
+        assert_eq!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        // Bits 1 and 3 are missing, and refrigeration can't be waived
+        let (digest, status, _) = required(0b101, 0b1111);
+        assert_eq!(digest, 0);
+        assert_ne!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        assert_eq!(missing_environment(status), 2);
+        // Passing the old mask isn't enough for the stricter one
+        let (_, status, _) = required(0b0111, 0b1111);
+        assert_eq!(missing_environment(status), 1);
+        // Four missing bits read as four, and more than seven saturate
+        let (_, status, _) = required(0, 0b1111);
+        assert_eq!(missing_environment(status), 4);
+        let (_, status, _) = required(0, 0b1_1111);
+        assert_eq!(missing_environment(status), 5);
+        let (_, status, _) = required(0, 0xFFFF);
+        assert_eq!(
+            missing_environment(status),
+            STATUS_MISSING_ENVIRONMENT_MAX.into()
+        );
+        // Without spilling into the dependency violations above
+        assert_eq!(status >> STATUS_DEPENDENCY_VIOLATION_SHIFT & 0b111, 0);
+    }
+
+    #[test]
+    fn zero_required_mask_skips_the_environment_check() {
+        for environment_flag in [0, 0b010, 0b101] {
+            let (digest, status, _) = required(environment_flag, 0);
+            assert_ne!(digest, 0);
+            assert_eq!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+            assert_eq!(missing_environment(status), 0);
+        }
+        // The same record, hashed straight through rather than down the fallback path
+        assert_ne!(required(0b001, 0).0, required(0b001, 0b101).0);
+    }
+
+    #[test]
+    fn satisfied_dependency_chain_is_kept() {
+        let chain = 1 << 1 | 1 << 5 | 1 << 6;
+        assert_eq!(enforce_cert_dependencies(chain), (chain, 0));
+        let chain = 1 << 8 | 1 << 9 | 1 << 20;
+        assert_eq!(enforce_cert_dependencies(chain), (chain, 0));
+    }
+
+    #[test]
+    fn broken_dependency_clears_the_dependent() {
+        assert_eq!(enforce_cert_dependencies(1 << 1 | 1 << 6), (1 << 1, 1));
+        // Clearing bit 5 takes bit 6 down with it in the same pass
+        assert_eq!(enforce_cert_dependencies(1 << 5 | 1 << 6), (0, 2));
+    }
+
+    #[test]
+    fn every_violation_is_counted() {
+        let (effective, violations) =
+            enforce_cert_dependencies(1 << 5 | 1 << 12 | 1 << 17 | 1 << 18);
+        assert_eq!((effective, violations), (1 << 18, 3));
+        // The count lands in the status, the digest only sees what's left
+        let (digest, status, _) = MainArgs {
+            certification_bitmask: 1 << 12 | 1 << 17 | 1 << 18,
+            ..MainArgs::default()
+        }
+        .result();
+        assert_eq!(status >> STATUS_DEPENDENCY_VIOLATION_SHIFT & 0b111, 2);
+        assert_eq!(digest, MainArgs::default().result().0);
+    }
+
+    #[test]
+    fn masks_without_dependents_are_unchanged() {
+        for mask in [0, 1 << 1, 1 << 18 | 1 << 30, 1 << 3 | 1 << 16] {
+            assert_eq!(enforce_cert_dependencies(mask), (mask, 0));
+        }
+    }
+
+    /// `result` for the default record at quality 80 in this granularity.
+    fn granular(environment_flag: u32, granularity: u32, unit_count: u64) -> (u64, u64, u32) {
+        MainArgs {
+            environment_flag,
+            quality_score: 80,
+            granularity,
+            unit_count,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn unit_mode_ignores_the_unit_count() {
+        let unit = granular(0b101, GRANULARITY_UNIT, 0);
+        assert_eq!(granular(0b101, GRANULARITY_UNIT, 10_000), unit);
+        assert_eq!(unit.1, 0);
+    }
+
+    #[test]
+    fn batch_hash_depends_on_the_unit_count() {
+        let small = granular(0b101, GRANULARITY_BATCH, 10);
+        let large = granular(0b101, GRANULARITY_BATCH, 10_000);
+        assert_ne!(small.0, large.0);
+        assert_ne!(small.0, granular(0b101, GRANULARITY_UNIT, 0).0);
+        assert_eq!((small.1, large.1), (0, 0));
+    }
+
+    #[test]
+    fn empty_batch_is_rejected() {
+        assert_eq!(
+            granular(0b101, GRANULARITY_BATCH, 0),
+            (0, STATUS_INVALID_MODE, 0)
+        );
+        assert_eq!(granular(0b101, 2, 10), (0, STATUS_INVALID_MODE, 0));
+    }
+
+    #[test]
+    fn fallback_penalizes_the_batch_mean() {
+        let unit = granular(0b001, GRANULARITY_UNIT, 0);
+        let batch = granular(0b001, GRANULARITY_BATCH, 10);
+        assert_ne!(unit.0, batch.0);
+        assert_eq!(unit.1, batch.1);
+        // Waiving bit 2 costs the mean of 80 a quarter, just as it would a single unit
+        let product_id = MainArgs::default().product_id;
+        let lineage_hash = compute_data_lineage_hash(product_id, 0b001, 60) * 11u32;
+        let cert_transform = transform_certification_bitmask(1 << 18);
+        assert_eq!(
+            batch.0,
+            combine_final(&lineage_hash, true, cert_transform, 60, u64::from(QUORUM))
+        );
+    }
+
+    #[test]
+    fn swapped_factors_collide_only_in_v1() {
+        let product_id = MainArgs::default().product_id;
+        // (environment + 1) and (quality + 1) trade places
+        assert_eq!(
+            compute_data_lineage_hash(product_id, 1, 2),
+            compute_data_lineage_hash(product_id, 2, 1)
+        );
+        assert_ne!(
+            compute_data_lineage_hash_v2(product_id, 1, 2),
+            compute_data_lineage_hash_v2(product_id, 2, 1)
+        );
+        // Doubling the serial while halving (quality + 1)
+        assert_eq!(
+            compute_data_lineage_hash(2, 0, 1),
+            compute_data_lineage_hash(1, 0, 3)
+        );
+        assert_ne!(
+            compute_data_lineage_hash_v2(2, 0, 1),
+            compute_data_lineage_hash_v2(1, 0, 3)
+        );
+    }
+
+    #[test]
+    fn equal_values_cancel_only_in_v1() {
+        let lineage_hash = compute_data_lineage_hash(9, 0b101, 77);
+        assert_eq!(
+            combine_final(&lineage_hash, true, 5, 7, 0),
+            combine_final(&lineage_hash, true, 7, 5, 0)
+        );
+        assert_eq!(
+            combine_final(&lineage_hash, true, 5, 5, 0),
+            combine_final(&lineage_hash, true, 0, 0, 0)
+        );
+        assert_ne!(
+            combine_final_v2(&lineage_hash, true, 5, 7, 0),
+            combine_final_v2(&lineage_hash, true, 7, 5, 0)
+        );
+        assert_ne!(
+            combine_final_v2(&lineage_hash, true, 5, 5, 0),
+            combine_final_v2(&lineage_hash, true, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn v1_stays_the_default_and_is_pinned() {
+        // (9 << 3) * (5 + 1) * (77 + 1) + 1 * MANUFACTURER_MULTIPLIER, times 104729
+        assert_eq!(
+            compute_data_lineage_hash(1 << MANUFACTURER_SHIFT | 9, 0b101, 77),
+            BigUint::from(108_258_262_571u64)
+        );
+        assert_eq!(MainArgs::default().run(), 0x2800_0000_00db_8686);
+        assert_eq!(MainArgs::default().result().0, 0xc9a_58da_15cd);
+        let certified = MainArgs {
+            environment_flag: 0b1101,
+            quality_score: 10_000,
+            certification_bitmask: 0xFF_FFFF,
+            ..MainArgs::default()
+        };
+        assert_eq!(certified.run(), 0x5c00_0000_0728_4640);
+        let v2 = MainArgs {
+            hash_version: HASH_V2,
+            ..MainArgs::default()
+        };
+        assert_ne!(v2.run(), MainArgs::default().run());
+    }
+
+    /// 2.0 to 8.0 degrees, offset-encoded.
+    const FRIDGE_MIN: u32 = 1_020;
+    const FRIDGE_MAX: u32 = 1_080;
+
+    /// The `cold_chain_result` digest of the default product if `certification_bitmask` is
+    /// what's left of its certifications after `in_band` readings inside the band.
+    fn cold_chain_digest(certification_bitmask: u32, in_band: u64) -> u64 {
+        lineage_chain_digest(
+            MainArgs::default().product_id,
+            &[LineageHop {
+                environment_flag: REQUIRED_ENVIRONMENT_MASK | REFRIGERATED_ENVIRONMENT_BIT,
+                certification_bitmask,
+                quality_score: in_band,
+            }],
+        )
+    }
+
+    /// `cold_chain_result` for the default product with the cold-chain certification.
+    fn cold_chain(readings: &[u16]) -> u64 {
+        cold_chain_result(
+            readings,
+            FRIDGE_MIN,
+            FRIDGE_MAX,
+            MainArgs::default().product_id,
+            CERT_COLD_CHAIN_BIT | 1 << 18,
+        )
+    }
+
+    #[test]
+    fn clean_temperature_log_keeps_the_certification() {
+        assert_eq!(
+            temperature_excursions(&[1_050; 10], FRIDGE_MIN, FRIDGE_MAX),
+            (0, 0)
+        );
+        assert_eq!(
+            cold_chain(&[1_050; 10]),
+            cold_chain_digest(CERT_COLD_CHAIN_BIT | 1 << 18, 10)
+        );
+    }
+
+    #[test]
+    fn isolated_excursions_under_the_limit_are_counted() {
+        let readings = [1_050, 1_000, 1_050, 1_090, 1_050, 1_019, 1_050];
+        assert_eq!(
+            temperature_excursions(&readings, FRIDGE_MIN, FRIDGE_MAX),
+            (3, 1)
+        );
+        assert_eq!(
+            cold_chain(&readings),
+            cold_chain_digest(CERT_COLD_CHAIN_BIT | 1 << 18, 4)
+                | 3 << COLD_CHAIN_EXCURSIONS_SHIFT
+                | 1 << COLD_CHAIN_RUN_SHIFT
+        );
+    }
+
+    #[test]
+    fn too_many_excursions_void_the_certification() {
+        let run = [1_050, 1_100, 1_100, 1_100, 1_100, 1_100, 1_100, 1_050];
+        assert_eq!(temperature_excursions(&run, FRIDGE_MIN, FRIDGE_MAX), (6, 6));
+        assert_eq!(
+            cold_chain(&run),
+            cold_chain_digest(1 << 18, 2)
+                | 6 << COLD_CHAIN_EXCURSIONS_SHIFT
+                | 6 << COLD_CHAIN_RUN_SHIFT
+                | COLD_CHAIN_VOID_BIT
+        );
+        // Four short excursions are one too many even without a long run
+        let scattered = [1_000, 1_050, 1_000, 1_050, 1_000, 1_050, 1_000];
+        assert_ne!(cold_chain(&scattered) & COLD_CHAIN_VOID_BIT, 0);
+        assert_eq!(
+            cold_chain(&scattered) & DIGEST_MASK,
+            cold_chain_digest(1 << 18, 3)
+        );
+    }
+
+    #[test]
+    fn readings_on_the_band_edges_are_inside() {
+        let edges = [FRIDGE_MIN as u16, FRIDGE_MAX as u16];
+        assert_eq!(
+            temperature_excursions(&edges, FRIDGE_MIN, FRIDGE_MAX),
+            (0, 0)
+        );
+        let just_outside = [FRIDGE_MIN as u16 - 1, FRIDGE_MAX as u16 + 1];
+        assert_eq!(
+            temperature_excursions(&just_outside, FRIDGE_MIN, FRIDGE_MAX),
+            (2, 2)
+        );
+        // -20.0 to -15.0 degrees, below the offset
+        assert_eq!(temperature_excursions(&[800, 850], 800, 850), (0, 0));
+        assert_eq!(temperature_excursions(&[799, 851], 800, 850), (2, 2));
+    }
+
+    #[test]
+    fn empty_temperature_log_is_clean() {
+        let product_id = MainArgs::default().product_id;
+        let cert_mask = CERT_COLD_CHAIN_BIT | 1 << 18;
+        // Safety: null pointers are never read
+        let empty =
+            unsafe { check_cold_chain(0, 0, FRIDGE_MIN, FRIDGE_MAX, product_id, cert_mask) };
+        assert_eq!(empty, cold_chain(&[]));
+        assert_eq!(empty, cold_chain_digest(cert_mask, 0));
+        // Readings that can't be read aren't mistaken for an empty log
+        assert_eq!(
+            unsafe { check_cold_chain(0, 1, FRIDGE_MIN, FRIDGE_MAX, product_id, cert_mask) },
+            0
+        );
+    }
+
+    /// The digest `main` computes for the default record of `product_id` if its quality was
+    /// weighed down to `weighted_quality`.
+    fn weighted_digest(product_id: u64, weighted_quality: u64) -> u64 {
+        combine_final(
+            &compute_data_lineage_hash(product_id, 0b101, weighted_quality),
+            true,
+            transform_certification_bitmask(1 << 18),
+            weighted_quality,
+            u64::from(QUORUM),
+        )
+    }
+
+    /// `result` for the default record of `product_id` at quality 1000.
+    fn manufactured(product_id: u64) -> (u64, u64, u32) {
+        MainArgs {
+            product_id,
+            quality_score: 1_000,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn known_manufacturer_weighs_the_quality() {
+        let product_id = 0x0B07 << MANUFACTURER_SHIFT | 0x1234;
+        assert_eq!(split_product_id(product_id), (0x0B07, 0x1234));
+        let (digest, status, _) = manufactured(product_id);
+        assert_eq!(status & STATUS_UNKNOWN_MANUFACTURER, 0);
+        assert_eq!(digest, weighted_digest(product_id, 900));
+    }
+
+    #[test]
+    fn unknown_manufacturer_loses_a_fifth() {
+        let product_id = 0x1234 << MANUFACTURER_SHIFT | 0x1234;
+        assert_eq!(manufacturer_weight(0x1234), None);
+        let (digest, status, _) = manufactured(product_id);
+        assert_ne!(status & STATUS_UNKNOWN_MANUFACTURER, 0);
+        assert_eq!(digest, weighted_digest(product_id, 800));
+    }
+
+    #[test]
+    fn manufacturer_zero_is_rejected() {
+        assert_eq!(split_product_id(0x1234), (MANUFACTURER_INVALID, 0x1234));
+        assert_eq!(manufactured(0x1234), (0, STATUS_INVALID_PRODUCT_ID, 0));
+        assert_eq!(manufactured(SERIAL_MASK), (0, STATUS_INVALID_PRODUCT_ID, 0));
+    }
+
+    #[test]
+    fn lineage_hash_depends_on_the_manufacturer() {
+        let first = compute_data_lineage_hash(1 << MANUFACTURER_SHIFT | 9, 0b101, 77);
+        let second = compute_data_lineage_hash(2 << MANUFACTURER_SHIFT | 9, 0b101, 77);
+        assert_eq!(
+            second - first,
+            BigUint::from(MANUFACTURER_MULTIPLIER * 104_729)
+        );
+        // Both manufacturers weigh 100, so only the manufacturer term tells them apart
+        assert_ne!(
+            manufactured(1 << MANUFACTURER_SHIFT | 9).0,
+            manufactured(2 << MANUFACTURER_SHIFT | 9).0
+        );
+    }
+
+    /// `result` for the default record at quality 10_000 with a 100-day shelf life, checked
+    /// `age_days` after it was made.
+    fn aged(age_days: u64) -> (u64, u64, u32) {
+        MainArgs {
+            quality_score: 10_000,
+            current_time: age_days * SECONDS_PER_DAY,
+            shelf_life_days: 100,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn fresh_product_keeps_its_quality() {
+        assert_eq!(decay_quality_by_age(10_000, 0, 100), 10_000);
+        assert_eq!(decay_quality_by_age(10_000, 1_000, NO_SHELF_LIFE), 10_000);
+        assert_eq!(aged(0).1 & STATUS_PRODUCT_EXPIRED, 0);
+    }
+
+    #[test]
+    fn quality_decays_linearly_within_the_shelf_life() {
+        assert_eq!(decay_quality_by_age(10_000, 50, 100), 7_500);
+        assert_eq!(decay_quality_by_age(10_000, 100, 100), 5_000);
+        // A day into a three-day shelf life still costs a sixth
+        assert_eq!(decay_quality_by_age(10_000, 1, 3), 8_333);
+        let (digest, _, _) = aged(50);
+        let decayed = MainArgs {
+            quality_score: 7_500,
+            ..MainArgs::default()
+        };
+        assert_eq!(digest, decayed.result().0);
+    }
+
+    #[test]
+    fn quality_decays_quadratically_past_the_shelf_life() {
+        // 10_000 * 99 * 99 / (2 * 100 * 100)
+        assert_eq!(decay_quality_by_age(10_000, 101, 100), 4_900);
+        assert_eq!(decay_quality_by_age(10_000, 150, 100), 1_250);
+        assert_eq!(aged(101).1 & STATUS_PRODUCT_EXPIRED, 0);
+    }
+
+    #[test]
+    fn double_the_shelf_life_is_the_last_day() {
+        assert_eq!(decay_quality_by_age(10_000, 200, 100), 0);
+        let (digest, status, _) = aged(200);
+        assert_ne!(digest, 0);
+        assert_eq!(status & STATUS_PRODUCT_EXPIRED, 0);
+    }
+
+    #[test]
+    fn expired_product_still_gets_a_lineage_hash() {
+        let product_id = MainArgs::default().product_id;
+        let expired = (
+            lower_64(&compute_data_lineage_hash(product_id, 0b101, 0)),
+            STATUS_PRODUCT_EXPIRED,
+            0,
+        );
+        assert_eq!(aged(201), expired);
+        assert_eq!(aged(10_000), expired);
+        // Made after it was checked is invalid, not fresh
+        let early = MainArgs {
+            produced_at: 1,
+            ..MainArgs::default()
+        };
+        assert_eq!(early.result(), (0, STATUS_INVALID_TIMESTAMPS, 0));
+    }
+
+    /// A product id under the manufacturer code counterfeits claim.
+    const COUNTERFEIT_PRODUCT_ID: u64 = (COUNTERFEIT_MANUFACTURER as u64) << MANUFACTURER_SHIFT | 9;
+
+    /// The default record's STATUS_* flags with these fields.
+    fn suspect_status(product_id: u64, certification_bitmask: u32, quality_score: u64) -> u64 {
+        MainArgs {
+            product_id,
+            certification_bitmask,
+            quality_score,
+            ..MainArgs::default()
+        }
+        .result()
+        .1
+    }
+
+    #[test]
+    fn clean_record_isnt_suspect() {
+        let product_id = MainArgs::default().product_id;
+        assert_eq!(counterfeit_score(product_id, 0b101, 1 << 18, 77), 0);
+        // Certifications backed by their environment bit are fine
+        assert_eq!(counterfeit_score(product_id, 0b101, 0b101, 2_000_000), 0);
+        assert_eq!(suspect_status(product_id, 1 << 18, 77) & STATUS_SUSPECT, 0);
+    }
+
+    #[test]
+    fn each_red_flag_alone_stays_under_the_threshold() {
+        let product_id = MainArgs::default().product_id;
+        assert_eq!(
+            counterfeit_score(product_id, 0b101, 1 << 1, 77),
+            COUNTERFEIT_UNMATCHED_CERT_WEIGHT
+        );
+        assert_eq!(
+            counterfeit_score(product_id, 0b101, 1 << 18, 1_000_001),
+            COUNTERFEIT_IMPROBABLE_QUALITY_WEIGHT
+        );
+        assert_eq!(counterfeit_score(product_id, 0b101, 1 << 18, 1_000_000), 0);
+        assert_eq!(
+            counterfeit_score(COUNTERFEIT_PRODUCT_ID, 0b101, 1 << 18, 77),
+            COUNTERFEIT_MANUFACTURER_WEIGHT
+        );
+        assert_eq!(suspect_status(product_id, 1 << 1, 77) & STATUS_SUSPECT, 0);
+        assert_eq!(
+            suspect_status(product_id, 1 << 18, 2_000_000) & STATUS_SUSPECT,
+            0
+        );
+        assert_eq!(
+            suspect_status(COUNTERFEIT_PRODUCT_ID, 1 << 18, 77) & STATUS_SUSPECT,
+            0
+        );
+    }
+
+    #[test]
+    fn red_flags_add_up() {
+        let product_id = MainArgs::default().product_id;
+        assert_eq!(counterfeit_score(product_id, 0b101, 1 << 1, 2_000_000), 70);
+        assert_eq!(
+            counterfeit_score(COUNTERFEIT_PRODUCT_ID, 0b101, 1 << 1, 77),
+            70
+        );
+        assert_eq!(
+            counterfeit_score(COUNTERFEIT_PRODUCT_ID, 0b101, 1 << 1, 2_000_000),
+            100
+        );
+        // Exactly the threshold isn't above it
+        assert_eq!(
+            counterfeit_score(COUNTERFEIT_PRODUCT_ID, 0b101, 1 << 18, 2_000_000),
+            COUNTERFEIT_SUSPECT_THRESHOLD
+        );
+        assert_eq!(
+            suspect_status(COUNTERFEIT_PRODUCT_ID, 1 << 18, 2_000_000) & STATUS_SUSPECT,
+            0
+        );
+    }
+
+    #[test]
+    fn suspect_record_falls_back_despite_its_environment() {
+        let status = suspect_status(COUNTERFEIT_PRODUCT_ID, 1 << 1, 77);
+        assert_ne!(status & STATUS_SUSPECT, 0);
+        assert_eq!(status & STATUS_ENVIRONMENT_FALLBACK, 0);
+        assert_eq!(
+            status & STATUS_FALLBACK_GAVE_UP,
+            STATUS_FALLBACK_OTHER_CHECK
+        );
+        // Suspicion costs the score a failed check
+        let suspect = MainArgs {
+            product_id: COUNTERFEIT_PRODUCT_ID,
+            certification_bitmask: 1 << 1,
+            ..MainArgs::default()
+        };
+        assert_eq!(suspect.result().2, compute_provenance_score(true, 1, 77, 1));
+    }
+
+    /// Environment bits 0 and 2, refrigerated and humidity controlled, so every industry's
+    /// requirements are met.
+    const EVERY_ENVIRONMENT: u32 =
+        0b101 | REFRIGERATED_ENVIRONMENT_BIT | HUMIDITY_CONTROLLED_ENVIRONMENT_BIT;
+
+    /// `result` for a record meeting every requirement, with a one-year and a permanent
+    /// certification, in this industry.
+    fn in_industry(industry: u32) -> (u64, u64, u32) {
+        MainArgs {
+            environment_flag: EVERY_ENVIRONMENT,
+            quality_score: 1_000,
+            certification_bitmask: 1 << 3 | 1 << 18,
+            industry,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    /// The V1 digest of that record with the certifications and quality it ends up with.
+    fn industry_digest(cert_transform: u32, quality_score: u64) -> u64 {
+        let product_id = MainArgs::default().product_id;
+        combine_final(
+            &compute_data_lineage_hash(product_id, EVERY_ENVIRONMENT, 1_000),
+            true,
+            cert_transform,
+            quality_score,
+            u64::from(QUORUM),
+        )
+    }
+
+    #[test]
+    fn industry_profiles_weigh_the_same_record_differently() {
+        let every = transform_certification_bitmask(1 << 3 | 1 << 18);
+        // Food only counts the expiring certification
+        let food = transform_certification_bitmask(1 << 3);
+        assert_eq!(in_industry(INDUSTRY_FOOD).0, industry_digest(food, 1_000));
+        assert_eq!(
+            in_industry(INDUSTRY_PHARMA).0,
+            industry_digest(every, 3_000)
+        );
+        // Electronics only counts the permanent one, twice
+        let electronics = transform_certification_bitmask(1 << 18).wrapping_mul(2);
+        assert_eq!(
+            in_industry(INDUSTRY_ELECTRONICS).0,
+            industry_digest(electronics, 1_000)
+        );
+        for industry in [INDUSTRY_FOOD, INDUSTRY_PHARMA, INDUSTRY_ELECTRONICS] {
+            assert_eq!(in_industry(industry).1, 0);
+        }
+        // Food and pharma need their products refrigerated
+        for industry in [INDUSTRY_FOOD, INDUSTRY_PHARMA] {
+            let warm = MainArgs {
+                industry,
+                ..MainArgs::default()
+            };
+            assert_eq!(warm.result().0, 0);
+            assert_ne!(warm.result().1 & STATUS_ENVIRONMENT_FALLBACK, 0);
+        }
+    }
+
+    #[test]
+    fn generic_profile_changes_nothing() {
+        let every = transform_certification_bitmask(1 << 3 | 1 << 18);
+        assert_eq!(
+            in_industry(INDUSTRY_GENERIC).0,
+            industry_digest(every, 1_000)
+        );
+        assert_eq!(in_industry(INDUSTRY_GENERIC).1, 0);
+        assert_eq!(MainArgs::default().run(), 0x2800_0000_00db_8686);
+    }
+
+    #[test]
+    fn unknown_industry_is_treated_as_generic() {
+        let (digest, status, score) = in_industry(4);
+        let generic = in_industry(INDUSTRY_GENERIC);
+        assert_eq!((digest, score), (generic.0, generic.2));
+        assert_eq!(status, generic.1 | STATUS_UNKNOWN_INDUSTRY);
+        assert_eq!(in_industry(u32::MAX), (digest, status, score));
+    }
+
+    /// A parent lot's lineage hash low bits.
+    const PARENT_LOT: u64 = 0x1234_5678_9abc_def0;
+
+    #[test]
+    fn split_children_differ() {
+        let first = split_lineage(PARENT_LOT, 5_000, 0);
+        let second = split_lineage(PARENT_LOT, 5_000, 1);
+        // An even split still tells the children apart by index
+        assert_ne!(first, second);
+        assert_eq!(first, merkle_mix(PARENT_LOT, 5_000));
+        assert_eq!(
+            second,
+            merkle_mix(PARENT_LOT, 1 << LOT_CHILD_INDEX_SHIFT | 5_000)
+        );
+        // Deterministic, and tied to the parent and the share
+        assert_eq!(split_lineage(PARENT_LOT, 5_000, 0), first);
+        assert_ne!(split_lineage(PARENT_LOT ^ 1, 5_000, 0), first);
+        assert_ne!(split_lineage(PARENT_LOT, 3_000, 0), first);
+        assert_eq!(split_lineage(PARENT_LOT, 5_000, LOT_SPLIT_CHILDREN), 0);
+    }
+
+    #[test]
+    fn merge_order_doesnt_matter() {
+        assert_eq!(
+            merge_lineage(PARENT_LOT, 42, 300, 700),
+            merge_lineage(42, PARENT_LOT, 700, 300)
+        );
+        assert_eq!(
+            merge_lineage(PARENT_LOT, PARENT_LOT, 1, 2),
+            merge_lineage(PARENT_LOT, PARENT_LOT, 2, 1)
+        );
+    }
+
+    #[test]
+    fn merge_depends_on_the_quantities() {
+        let merged = merge_lineage(PARENT_LOT, 42, 300, 700);
+        assert_ne!(merge_lineage(PARENT_LOT, 42, 700, 300), merged);
+        assert_ne!(merge_lineage(PARENT_LOT, 42, 500, 500), merged);
+        // Only the shares count, not the units they're in
+        assert_eq!(merge_lineage(PARENT_LOT, 42, 3, 7), merged);
+        assert_eq!(merge_lineage(PARENT_LOT, 42, 0, 0), 0);
+    }
+
+    #[test]
+    fn degenerate_split_ratio_is_rejected() {
+        for child_index in 0..LOT_SPLIT_CHILDREN {
+            assert_eq!(split_lineage(PARENT_LOT, 0, child_index), 0);
+            assert_eq!(split_lineage(PARENT_LOT, 10_000, child_index), 0);
+            assert_eq!(split_lineage(PARENT_LOT, u32::MAX, child_index), 0);
+            assert_ne!(split_lineage(PARENT_LOT, 1, child_index), 0);
+            assert_ne!(split_lineage(PARENT_LOT, 9_999, child_index), 0);
+        }
+    }
+
+    /// `partial_fallback` for the default generic, road-shipped unit with this environment,
+    /// required mask and quality.
+    fn fallback(environment_flag: u32, required_mask: u32, quality_score: u64) -> (u64, u64) {
+        partial_fallback(
+            environment_flag,
+            quality_score,
+            MainArgs::default().product_id,
+            1 << 18,
+            required_mask,
+            None,
+            HashVersion::V1,
+            u64::from(QUORUM),
+            &INDUSTRY_PROFILES[INDUSTRY_GENERIC as usize],
+            TransportMode::Road,
+        )
+    }
+
+    /// The digest the fallback hashes that unit to at `quality_score`.
+    fn fallback_digest(environment_flag: u32, quality_score: u64) -> u64 {
+        combine_final(
+            &compute_data_lineage_hash(
+                MainArgs::default().product_id,
+                environment_flag,
+                quality_score,
+            ),
+            true,
+            transform_certification_bitmask(1 << 18),
+            quality_score,
+            u64::from(QUORUM),
+        )
+    }
+
+    #[test]
+    fn missing_soft_bits_are_waived_at_a_cost() {
+        // Bit 2 is soft, so a lot without it keeps three quarters of its quality
+        assert_eq!(
+            fallback(0b001, 0b101, 1_000),
+            (fallback_digest(0b001, 750), STATUS_FALLBACK_WAIVED)
+        );
+        // Two soft bits cost half, however good the quality
+        let soft_mask = 0b1 | SOFT_ENVIRONMENT_MASK;
+        assert_eq!(
+            fallback(0b001, soft_mask, 9_000),
+            (fallback_digest(0b001, 4_500), STATUS_FALLBACK_WAIVED)
+        );
+    }
+
+    #[test]
+    fn high_quality_compensates_for_one_soft_bit() {
+        assert_eq!(
+            fallback(0b001, 0b101, FALLBACK_COMPENSATING_QUALITY),
+            (
+                fallback_digest(0b001, FALLBACK_COMPENSATING_QUALITY),
+                STATUS_FALLBACK_COMPENSATED
+            )
+        );
+        assert_eq!(
+            fallback(0b001, 0b101, FALLBACK_COMPENSATING_QUALITY - 1).1,
+            STATUS_FALLBACK_WAIVED
+        );
+    }
+
+    #[test]
+    fn missing_hard_bits_give_up() {
+        // Bit 0 used to be "repaired" by shifting the flag left, which can never set it
+        assert_eq!(fallback(0b100, 0b101, 9_000), (0, STATUS_FALLBACK_GAVE_UP));
+        assert_eq!(fallback(0b110, 0b101, 9_000), (0, STATUS_FALLBACK_GAVE_UP));
+        assert_eq!(
+            fallback(0b101, 0b101 | REFRIGERATED_ENVIRONMENT_BIT, 9_000),
+            (0, STATUS_FALLBACK_GAVE_UP)
+        );
+    }
+
+    #[test]
+    fn valid_environment_is_hashed_as_it_is() {
+        // Sent down the fallback path by another check, with nothing to repair
+        assert_eq!(
+            fallback(0b101, 0b101, 1_000),
+            (fallback_digest(0b101, 1_000), STATUS_FALLBACK_OTHER_CHECK)
+        );
+    }
+
+    /// `result` for the default record at quality 1000 after `transit_hops` transfers, shipped
+    /// this way.
+    fn transported(
+        transport_mode: u32,
+        environment_flag: u32,
+        transit_hops: u32,
+    ) -> (u64, u64, u32) {
+        MainArgs {
+            environment_flag,
+            quality_score: 1_000,
+            transit_hops,
+            transport_mode,
+            ..MainArgs::default()
+        }
+        .result()
+    }
+
+    #[test]
+    fn transport_modes_are_pinned() {
+        let product_id = MainArgs::default().product_id;
+        assert_eq!(
+            TransportMode::Road.adjust_quality(1_000, 1_000, 0b101),
+            1_000
+        );
+        assert_eq!(
+            TransportMode::Air.adjust_quality(1_000, 1_000, 0b101),
+            1_000
+        );
+        assert_eq!(TransportMode::Sea.adjust_quality(1_000, 1_000, 0b101), 850);
+        assert_eq!(TransportMode::Rail.adjust_quality(900, 1_000, 0b101), 918);
+
+        let road = transported(TRANSPORT_ROAD, 0b101, 0);
+        assert_eq!(road.0, weighted_digest(product_id, 1_000));
+        assert_eq!(
+            transported(TRANSPORT_SEA, 0b101, 0).0,
+            weighted_digest(product_id, 850)
+        );
+        // Rail makes up some of what five hops cost
+        assert_eq!(
+            transported(TRANSPORT_RAIL, 0b101, 5).0,
+            weighted_digest(product_id, 918)
+        );
+        // Air keeps the quality but adds its surcharge to the lineage hash
+        let air_hash =
+            compute_data_lineage_hash(product_id, 0b101, 1_000) + TRANSPORT_AIR_EMISSIONS_SURCHARGE;
+        assert_eq!(
+            transported(TRANSPORT_AIR, 0b101, 0).0,
+            combine_final(
+                &air_hash,
+                true,
+                transform_certification_bitmask(1 << 18),
+                1_000,
+                u64::from(QUORUM)
+            )
+        );
+        for mode in [TRANSPORT_SEA, TRANSPORT_AIR] {
+            assert_ne!(transported(mode, 0b101, 0).0, road.0, "mode {mode}");
+        }
+    }
+
+    #[test]
+    fn sea_without_humidity_control_costs_15_percent() {
+        let humid = 0b101 | HUMIDITY_CONTROLLED_ENVIRONMENT_BIT;
+        assert_eq!(
+            TransportMode::Sea.adjust_quality(1_000, 1_000, humid),
+            1_000
+        );
+        assert_eq!(
+            transported(TRANSPORT_SEA, humid, 0),
+            transported(TRANSPORT_ROAD, humid, 0)
+        );
+        assert_ne!(
+            transported(TRANSPORT_SEA, 0b101, 0),
+            transported(TRANSPORT_ROAD, 0b101, 0)
+        );
+    }
+
+    #[test]
+    fn rail_bonus_never_beats_the_claimed_quality() {
+        assert_eq!(
+            TransportMode::Rail.adjust_quality(1_000, 1_000, 0b101),
+            1_000
+        );
+        assert_eq!(TransportMode::Rail.adjust_quality(990, 1_000, 0b101), 1_000);
+        assert_eq!(
+            TransportMode::Rail.adjust_quality(u64::MAX, u64::MAX, 0b101),
+            u64::MAX
+        );
+        // Without transit losses rail hashes just like road
+        assert_eq!(
+            transported(TRANSPORT_RAIL, 0b101, 0),
+            transported(TRANSPORT_ROAD, 0b101, 0)
+        );
+    }
+
+    #[test]
+    fn unknown_transport_mode_is_road() {
+        let (digest, status, score) = transported(4, 0b101, 0);
+        let road = transported(TRANSPORT_ROAD, 0b101, 0);
+        assert_eq!((digest, score), (road.0, road.2));
+        assert_eq!(status, road.1 | STATUS_UNKNOWN_TRANSPORT);
+    }
+
+    /// `main` for the default record with `certification_bitmask`, less `revoked_mask`.
+    fn revoked(certification_bitmask: u32, revoked_mask: u32) -> u64 {
+        MainArgs {
+            certification_bitmask,
+            revoked_mask,
+            ..MainArgs::default()
+        }
+        .run()
+    }
+
+    #[test]
+    fn empty_revocation_list_changes_nothing() {
+        let certified = 1 << 16 | 1 << 18;
+        assert_eq!(revoked(1 << 18, 0), 0x2800_0000_00db_8686);
+        // Revoking bits that were never set is just as much a no-op
+        for revoked_mask in [0, !certified, 1 << 2] {
+            assert_eq!(
+                revoked(certified, revoked_mask),
+                revoked(certified, 0),
+                "{revoked_mask:#x}"
+            );
+        }
+        assert_eq!(scored_status(revoked(certified, 0)), 0);
+    }
+
+    #[test]
+    fn partial_revocation_is_counted() {
+        let certified = 1 << 16 | 1 << 18 | 1 << 19;
+        let result = revoked(certified, 1 << 16 | 1 << 19 | 1 << 2);
+        assert_eq!(
+            scored_status(result) >> STATUS_REVOCATION_COUNT_SHIFT & 0b11_1111,
+            2
+        );
+        assert_eq!(scored_status(result) & STATUS_CERTIFICATION_VOID, 0);
+        // Otherwise it's the record certified with what's left
+        assert_eq!(
+            result,
+            revoked(1 << 18, 0) | 2 << (SCORED_STATUS_SHIFT + STATUS_REVOCATION_COUNT_SHIFT)
+        );
+    }
+
+    #[test]
+    fn full_revocation_voids_the_certification() {
+        let certified = 1 << 16 | 1 << 18;
+        let result = revoked(certified, u32::MAX);
+        let status = STATUS_CERTIFICATION_VOID | 2 << STATUS_REVOCATION_COUNT_SHIFT;
+        assert_eq!(scored_status(result), status);
+        // The lineage is still hashed, as if the record had never been certified
+        assert_ne!(result & SCORED_DIGEST_MASK, 0);
+        assert_eq!(result, revoked(0, 0) | status << SCORED_STATUS_SHIFT);
+        // An uncertified record has nothing to void
+        assert_eq!(scored_status(revoked(0, u32::MAX)), 0);
+    }
+
+    #[test]
+    fn legacy_layout_is_the_full_original_digest() {
+        for (product_id, environment_flag, quality_score, certification_bitmask) in [
+            (1 << MANUFACTURER_SHIFT | 9, 0b101, 77, 1 << 18),
+            (0xFFFF_FFFF_FFFF_FFFF, 0b1111, 1 << 40, 0xFF00_00F0),
+            (42, 0b101, 0, 0),
+        ] {
+            let legacy = MainArgs {
+                product_id,
+                environment_flag,
+                quality_score,
+                certification_bitmask,
+                legacy_layout: 1,
+                ..MainArgs::default()
+            };
+            // The original formula, over the whole product id and without any sign-offs
+            let lineage_hash = BigUint::from(product_id)
+                * 8u32
+                * (environment_flag + 1)
+                * (BigUint::from(quality_score) + 1u32)
+                * 104729u32;
+            let expected = lower_64(&lineage_hash).rotate_left(7)
+                ^ 1
+                ^ u64::from(transform_certification_bitmask(certification_bitmask))
+                ^ quality_score;
+            assert_eq!(legacy.run(), expected, "{product_id:#x}");
+        }
+        // Nothing is cut off above the scored digest or the status
+        let legacy = MainArgs {
+            legacy_layout: 1,
+            ..MainArgs::default()
+        };
+        assert_ne!(legacy.run() >> STATUS_SHIFT, 0);
+        assert_eq!(main_result(0b001, 77, 1 << 18, 1), 0);
+    }
+
+    #[test]
+    fn legacy_layout_keeps_the_baseline_outputs() {
+        // What main returned for these records before it took timestamps or anything else,
+        // whatever the certification's age now
+        for (issued_at, current_time) in [(0, 0), (0, 3 * SECONDS_PER_YEAR)] {
+            let legacy = |environment_flag, quality_score, certification_bitmask| {
+                MainArgs {
+                    environment_flag,
+                    quality_score,
+                    certification_bitmask,
+                    issued_at,
+                    current_time,
+                    legacy_layout: 1,
+                    ..MainArgs::default()
+                }
+                .run()
+            };
+            assert_eq!(legacy(0b101, 77, 1 << 18), 0xd000_0069_2b99_5048);
+            assert_eq!(legacy(0b111, 5_000, 0xFF00_00F0), 0x2000_231e_3d8d_3440);
+            assert_eq!(legacy(0b001, 77, 1 << 18), 0);
+        }
+    }
 }
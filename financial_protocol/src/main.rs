@@ -1,4 +1,4 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 // We assume you have added `num-bigint = "0.4"` in Cargo.toml
 extern crate num_bigint;
@@ -6,25 +6,74 @@ extern crate num_traits;
 
 use num_bigint::{BigUint, ToBigUint};
 use num_traits::{One, Zero};
+use safe_math::{safe_add_u64, safe_div_u64, safe_mul_u64, safe_sub_u64};
+use wasm_utils::write_bytes;
 
-// Safe operations for u32
-fn safe_add_u32(a: u32, b: u32) -> u32 {
-    a.checked_add(b).unwrap_or(u32::MAX)
-}
+// Status codes returned by the exports in place of a combined result.
+// They sit at the very top of the u64 range, far above any realistic XOR of amounts.
+const STATUS_CAP_EXCEEDED: u64 = u64::MAX;
+const STATUS_FEE_TOO_HIGH: u64 = u64::MAX - 1;
+const STATUS_REFINANCE_UNHEALTHY: u64 = u64::MAX - 2;
+const STATUS_NOTHING_TO_REFINANCE: u64 = u64::MAX - 3;
+const STATUS_RATIO_TOO_LOW: u64 = u64::MAX - 4;
+const STATUS_FLASH_LOAN_OVERSIZE: u64 = u64::MAX - 5;
+const STATUS_FLASH_LOAN_REVERTED: u64 = u64::MAX - 6;
+const STATUS_STAKE_RATIO_INVALID: u64 = u64::MAX - 8;
 
-fn safe_sub_u32(a: u32, b: u32) -> u32 {
-    a.checked_sub(b).unwrap_or(0)
-}
+// Warning bits OR-ed into an otherwise valid combined result.
+const WARN_INTEREST_OVER_CAP: u64 = 1 << 62;
+const WARN_STALE_ORACLE: u64 = 1 << 61;
 
-fn safe_mul_u32(a: u32, b: u32) -> u32 {
-    a.checked_mul(b).unwrap_or(u32::MAX)
-}
+// Validator misbehavior flags. Bits 8-15 each mark one offline epoch under MISBEHAVIOR_DOWNTIME.
+const MISBEHAVIOR_DOUBLE_SIGN: u32 = 1 << 0;
+const MISBEHAVIOR_DOWNTIME: u32 = 1 << 1;
+const MISBEHAVIOR_SEVERE: u32 = 1 << 2;
+const MISBEHAVIOR_DOWNTIME_EPOCHS: u32 = 0xFF << 8;
 
-fn safe_div_u32(a: u32, b: u32) -> u32 {
-    if b == 0 {
-        0
-    } else {
-        a / b
+// Oracle freshness: prices older than the first bound take a 5% haircut,
+// prices older than the second are stale and only count at a conservative half.
+const PRICE_HAIRCUT_AGE_BLOCKS: u64 = 100;
+const PRICE_STALE_AGE_BLOCKS: u64 = 1000;
+const STALE_PRICE_HAIRCUT_BPS: u64 = 5000;
+
+// Junior-tranche debt counts at this percentage in the health check.
+const JUNIOR_DEBT_WEIGHT_PCT: u64 = 120;
+
+// `partial_fallback_loan` bisects for the largest healthy borrow unless asked for the
+// older fixed steps (half, then a quarter of the request, then the exact maximum) with
+// this mode.
+const FALLBACK_MODE_HALVING: u32 = 1;
+const MAX_BISECTION_STEPS: u32 = 32;
+
+// Upper bound on any per-slice compounding loop.
+const MAX_COMPOUNDING_STEPS: u32 = 10_000;
+
+// Lowest collateral ratio a caller may choose.
+const MIN_ACCEPTED_COLLATERAL_RATIO_PCT: u32 = 100;
+
+// Origination fees above 10% are rejected as unreasonable.
+const MAX_ORIGINATION_FEE_BPS: u32 = 1000;
+
+// `refinance`, `delegate_credit` and `simulate_repayment` write their three amounts to
+// `out_ptr` as full little-endian u64s, in the order their docs list them.
+const AMOUNT_TRIPLE_SIZE: usize = 24;
+
+// Staking rewards paid out by `main` vest linearly over this many slices, after a cliff.
+const REWARD_VEST_SLICES: u32 = 10;
+const REWARD_CLIFF_SLICES: u32 = 2;
+
+/// `a * b / divisor` without losing the result when the intermediate product overflows u64.
+/// Saturates if the quotient itself doesn't fit.
+fn mul_div_u64(a: u64, b: u64, divisor: u64) -> u64 {
+    match a.checked_mul(b) {
+        Some(product) => safe_div_u64(product, divisor),
+        None if divisor == 0 => 0,
+        None => {
+            let product = a.to_biguint().unwrap_or(BigUint::zero())
+                * b.to_biguint().unwrap_or(BigUint::zero());
+            let divisor_big = divisor.to_biguint().unwrap_or(BigUint::one());
+            (product / divisor_big).try_into().unwrap_or(u64::MAX)
+        }
     }
 }
 
@@ -49,59 +98,421 @@ fn safe_div_i32(a: i32, b: i32) -> i32 {
     }
 }
 
+/// Split a u64 into four 16-bit fields, lowest field first.
+fn unpack_u16_fields(packed: u64) -> [u64; 4] {
+    let mut fields = [0u64; 4];
+    for (i, field) in fields.iter_mut().enumerate() {
+        *field = (packed >> (i * 16)) & 0xFFFF;
+    }
+    fields
+}
+
+/// Value a multi-asset collateral portfolio after per-asset haircuts:
+/// sum of `amount * haircut_bps / 10000`. A haircut of 0 drops the asset entirely,
+/// haircuts above 10000 bps are clamped to full value, and the sum saturates.
+fn compute_portfolio_collateral(amounts: [u64; 4], haircuts: [u64; 4]) -> u64 {
+    let mut total = 0u64;
+    for i in 0..amounts.len() {
+        let haircut = haircuts[i].min(10000);
+        // A saturated product must stay saturated rather than shrink back down by the divide
+        let adjusted = amounts[i]
+            .checked_mul(haircut)
+            .map_or(u64::MAX, |product| safe_div_u64(product, 10000));
+        total = safe_add_u64(total, adjusted);
+    }
+    total
+}
+
+/// Slash a validator's stake for the misbehavior recorded in `misbehavior_flags`.
+/// Penalties stack additively and are capped at 100%.
+/// Returns `(remaining_stake, slashed_amount, tombstoned)`.
+fn apply_slashing(staked_amount: u64, misbehavior_flags: u32) -> (u64, u64, bool) {
+    let mut slash_bps = 0u32;
+    if misbehavior_flags & MISBEHAVIOR_DOUBLE_SIGN != 0 {
+        slash_bps = slash_bps.saturating_add(500);
+    }
+    if misbehavior_flags & MISBEHAVIOR_DOWNTIME != 0 {
+        // 0.1% per offline epoch recorded in the downtime sub-bits
+        let offline_epochs = (misbehavior_flags & MISBEHAVIOR_DOWNTIME_EPOCHS).count_ones();
+        slash_bps = slash_bps.saturating_add(offline_epochs * 10);
+    }
+    let tombstoned = misbehavior_flags & MISBEHAVIOR_SEVERE != 0;
+    if tombstoned {
+        slash_bps = slash_bps.saturating_add(5000);
+    }
+    let slash_bps = slash_bps.min(10000) as u64;
+
+    // Divide first when the product would saturate, so the slash never exceeds the stake
+    let slashed = match staked_amount.checked_mul(slash_bps) {
+        Some(product) => safe_div_u64(product, 10000),
+        None => safe_mul_u64(safe_div_u64(staked_amount, 10000), slash_bps),
+    };
+    (safe_sub_u64(staked_amount, slashed), slashed, tombstoned)
+}
+
+/// Value `amount` of collateral in borrow units at `price_e6` (1e6 = parity).
+/// A price older than PRICE_HAIRCUT_AGE_BLOCKS loses 5%; one older than
+/// PRICE_STALE_AGE_BLOCKS loses STALE_PRICE_HAIRCUT_BPS instead.
+/// Returns `(value, stale)`.
+fn price_collateral(amount: u64, price_e6: u64, price_age_blocks: u64) -> (u64, bool) {
+    let value = mul_div_u64(amount, price_e6, 1_000_000);
+    if price_age_blocks > PRICE_STALE_AGE_BLOCKS {
+        let haircut = 10000 - STALE_PRICE_HAIRCUT_BPS;
+        return (mul_div_u64(value, haircut, 10000), true);
+    }
+    if price_age_blocks > PRICE_HAIRCUT_AGE_BLOCKS {
+        return (mul_div_u64(value, 9500, 10000), false);
+    }
+    (value, false)
+}
+
+/// Split collateral into the part locked in staking and the part left free to back a loan.
+/// Returns `(staked, unstaked)`.
+fn split_collateral(collateral: u64, stake_ratio: u32) -> (u64, u64) {
+    let staked = mul_div_u64(collateral, stake_ratio.min(100) as u64, 100);
+    (staked, safe_sub_u64(collateral, staked))
+}
+
 /// Validate if the collateral is sufficient for the borrowed amount
-/// Enforce a 200% collateral ratio for "healthy" loans.
-fn validate_loan_health(collateral: u32, borrowed: u32) -> bool {
+/// Enforce a `min_collateral_ratio_pct` collateral ratio (e.g. 200%) for "healthy" loans.
+fn validate_loan_health(collateral: u64, borrowed: u64, min_collateral_ratio_pct: u32) -> bool {
     if borrowed == 0 {
         return false;
     }
-    let ratio = safe_div_u32(safe_mul_u32(collateral, 100), borrowed);
-    ratio >= 200
+    let ratio = safe_div_u64(safe_mul_u64(collateral, 100), borrowed);
+    ratio >= min_collateral_ratio_pct as u64
+}
+
+/// Largest borrow that `collateral` supports at `min_collateral_ratio_pct`.
+fn max_healthy_borrow(collateral: u64, min_collateral_ratio_pct: u32) -> u64 {
+    safe_div_u64(
+        safe_mul_u64(collateral, 100),
+        min_collateral_ratio_pct as u64,
+    )
+}
+
+/// Split debt into the senior tranche (up to `senior_ceiling`) and the junior
+/// tranche above it. Returns `(senior, junior)`.
+fn split_debt(borrowed: u64, senior_ceiling: u64) -> (u64, u64) {
+    let senior = borrowed.min(senior_ceiling);
+    (senior, safe_sub_u64(borrowed, senior))
+}
+
+/// Debt as the health check sees it: junior debt is riskier and counts at 120%.
+fn tranche_weighted_debt(borrowed: u64, senior_ceiling: u64) -> u64 {
+    let (senior, junior) = split_debt(borrowed, senior_ceiling);
+    safe_add_u64(senior, mul_div_u64(junior, JUNIOR_DEBT_WEIGHT_PCT, 100))
+}
+
+/// Largest borrow whose tranche-weighted debt still fits under `max_weighted_debt`.
+fn max_tranched_borrow(max_weighted_debt: u64, senior_ceiling: u64) -> u64 {
+    if max_weighted_debt <= senior_ceiling {
+        return max_weighted_debt;
+    }
+    let junior_room = safe_sub_u64(max_weighted_debt, senior_ceiling);
+    safe_add_u64(
+        senior_ceiling,
+        mul_div_u64(junior_room, 100, JUNIOR_DEBT_WEIGHT_PCT),
+    )
+}
+
+/// Interest on tranched debt: the senior tranche accrues at `annual_interest_bps`,
+/// the junior tranche at double that. Each compounds on its own and the two are summed.
+fn compute_tranche_interest(
+    borrowed: u64,
+    senior_ceiling: u64,
+    annual_interest_bps: u32,
+    time_slices: u32,
+) -> u64 {
+    let (senior, junior) = split_debt(borrowed, senior_ceiling);
+    let junior_bps = annual_interest_bps.saturating_mul(2);
+    safe_add_u64(
+        compute_compound_interest(senior, annual_interest_bps, time_slices),
+        compute_compound_interest(junior, junior_bps, time_slices),
+    )
+}
+
+/// Is `amount` above the borrow cap? A cap of 0 means uncapped.
+fn exceeds_borrow_cap(amount: u64, borrow_cap: u64) -> bool {
+    borrow_cap != 0 && amount > borrow_cap
+}
+
+/// Split a loan into the upfront origination fee kept by the protocol and the
+/// net amount actually disbursed. Returns `(fee, net_disbursement)`.
+fn compute_origination_fee(borrowed: u64, origination_fee_bps: u32) -> (u64, u64) {
+    let fee = safe_div_u64(safe_mul_u64(borrowed, origination_fee_bps as u64), 10000);
+    (fee, safe_sub_u64(borrowed, fee))
 }
 
 /// Compute interest in basis points (bps), with more complex logic and loops:
 /// We simulate compounding per time slice to increase complexity.
-fn compute_compound_interest(borrowed: u32, annual_interest_bps: u32, time_slices: u32) -> u32 {
+fn compute_compound_interest(borrowed: u64, annual_interest_bps: u32, time_slices: u32) -> u64 {
     // We'll do naive compounding in steps. For each slice, interest = borrowed*(annual_interest_bps/10000)* (1/time_slices)
     // Then borrowed += interest. Return final borrowed - original as the total interest accrued.
     if time_slices == 0 {
         return 0;
     }
     let mut principal = borrowed;
-    let fraction_bps = safe_div_u32(annual_interest_bps, time_slices);
+    // Multiply before dividing: annual_interest_bps / 10000 on its own is 0 below 100%.
+    // The division remainder carries into the next slice so many small slices don't
+    // each lose a fraction of a unit.
+    let rate = annual_interest_bps as u64;
+    let slice_divisor = safe_mul_u64(10000, time_slices as u64);
+    let mut carry = 0u64;
 
     for _ in 0..time_slices {
-        let rate = safe_div_u32(fraction_bps, 10000);
-        let slice_interest = safe_mul_u32(principal, rate);
-        principal = safe_add_u32(principal, slice_interest);
+        let slice_interest = match principal
+            .checked_mul(rate)
+            .and_then(|n| n.checked_add(carry))
+        {
+            Some(numerator) => {
+                carry = numerator % slice_divisor;
+                numerator / slice_divisor
+            }
+            None => mul_div_u64(principal, rate, slice_divisor),
+        };
+        principal = safe_add_u64(principal, slice_interest);
+    }
+    safe_sub_u64(principal, borrowed)
+}
+
+/// Effective annual yield of `apr_bps` compounded `compounds_per_year` times,
+/// measured with `compute_compound_interest` on a 1e9-scaled unit principal.
+/// 0 or 1 compounds is just the APR.
+fn apr_to_apy_bps(apr_bps: u32, compounds_per_year: u32) -> u32 {
+    if compounds_per_year <= 1 {
+        return apr_bps;
+    }
+    let unit = 1_000_000_000u64;
+    let compounds = compounds_per_year.min(MAX_COMPOUNDING_STEPS);
+    let interest = compute_compound_interest(unit, apr_bps, compounds);
+    let apy_bps = mul_div_u64(interest, 10000, unit);
+    apy_bps.try_into().unwrap_or(u32::MAX)
+}
+
+/// Fixed per-period payment that pays `borrowed` off in `periods` payments:
+/// payment = P * r * (1+r)^n / ((1+r)^n - 1) with r = annual_interest_bps / (10000 * n),
+/// worked out exactly in BigUint and rounded to the nearest unit. Zero periods means the
+/// whole principal is due at once, and terms longer than MAX_COMPOUNDING_STEPS periods are
+/// clamped to it.
+#[no_mangle]
+pub fn compute_amortized_payment(borrowed: u64, annual_interest_bps: u32, periods: u32) -> u64 {
+    if periods == 0 {
+        return borrowed;
+    }
+    let periods = periods.min(MAX_COMPOUNDING_STEPS);
+    if annual_interest_bps == 0 {
+        // No interest: the principal is simply spread across the periods
+        return safe_div_u64(safe_add_u64(borrowed, periods as u64 / 2), periods as u64);
+    }
+
+    // With r = a / d, (1+r)^n = (d+a)^n / d^n and the payment is
+    // P * a * (d+a)^n / (d * ((d+a)^n - d^n))
+    let rate = BigUint::from(annual_interest_bps);
+    let divisor = BigUint::from(10000u64 * periods as u64);
+    let grown = (&divisor + &rate).pow(periods);
+    let numerator = BigUint::from(borrowed) * &rate * &grown;
+    let denominator = &divisor * (grown - divisor.pow(periods));
+    let payment = (numerator + (&denominator >> 1u32)) / denominator;
+    payment.try_into().unwrap_or(u64::MAX)
+}
+
+/// Walk the amortization schedule of `periods` payments of `payment` (clamped like
+/// `compute_amortized_payment`): accrue interest on the outstanding balance, then take the
+/// payment out of it. Returns the total interest paid and the balance left, which is
+/// negative if the payments overshot.
+fn repayment_schedule(borrowed: u64, rate_bps: u32, periods: u32, payment: u64) -> (u64, i128) {
+    let periods = periods.min(MAX_COMPOUNDING_STEPS);
+    let divisor = 10000 * i128::from(periods);
+    let mut balance = i128::from(borrowed);
+    let mut total_interest = 0u64;
+    for _ in 0..periods {
+        let interest = balance.max(0) * i128::from(rate_bps) / divisor;
+        total_interest = safe_add_u64(total_interest, interest.try_into().unwrap_or(u64::MAX));
+        balance += interest - i128::from(payment);
+    }
+    (total_interest, balance)
+}
+
+/// Check `compute_amortized_payment` against the schedule it pays off, see
+/// `repayment_schedule`. Writes `(total_interest, still_owed, overpaid)` to `out_ptr`,
+/// see AMOUNT_TRIPLE_SIZE, where at most one of the last two is nonzero and both should
+/// be within `periods` units of 0. Returns the bytes written, or 0 if memory is out of
+/// range.
+///
+/// # Safety
+///
+/// As for `write_amount_triple`.
+#[no_mangle]
+pub unsafe fn simulate_repayment(borrowed: u64, rate_bps: u32, periods: u32, out_ptr: u32) -> u64 {
+    let payment = compute_amortized_payment(borrowed, rate_bps, periods);
+    let (total_interest, residual) = repayment_schedule(borrowed, rate_bps, periods, payment);
+    let still_owed = residual.max(0).try_into().unwrap_or(u64::MAX);
+    let overpaid = (-residual).max(0).try_into().unwrap_or(u64::MAX);
+    write_amount_triple(out_ptr, [total_interest, still_owed, overpaid])
+}
+
+/// Interest on `principal` over `slices` of a `slices_total`-slice term at `annual_interest_bps`.
+/// The rate is pro-rated to the covered part of the term, then compounded per slice,
+/// with at most MAX_COMPOUNDING_STEPS slices.
+fn compute_term_interest(
+    principal: u64,
+    annual_interest_bps: u32,
+    slices: u32,
+    slices_total: u32,
+) -> u64 {
+    let prorated_bps = safe_div_u64(
+        safe_mul_u64(annual_interest_bps as u64, slices as u64),
+        slices_total as u64,
+    );
+    compute_compound_interest(
+        principal,
+        prorated_bps.try_into().unwrap_or(u32::MAX),
+        slices.min(MAX_COMPOUNDING_STEPS),
+    )
+}
+
+/// Write an export's three amounts to `out_ptr`, see AMOUNT_TRIPLE_SIZE.
+/// Returns the bytes written, or 0 if memory is out of range.
+///
+/// # Safety
+///
+/// `out_ptr` must be 0 or point to AMOUNT_TRIPLE_SIZE writable bytes.
+unsafe fn write_amount_triple(out_ptr: u32, amounts: [u64; 3]) -> u64 {
+    let mut record = [0u8; AMOUNT_TRIPLE_SIZE];
+    for (chunk, amount) in record.chunks_exact_mut(8).zip(amounts) {
+        chunk.copy_from_slice(&amount.to_le_bytes());
+    }
+    if write_bytes(out_ptr, &record) {
+        AMOUNT_TRIPLE_SIZE as u64
+    } else {
+        0
+    }
+}
+
+/// Refinance a loan part-way through its term: interest at the old rate is capitalized
+/// into the principal, then the rest of the term runs at the new rate. Moving to a lower
+/// rate costs a 1% prepayment-style penalty, also capitalized. The capitalized loan must
+/// still be healthy at `min_collateral_ratio_pct`. Writes `(new_principal, interest_old,
+/// interest_new)` to `out_ptr`, see AMOUNT_TRIPLE_SIZE, and returns the bytes written,
+/// a STATUS_* code if the refinance is rejected, or 0 if memory is out of range.
+///
+/// # Safety
+///
+/// As for `write_amount_triple`.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn refinance(
+    collateral: u64,
+    borrowed: u64,
+    old_rate_bps: u32,
+    new_rate_bps: u32,
+    slices_elapsed: u32,
+    slices_total: u32,
+    min_collateral_ratio_pct: u32,
+    out_ptr: u32,
+) -> u64 {
+    match refinance_terms(
+        collateral,
+        borrowed,
+        old_rate_bps,
+        new_rate_bps,
+        slices_elapsed,
+        slices_total,
+        min_collateral_ratio_pct,
+    ) {
+        Ok(terms) => write_amount_triple(out_ptr, terms),
+        Err(status) => status,
+    }
+}
+
+/// `refinance`'s `[new_principal, interest_old, interest_new]`, or the STATUS_* code
+/// rejecting it.
+fn refinance_terms(
+    collateral: u64,
+    borrowed: u64,
+    old_rate_bps: u32,
+    new_rate_bps: u32,
+    slices_elapsed: u32,
+    slices_total: u32,
+    min_collateral_ratio_pct: u32,
+) -> Result<[u64; 3], u64> {
+    if min_collateral_ratio_pct < MIN_ACCEPTED_COLLATERAL_RATIO_PCT {
+        return Err(STATUS_RATIO_TOO_LOW);
+    }
+    if slices_elapsed >= slices_total {
+        return Err(STATUS_NOTHING_TO_REFINANCE);
     }
-    safe_sub_u32(principal, borrowed)
+
+    // Accrue at the old rate for the elapsed slices and capitalize it
+    let interest_old = compute_term_interest(borrowed, old_rate_bps, slices_elapsed, slices_total);
+    let mut new_principal = safe_add_u64(borrowed, interest_old);
+    if new_rate_bps < old_rate_bps {
+        new_principal = safe_add_u64(new_principal, safe_div_u64(new_principal, 100));
+    }
+    if !validate_loan_health(collateral, new_principal, min_collateral_ratio_pct) {
+        return Err(STATUS_REFINANCE_UNHEALTHY);
+    }
+
+    // The remainder of the term runs at the new rate
+    let slices_remaining = slices_total - slices_elapsed;
+    let interest_new =
+        compute_term_interest(new_principal, new_rate_bps, slices_remaining, slices_total);
+    Ok([new_principal, interest_old, interest_new])
+}
+
+/// Divert `insurance_bps` of accrued interest to the insurance fund.
+/// Returns `(interest_left_for_lenders, insurance_contribution)`.
+fn divert_to_insurance(interest: u64, insurance_bps: u32) -> (u64, u64) {
+    let contribution = mul_div_u64(interest, insurance_bps.min(10000) as u64, 10000);
+    (safe_sub_u64(interest, contribution), contribution)
+}
+
+/// Let the insurance fund absorb as much of a shortfall as its balance allows.
+/// Returns `(fund_after, uncovered_shortfall)`; the fund never goes negative.
+fn absorb_shortfall(insurance_fund: u64, shortfall: u64) -> (u64, u64) {
+    let absorbed = shortfall.min(insurance_fund);
+    (
+        safe_sub_u64(insurance_fund, absorbed),
+        safe_sub_u64(shortfall, absorbed),
+    )
 }
 
 /// Compute staking rewards using big integer logic for complexity:
-/// We'll treat the staked amount as a BigUint, do some arbitrary expansions, then reduce back to u32.
+/// We'll treat the staked amount as a BigUint, do some arbitrary expansions, then reduce back to u64.
+/// Exiting before `lockup_slices` forfeits 10% of the reward per slice left in the lockup
+/// (capped at 100%). Returns `(reward_after_slash, slash_amount)`.
+/// A tombstoned validator earns nothing.
 fn compute_staking_rewards_bigint(
-    collateral: u32,
+    collateral: u64,
     stake_ratio: u32,
     reward_rate_bps: u32,
     time_slices: u32,
-) -> u32 {
+    lockup_slices: u32,
+    exit_slice: u32,
+    tombstoned: bool,
+) -> (u64, u64) {
+    if tombstoned {
+        return (0, 0);
+    }
     let collateral_big = collateral.to_biguint().unwrap_or(BigUint::zero());
     let ratio_big = stake_ratio.to_biguint().unwrap_or(BigUint::zero());
     let hundred_big = 100u32.to_biguint().unwrap_or(BigUint::one());
-    let reward_bps_big = reward_rate_bps.to_biguint().unwrap_or(BigUint::zero());
-    let ten_thousand_big = 10000u32.to_biguint().unwrap_or(BigUint::one());
+    let million_big = 1_000_000u32.to_biguint().unwrap_or(BigUint::one());
 
     // staked_amount = (collateral * stake_ratio)/100 as BigUint
     let staked = (&collateral_big * &ratio_big) / &hundred_big;
-    // total_reward_rate = (reward_rate_bps/time_slices)/10000 in BigUint
+    // partial_rate = (reward_rate_bps/time_slices)/10000. The bps are scaled by 1e6 (x100 of
+    // the /10000) and the division by time_slices is folded into the per-slice divisor, so the
+    // rate never floors to 0. Neither changes between slices, so both are computed once.
     // We'll compound similarly over time_slices
+    let partial_rate_e6 = safe_mul_u64(reward_rate_bps as u64, 100)
+        .to_biguint()
+        .unwrap_or(BigUint::zero());
+    let slice_divisor = (&million_big * time_slices).max(BigUint::one());
     let mut current_staked = staked.clone();
     for _ in 0..time_slices {
-        let partial_rate = &reward_bps_big / &ten_thousand_big / time_slices;
-        // biguint does not do fractional divides precisely, so we'll keep it integer-limited
-        // But we will artificially simulate partial compounding
-        let yield_part = &current_staked * &partial_rate;
+        let yield_part = &current_staked * &partial_rate_e6 / &slice_divisor;
         current_staked = &current_staked + yield_part;
     }
     // The difference is the reward
@@ -110,118 +521,1907 @@ fn compute_staking_rewards_bigint(
     } else {
         BigUint::zero()
     };
-    // Convert back to u32
-    reward.try_into().unwrap_or(u32::MAX)
+    // Early-exit slash, taken on the BigUint reward so a huge reward isn't clipped first
+    let slash_pct = if exit_slice < lockup_slices {
+        (lockup_slices - exit_slice).saturating_mul(10).min(100)
+    } else {
+        0
+    };
+    let slash_pct_big = slash_pct.to_biguint().unwrap_or(BigUint::zero());
+    let slash = (&reward * &slash_pct_big) / &hundred_big;
+    let kept = &reward - &slash;
+    // Convert back to u64
+    (
+        kept.try_into().unwrap_or(u64::MAX),
+        slash.try_into().unwrap_or(u64::MAX),
+    )
+}
+
+/// Linear vesting of a reward: nothing before `cliff_slices`, then
+/// `total_reward * elapsed / vest_slices`, clamped to fully vested at the end.
+/// Returns `(vested, unvested)`.
+fn compute_vested(
+    total_reward: u64,
+    vest_slices: u32,
+    elapsed_slices: u32,
+    cliff_slices: u32,
+) -> (u64, u64) {
+    if elapsed_slices < cliff_slices {
+        return (0, total_reward);
+    }
+    if vest_slices == 0 || elapsed_slices >= vest_slices {
+        return (total_reward, 0);
+    }
+    // Multiply before dividing; the product can exceed u64 for large rewards
+    let total_big = total_reward.to_biguint().unwrap_or(BigUint::zero());
+    let elapsed_big = elapsed_slices.to_biguint().unwrap_or(BigUint::zero());
+    let vest_big = vest_slices.to_biguint().unwrap_or(BigUint::one());
+    let vested: u64 = (total_big * elapsed_big / vest_big)
+        .try_into()
+        .unwrap_or(u64::MAX);
+    (vested, safe_sub_u64(total_reward, vested))
+}
+
+/// One LP's pro-rata share of a bad-debt shortfall: `shortfall * my_shares / total_shares`,
+/// rounded down. With no LPs (`total_shares == 0`) nobody absorbs anything.
+fn socialize_bad_debt(shortfall: u64, total_shares: u64, my_shares: u64) -> u64 {
+    if total_shares == 0 {
+        return 0;
+    }
+    // Multiply first; the product easily exceeds u64
+    let shortfall_big = shortfall.to_biguint().unwrap_or(BigUint::zero());
+    let my_big = my_shares.to_biguint().unwrap_or(BigUint::zero());
+    let total_big = total_shares.to_biguint().unwrap_or(BigUint::one());
+    (shortfall_big * my_big / total_big)
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+/// Spread a shortfall across every LP in `holdings`. Rounding dust goes to the largest
+/// holder (the first one on ties) so the losses add up to the shortfall exactly.
+/// Returns `(per_lp_losses, protocol_bad_debt)`; the shortfall only becomes protocol
+/// bad debt when there are no LP shares to absorb it.
+fn distribute_bad_debt(shortfall: u64, holdings: &[u64]) -> (Vec<u64>, u64) {
+    let mut total_shares = 0u64;
+    let mut largest = 0usize;
+    for (i, &shares) in holdings.iter().enumerate() {
+        total_shares = safe_add_u64(total_shares, shares);
+        if shares > holdings[largest] {
+            largest = i;
+        }
+    }
+    if total_shares == 0 {
+        return (vec![0; holdings.len()], shortfall);
+    }
+
+    let mut losses = Vec::with_capacity(holdings.len());
+    let mut assigned = 0u64;
+    for &shares in holdings {
+        let loss = socialize_bad_debt(shortfall, total_shares, shares);
+        assigned = safe_add_u64(assigned, loss);
+        losses.push(loss);
+    }
+    losses[largest] = safe_add_u64(losses[largest], safe_sub_u64(shortfall, assigned));
+    (losses, 0)
 }
 
 /// Simulate a complex liquidity pool shares mechanism with multiple steps:
 /// 1. There's a base decay each block
 /// 2. There's a performance fee (subtraction)
 /// 3. Optional partial fallback if shares drop below a threshold
+/// 4. Flash-loan fee income earned each slice offsets that slice's decay (never beyond it)
+/// 5. This position's socialized share of any bad-debt `shortfall`, split against
+///    `other_lp_shares` held by everyone else in the pool, taken at the end
+///
+/// Every share that leaves or enters is accounted for, so
+/// `shares == final_shares + total_decay + total_fees_collected - fallback_restakes`.
+/// Socialized bad debt counts towards `total_decay`; whatever part of the shortfall no LP
+/// can absorb (there are no shares at all, or this position's loss is more than it holds)
+/// is reported as `protocol_bad_debt`.
+/// Returns `(final_shares, total_decay, total_fees_collected, fallback_restakes,
+/// protocol_bad_debt)`; e.g. `main`'s pool (2000 shares, 5 slices, 100 bps decay, 50 bps fee)
+/// gives (1860, 95, 45, 0, 0).
 fn simulate_liquidity_pool_shares_complex(
-    shares: u32,
+    shares: u64,
     time_slices: u32,
     decay_bps: u32,
     performance_fee_bps: u32,
-) -> u32 {
-    if time_slices == 0 {
-        return shares;
-    }
-
+    shortfall: u64,
+    other_lp_shares: u64,
+    flash_fee_income_per_slice: u64,
+) -> (u64, u64, u64, u64, u64) {
     let mut current_shares = shares;
+    let mut total_decay = 0u64;
+    let mut total_fees_collected = 0u64;
+    let mut fallback_restakes = 0u64;
     for _ in 0..time_slices {
-        // Decay
-        let decay_rate = safe_div_u32(decay_bps, 10000);
-        let decay_amount = safe_mul_u32(current_shares, decay_rate);
-        current_shares = safe_sub_u32(current_shares, decay_amount);
+        // Decay (multiply before dividing; decay_bps / 10000 alone is 0 below 100%)
+        let decay_amount = safe_sub_u64(
+            mul_div_u64(current_shares, decay_bps as u64, 10000),
+            flash_fee_income_per_slice,
+        )
+        .min(current_shares);
+        current_shares = safe_sub_u64(current_shares, decay_amount);
+        total_decay = safe_add_u64(total_decay, decay_amount);
 
-        // Performance fee
-        let fee_rate = safe_div_u32(performance_fee_bps, 10000);
-        let fee_amount = safe_mul_u32(current_shares, fee_rate);
-        current_shares = safe_sub_u32(current_shares, fee_amount);
+        // Performance fee, collected rather than burned
+        let fee_amount =
+            mul_div_u64(current_shares, performance_fee_bps as u64, 10000).min(current_shares);
+        current_shares = safe_sub_u64(current_shares, fee_amount);
+        total_fees_collected = safe_add_u64(total_fees_collected, fee_amount);
 
         // If shares drop below 100, do partial fallback: attempt re-stake half
         if current_shares < 100 {
-            let half_stake = safe_div_u32(current_shares, 2);
-            current_shares = safe_add_u32(current_shares, half_stake); // artificially re-stake half
+            let half_stake = safe_div_u64(current_shares, 2);
+            current_shares = safe_add_u64(current_shares, half_stake); // artificially re-stake half
+            fallback_restakes = safe_add_u64(fallback_restakes, half_stake);
+        }
+    }
+
+    // Socialized bad debt comes out of whatever is left
+    let (losses, unabsorbed) = distribute_bad_debt(shortfall, &[current_shares, other_lp_shares]);
+    let socialized_loss = losses[0].min(current_shares);
+    current_shares = safe_sub_u64(current_shares, socialized_loss);
+    total_decay = safe_add_u64(total_decay, socialized_loss);
+    let protocol_bad_debt = safe_add_u64(unabsorbed, safe_sub_u64(losses[0], socialized_loss));
+
+    (
+        current_shares,
+        total_decay,
+        total_fees_collected,
+        fallback_restakes,
+        protocol_bad_debt,
+    )
+}
+
+/// Flash loan against the pool: borrowed and repaid within one transaction, so the only
+/// lasting effect is the fee. The loan must fit in the pool, and the borrower's profit must
+/// cover the fee or the whole thing reverts. Returns the pool's new liquidity and the fee
+/// earned, combined with XOR.
+#[no_mangle]
+pub fn flash_loan(
+    pool_liquidity: u64,
+    loan_amount: u64,
+    fee_bps: u32,
+    borrower_profit: u64,
+) -> u64 {
+    if loan_amount > pool_liquidity {
+        return STATUS_FLASH_LOAN_OVERSIZE;
+    }
+    let fee = mul_div_u64(loan_amount, fee_bps as u64, 10000);
+    if borrower_profit < fee {
+        return STATUS_FLASH_LOAN_REVERTED;
+    }
+    let new_liquidity = safe_add_u64(pool_liquidity, fee);
+    combine_results(&[new_liquidity, fee])
+}
+
+/// Health factor as a collateral percentage of debt; no debt at all saturates.
+fn health_factor_pct(collateral: u64, debt: u64) -> u64 {
+    if debt == 0 {
+        return u64::MAX;
+    }
+    mul_div_u64(collateral, 100, debt)
+}
+
+/// Credit delegation: the delegator lends its unused borrowing capacity to a delegatee.
+/// The grant is capped by the delegator's free capacity at `min_collateral_ratio_pct` and
+/// by `delegation_limit`, and the delegated amount then counts as the delegator's own debt.
+/// A zero request or an already unhealthy delegator grants nothing.
+/// Writes `(granted, delegator_health_pct, delegatee_health_pct)` to `out_ptr`, see
+/// AMOUNT_TRIPLE_SIZE, where the delegatee's health is the delegator's collateral measured
+/// against the grant alone and no debt at all is u64::MAX. Returns the bytes written,
+/// STATUS_RATIO_TOO_LOW for a ratio below 100%, or 0 if memory is out of range.
+///
+/// # Safety
+///
+/// As for `write_amount_triple`.
+#[no_mangle]
+pub unsafe fn delegate_credit(
+    delegator_collateral: u64,
+    delegator_borrowed: u64,
+    delegatee_borrow_request: u64,
+    delegation_limit: u64,
+    min_collateral_ratio_pct: u32,
+    out_ptr: u32,
+) -> u64 {
+    match delegation_terms(
+        delegator_collateral,
+        delegator_borrowed,
+        delegatee_borrow_request,
+        delegation_limit,
+        min_collateral_ratio_pct,
+    ) {
+        Ok(terms) => write_amount_triple(out_ptr, terms),
+        Err(status) => status,
+    }
+}
+
+/// `delegate_credit`'s `[granted, delegator_health_pct, delegatee_health_pct]`, or the
+/// STATUS_* code rejecting the ratio.
+fn delegation_terms(
+    delegator_collateral: u64,
+    delegator_borrowed: u64,
+    delegatee_borrow_request: u64,
+    delegation_limit: u64,
+    min_collateral_ratio_pct: u32,
+) -> Result<[u64; 3], u64> {
+    if min_collateral_ratio_pct < MIN_ACCEPTED_COLLATERAL_RATIO_PCT {
+        return Err(STATUS_RATIO_TOO_LOW);
+    }
+    let delegator_healthy = delegator_borrowed == 0
+        || validate_loan_health(
+            delegator_collateral,
+            delegator_borrowed,
+            min_collateral_ratio_pct,
+        );
+
+    let mut granted = 0u64;
+    if delegatee_borrow_request > 0 && delegator_healthy {
+        let free_capacity = safe_sub_u64(
+            max_healthy_borrow(delegator_collateral, min_collateral_ratio_pct),
+            delegator_borrowed,
+        );
+        granted = delegatee_borrow_request
+            .min(free_capacity)
+            .min(delegation_limit);
+        // The delegator carries the delegated debt and has to stay healthy with it
+        let delegator_debt = safe_add_u64(delegator_borrowed, granted);
+        if granted > 0
+            && !validate_loan_health(
+                delegator_collateral,
+                delegator_debt,
+                min_collateral_ratio_pct,
+            )
+        {
+            granted = 0;
+        }
+    }
+
+    let delegator_debt = safe_add_u64(delegator_borrowed, granted);
+    Ok([
+        granted,
+        health_factor_pct(delegator_collateral, delegator_debt),
+        health_factor_pct(delegator_collateral, granted),
+    ])
+}
+
+/// Largest principal in `[lo, hi]` that passes `healthy`, found by bisection.
+/// `healthy` must be monotone (true up to some principal, false beyond it) and `lo`
+/// must pass it. Exact as long as `hi - lo` is under 2^MAX_BISECTION_STEPS.
+fn bisect_max_healthy(mut lo: u64, mut hi: u64, healthy: impl Fn(u64) -> bool) -> u64 {
+    if healthy(hi) {
+        return hi;
+    }
+    for _ in 0..MAX_BISECTION_STEPS {
+        if hi - lo <= 1 {
+            break;
+        }
+        let mid = lo + (hi - lo) / 2;
+        if healthy(mid) {
+            lo = mid;
+        } else {
+            hi = mid;
         }
     }
-    current_shares
+    lo
+}
+
+/// Largest healthy principal up to `borrowed`, or `None` if even a borrow of 1 fails.
+/// The bisection bracket starts as a narrow window around the closed-form `estimate`
+/// so that it stays exact for amounts far beyond 2^32, and widens to the full range
+/// whenever the window doesn't actually straddle the answer.
+fn search_max_healthy_borrow(
+    borrowed: u64,
+    estimate: u64,
+    healthy: impl Fn(u64) -> bool,
+) -> Option<u64> {
+    const WINDOW: u64 = 1 << 16;
+    if borrowed == 0 || !healthy(1) {
+        return None;
+    }
+    let mut lo = estimate.saturating_sub(WINDOW).clamp(1, borrowed);
+    if !healthy(lo) {
+        lo = 1;
+    }
+    let mut hi = estimate.saturating_add(WINDOW).clamp(lo, borrowed);
+    if healthy(hi) {
+        hi = borrowed;
+    }
+    Some(bisect_max_healthy(lo, hi, healthy))
 }
 
 /// Attempt a partial fallback to fix a loan that isn't healthy:
 /// We do multiple stages: reduce borrowed, re-check, compound interest again,
 /// and combine all partial results with XOR at the end.
+/// Returns that combined result and the shortfall neither the collateral nor the insurance
+/// fund covers, which is left for the liquidity pool to absorb (0 if a reduced loan settles).
+#[allow(clippy::too_many_arguments)]
 fn partial_fallback_loan(
-    collateral: u32,
-    borrowed: u32,
+    collateral: u64,
+    borrowed: u64,
     annual_interest_bps: u32,
     stake_ratio: u32,
     reward_rate_bps: u32,
-) -> u32 {
-    // 1) Half the borrowed
-    let half_borrowed = safe_div_u32(borrowed, 2);
-    if validate_loan_health(collateral, half_borrowed) {
-        let interest = compute_compound_interest(half_borrowed, annual_interest_bps, 3);
-        let staking = compute_staking_rewards_bigint(collateral, stake_ratio, reward_rate_bps, 3);
-        return combine_results(&[half_borrowed, interest, staking, collateral]);
+    borrow_cap: u64,
+    lockup_slices: u32,
+    exit_slice: u32,
+    tombstoned: bool,
+    origination_fee_bps: u32,
+    min_collateral_ratio_pct: u32,
+    senior_ceiling: u64,
+    insurance_bps: u32,
+    insurance_fund_in: u64,
+    fallback_mode: u32,
+) -> (u64, u64) {
+    // Interest and staking on whichever reduced principal turns out healthy;
+    // the origination fee comes out of that principal, interest accrues on all of it
+    // and part of that interest tops up the insurance fund
+    let settle = |principal: u64| {
+        let (fee, net) = compute_origination_fee(principal, origination_fee_bps);
+        let accrued = compute_tranche_interest(principal, senior_ceiling, annual_interest_bps, 3);
+        let (interest, contribution) = divert_to_insurance(accrued, insurance_bps);
+        let insurance_fund_out = safe_add_u64(insurance_fund_in, contribution);
+        let (staking, slash) = compute_staking_rewards_bigint(
+            collateral,
+            stake_ratio,
+            reward_rate_bps,
+            3,
+            lockup_slices,
+            exit_slice,
+            tombstoned,
+        );
+        let combined = combine_results(&[
+            net,
+            fee,
+            interest,
+            staking,
+            slash,
+            collateral,
+            insurance_fund_out,
+        ]);
+        (combined, 0)
+    };
+
+    // Staked collateral is locked; only the rest can back the reduced loan,
+    // and junior debt weighs more against it
+    let (_, backing) = split_collateral(collateral, stake_ratio);
+    let healthy = |principal: u64| {
+        validate_loan_health(
+            backing,
+            tranche_weighted_debt(principal, senior_ceiling),
+            min_collateral_ratio_pct,
+        )
+    };
+
+    // 0) Clamp an over-cap borrow to exactly the cap; halving continues from there
+    let mut borrowed = borrowed;
+    if exceeds_borrow_cap(borrowed, borrow_cap) {
+        borrowed = borrow_cap;
+        if healthy(borrowed) {
+            return settle(borrowed);
+        }
     }
 
-    // 2) Quarter the borrowed
-    let quarter_borrowed = safe_div_u32(half_borrowed, 2);
-    if validate_loan_health(collateral, quarter_borrowed) {
-        let interest = compute_compound_interest(quarter_borrowed, annual_interest_bps, 3);
-        let staking = compute_staking_rewards_bigint(collateral, stake_ratio, reward_rate_bps, 3);
-        return combine_results(&[quarter_borrowed, interest, staking, collateral]);
+    // The closed-form maximum the collateral supports at the required ratio
+    let max_weighted_debt = max_healthy_borrow(backing, min_collateral_ratio_pct);
+    let max_borrowed = borrowed.min(max_tranched_borrow(max_weighted_debt, senior_ceiling));
+
+    if fallback_mode == FALLBACK_MODE_HALVING {
+        // 1) Half of the request
+        let half_borrowed = safe_div_u64(borrowed, 2);
+        if healthy(half_borrowed) {
+            return settle(half_borrowed);
+        }
+
+        // 2) Quarter of the request
+        let quarter_borrowed = safe_div_u64(half_borrowed, 2);
+        if healthy(quarter_borrowed) {
+            return settle(quarter_borrowed);
+        }
+
+        // 3) The exact maximum, when even a quarter asks too much
+        if healthy(max_borrowed) {
+            return settle(max_borrowed);
+        }
+    } else if let Some(found) = search_max_healthy_borrow(borrowed, max_borrowed, healthy) {
+        // 1) Bisect for the largest borrow that passes the health check
+        return settle(found);
     }
 
-    // If still not healthy:
-    0
+    // If still not healthy, whatever debt the free collateral can't cover is a shortfall.
+    // The period's interest still tops up the insurance fund first, then the fund absorbs
+    // as much of the shortfall as it can.
+    let accrued = compute_tranche_interest(borrowed, senior_ceiling, annual_interest_bps, 3);
+    let (_, contribution) = divert_to_insurance(accrued, insurance_bps);
+    let insurance_fund = safe_add_u64(insurance_fund_in, contribution);
+    let shortfall = safe_sub_u64(borrowed, backing);
+    let (insurance_fund_out, uncovered_shortfall) = absorb_shortfall(insurance_fund, shortfall);
+    (
+        combine_results(&[insurance_fund_out, uncovered_shortfall]),
+        uncovered_shortfall,
+    )
 }
 
-/// Combine multiple results with XOR for final single-u32 output.
-fn combine_results(results: &[u32]) -> u32 {
-    let mut out = 0u32;
+/// Combine multiple results with XOR for final single-u64 output.
+fn combine_results(results: &[u64]) -> u64 {
+    let mut out = 0u64;
     for &r in results {
         out ^= r;
     }
     out
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
+#[allow(clippy::too_many_arguments)]
 pub fn main(
-    collateral_amount: u32,
-    borrowed_amount: u32,
+    collateral_amount_0: u64,
+    collateral_amount_1: u64,
+    collateral_amount_2: u64,
+    collateral_amount_3: u64,
+    collateral_haircuts_bps: u64,
+    borrowed_amount: u64,
     stake_ratio: u32,
     annual_interest_bps: u32,
-) -> u32 {
-    // Step 1: Validate the loan
-    if !validate_loan_health(collateral_amount, borrowed_amount) {
+    borrow_cap: u64,
+    lockup_slices: u32,
+    exit_slice: u32,
+    misbehavior_flags: u32,
+    elapsed_slices: u32,
+    origination_fee_bps: u32,
+    min_collateral_ratio_pct: u32,
+    time_slices: u32,
+    collateral_price_e6: u64,
+    price_age_blocks: u64,
+    senior_ceiling: u64,
+    insurance_bps: u32,
+    insurance_fund_in: u64,
+    fallback_mode: u32,
+    other_lp_shares: u64,
+    flash_fee_income_per_slice: u64,
+) -> u64 {
+    // Every per-slice loop below is bounded by the same cap
+    let time_slices = time_slices.min(MAX_COMPOUNDING_STEPS);
+    if origination_fee_bps > MAX_ORIGINATION_FEE_BPS {
+        return STATUS_FEE_TOO_HIGH;
+    }
+    if min_collateral_ratio_pct < MIN_ACCEPTED_COLLATERAL_RATIO_PCT {
+        return STATUS_RATIO_TOO_LOW;
+    }
+    if stake_ratio > 100 {
+        return STATUS_STAKE_RATIO_INVALID;
+    }
+
+    // Step 0: Value the collateral portfolio (up to four assets, their haircuts 16 bits each)
+    let portfolio_value = compute_portfolio_collateral(
+        [
+            collateral_amount_0,
+            collateral_amount_1,
+            collateral_amount_2,
+            collateral_amount_3,
+        ],
+        unpack_u16_fields(collateral_haircuts_bps),
+    );
+
+    // Step 0b: Validator slashing eats into the collateral before any health check
+    let (slashed_collateral, validator_slash, tombstoned) =
+        apply_slashing(portfolio_value, misbehavior_flags);
+
+    // Step 0c: Price what's left. A stale oracle only gets a conservative valuation, and
+    // every result built on it carries WARN_STALE_ORACLE.
+    let (collateral_amount, stale_price) =
+        price_collateral(slashed_collateral, collateral_price_e6, price_age_blocks);
+    let stale_warning = if stale_price { WARN_STALE_ORACLE } else { 0 };
+
+    // The liquidity pool this position sits in: 2000 shares of its own beside the caller's
+    // `other_lp_shares`, 100 bps decay offset by `flash_fee_income_per_slice` and a 50 bps
+    // fee per slice. Any bad debt the loan leaves behind is socialized across it.
+    let pool_outcome = |shortfall: u64| {
+        let (final_shares, pool_decay, pool_fees, pool_restakes, protocol_bad_debt) =
+            simulate_liquidity_pool_shares_complex(
+                2000,
+                time_slices,
+                100,
+                50,
+                shortfall,
+                other_lp_shares,
+                flash_fee_income_per_slice,
+            );
+        combine_results(&[
+            final_shares,
+            pool_decay,
+            pool_fees,
+            pool_restakes,
+            protocol_bad_debt,
+        ])
+    };
+
+    // Step 1: Validate the loan against the collateral that isn't locked in staking
+    let (_, unstaked_collateral) = split_collateral(collateral_amount, stake_ratio);
+    let weighted_debt = tranche_weighted_debt(borrowed_amount, senior_ceiling);
+    if !validate_loan_health(unstaked_collateral, weighted_debt, min_collateral_ratio_pct) {
         // Step 1a: Attempt partial fallback if invalid
-        return partial_fallback_loan(
+        let (fallback, uncovered_shortfall) = partial_fallback_loan(
             collateral_amount,
             borrowed_amount,
             annual_interest_bps,
             stake_ratio,
             600,
+            borrow_cap,
+            lockup_slices,
+            exit_slice,
+            tombstoned,
+            origination_fee_bps,
+            min_collateral_ratio_pct,
+            senior_ceiling,
+            insurance_bps,
+            insurance_fund_in,
+            fallback_mode,
         );
+        // Step 1b: The pool's LPs absorb whatever the fallback left uncovered
+        return combine_results(&[fallback, pool_outcome(uncovered_shortfall)]) | stale_warning;
+    }
+
+    // Step 1c: A healthy loan still may not borrow past the cap
+    if exceeds_borrow_cap(borrowed_amount, borrow_cap) {
+        return STATUS_CAP_EXCEEDED;
     }
 
-    // Step 2: Calculate compound interest over 5 time slices
-    let interest_accrued = compute_compound_interest(borrowed_amount, annual_interest_bps, 5);
+    // Step 1d: The protocol keeps the origination fee; interest still runs on the full amount
+    let (origination_fee, net_disbursement) =
+        compute_origination_fee(borrowed_amount, origination_fee_bps);
+
+    // Step 2: Calculate compound interest over the requested time slices, per tranche
+    let total_interest = compute_tranche_interest(
+        borrowed_amount,
+        senior_ceiling,
+        annual_interest_bps,
+        time_slices,
+    );
+    // Part of the interest goes to the insurance fund rather than to lenders
+    let (interest_accrued, insurance_contribution) =
+        divert_to_insurance(total_interest, insurance_bps);
+    let insurance_fund_out = safe_add_u64(insurance_fund_in, insurance_contribution);
+    // Effective yield of the nominal rate under the same compounding, reported alongside it
+    let effective_apy_bps = apr_to_apy_bps(annual_interest_bps, time_slices) as u64;
+    // Interest alone pushing the debt past the cap is only worth a warning
+    let cap_warning =
+        if exceeds_borrow_cap(safe_add_u64(borrowed_amount, total_interest), borrow_cap) {
+            WARN_INTEREST_OVER_CAP
+        } else {
+            0
+        };
 
     // Step 3: Calculate staking rewards with BigUint-based compounding
-    let staking_rewards = compute_staking_rewards_bigint(collateral_amount, stake_ratio, 600, 5);
+    let (staking_rewards, early_exit_slash) = compute_staking_rewards_bigint(
+        collateral_amount,
+        stake_ratio,
+        600,
+        time_slices,
+        lockup_slices,
+        exit_slice,
+        tombstoned,
+    );
+    let (vested_rewards, unvested_rewards) = compute_vested(
+        staking_rewards,
+        REWARD_VEST_SLICES,
+        elapsed_slices,
+        REWARD_CLIFF_SLICES,
+    );
 
-    // Step 4: Simulate a more complex liquidity pool scenario for further complexity
-    let final_shares = simulate_liquidity_pool_shares_complex(2000, 5, 100, 50);
+    // Step 4: Simulate the liquidity pool, with no bad debt to absorb on the healthy path
+    let pool = pool_outcome(0);
 
     // Combine everything
     combine_results(&[
-        borrowed_amount,
+        net_disbursement,
+        origination_fee,
         interest_accrued,
-        staking_rewards,
-        final_shares,
+        effective_apy_bps,
+        vested_rewards,
+        unvested_rewards,
+        early_exit_slash,
+        pool,
         collateral_amount,
-    ])
+        validator_slash,
+        insurance_fund_out,
+    ]) | cap_warning
+        | stale_warning
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL_HAIRCUTS: u64 = 10000 | 10000 << 16 | 10000 << 32 | 10000 << 48;
+
+    /// Every `main` input, defaulting to a plain loan: 20% staked, 5% interest over 5 slices,
+    /// a 1% fee, 200% ratio and a fresh 1:1 price, uncapped and all senior.
+    struct MainArgs {
+        collateral: [u64; 4],
+        haircuts_bps: u64,
+        borrowed: u64,
+        stake_ratio: u32,
+        annual_interest_bps: u32,
+        borrow_cap: u64,
+        lockup_slices: u32,
+        exit_slice: u32,
+        misbehavior_flags: u32,
+        elapsed_slices: u32,
+        origination_fee_bps: u32,
+        min_collateral_ratio_pct: u32,
+        time_slices: u32,
+        price_e6: u64,
+        age_blocks: u64,
+        senior_ceiling: u64,
+        insurance_bps: u32,
+        insurance_fund_in: u64,
+        fallback_mode: u32,
+        other_lp_shares: u64,
+        flash_fee_income_per_slice: u64,
+    }
+
+    impl Default for MainArgs {
+        fn default() -> Self {
+            MainArgs {
+                collateral: [0; 4],
+                haircuts_bps: FULL_HAIRCUTS,
+                borrowed: 0,
+                stake_ratio: 20,
+                annual_interest_bps: 500,
+                borrow_cap: u64::MAX,
+                lockup_slices: 0,
+                exit_slice: 0,
+                misbehavior_flags: 0,
+                elapsed_slices: 10,
+                origination_fee_bps: 100,
+                min_collateral_ratio_pct: 200,
+                time_slices: 5,
+                price_e6: 1_000_000,
+                age_blocks: 0,
+                senior_ceiling: 10_000,
+                insurance_bps: 0,
+                insurance_fund_in: 0,
+                fallback_mode: 0,
+                other_lp_shares: 0,
+                flash_fee_income_per_slice: 0,
+            }
+        }
+    }
+
+    impl MainArgs {
+        fn run(&self) -> u64 {
+            main(
+                self.collateral[0],
+                self.collateral[1],
+                self.collateral[2],
+                self.collateral[3],
+                self.haircuts_bps,
+                self.borrowed,
+                self.stake_ratio,
+                self.annual_interest_bps,
+                self.borrow_cap,
+                self.lockup_slices,
+                self.exit_slice,
+                self.misbehavior_flags,
+                self.elapsed_slices,
+                self.origination_fee_bps,
+                self.min_collateral_ratio_pct,
+                self.time_slices,
+                self.price_e6,
+                self.age_blocks,
+                self.senior_ceiling,
+                self.insurance_bps,
+                self.insurance_fund_in,
+                self.fallback_mode,
+                self.other_lp_shares,
+                self.flash_fee_income_per_slice,
+            )
+        }
+    }
+
+    /// `main` with the given collateral and borrow, every other input at its default.
+    fn run_main(collateral: [u64; 4], borrowed: u64) -> u64 {
+        run_priced(collateral, borrowed, 1_000_000, 0)
+    }
+
+    /// `run_main` at the given collateral price.
+    fn run_priced(collateral: [u64; 4], borrowed: u64, price_e6: u64, age_blocks: u64) -> u64 {
+        run_with_pool_income(collateral, borrowed, price_e6, age_blocks, 0)
+    }
+
+    /// `run_priced` with flash-loan fee income offsetting the pool's decay.
+    fn run_with_pool_income(
+        collateral: [u64; 4],
+        borrowed: u64,
+        price_e6: u64,
+        age_blocks: u64,
+        flash_fee_income_per_slice: u64,
+    ) -> u64 {
+        MainArgs {
+            collateral,
+            borrowed,
+            price_e6,
+            age_blocks,
+            flash_fee_income_per_slice,
+            ..MainArgs::default()
+        }
+        .run()
+    }
+
+    /// `partial_fallback_loan` with `main`'s defaults for everything but the loan's shape.
+    fn run_fallback(
+        collateral: u64,
+        borrowed: u64,
+        borrow_cap: u64,
+        min_collateral_ratio_pct: u32,
+        fallback_mode: u32,
+    ) -> (u64, u64) {
+        partial_fallback_loan(
+            collateral,
+            borrowed,
+            500,
+            20,
+            600,
+            borrow_cap,
+            0,
+            0,
+            false,
+            100,
+            min_collateral_ratio_pct,
+            u64::MAX,
+            0,
+            0,
+            fallback_mode,
+        )
+    }
+
+    #[test]
+    fn refinance_to_a_lower_rate_pays_the_penalty() {
+        let interest_old = compute_term_interest(1_000_000, 1000, 5, 10);
+        assert!(interest_old > 0);
+        let capitalized = 1_000_000 + interest_old;
+        let new_principal = capitalized + capitalized / 100;
+        assert_eq!(
+            refinance_terms(10_000_000, 1_000_000, 1000, 500, 5, 10, 200),
+            Ok([
+                new_principal,
+                interest_old,
+                compute_term_interest(new_principal, 500, 5, 10)
+            ])
+        );
+    }
+
+    #[test]
+    fn refinance_to_a_higher_rate_has_no_penalty() {
+        let interest_old = compute_term_interest(1_000_000, 500, 5, 10);
+        let new_principal = 1_000_000 + interest_old;
+        assert_eq!(
+            refinance_terms(10_000_000, 1_000_000, 500, 1000, 5, 10, 200),
+            Ok([
+                new_principal,
+                interest_old,
+                compute_term_interest(new_principal, 1000, 5, 10)
+            ])
+        );
+    }
+
+    #[test]
+    fn refinance_rejects_a_capitalization_that_tips_the_loan() {
+        // Healthy at exactly 200% before the interest is capitalized
+        assert!(validate_loan_health(2_000_000, 1_000_000, 200));
+        assert_eq!(
+            refinance_terms(2_000_000, 1_000_000, 1000, 1000, 5, 10, 200),
+            Err(STATUS_REFINANCE_UNHEALTHY)
+        );
+        // The caller's ratio is the one enforced
+        assert!(refinance_terms(2_000_000, 1_000_000, 1000, 1000, 5, 10, 150).is_ok());
+        assert_eq!(
+            refinance_terms(2_000_000, 1_000_000, 1000, 1000, 5, 10, 99),
+            Err(STATUS_RATIO_TOO_LOW)
+        );
+    }
+
+    #[test]
+    fn refinance_needs_slices_left() {
+        assert_eq!(
+            refinance_terms(10_000, 1_000, 1000, 500, 10, 10, 200),
+            Err(STATUS_NOTHING_TO_REFINANCE)
+        );
+        // Safety: a null out_ptr is never written to
+        assert_eq!(
+            unsafe { refinance(10_000, 1_000, 1000, 500, 10, 10, 200, 0) },
+            STATUS_NOTHING_TO_REFINANCE
+        );
+    }
+
+    #[test]
+    fn refinance_keeps_amounts_past_21_bits() {
+        let borrowed = 1_000_000_000_000;
+        let [new_principal, interest_old, interest_new] =
+            refinance_terms(4 * borrowed, borrowed, 1000, 1000, 5, 10, 200).unwrap();
+        assert_eq!(interest_old, compute_term_interest(borrowed, 1000, 5, 10));
+        assert_eq!(new_principal, borrowed + interest_old);
+        assert!(interest_new > 1 << 21);
+        // Nowhere to write the record natively
+        // Safety: a null out_ptr is never written to
+        assert_eq!(
+            unsafe { refinance(4 * borrowed, borrowed, 1000, 1000, 5, 10, 200, 0) },
+            0
+        );
+    }
+
+    #[test]
+    fn delegation_grants_the_whole_request() {
+        // 200% of 10_000 leaves 5_000 of capacity, 1_000 of it already borrowed
+        assert_eq!(
+            delegation_terms(10_000, 1_000, 2_000, 5_000, 200),
+            Ok([2_000, 333, 500])
+        );
+    }
+
+    #[test]
+    fn delegation_is_capped_by_the_limit() {
+        assert_eq!(
+            delegation_terms(10_000, 1_000, 3_000, 1_500, 200),
+            Ok([1_500, 400, 666])
+        );
+    }
+
+    #[test]
+    fn delegation_is_capped_by_free_capacity() {
+        assert_eq!(
+            delegation_terms(10_000, 1_000, 9_000, 9_000, 200),
+            Ok([4_000, 200, 250])
+        );
+        // A looser ratio frees more of it
+        assert_eq!(
+            delegation_terms(10_000, 1_000, 9_000, 9_000, 125),
+            Ok([7_000, 125, 142])
+        );
+        assert_eq!(
+            delegation_terms(10_000, 1_000, 9_000, 9_000, 99),
+            Err(STATUS_RATIO_TOO_LOW)
+        );
+    }
+
+    #[test]
+    fn unhealthy_delegator_grants_nothing() {
+        assert_eq!(
+            delegation_terms(10_000, 6_000, 1_000, 1_000, 200),
+            Ok([0, 166, u64::MAX])
+        );
+        assert_eq!(
+            delegation_terms(10_000, 0, 0, 1_000, 200),
+            Ok([0, u64::MAX, u64::MAX])
+        );
+    }
+
+    #[test]
+    fn delegation_keeps_amounts_past_21_bits() {
+        let collateral = 1_000_000_000_000;
+        assert_eq!(
+            delegation_terms(collateral, 0, collateral, collateral, 200),
+            Ok([collateral / 2, 200, 200])
+        );
+        // Safety: a null out_ptr is never written to
+        assert_eq!(
+            unsafe { delegate_credit(collateral, 0, collateral, collateral, 200, 0) },
+            0
+        );
+    }
+
+    #[test]
+    fn fresh_price_values_collateral_at_par_times_price() {
+        assert_eq!(price_collateral(10_000, 1_500_000, 0), (15_000, false));
+        assert_eq!(
+            price_collateral(10_000, 1_500_000, PRICE_HAIRCUT_AGE_BLOCKS),
+            (15_000, false)
+        );
+        assert_eq!(run_priced([40_000, 0, 0, 0], 10_000, 1_000_000, 0), 48212);
+    }
+
+    #[test]
+    fn aging_price_takes_the_haircut() {
+        assert_eq!(
+            price_collateral(10_000, 1_500_000, PRICE_HAIRCUT_AGE_BLOCKS + 1),
+            (14_250, false)
+        );
+        assert_eq!(
+            price_collateral(10_000, 1_500_000, PRICE_STALE_AGE_BLOCKS),
+            (14_250, false)
+        );
+    }
+
+    #[test]
+    fn stale_price_only_warns() {
+        assert_eq!(
+            price_collateral(10_000, 1_500_000, PRICE_STALE_AGE_BLOCKS + 1),
+            (7_500, true)
+        );
+        // 80_000 at half price still backs the loan exactly like 40_000 at a fresh price
+        let stale = run_priced(
+            [80_000, 0, 0, 0],
+            10_000,
+            1_000_000,
+            PRICE_STALE_AGE_BLOCKS + 1,
+        );
+        assert_eq!(stale, 48212 | WARN_STALE_ORACLE);
+        assert_eq!(
+            stale,
+            run_priced([40_000, 0, 0, 0], 10_000, 1_000_000, 0) | WARN_STALE_ORACLE
+        );
+    }
+
+    #[test]
+    fn stale_price_never_zeroes_the_collateral() {
+        // At half price 40_000 leaves 16_000 unstaked, 160% of the borrow: the fallback
+        // settles on the 8_000 it supports instead of socializing all of it
+        let stale = run_priced(
+            [40_000, 0, 0, 0],
+            10_000,
+            1_000_000,
+            PRICE_STALE_AGE_BLOCKS + 1,
+        );
+        let (fallback, uncovered) = run_fallback(20_000, 10_000, u64::MAX, 200, 0);
+        assert_eq!(uncovered, 0);
+        assert_eq!(stale, (fallback ^ 1860 ^ 95 ^ 45) | WARN_STALE_ORACLE);
+        assert_ne!(stale & !WARN_STALE_ORACLE, 16210);
+    }
+
+    #[test]
+    fn zero_price_is_worthless_collateral() {
+        assert_eq!(price_collateral(10_000, 0, 0), (0, false));
+        assert_eq!(run_priced([40_000, 0, 0, 0], 10_000, 0, 0), 16210);
+        // Nothing borrowed goes to the fallback too, which settles nothing: only the pool is left
+        assert_eq!(run_priced([0, 0, 0, 0], 0, 0, 0), 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn bad_debt_losses_add_up_to_the_shortfall() {
+        assert_eq!(socialize_bad_debt(1_000, 3_000, 1_000), 333);
+        let (losses, protocol_bad_debt) = distribute_bad_debt(1_000, &[1_000, 1_000, 1_000]);
+        // The dust goes to the first of the tied largest holders
+        assert_eq!((losses, protocol_bad_debt), (vec![334, 333, 333], 0));
+        let (losses, _) = distribute_bad_debt(1_001, &[7, 500, 13, 499, 0]);
+        assert_eq!(losses.iter().sum::<u64>(), 1_001);
+        assert_eq!(losses[4], 0);
+    }
+
+    #[test]
+    fn bad_debt_without_shares_is_the_protocols() {
+        assert_eq!(socialize_bad_debt(1_000, 0, 0), 0);
+        assert_eq!(distribute_bad_debt(1_000, &[0, 0]), (vec![0, 0], 1_000));
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(0, 0, 100, 50, 1_000, 0, 0),
+            (0, 0, 0, 0, 1_000)
+        );
+    }
+
+    #[test]
+    fn pool_shares_a_shortfall_with_the_other_lps() {
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 50, 0, 0, 0),
+            (1860, 95, 45, 0, 0)
+        );
+        // 1860 of 3720 shares takes half of the shortfall
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 50, 1_000, 1860, 0),
+            (1360, 595, 45, 0, 0)
+        );
+    }
+
+    #[test]
+    fn sole_lp_absorbs_the_fallback_shortfall() {
+        // A loss past the position's 1860 shares is protocol bad debt
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 50, 10_000, 0, 0),
+            (0, 1955, 45, 0, 8140)
+        );
+        // With all the collateral staked nothing backs the loan, and with no insurance fund
+        // the fallback leaves all of it uncovered
+        let (fallback, uncovered) = partial_fallback_loan(
+            10_000,
+            100_000,
+            500,
+            100,
+            600,
+            u64::MAX,
+            0,
+            0,
+            false,
+            100,
+            200,
+            u64::MAX,
+            0,
+            0,
+            FALLBACK_MODE_HALVING,
+        );
+        assert_eq!((fallback, uncovered), (100_000, 100_000));
+    }
+
+    #[test]
+    fn profitable_flash_loan_pays_the_pool() {
+        // 0.3% of 100_000 is a 300 fee
+        assert_eq!(flash_loan(1_000_000, 100_000, 30, 1_000), 1_000_300 ^ 300);
+    }
+
+    #[test]
+    fn break_even_flash_loan_goes_through() {
+        assert_eq!(flash_loan(1_000_000, 100_000, 30, 300), 1_000_300 ^ 300);
+        assert_eq!(
+            flash_loan(1_000_000, 1_000_000, 30, 3_000),
+            1_003_000 ^ 3_000
+        );
+    }
+
+    #[test]
+    fn unprofitable_flash_loan_reverts() {
+        assert_eq!(
+            flash_loan(1_000_000, 100_000, 30, 299),
+            STATUS_FLASH_LOAN_REVERTED
+        );
+    }
+
+    #[test]
+    fn oversize_flash_loan_is_refused() {
+        assert_eq!(
+            flash_loan(1_000_000, 1_000_001, 30, u64::MAX),
+            STATUS_FLASH_LOAN_OVERSIZE
+        );
+    }
+
+    #[test]
+    fn flash_fee_income_offsets_pool_decay() {
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 50, 0, 0, 10),
+            (1909, 46, 45, 0, 0)
+        );
+        // Income beyond a slice's decay doesn't mint shares
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 50, 0, 0, 100),
+            (1954, 0, 46, 0, 0)
+        );
+        let without = run_main([40_000, 0, 0, 0], 10_000);
+        let with = run_with_pool_income([40_000, 0, 0, 0], 10_000, 1_000_000, 0, 10);
+        assert_eq!(with ^ without, (1909 ^ 46 ^ 45) ^ (1860 ^ 95 ^ 45));
+    }
+
+    #[test]
+    fn u32_range_answers_are_unchanged() {
+        // Pinned from the packed 16-bit input, so every amount here fit it
+        assert_eq!(run_main([40_000, 0, 0, 0], 10_000), 48212);
+        assert!(validate_loan_health(40_000, 20_000, 200));
+        assert!(!validate_loan_health(40_000, 20_001, 200));
+        assert_eq!(max_healthy_borrow(40_000, 200), 20_000);
+        assert_eq!(
+            compute_portfolio_collateral([40_000, 2_000, 0, 0], [10000, 5000, 0, 0]),
+            41_000
+        );
+    }
+
+    #[test]
+    fn trillion_collateral_does_not_saturate() {
+        let collateral = 1_000_000_000_000;
+        assert_eq!(
+            compute_portfolio_collateral([collateral, 0, 0, 0], [10000, 0, 0, 0]),
+            collateral
+        );
+        assert_eq!(max_healthy_borrow(collateral, 200), collateral / 2);
+        assert!(validate_loan_health(collateral, collateral / 2, 200));
+        assert!(!validate_loan_health(collateral, collateral / 2 + 1, 200));
+        // 40% of the unstaked 80% is well inside the ratio, so no status comes back
+        let result = run_main([collateral, 0, 0, 0], collateral / 5);
+        assert!(result < STATUS_STAKE_RATIO_INVALID);
+        assert_ne!(result, run_main([collateral, 0, 0, 0], collateral / 5 + 1));
+    }
+
+    #[test]
+    fn single_full_haircut_asset_is_its_amount() {
+        assert_eq!(
+            compute_portfolio_collateral([40_000, 0, 0, 0], [10000, 0, 0, 0]),
+            40_000
+        );
+        assert_eq!(unpack_u16_fields(FULL_HAIRCUTS), [10000; 4]);
+        assert_eq!(run_main([40_000, 0, 0, 0], 10_000), 48212);
+    }
+
+    #[test]
+    fn mixed_portfolio_takes_each_haircut() {
+        assert_eq!(unpack_u16_fields(0x0004_0003_0002_0001), [1, 2, 3, 4]);
+        assert_eq!(
+            compute_portfolio_collateral([1000, 2000, 4000, 0], [10000, 5000, 2500, 0]),
+            3000
+        );
+        // Haircuts past 100% count at full value, and the sum saturates
+        assert_eq!(
+            compute_portfolio_collateral([1000, 0, 0, 0], [20000, 0, 0, 0]),
+            1000
+        );
+        assert_eq!(
+            compute_portfolio_collateral([u64::MAX; 4], [10000; 4]),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn zero_haircut_asset_is_ignored() {
+        assert_eq!(
+            compute_portfolio_collateral([1000, 9999, 0, 0], [10000, 0, 0, 0]),
+            1000
+        );
+        assert_eq!(
+            run_main([40_000, 0, 0, 0], 10_000),
+            MainArgs {
+                collateral: [40_000, 9_999, 0, 0],
+                haircuts_bps: 10000,
+                borrowed: 10_000,
+                ..MainArgs::default()
+            }
+            .run()
+        );
+    }
+
+    #[test]
+    fn amortized_payment_clears_the_schedule() {
+        for (borrowed, rate_bps, periods) in [
+            (1_000_000_000_000, 500, 12),
+            (10_000_000_000, 1200, 360),
+            (u64::MAX / 4, 2500, 120),
+            (1_000_000_000, 600, 10_000),
+            (50_000, 0, 7),
+            (4_000_000, 10000, 1),
+            (123_457, 350, 48),
+            (1000, 3000, 365),
+        ] {
+            let payment = compute_amortized_payment(borrowed, rate_bps, periods);
+            let (interest, residual) = repayment_schedule(borrowed, rate_bps, periods, payment);
+            assert!(
+                residual.unsigned_abs() <= u128::from(periods),
+                "{borrowed} at {rate_bps} bps over {periods}: {residual} left"
+            );
+            // What's paid is the principal and interest, up to that residual
+            assert_eq!(
+                i128::from(payment) * i128::from(periods) + residual,
+                i128::from(borrowed) + i128::from(interest)
+            );
+        }
+        // Interest past u32::MAX is counted in full
+        let payment = compute_amortized_payment(1_000_000_000_000, 500, 12);
+        let (interest, _) = repayment_schedule(1_000_000_000_000, 500, 12, payment);
+        assert!(interest > u64::from(u32::MAX));
+    }
+
+    #[test]
+    fn overpaid_schedule_is_reported_as_negative() {
+        let payment = compute_amortized_payment(1_000_000, 1200, 12);
+        let (_, residual) = repayment_schedule(1_000_000, 1200, 12, payment + 100);
+        assert!(residual < -1_000, "{residual} left");
+        let (_, residual) = repayment_schedule(1_000_000, 1200, 12, payment - 100);
+        assert!(residual > 1_000, "{residual} left");
+    }
+
+    #[test]
+    fn amortization_terms_are_clamped() {
+        assert_eq!(
+            compute_amortized_payment(1_000_000_000, 600, u32::MAX),
+            compute_amortized_payment(1_000_000_000, 600, MAX_COMPOUNDING_STEPS)
+        );
+        assert_eq!(
+            repayment_schedule(1_000_000_000, 600, u32::MAX, 100_000),
+            repayment_schedule(1_000_000_000, 600, MAX_COMPOUNDING_STEPS, 100_000)
+        );
+    }
+
+    #[test]
+    fn amortized_payment_without_periods_is_the_principal() {
+        assert_eq!(compute_amortized_payment(777, 500, 0), 777);
+        assert_eq!(repayment_schedule(777, 500, 0, 777), (0, 777));
+        // A single period pays principal plus one period's interest
+        assert_eq!(compute_amortized_payment(4_000_000, 10000, 1), 8_000_000);
+        // Memory out of range writes nothing
+        // Safety: a null out_ptr is never written to
+        assert_eq!(unsafe { simulate_repayment(777, 500, 0, 0) }, 0);
+    }
+
+    #[test]
+    fn borrow_under_the_cap_is_unaffected() {
+        let capped = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            borrow_cap: 20_000,
+            ..MainArgs::default()
+        };
+        assert_eq!(capped.run(), 48212);
+        assert!(!exceeds_borrow_cap(10_000, 10_000));
+        assert!(!exceeds_borrow_cap(u64::MAX, 0));
+    }
+
+    #[test]
+    fn borrow_over_the_cap_is_rejected() {
+        let capped = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            borrow_cap: 9_999,
+            ..MainArgs::default()
+        };
+        assert_eq!(capped.run(), STATUS_CAP_EXCEEDED);
+    }
+
+    #[test]
+    fn fallback_clamps_to_the_cap() {
+        // 32_000 unstaked backs 16_000 at 200%, so the capped 10_000 settles at once
+        // instead of being halved
+        let clamped = run_fallback(40_000, 30_000, 10_000, 200, FALLBACK_MODE_HALVING);
+        assert_eq!(clamped.1, 0);
+        assert_eq!(clamped, run_fallback(40_000, 10_000, u64::MAX, 200, 0));
+    }
+
+    #[test]
+    fn interest_over_the_cap_only_warns() {
+        let capped = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            borrow_cap: 10_001,
+            ..MainArgs::default()
+        };
+        assert_eq!(capped.run(), 48212 | WARN_INTEREST_OVER_CAP);
+    }
+
+    #[test]
+    fn full_term_exit_keeps_the_reward() {
+        assert_eq!(
+            compute_staking_rewards_bigint(1_000_000, 50, 600, 10, 5, 5, false),
+            (30820, 0)
+        );
+        assert_eq!(
+            compute_staking_rewards_bigint(1_000_000, 50, 600, 10, 5, 9, false),
+            (30820, 0)
+        );
+    }
+
+    #[test]
+    fn exit_one_slice_early_forfeits_a_tenth() {
+        assert_eq!(
+            compute_staking_rewards_bigint(1_000_000, 50, 600, 10, 5, 4, false),
+            (27738, 3082)
+        );
+    }
+
+    #[test]
+    fn immediate_exit_forfeits_everything() {
+        assert_eq!(
+            compute_staking_rewards_bigint(1_000_000, 50, 600, 10, 10, 0, false),
+            (0, 30820)
+        );
+        // Past ten slices of lockup the slash stays capped at 100%
+        assert_eq!(
+            compute_staking_rewards_bigint(1_000_000, 50, 600, 10, 50, 0, false),
+            (0, 30820)
+        );
+    }
+
+    #[test]
+    fn slash_is_taken_before_narrowing_the_reward() {
+        // 100% over 10 slices grows u64::MAX by ~1.59x; clipping the reward to u64::MAX
+        // first would have slashed only a tenth of that
+        let (kept, slash) = compute_staking_rewards_bigint(u64::MAX, 100, 10000, 10, 1, 0, false);
+        assert_eq!(kept, u64::MAX);
+        assert_eq!(slash, 2939935928086895651);
+        assert!(slash > u64::MAX / 10);
+    }
+
+    #[test]
+    fn each_misbehavior_flag_alone() {
+        assert_eq!(
+            apply_slashing(100_000, MISBEHAVIOR_DOUBLE_SIGN),
+            (95_000, 5_000, false)
+        );
+        // Three offline epochs at 0.1% each
+        assert_eq!(
+            apply_slashing(100_000, MISBEHAVIOR_DOWNTIME | 0b111 << 8),
+            (99_700, 300, false)
+        );
+        // Epochs only count with the downtime bit set
+        assert_eq!(apply_slashing(100_000, 0b111 << 8), (100_000, 0, false));
+        assert_eq!(
+            apply_slashing(100_000, MISBEHAVIOR_SEVERE),
+            (50_000, 50_000, true)
+        );
+        // A tombstoned validator earns nothing
+        assert_eq!(
+            compute_staking_rewards_bigint(1_000_000, 50, 600, 10, 0, 0, true),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn misbehavior_flags_stack_up_to_the_whole_stake() {
+        let all_flags = MISBEHAVIOR_DOUBLE_SIGN
+            | MISBEHAVIOR_DOWNTIME
+            | MISBEHAVIOR_DOWNTIME_EPOCHS
+            | MISBEHAVIOR_SEVERE;
+        // 5% + 8 * 0.1% + 50%
+        assert_eq!(apply_slashing(100_000, all_flags), (44_200, 55_800, true));
+        assert_eq!(
+            apply_slashing(u64::MAX, all_flags).1,
+            u64::MAX / 10000 * 5580
+        );
+        for flags in [0, MISBEHAVIOR_DOUBLE_SIGN, all_flags, u32::MAX] {
+            let (remaining, slashed, _) = apply_slashing(12_345, flags);
+            assert_eq!(remaining + slashed, 12_345);
+        }
+    }
+
+    #[test]
+    fn slashing_can_make_a_healthy_loan_liquidatable() {
+        // 32_000 unstaked of 40_000 backs 16_000 at exactly 200%
+        let (_, unstaked) = split_collateral(40_000, 20);
+        assert!(validate_loan_health(unstaked, 16_000, 200));
+        let (slashed, _, _) = apply_slashing(40_000, MISBEHAVIOR_DOUBLE_SIGN);
+        let (_, unstaked) = split_collateral(slashed, 20);
+        assert!(!validate_loan_health(unstaked, 16_000, 200));
+
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 16_000,
+            ..MainArgs::default()
+        };
+        let slashed_loan = MainArgs {
+            misbehavior_flags: MISBEHAVIOR_DOUBLE_SIGN,
+            ..loan
+        };
+        assert!(loan.run() < STATUS_STAKE_RATIO_INVALID);
+        // The slashed loan is reduced by the fallback, and leaves the pool untouched
+        let (fallback, uncovered) = partial_fallback_loan(
+            slashed,
+            16_000,
+            500,
+            20,
+            600,
+            u64::MAX,
+            0,
+            0,
+            false,
+            100,
+            200,
+            10_000,
+            0,
+            0,
+            0,
+        );
+        assert_eq!(uncovered, 0);
+        assert_eq!(slashed_loan.run(), fallback ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn nothing_vests_before_the_cliff() {
+        assert_eq!(compute_vested(1_000, 10, 0, 2), (0, 1_000));
+        assert_eq!(compute_vested(1_000, 10, 1, 2), (0, 1_000));
+    }
+
+    #[test]
+    fn cliff_releases_what_accrued_so_far() {
+        assert_eq!(compute_vested(1_000, 10, 2, 2), (200, 800));
+    }
+
+    #[test]
+    fn half_vests_at_the_midpoint() {
+        assert_eq!(compute_vested(1_000, 10, 5, 2), (500, 500));
+        // The product is taken in BigUint, so large rewards don't overflow
+        assert_eq!(
+            compute_vested(u64::MAX, 10, 5, 2),
+            (u64::MAX / 2, u64::MAX - u64::MAX / 2)
+        );
+    }
+
+    #[test]
+    fn reward_fully_vests_at_the_end() {
+        assert_eq!(compute_vested(1_000, 10, 10, 2), (1_000, 0));
+        assert_eq!(compute_vested(1_000, 10, 15, 2), (1_000, 0));
+        assert_eq!(compute_vested(1_000, 0, 0, 0), (1_000, 0));
+    }
+
+    #[test]
+    fn zero_fee_disburses_the_whole_borrow() {
+        assert_eq!(compute_origination_fee(10_000, 0), (0, 10_000));
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            origination_fee_bps: 0,
+            ..MainArgs::default()
+        };
+        // Only the fee and disbursement change; interest still runs on the full borrow
+        assert_eq!(loan.run(), 48212 ^ (100 ^ 9_900) ^ 10_000);
+    }
+
+    #[test]
+    fn one_percent_fee_is_kept_upfront() {
+        assert_eq!(compute_origination_fee(10_000, 100), (100, 9_900));
+        assert_eq!(compute_tranche_interest(10_000, 10_000, 500, 5), 510);
+        assert_eq!(run_main([40_000, 0, 0, 0], 10_000), 48212);
+    }
+
+    #[test]
+    fn fee_past_ten_percent_is_rejected() {
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            origination_fee_bps: MAX_ORIGINATION_FEE_BPS + 1,
+            ..MainArgs::default()
+        };
+        assert_eq!(loan.run(), STATUS_FEE_TOO_HIGH);
+        let at_max = MainArgs {
+            origination_fee_bps: MAX_ORIGINATION_FEE_BPS,
+            ..loan
+        };
+        assert_ne!(at_max.run(), STATUS_FEE_TOO_HIGH);
+    }
+
+    #[test]
+    fn fallback_takes_the_fee_from_the_reduced_principal() {
+        // 30_000 is halved to 15_000, which 32_000 unstaked backs at 200%
+        let (with_fee, _) = run_fallback(40_000, 30_000, u64::MAX, 200, FALLBACK_MODE_HALVING);
+        let (without_fee, _) = partial_fallback_loan(
+            40_000,
+            30_000,
+            500,
+            20,
+            600,
+            u64::MAX,
+            0,
+            0,
+            false,
+            0,
+            200,
+            u64::MAX,
+            0,
+            0,
+            FALLBACK_MODE_HALVING,
+        );
+        assert_eq!(with_fee ^ without_fee, (150 ^ 14_850) ^ 15_000);
+    }
+
+    #[test]
+    fn looser_ratio_accepts_what_200_rejects() {
+        // 32_000 unstaked against 20_000 is 160%
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 20_000,
+            senior_ceiling: u64::MAX,
+            ..MainArgs::default()
+        };
+        let (fallback, _) = run_fallback(40_000, 20_000, u64::MAX, 200, 0);
+        assert_eq!(loan.run(), fallback ^ 1860 ^ 95 ^ 45);
+        let looser = MainArgs {
+            min_collateral_ratio_pct: 150,
+            ..loan
+        };
+        let (fallback, _) = run_fallback(40_000, 20_000, u64::MAX, 150, 0);
+        assert!(validate_loan_health(32_000, 20_000, 150));
+        assert_ne!(looser.run(), fallback ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn stricter_ratio_sends_a_healthy_loan_to_the_fallback() {
+        // 32_000 unstaked against 12_000 is 266%
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 12_000,
+            senior_ceiling: u64::MAX,
+            min_collateral_ratio_pct: 300,
+            ..MainArgs::default()
+        };
+        assert!(validate_loan_health(32_000, 12_000, 200));
+        let (fallback, uncovered) = run_fallback(40_000, 12_000, u64::MAX, 300, 0);
+        assert_eq!(uncovered, 0);
+        assert_eq!(loan.run(), fallback ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn ratio_below_100_is_rejected() {
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            min_collateral_ratio_pct: MIN_ACCEPTED_COLLATERAL_RATIO_PCT - 1,
+            ..MainArgs::default()
+        };
+        assert_eq!(loan.run(), STATUS_RATIO_TOO_LOW);
+        let at_par = MainArgs {
+            min_collateral_ratio_pct: MIN_ACCEPTED_COLLATERAL_RATIO_PCT,
+            ..loan
+        };
+        assert!(at_par.run() < STATUS_STAKE_RATIO_INVALID);
+    }
+
+    #[test]
+    fn halving_fallback_ends_on_the_exact_maximum() {
+        assert_eq!(max_healthy_borrow(32_000, 300), 10_666);
+        assert!(validate_loan_health(32_000, 10_666, 300));
+        assert!(!validate_loan_health(32_000, 10_667, 300));
+        // Half and a quarter of 60_000 are both too much at 300%
+        assert_eq!(
+            run_fallback(40_000, 60_000, u64::MAX, 300, FALLBACK_MODE_HALVING),
+            run_fallback(40_000, 10_666, u64::MAX, 300, 0)
+        );
+        // 15_000 is too much, its half is healthy
+        assert_eq!(
+            run_fallback(40_000, 30_000, u64::MAX, 300, FALLBACK_MODE_HALVING),
+            run_fallback(40_000, 7_500, u64::MAX, 300, 0)
+        );
+    }
+
+    #[test]
+    fn monthly_compounding_apy() {
+        assert!((1046..=1048).contains(&apr_to_apy_bps(1000, 12)));
+        assert_eq!(apr_to_apy_bps(1000, 12), 1047);
+    }
+
+    #[test]
+    fn daily_compounding_apy() {
+        assert_eq!(apr_to_apy_bps(1000, 365), 1051);
+        // Compounding more often than MAX_COMPOUNDING_STEPS gains nothing
+        assert_eq!(apr_to_apy_bps(1000, u32::MAX), apr_to_apy_bps(1000, 10_000));
+    }
+
+    #[test]
+    fn single_compound_apy_is_the_apr() {
+        assert_eq!(apr_to_apy_bps(1000, 1), 1000);
+        assert_eq!(apr_to_apy_bps(1000, 0), 1000);
+    }
+
+    #[test]
+    fn more_slices_compound_to_more_interest() {
+        let yearly = compute_compound_interest(1_000_000, 1000, 1);
+        let monthly = compute_compound_interest(1_000_000, 1000, 12);
+        let daily = compute_compound_interest(1_000_000, 1000, 365);
+        assert_eq!(yearly, 100_000);
+        assert!(yearly < monthly && monthly < daily);
+        assert_eq!(compute_compound_interest(1_000_000, 1000, 0), 0);
+    }
+
+    #[test]
+    fn slices_are_capped_at_max_compounding_steps() {
+        let capped = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            time_slices: MAX_COMPOUNDING_STEPS,
+            ..MainArgs::default()
+        };
+        let past_cap = MainArgs {
+            time_slices: u32::MAX,
+            ..capped
+        };
+        assert_eq!(capped.run(), 46568);
+        assert_eq!(past_cap.run(), 46568);
+    }
+
+    #[test]
+    fn five_slices_are_pinned() {
+        assert_eq!(compute_compound_interest(1_000_000, 1000, 5), 104_080);
+        assert_eq!(run_main([40_000, 0, 0, 0], 10_000), 48212);
+    }
+
+    #[test]
+    fn pool_shares_are_conserved() {
+        for shares in [0, 1, 99, 150, 2000, 1 << 40] {
+            for time_slices in [0, 1, 5, 50] {
+                for decay_bps in [0, 1, 100, 5000, 10000] {
+                    for fee_bps in [0, 50, 3000, 10000] {
+                        let (final_shares, decay, fees, restakes, bad_debt) =
+                            simulate_liquidity_pool_shares_complex(
+                                shares,
+                                time_slices,
+                                decay_bps,
+                                fee_bps,
+                                0,
+                                0,
+                                0,
+                            );
+                        assert_eq!(shares + restakes, final_shares + decay + fees);
+                        assert_eq!(bad_debt, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decay_below_100_percent_takes_effect() {
+        // Before the multiply-first fix 100 bps decayed nothing
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 0, 0, 0, 0),
+            (1904, 96, 0, 0, 0)
+        );
+        // A pool that drops below 100 shares re-stakes half of what's left
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(150, 1, 5000, 0, 0, 0, 0),
+            (112, 75, 0, 37, 0)
+        );
+    }
+
+    #[test]
+    fn nothing_staked_backs_the_loan_in_full() {
+        assert_eq!(split_collateral(40_000, 0), (0, 40_000));
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 20_000,
+            stake_ratio: 0,
+            senior_ceiling: u64::MAX,
+            ..MainArgs::default()
+        };
+        assert!(validate_loan_health(40_000, 20_000, 200));
+        let (fallback, _) = run_fallback(40_000, 20_000, u64::MAX, 200, 0);
+        assert_ne!(loan.run(), fallback ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn everything_staked_backs_nothing() {
+        assert_eq!(split_collateral(40_000, 100), (40_000, 0));
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            stake_ratio: 100,
+            ..MainArgs::default()
+        };
+        // The whole borrow is left uncovered for the pool, see `zero_price_is_worthless_collateral`
+        assert_eq!(loan.run(), 16210);
+    }
+
+    #[test]
+    fn stake_ratio_past_100_is_rejected() {
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            stake_ratio: 150,
+            ..MainArgs::default()
+        };
+        assert_eq!(loan.run(), STATUS_STAKE_RATIO_INVALID);
+    }
+
+    #[test]
+    fn unstaking_rescues_the_loan() {
+        // 20_000 unstaked against 12_000 is 166%, 32_000 is 266%
+        let (_, unstaked) = split_collateral(40_000, 50);
+        assert!(!validate_loan_health(unstaked, 12_000, 200));
+        let (_, unstaked) = split_collateral(40_000, 20);
+        assert!(validate_loan_health(unstaked, 12_000, 200));
+
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 12_000,
+            stake_ratio: 50,
+            senior_ceiling: u64::MAX,
+            ..MainArgs::default()
+        };
+        let (fallback, _) = partial_fallback_loan(
+            40_000,
+            12_000,
+            500,
+            50,
+            600,
+            u64::MAX,
+            0,
+            0,
+            false,
+            100,
+            200,
+            u64::MAX,
+            0,
+            0,
+            0,
+        );
+        assert_eq!(loan.run(), fallback ^ 1860 ^ 95 ^ 45);
+        let unstaked = MainArgs {
+            stake_ratio: 20,
+            ..loan
+        };
+        assert_ne!(unstaked.run(), fallback ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn all_senior_debt_accrues_at_the_base_rate() {
+        assert_eq!(split_debt(10_000, u64::MAX), (10_000, 0));
+        assert_eq!(
+            compute_tranche_interest(10_000, u64::MAX, 500, 5),
+            compute_compound_interest(10_000, 500, 5)
+        );
+        assert_eq!(tranche_weighted_debt(10_000, u64::MAX), 10_000);
+    }
+
+    #[test]
+    fn debt_straddling_the_ceiling_splits_its_interest() {
+        assert_eq!(split_debt(20_000, 10_000), (10_000, 10_000));
+        assert_eq!(compute_compound_interest(10_000, 500, 5), 510);
+        assert_eq!(compute_compound_interest(10_000, 1000, 5), 1040);
+        assert_eq!(compute_tranche_interest(20_000, 10_000, 500, 5), 510 + 1040);
+    }
+
+    #[test]
+    fn all_junior_debt_accrues_at_double_the_rate() {
+        assert_eq!(split_debt(10_000, 0), (0, 10_000));
+        assert_eq!(compute_tranche_interest(10_000, 0, 500, 5), 1040);
+        assert_eq!(tranche_weighted_debt(10_000, 0), 12_000);
+    }
+
+    #[test]
+    fn junior_weight_flips_a_borderline_loan() {
+        // 32_000 unstaked backs exactly 16_000 of senior debt at 200%
+        assert!(validate_loan_health(
+            32_000,
+            tranche_weighted_debt(16_000, u64::MAX),
+            200
+        ));
+        assert_eq!(tranche_weighted_debt(16_000, 10_000), 17_200);
+        assert!(!validate_loan_health(32_000, 17_200, 200));
+        assert_eq!(max_tranched_borrow(16_000, 10_000), 15_000);
+
+        let loan = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 16_000,
+            senior_ceiling: u64::MAX,
+            ..MainArgs::default()
+        };
+        let tranched = MainArgs {
+            senior_ceiling: 10_000,
+            ..loan
+        };
+        let (fallback, _) = partial_fallback_loan(
+            40_000,
+            16_000,
+            500,
+            20,
+            600,
+            u64::MAX,
+            0,
+            0,
+            false,
+            100,
+            200,
+            10_000,
+            0,
+            0,
+            0,
+        );
+        assert_eq!(tranched.run(), fallback ^ 1860 ^ 95 ^ 45);
+        assert_ne!(loan.run(), tranched.run());
+    }
+
+    #[test]
+    fn healthy_period_only_contributes() {
+        assert_eq!(divert_to_insurance(1_000, 1000), (900, 100));
+        assert_eq!(divert_to_insurance(1_000, 20000), (0, 1_000));
+        // 10% of the 510 interest tops up the fund
+        let insured = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            insurance_bps: 1000,
+            insurance_fund_in: 500,
+            ..MainArgs::default()
+        };
+        assert_eq!(insured.run(), 48212 ^ 510 ^ 459 ^ 551);
+    }
+
+    #[test]
+    fn fund_absorbs_the_whole_shortfall() {
+        assert_eq!(absorb_shortfall(1_000, 400), (600, 0));
+        // With everything staked the whole 10_000 borrow is a shortfall
+        let insured = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            stake_ratio: 100,
+            insurance_fund_in: 20_000,
+            ..MainArgs::default()
+        };
+        assert_eq!(insured.run(), 10_000 ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn shortfall_past_the_fund_is_left_uncovered() {
+        assert_eq!(absorb_shortfall(1_000, 1_500), (0, 500));
+        let insured = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 10_000,
+            stake_ratio: 100,
+            insurance_fund_in: 4_000,
+            ..MainArgs::default()
+        };
+        // The pool takes the other 6_000, 4_140 of it past the position's 1_860 shares
+        assert_eq!(
+            simulate_liquidity_pool_shares_complex(2000, 5, 100, 50, 6_000, 0, 0),
+            (0, 1955, 45, 0, 4140)
+        );
+        assert_eq!(insured.run(), 6_000 ^ 1955 ^ 45 ^ 4140);
+    }
+
+    #[test]
+    fn no_insurance_leaves_interest_alone() {
+        assert_eq!(divert_to_insurance(510, 0), (510, 0));
+        assert_eq!(run_main([40_000, 0, 0, 0], 10_000), 48212);
+    }
+
+    #[test]
+    fn bisection_finds_the_exact_largest_healthy_borrow() {
+        for collateral in [2, 3, 999, 32_000, 1 << 40, u64::MAX / 200] {
+            let healthy = |principal| validate_loan_health(collateral, principal, 200);
+            let estimate = max_healthy_borrow(collateral, 200);
+            let found = search_max_healthy_borrow(u64::MAX, estimate, healthy).unwrap();
+            assert!(healthy(found));
+            assert!(!healthy(found + 1));
+            // A bad estimate only costs steps while the whole range fits MAX_BISECTION_STEPS
+            let found = search_max_healthy_borrow(1 << 32, 0, healthy).unwrap();
+            assert_eq!(found, estimate.min(1 << 32));
+        }
+    }
+
+    #[test]
+    fn bisection_gives_up_when_a_borrow_of_1_fails() {
+        let healthy = |principal| validate_loan_health(1, principal, 200);
+        assert!(!healthy(1));
+        assert_eq!(search_max_healthy_borrow(1_000, 0, healthy), None);
+        assert_eq!(search_max_healthy_borrow(0, 0, |_| true), None);
+    }
+
+    #[test]
+    fn halving_mode_keeps_its_regression_vectors() {
+        // Bisection settles on the 16_000 maximum, halving stops at the healthy 15_000
+        assert_eq!(
+            run_fallback(40_000, 15_000, u64::MAX, 200, 0),
+            run_fallback(40_000, 30_000, u64::MAX, 200, FALLBACK_MODE_HALVING)
+        );
+        assert_ne!(
+            run_fallback(40_000, 30_000, u64::MAX, 200, 0),
+            run_fallback(40_000, 30_000, u64::MAX, 200, FALLBACK_MODE_HALVING)
+        );
+        let halving = MainArgs {
+            collateral: [40_000, 0, 0, 0],
+            borrowed: 30_000,
+            fallback_mode: FALLBACK_MODE_HALVING,
+            ..MainArgs::default()
+        };
+        let (fallback, _) = partial_fallback_loan(
+            40_000,
+            30_000,
+            500,
+            20,
+            600,
+            u64::MAX,
+            0,
+            0,
+            false,
+            100,
+            200,
+            10_000,
+            0,
+            0,
+            FALLBACK_MODE_HALVING,
+        );
+        assert_eq!(halving.run(), fallback ^ 1860 ^ 95 ^ 45);
+    }
+
+    #[test]
+    fn refinance_slices_are_clamped() {
+        // 2e8 slices would compound 1e8 times on each side of the refinance
+        let interest_old = compute_term_interest(1_000_000, 1000, 100_000_000, 200_000_000);
+        assert_eq!(
+            interest_old,
+            compute_compound_interest(1_000_000, 500, MAX_COMPOUNDING_STEPS)
+        );
+        let [new_principal, old, new] = refinance_terms(
+            10_000_000,
+            1_000_000,
+            1000,
+            1000,
+            100_000_000,
+            200_000_000,
+            200,
+        )
+        .unwrap();
+        assert_eq!(old, interest_old);
+        assert_eq!(new_principal, 1_000_000 + interest_old);
+        assert_eq!(
+            new,
+            compute_compound_interest(new_principal, 500, MAX_COMPOUNDING_STEPS)
+        );
+    }
 }
@@ -1,53 +1,165 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 extern crate num_bigint;
 extern crate num_traits;
 
 use num_bigint::{BigInt, BigUint, ToBigInt, ToBigUint};
 use num_traits::{One, Zero};
+use safe_math::{
+    safe_add_u32, safe_add_u64, safe_div_u32, safe_div_u64, safe_mul_u32, safe_mul_u64,
+    safe_sub_u32, safe_sub_u64,
+};
 
-// Safe operations for 64-bit
-fn safe_add_u64(a: u64, b: u64) -> u64 {
-    a.checked_add(b).unwrap_or(u64::MAX)
-}
+/// `simulate_combat` result: the winner in the top 2 bits, the rounds fought in the 14 bits
+/// below and the winner's remaining HP in the low 48, saturating.
+const COMBAT_WINNER_SHIFT: u32 = 62;
+const COMBAT_ROUNDS_SHIFT: u32 = 48;
+const COMBAT_HP_MASK: u64 = (1 << COMBAT_ROUNDS_SHIFT) - 1;
+const COMBAT_ATTACKER_WINS: u64 = 0;
+const COMBAT_DEFENDER_WINS: u64 = 1;
+const COMBAT_DRAW: u64 = 2;
+/// Fights stop at this many rounds however many were asked for, so one always ends.
+const COMBAT_MAX_ROUNDS: u32 = 10_000;
 
-fn safe_sub_u64(a: u64, b: u64) -> u64 {
-    a.checked_sub(b).unwrap_or(0)
-}
+/// Critical hits deal double damage. Their chance is in basis points, so 10_000 and up always
+/// crits. `main`'s fight crits 5% of the time.
+const CRIT_CHANCE_DENOMINATOR: u64 = 10_000;
+const CRIT_DAMAGE_MULTIPLIER: u64 = 2;
+const MAIN_CRIT_CHANCE_BPS: u32 = 500;
 
-fn safe_mul_u64(a: u64, b: u64) -> u64 {
-    a.checked_mul(b).unwrap_or(u64::MAX)
-}
+/// Attack elements: 0 physical, 1 fire, 2 ice, 3 lightning and 4 poison. A defender's
+/// resistance word holds a byte per element, element 0 in the low byte: the percentage of
+/// that element's damage it shrugs off, clamped to 100. A byte of WEAKNESS_BIT and up is a
+/// weakness instead, read as a negative (two's complement) resistance that adds damage, at
+/// most WEAKNESS_MAX_PERCENT. Elements from ELEMENT_COUNT up take no resistance.
+const ELEMENT_COUNT: u32 = 5;
+const RESISTANCE_BITS: u32 = 8;
+const RESISTANCE_MAX_PERCENT: u64 = 100;
+const WEAKNESS_BIT: u8 = 0x80;
+const WEAKNESS_MAX_PERCENT: u64 = 50;
 
-fn safe_div_u64(a: u64, b: u64) -> u64 {
-    if b == 0 {
-        0
-    } else {
-        a / b
-    }
-}
+/// Crafting materials: 0 ore, 1 hide, 2 crystal and 3 essence. A player's materials are
+/// packed a byte per type, ore in the low byte, and so are the leftovers `craft_item` hands
+/// back.
+const MATERIAL_TYPES: usize = 4;
+const MATERIAL_BITS: u32 = 8;
+/// A recipe of tier n costs n times these in energy and focus.
+const CRAFT_ENERGY_PER_TIER: u64 = 100;
+const CRAFT_FOCUS_PER_TIER: u32 = 10;
 
-// Safe operations for 32-bit
-fn safe_add_u32(a: u32, b: u32) -> u32 {
-    a.checked_add(b).unwrap_or(u32::MAX)
-}
+/// `craft_item` result: the crafted item's value in bits 32-62, saturating, and the leftover
+/// materials below. A failure sets the top bit instead, with a CRAFT_* reason from
+/// CRAFT_REASON_SHIFT and, if materials were short, a bit per short type in the low bits.
+const CRAFT_VALUE_SHIFT: u32 = 32;
+const CRAFT_VALUE_MAX: u64 = (1 << 31) - 1;
+const CRAFT_FAILED_BIT: u64 = 1 << 63;
+const CRAFT_REASON_SHIFT: u32 = 32;
+const CRAFT_UNKNOWN_RECIPE: u64 = 1;
+const CRAFT_MISSING_MATERIALS: u64 = 2;
+const CRAFT_EXHAUSTED: u64 = 3;
 
-fn safe_sub_u32(a: u32, b: u32) -> u32 {
-    a.checked_sub(b).unwrap_or(0)
+struct Recipe {
+    /// Units of each material type used up
+    materials: [u32; MATERIAL_TYPES],
+    tier: u32,
+    /// The forging rarity factor the item's value is computed with
+    rarity_factor: u32,
 }
 
-fn safe_mul_u32(a: u32, b: u32) -> u32 {
-    a.checked_mul(b).unwrap_or(u32::MAX)
+const RECIPES: [Recipe; 5] = [
+    // Iron sword
+    Recipe {
+        materials: [3, 1, 0, 0],
+        tier: 1,
+        rarity_factor: 2,
+    },
+    // Hide shield
+    Recipe {
+        materials: [2, 5, 0, 0],
+        tier: 2,
+        rarity_factor: 3,
+    },
+    // Arcane staff
+    Recipe {
+        materials: [0, 2, 4, 0],
+        tier: 3,
+        rarity_factor: 5,
+    },
+    // Dragon armor
+    Recipe {
+        materials: [8, 4, 2, 1],
+        tier: 4,
+        rarity_factor: 8,
+    },
+    // Legendary blade
+    Recipe {
+        materials: [10, 6, 4, 3],
+        tier: 5,
+        rarity_factor: 13,
+    },
+];
+
+/// Loot tables of weighted entries, rolled with the xorshift64* generator. Entries lighter
+/// than LOOT_RARE_WEIGHT are rare, and every point of luck adds LOOT_LUCK_BPS_PER_POINT to
+/// their weight, at most doubling it. The quantity is rolled uniformly in the entry's range.
+const LOOT_RARE_WEIGHT: u64 = 100;
+const LOOT_LUCK_BPS_PER_POINT: u64 = 50;
+const LOOT_LUCK_MAX_BPS: u64 = 10_000;
+const LOOT_BPS_DENOMINATOR: u64 = 10_000;
+
+/// `roll_loot` result: the item id in the top 16 bits and the quantity in the 16 below. Item
+/// ids start at 1, so 0 is an unknown or empty table. The seed for the next roll needs all
+/// 64 bits and comes from `next_loot_seed`.
+const LOOT_ITEM_SHIFT: u32 = 48;
+const LOOT_QUANTITY_SHIFT: u32 = 32;
+const LOOT_REJECTED: u64 = 0;
+const LOOT_TABLE_BOSS_HOARD: u32 = 1;
+
+struct LootEntry {
+    item_id: u16,
+    weight: u64,
+    min_quantity: u16,
+    max_quantity: u16,
 }
 
-fn safe_div_u32(a: u32, b: u32) -> u32 {
-    if b == 0 {
-        0
-    } else {
-        a / b
+const fn loot(item_id: u16, weight: u64, min_quantity: u16, max_quantity: u16) -> LootEntry {
+    LootEntry {
+        item_id,
+        weight,
+        min_quantity,
+        max_quantity,
     }
 }
 
+const LOOT_TABLES: [&[LootEntry]; 4] = [
+    // Common chest: coins, herbs, ore and the odd silver ring
+    &[
+        loot(1, 600, 5, 20),
+        loot(2, 300, 1, 3),
+        loot(3, 90, 1, 2),
+        loot(4, 10, 1, 1),
+    ],
+    // Boss hoard: gold, gems, a dragon scale or a legendary relic
+    &[
+        loot(10, 500, 50, 100),
+        loot(11, 300, 1, 2),
+        loot(12, 150, 1, 1),
+        loot(13, 50, 1, 1),
+    ],
+    // Fishing: trout, an old boot or a pearl
+    &[loot(20, 700, 1, 2), loot(21, 250, 1, 1), loot(22, 50, 1, 1)],
+    // Training dummy: drops nothing
+    &[],
+];
+
+/// `apply_experience` result: the new level in the top 8 bits, the levels gained in the 8
+/// below and the leftover XP in the low 48, saturating. Nobody levels past MAX_LEVEL, and XP
+/// gained there is kept as leftover.
+const MAX_LEVEL: u32 = 100;
+const EXPERIENCE_LEVEL_SHIFT: u32 = 56;
+const EXPERIENCE_GAINED_SHIFT: u32 = 48;
+const EXPERIENCE_XP_MASK: u64 = (1 << EXPERIENCE_GAINED_SHIFT) - 1;
+
 /// Check if the player has enough "energy" (64-bit) and "focus" (32-bit) to perform an action.
 fn validate_action_requirements(
     player_energy: u64,
@@ -72,6 +184,161 @@ fn compute_combat_damage(attack_power: u32, defense: u32) -> u64 {
     safe_div_u64(squared, def)
 }
 
+/// Fight until one side drops to 0 HP or `max_rounds` rounds have passed, a draw. Every round
+/// the attacker strikes first, through the defender's `defense_b`, so a blow that would
+/// knock both out goes to the attacker. The defender strikes back through `defense_a`. A
+/// side that starts at 0 HP loses without a round fought, and a draw if both do. Each strike
+/// rolls for a critical hit with `crit_chance_bps`, the rolls drawn in turn from `seed`.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub fn simulate_combat(
+    attacker_power: u32,
+    attacker_hp: u64,
+    defender_power: u32,
+    defender_hp: u64,
+    defense_a: u32,
+    defense_b: u32,
+    max_rounds: u32,
+    seed: u64,
+    crit_chance_bps: u32,
+) -> u64 {
+    let mut seed = seed;
+    let mut attacker_hp = attacker_hp;
+    let mut defender_hp = defender_hp;
+
+    let mut rounds = 0;
+    let (winner, remaining_hp) = loop {
+        match (attacker_hp, defender_hp) {
+            (0, 0) => break (COMBAT_DRAW, 0),
+            (0, _) => break (COMBAT_DEFENDER_WINS, defender_hp),
+            (_, 0) => break (COMBAT_ATTACKER_WINS, attacker_hp),
+            _ if rounds == max_rounds.min(COMBAT_MAX_ROUNDS) => break (COMBAT_DRAW, 0),
+            _ => {}
+        }
+        rounds += 1;
+        let (critical, next_seed) = roll_critical(seed, crit_chance_bps);
+        seed = next_seed;
+        let damage = compute_critical_damage(attacker_power, defense_b, critical);
+        defender_hp = safe_sub_u64(defender_hp, damage);
+        if defender_hp > 0 {
+            let (critical, next_seed) = roll_critical(seed, crit_chance_bps);
+            seed = next_seed;
+            let damage = compute_critical_damage(defender_power, defense_a, critical);
+            attacker_hp = safe_sub_u64(attacker_hp, damage);
+        }
+    };
+    winner << COMBAT_WINNER_SHIFT
+        | u64::from(rounds) << COMBAT_ROUNDS_SHIFT
+        | remaining_hp.min(COMBAT_HP_MASK)
+}
+
+/// `compute_combat_damage` for an attack of `element` against a defender's `resistances`,
+/// see ELEMENT_COUNT.
+fn compute_elemental_damage(
+    attack_power: u32,
+    defense: u32,
+    element: u32,
+    resistances: u64,
+) -> u64 {
+    let damage = compute_combat_damage(attack_power, defense);
+    if element >= ELEMENT_COUNT {
+        return damage;
+    }
+    let resistance = (resistances >> (element * RESISTANCE_BITS)) as u8;
+    let percent = if resistance >= WEAKNESS_BIT {
+        let weakness = u64::from((resistance as i8).unsigned_abs());
+        RESISTANCE_MAX_PERCENT + weakness.min(WEAKNESS_MAX_PERCENT)
+    } else {
+        RESISTANCE_MAX_PERCENT - u64::from(resistance).min(RESISTANCE_MAX_PERCENT)
+    };
+    // Multiply before dividing so small hits keep their share
+    safe_div_u64(safe_mul_u64(damage, percent), RESISTANCE_MAX_PERCENT)
+}
+
+/// Starting state of a xorshift64* generator for a seed. xorshift gets stuck on zero,
+/// so that seed starts from a fixed odd state instead.
+fn xorshift64_state(seed: u64) -> u64 {
+    if seed == 0 {
+        0x9E37_79B9_7F4A_7C15
+    } else {
+        seed
+    }
+}
+
+/// Next value of a xorshift64* generator. The state must never be zero.
+fn xorshift64_star(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Roll for a critical hit with `crit_chance_bps` in CRIT_CHANCE_DENOMINATOR. Returns
+/// whether it crits and the seed for the next roll, so a seed always replays the same rolls.
+fn roll_critical(seed: u64, crit_chance_bps: u32) -> (bool, u64) {
+    let mut state = xorshift64_state(seed);
+    let roll = xorshift64_star(&mut state) % CRIT_CHANCE_DENOMINATOR;
+    (roll < u64::from(crit_chance_bps), state)
+}
+
+/// `compute_combat_damage`, doubled for a critical hit.
+fn compute_critical_damage(attack_power: u32, defense: u32, critical: bool) -> u64 {
+    let damage = compute_combat_damage(attack_power, defense);
+    if critical {
+        safe_mul_u64(damage, CRIT_DAMAGE_MULTIPLIER)
+    } else {
+        damage
+    }
+}
+
+/// Roll loot table `loot_table_id` from `seed` with `luck_stat`, see LOOT_RARE_WEIGHT and
+/// LOOT_ITEM_SHIFT.
+#[no_mangle]
+pub fn roll_loot(seed: u64, loot_table_id: u32, luck_stat: u32) -> u64 {
+    loot_drop(seed, loot_table_id, luck_stat).map_or(LOOT_REJECTED, |(drop, _)| drop)
+}
+
+/// The full xorshift state after `roll_loot` with the same arguments. Feeding it back in as
+/// the seed continues the sequence. A rejected roll draws nothing, so `seed` comes back.
+#[no_mangle]
+pub fn next_loot_seed(seed: u64, loot_table_id: u32, luck_stat: u32) -> u64 {
+    loot_drop(seed, loot_table_id, luck_stat).map_or(seed, |(_, next_seed)| next_seed)
+}
+
+/// The packed `roll_loot` result and the generator state after it, or None if the table is
+/// unknown or empty.
+fn loot_drop(seed: u64, loot_table_id: u32, luck_stat: u32) -> Option<(u64, u64)> {
+    let table = LOOT_TABLES.get(loot_table_id as usize)?;
+    let luck_bps =
+        safe_mul_u64(u64::from(luck_stat), LOOT_LUCK_BPS_PER_POINT).min(LOOT_LUCK_MAX_BPS);
+    let weight_of = |entry: &LootEntry| {
+        if entry.weight < LOOT_RARE_WEIGHT {
+            entry.weight * (LOOT_BPS_DENOMINATOR + luck_bps) / LOOT_BPS_DENOMINATOR
+        } else {
+            entry.weight
+        }
+    };
+    let total_weight: u64 = table.iter().map(weight_of).sum();
+    if total_weight == 0 {
+        return None;
+    }
+
+    let mut state = xorshift64_state(seed);
+    let mut pick = xorshift64_star(&mut state) % total_weight;
+    for entry in table.iter() {
+        let weight = weight_of(entry);
+        if pick >= weight {
+            pick -= weight;
+            continue;
+        }
+        let spread = u64::from(entry.max_quantity - entry.min_quantity) + 1;
+        let quantity = u64::from(entry.min_quantity) + xorshift64_star(&mut state) % spread;
+        let drop = u64::from(entry.item_id) << LOOT_ITEM_SHIFT | quantity << LOOT_QUANTITY_SHIFT;
+        return Some((drop, state));
+    }
+    None
+}
+
 /// Use BigUint for forging "Legendary Items" that require very large integer logic.
 /// We artificially inflate computations to produce more opcodes.
 fn forge_legendary_item_materials(base_materials: u64, rarity_factor: u32) -> u64 {
@@ -85,6 +352,40 @@ fn forge_legendary_item_materials(base_materials: u64, rarity_factor: u32) -> u6
     cost_big.try_into().unwrap_or(u64::MAX)
 }
 
+/// Craft recipe `recipe_id` from the packed `materials` (see MATERIAL_TYPES), if the player
+/// has the energy and focus its tier costs. The item is valued like a forged one, from the
+/// units used up and the recipe's rarity. See CRAFT_VALUE_SHIFT for the result.
+#[no_mangle]
+pub fn craft_item(recipe_id: u32, materials: u32, player_energy: u64, player_focus: u32) -> u64 {
+    let Some(recipe) = RECIPES.get(recipe_id as usize) else {
+        return CRAFT_FAILED_BIT | CRAFT_UNKNOWN_RECIPE << CRAFT_REASON_SHIFT;
+    };
+
+    let mut short_mask = 0u64;
+    let mut leftover = 0u32;
+    for (slot, &needed) in recipe.materials.iter().enumerate() {
+        let shift = slot as u32 * MATERIAL_BITS;
+        let held = materials >> shift & 0xFF;
+        if held < needed {
+            short_mask |= 1 << slot;
+        }
+        leftover |= safe_sub_u32(held, needed) << shift;
+    }
+    if short_mask != 0 {
+        return CRAFT_FAILED_BIT | CRAFT_MISSING_MATERIALS << CRAFT_REASON_SHIFT | short_mask;
+    }
+
+    let cost_energy = safe_mul_u64(u64::from(recipe.tier), CRAFT_ENERGY_PER_TIER);
+    let cost_focus = safe_mul_u32(recipe.tier, CRAFT_FOCUS_PER_TIER);
+    if !validate_action_requirements(player_energy, player_focus, cost_energy, cost_focus) {
+        return CRAFT_FAILED_BIT | CRAFT_EXHAUSTED << CRAFT_REASON_SHIFT;
+    }
+
+    let units_used = recipe.materials.iter().map(|&units| u64::from(units)).sum();
+    let value = forge_legendary_item_materials(units_used, recipe.rarity_factor);
+    value.min(CRAFT_VALUE_MAX) << CRAFT_VALUE_SHIFT | u64::from(leftover)
+}
+
 /// Experience (XP) leveling system that uses BigInt for partial calculations.
 fn compute_experience_for_level(current_level: u32, xp_rate: u32) -> u64 {
     // XP needed for next level: (current_level^3 + xp_rate^2)
@@ -99,6 +400,25 @@ fn compute_experience_for_level(current_level: u32, xp_rate: u32) -> u64 {
     needed.try_into().unwrap_or(u64::MAX)
 }
 
+/// Add `gained_xp` to `current_xp` and level up while it covers the next level's
+/// requirement, see MAX_LEVEL. A level above the cap counts as the cap.
+fn apply_experience(current_level: u32, current_xp: u64, gained_xp: u64, xp_rate: u32) -> u64 {
+    let mut level = current_level.min(MAX_LEVEL);
+    let mut xp = safe_add_u64(current_xp, gained_xp);
+    while level < MAX_LEVEL {
+        let needed = compute_experience_for_level(level, xp_rate);
+        if xp < needed {
+            break;
+        }
+        xp -= needed;
+        level += 1;
+    }
+    let levels_gained = level - current_level.min(MAX_LEVEL);
+    u64::from(level) << EXPERIENCE_LEVEL_SHIFT
+        | u64::from(levels_gained) << EXPERIENCE_GAINED_SHIFT
+        | xp.min(EXPERIENCE_XP_MASK)
+}
+
 /// Complex fallback logic: If resources insufficient, try partial usage or alternate strategy.
 /// For example, reduce half the cost, recalculate forging or damage.
 fn partial_fallback(
@@ -154,8 +474,18 @@ fn combine_results(results: &[u32]) -> u32 {
     out
 }
 
-#[no_mangle]
-pub fn main(player_energy: u64, player_focus: u32, base_materials: u64, rarity_factor: u32) -> u64 {
+#[cfg_attr(not(test), no_mangle)]
+#[allow(clippy::too_many_arguments)]
+pub fn main(
+    player_energy: u64,
+    player_focus: u32,
+    base_materials: u64,
+    rarity_factor: u32,
+    seed: u64,        // drives the combat step's critical hits
+    element: u32,     // element of the forged item's attack, see ELEMENT_COUNT
+    resistances: u64, // the target's resistance word
+    loot_roll: u32,   // nonzero to roll the boss hoard after the fight, with rarity as luck
+) -> u64 {
     // Step 1: Validate basic action requirements for forging a "Legendary Item"
     let forging_cost = forge_legendary_item_materials(base_materials, rarity_factor);
     let forging_focus_cost = safe_mul_u32(rarity_factor, 2); // e.g. forging consumes focus at 2x rarity
@@ -177,19 +507,438 @@ pub fn main(player_energy: u64, player_focus: u32, base_materials: u64, rarity_f
     }
 
     // Step 2: Simulate a short combat scenario with fixed stats for demonstration
-    // e.g. Attack power = (rarity_factor + 50), defense = 100
+    // e.g. Attack power = (rarity_factor + 50) against 1000 HP, defense = 100 on both sides
     let attack_power = safe_add_u32(rarity_factor, 50);
-    let damage_dealt = compute_combat_damage(attack_power, 100);
+    let combat_outcome = simulate_combat(
+        attack_power,
+        1_000,
+        50,
+        1_000,
+        100,
+        100,
+        3,
+        seed,
+        MAIN_CRIT_CHANCE_BPS,
+    );
+
+    // How the fight went decides the loot along with the seed
+    let loot = if loot_roll != 0 {
+        loot_drop(seed ^ combat_outcome, LOOT_TABLE_BOSS_HOARD, rarity_factor)
+            .map_or(LOOT_REJECTED, |(drop, next_seed)| drop ^ next_seed)
+    } else {
+        0
+    };
+
+    // The forged item's elemental strike against the same defense
+    let elemental_damage = compute_elemental_damage(attack_power, 100, element, resistances);
 
     // Step 3: Compute XP needed for next level
     let xp_needed = compute_experience_for_level(rarity_factor, 10);
+    // and what forging is worth to a fresh level 1 character, with the rarity as XP
+    let progression = apply_experience(1, 0, u64::from(rarity_factor), 10);
 
     // Step 4: Combine results into a single 64-bit output
     combine_results_64(&[
         forging_cost,
-        damage_dealt,
+        combat_outcome,
+        elemental_damage,
+        loot,
         xp_needed,
+        progression,
         player_energy,
         base_materials,
     ])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Item id and quantity of a `roll_loot` result.
+    fn unpack_loot(drop: u64) -> (u64, u64) {
+        (
+            drop >> LOOT_ITEM_SHIFT,
+            drop >> LOOT_QUANTITY_SHIFT & 0xFFFF,
+        )
+    }
+
+    #[test]
+    fn pinned_loot_rolls() {
+        let pinned = [
+            (1, 18, 8398849),
+            (2, 3, 17043490),
+            (1, 11, 3536530014),
+            (10, 64, 8398849),
+            (11, 1, 17043490),
+            (11, 1, 3536530014),
+            (20, 2, 8398849),
+            (20, 1, 17043490),
+            (20, 1, 3536530014),
+        ];
+        let rolls = (0..3).flat_map(|table| [1, 42, 0xDEAD_BEEF].map(|seed| (seed, table)));
+        for ((seed, table), (item_id, quantity, seed_low)) in rolls.zip(pinned) {
+            assert_eq!(unpack_loot(roll_loot(seed, table, 0)), (item_id, quantity));
+            assert_eq!(next_loot_seed(seed, table, 0) & 0xFFFF_FFFF, seed_low);
+        }
+    }
+
+    #[test]
+    fn next_loot_seed_keeps_the_whole_state() {
+        let mut state = xorshift64_state(1);
+        xorshift64_star(&mut state);
+        xorshift64_star(&mut state);
+        assert_eq!(next_loot_seed(1, LOOT_TABLE_BOSS_HOARD, 0), state);
+        assert_ne!(state >> 32, 0);
+
+        // Chaining the seeds replays the same sequence, with every quantity in range
+        let mut seed = 7;
+        for _ in 0..1000 {
+            let (item_id, quantity) = unpack_loot(roll_loot(seed, LOOT_TABLE_BOSS_HOARD, 0));
+            let entry = LOOT_TABLES[LOOT_TABLE_BOSS_HOARD as usize]
+                .iter()
+                .find(|entry| u64::from(entry.item_id) == item_id)
+                .unwrap();
+            assert!(
+                (u64::from(entry.min_quantity)..=u64::from(entry.max_quantity)).contains(&quantity)
+            );
+            let next_seed = next_loot_seed(seed, LOOT_TABLE_BOSS_HOARD, 0);
+            assert_eq!(next_seed, next_loot_seed(seed, LOOT_TABLE_BOSS_HOARD, 0));
+            seed = next_seed;
+        }
+    }
+
+    #[test]
+    fn luck_favours_rare_entries() {
+        let rare_drops = |luck_stat| {
+            (1..=1000u64)
+                .filter(|seed| {
+                    let (item_id, _) = unpack_loot(roll_loot(seed * 0x9E37_79B9, 0, luck_stat));
+                    item_id == 3 || item_id == 4
+                })
+                .count()
+        };
+        let (unlucky, lucky) = (rare_drops(0), rare_drops(200));
+        // Luck is capped at doubling the rare weights
+        assert_eq!(rare_drops(u32::MAX), lucky);
+        assert!(lucky * 10 > unlucky * 16 && lucky * 10 <= unlucky * 24);
+    }
+
+    #[test]
+    fn empty_and_unknown_tables_are_rejected() {
+        for table in [3, 4, u32::MAX] {
+            assert_eq!(roll_loot(1, table, 100), LOOT_REJECTED);
+            assert_eq!(next_loot_seed(1, table, 100), 1);
+        }
+    }
+
+    #[test]
+    fn main_folds_the_loot_roll() {
+        assert_ne!(
+            main(1 << 40, 1000, 1, 1, 7, 0, 0, 1),
+            main(1 << 40, 1000, 1, 1, 7, 0, 0, 0)
+        );
+    }
+
+    /// Winner, rounds fought and remaining HP of a `simulate_combat` result.
+    fn unpack_fight(outcome: u64) -> (u64, u64, u64) {
+        (
+            outcome >> COMBAT_WINNER_SHIFT,
+            outcome >> COMBAT_ROUNDS_SHIFT
+                & ((1 << (COMBAT_WINNER_SHIFT - COMBAT_ROUNDS_SHIFT)) - 1),
+            outcome & COMBAT_HP_MASK,
+        )
+    }
+
+    /// `simulate_combat` without critical hits, both sides at defense 10.
+    fn fight(
+        attacker_power: u32,
+        attacker_hp: u64,
+        defender_power: u32,
+        defender_hp: u64,
+        max_rounds: u32,
+    ) -> (u64, u64, u64) {
+        unpack_fight(simulate_combat(
+            attacker_power,
+            attacker_hp,
+            defender_power,
+            defender_hp,
+            10,
+            10,
+            max_rounds,
+            1,
+            0,
+        ))
+    }
+
+    #[test]
+    fn one_shot_kill() {
+        // 100^2 / 10 = 1000 damage against 500 HP, so the defender never strikes back
+        assert_eq!(fight(100, 300, 100, 500, 5), (COMBAT_ATTACKER_WINS, 1, 300));
+        // 10^2 / 10 = 10 per blow: the defender takes two rounds to bring down 20 HP
+        assert_eq!(fight(1, 20, 10, 1_000, 5), (COMBAT_DEFENDER_WINS, 2, 1_000));
+    }
+
+    #[test]
+    fn long_grind_stops_at_the_round_cap() {
+        // 3^2 / 10 rounds down to no damage at all
+        assert_eq!(fight(3, 100, 3, 100, 7), (COMBAT_DRAW, 7, 0));
+        assert_eq!(
+            fight(3, 100, 3, 100, u32::MAX),
+            (COMBAT_DRAW, u64::from(COMBAT_MAX_ROUNDS), 0)
+        );
+        // One point of damage a round: 10_001 HP outlasts the cap, 10_000 falls on its last round
+        assert_eq!(
+            fight(4, 10_001, 4, 10_001, u32::MAX),
+            (COMBAT_DRAW, u64::from(COMBAT_MAX_ROUNDS), 0)
+        );
+        assert_eq!(
+            fight(4, 10_000, 4, 10_001, u32::MAX),
+            (COMBAT_DEFENDER_WINS, u64::from(COMBAT_MAX_ROUNDS), 1)
+        );
+        assert_eq!(
+            fight(4, 10_001, 4, 10_000, u32::MAX),
+            (COMBAT_ATTACKER_WINS, u64::from(COMBAT_MAX_ROUNDS), 2)
+        );
+    }
+
+    #[test]
+    fn mutual_knockout_goes_to_the_attacker() {
+        assert_eq!(fight(100, 50, 100, 50, 5), (COMBAT_ATTACKER_WINS, 1, 50));
+        // Both at one blow from the end after two rounds of 10 damage
+        assert_eq!(fight(10, 30, 10, 30, 5), (COMBAT_ATTACKER_WINS, 3, 10));
+    }
+
+    #[test]
+    fn zero_hp_loses_before_a_round() {
+        assert_eq!(fight(100, 0, 1, 40, 5), (COMBAT_DEFENDER_WINS, 0, 40));
+        assert_eq!(fight(1, 40, 100, 0, 5), (COMBAT_ATTACKER_WINS, 0, 40));
+        assert_eq!(fight(100, 0, 100, 0, 5), (COMBAT_DRAW, 0, 0));
+        // Nor does a fight of no rounds start one
+        assert_eq!(fight(100, 40, 100, 40, 0), (COMBAT_DRAW, 0, 0));
+    }
+
+    /// Which of 20 rolls from `seed` crit, roll i in bit i, and the seed after them.
+    fn crit_sequence(seed: u64, crit_chance_bps: u32) -> (u32, u64) {
+        (0..20).fold((0, seed), |(crits, seed), i| {
+            let (critical, next_seed) = roll_critical(seed, crit_chance_bps);
+            (crits | u32::from(critical) << i, next_seed)
+        })
+    }
+
+    #[test]
+    fn pinned_crit_sequence() {
+        let sequence = (0b1001_0010_1000_1011_0001, 0x792e_8a37_8f7b_187b);
+        assert_eq!(crit_sequence(42, 5_000), sequence);
+        // Another seed rolls differently
+        assert_ne!(crit_sequence(43, 5_000), sequence);
+    }
+
+    #[test]
+    fn zero_chance_never_crits() {
+        for seed in [0, 1, 42, u64::MAX] {
+            assert_eq!(crit_sequence(seed, 0).0, 0);
+        }
+    }
+
+    #[test]
+    fn full_chance_always_crits() {
+        for seed in [0, 1, 42, u64::MAX] {
+            assert_eq!(crit_sequence(seed, 10_000).0, (1 << 20) - 1);
+            assert_eq!(crit_sequence(seed, u32::MAX).0, (1 << 20) - 1);
+        }
+        // The seed moves on the same whatever the chance
+        assert_eq!(crit_sequence(42, 10_000).1, crit_sequence(42, 0).1);
+    }
+
+    #[test]
+    fn critical_hits_double_the_damage() {
+        assert_eq!(compute_critical_damage(100, 10, false), 1_000);
+        assert_eq!(compute_critical_damage(100, 10, true), 2_000);
+        assert_eq!(compute_critical_damage(3, 10, true), 0);
+        // 1000 damage a blow, doubled, finishes 1500 HP in one
+        let outcome = simulate_combat(100, 300, 100, 1_500, 10, 10, 5, 1, 10_000);
+        assert_eq!(unpack_fight(outcome), (COMBAT_ATTACKER_WINS, 1, 300));
+        let outcome = simulate_combat(100, 3_000, 100, 1_500, 10, 10, 5, 1, 0);
+        assert_eq!(unpack_fight(outcome), (COMBAT_ATTACKER_WINS, 2, 2_000));
+    }
+
+    /// A resistance word with `resistance` against `element` only.
+    fn resisting(element: u32, resistance: u8) -> u64 {
+        u64::from(resistance) << (element * RESISTANCE_BITS)
+    }
+
+    #[test]
+    fn no_resistance_is_the_base_damage() {
+        // 100^2 / 10
+        for element in 0..ELEMENT_COUNT {
+            assert_eq!(compute_elemental_damage(100, 10, element, 0), 1_000);
+        }
+        // Resisting other elements doesn't help, nor does anything against an unknown one
+        assert_eq!(
+            compute_elemental_damage(100, 10, 1, resisting(2, 50)),
+            1_000
+        );
+        assert_eq!(
+            compute_elemental_damage(100, 10, ELEMENT_COUNT, u64::MAX),
+            1_000
+        );
+    }
+
+    #[test]
+    fn half_resistance_halves_the_damage() {
+        for element in 0..ELEMENT_COUNT {
+            assert_eq!(
+                compute_elemental_damage(100, 10, element, resisting(element, 50)),
+                500
+            );
+        }
+        // 3 * 50 / 100 rather than 3 / 100 * 50
+        assert_eq!(compute_elemental_damage(6, 10, 0, resisting(0, 50)), 1);
+    }
+
+    #[test]
+    fn resistance_clamps_at_immunity() {
+        assert_eq!(compute_elemental_damage(100, 10, 3, resisting(3, 100)), 0);
+        assert_eq!(compute_elemental_damage(100, 10, 3, resisting(3, 0x7F)), 0);
+        assert_eq!(compute_elemental_damage(100, 10, 3, resisting(3, 99)), 10);
+    }
+
+    #[test]
+    fn weakness_amplifies_up_to_half_again() {
+        // 0xF6 is -10%
+        assert_eq!(
+            compute_elemental_damage(100, 10, 4, resisting(4, 0xF6)),
+            1_100
+        );
+        assert_eq!(
+            compute_elemental_damage(100, 10, 4, resisting(4, 0xCF)),
+            1_490
+        );
+        assert_eq!(
+            compute_elemental_damage(100, 10, 4, resisting(4, 0xCE)),
+            1_500
+        );
+        assert_eq!(
+            compute_elemental_damage(100, 10, 4, resisting(4, WEAKNESS_BIT)),
+            1_500
+        );
+        assert_eq!(
+            compute_elemental_damage(100, 10, 4, resisting(4, 0xFF)),
+            1_010
+        );
+    }
+
+    /// Materials packed a byte per type, ore first.
+    fn materials(units: [u32; MATERIAL_TYPES]) -> u32 {
+        units.iter().enumerate().fold(0, |packed, (slot, &held)| {
+            packed | held << (slot as u32 * MATERIAL_BITS)
+        })
+    }
+
+    #[test]
+    fn exact_materials_craft_the_item() {
+        // Four units at rarity 2, forged at 1000 a unit, with nothing left over
+        assert_eq!(
+            craft_item(0, materials([3, 1, 0, 0]), 100, 10),
+            8_000 << CRAFT_VALUE_SHIFT
+        );
+        // The tier 5 blade costs 500 energy and 50 focus
+        let blade = materials([10, 6, 4, 3]);
+        assert_eq!(craft_item(4, blade, 500, 50), 299_000 << CRAFT_VALUE_SHIFT);
+        assert_eq!(
+            craft_item(4, blade, 499, 50),
+            CRAFT_FAILED_BIT | CRAFT_EXHAUSTED << CRAFT_REASON_SHIFT
+        );
+        assert_eq!(
+            craft_item(4, blade, 500, 49),
+            CRAFT_FAILED_BIT | CRAFT_EXHAUSTED << CRAFT_REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn short_materials_are_flagged_by_slot() {
+        // Dragon armor needs a fourth hide and an essence
+        assert_eq!(
+            craft_item(3, materials([8, 3, 2, 0]), u64::MAX, u32::MAX),
+            CRAFT_FAILED_BIT | CRAFT_MISSING_MATERIALS << CRAFT_REASON_SHIFT | 0b1010
+        );
+        assert_eq!(
+            craft_item(3, 0, u64::MAX, u32::MAX),
+            CRAFT_FAILED_BIT | CRAFT_MISSING_MATERIALS << CRAFT_REASON_SHIFT | 0b1111
+        );
+    }
+
+    #[test]
+    fn unknown_recipe_is_rejected() {
+        for recipe_id in [RECIPES.len() as u32, u32::MAX] {
+            assert_eq!(
+                craft_item(recipe_id, u32::MAX, u64::MAX, u32::MAX),
+                CRAFT_FAILED_BIT | CRAFT_UNKNOWN_RECIPE << CRAFT_REASON_SHIFT
+            );
+        }
+    }
+
+    #[test]
+    fn leftover_materials_are_handed_back() {
+        // The staff uses 2 hide and 4 crystal, six units at rarity 5
+        assert_eq!(
+            craft_item(2, materials([5, 3, 9, 7]), 300, 30),
+            30_000 << CRAFT_VALUE_SHIFT | u64::from(materials([5, 1, 5, 7]))
+        );
+        assert_eq!(
+            craft_item(0, u32::MAX, 100, 10),
+            8_000 << CRAFT_VALUE_SHIFT | u64::from(materials([252, 254, 255, 255]))
+        );
+    }
+
+    /// New level, levels gained and leftover XP of an `apply_experience` result.
+    fn unpack_experience(progression: u64) -> (u64, u64, u64) {
+        (
+            progression >> EXPERIENCE_LEVEL_SHIFT,
+            progression >> EXPERIENCE_GAINED_SHIFT & 0xFF,
+            progression & EXPERIENCE_XP_MASK,
+        )
+    }
+
+    /// `apply_experience` at XP rate 10, where level n takes n^3 + 100 XP to leave.
+    fn gain(current_level: u32, current_xp: u64, gained_xp: u64) -> (u64, u64, u64) {
+        unpack_experience(apply_experience(current_level, current_xp, gained_xp, 10))
+    }
+
+    #[test]
+    fn no_experience_gained() {
+        assert_eq!(gain(1, 0, 0), (1, 0, 0));
+        assert_eq!(gain(7, 50, 0), (7, 0, 50));
+    }
+
+    #[test]
+    fn exactly_one_level() {
+        assert_eq!(gain(1, 0, 101), (2, 1, 0));
+        assert_eq!(gain(1, 0, 100), (1, 0, 100));
+        // What was already banked counts towards it
+        assert_eq!(gain(1, 60, 41), (2, 1, 0));
+    }
+
+    #[test]
+    fn several_levels_at_once() {
+        assert_eq!(gain(1, 0, 101 + 108 + 127), (4, 3, 0));
+        assert_eq!(gain(1, 0, 101 + 108 + 127 + 164), (5, 4, 0));
+    }
+
+    #[test]
+    fn nobody_levels_past_the_cap() {
+        // Level 99 takes 99^3 + 100
+        assert_eq!(gain(99, 0, 970_399 + 5), (100, 1, 5));
+        assert_eq!(gain(100, 0, 1_000), (100, 0, 1_000));
+        assert_eq!(gain(150, 0, 7), (100, 0, 7));
+        assert_eq!(gain(1, u64::MAX, u64::MAX), (100, 99, EXPERIENCE_XP_MASK));
+    }
+
+    #[test]
+    fn leftover_is_what_the_levels_didnt_use() {
+        // Levels 1 to 99 take (99 * 100 / 2)^2 + 99 * 100 in all
+        assert_eq!(gain(1, 0, 24_512_400 + 42), (100, 99, 42));
+        assert_eq!(gain(1, 0, 101 + 108 + 126), (3, 2, 126));
+        assert_eq!(gain(2, 10, 200), (3, 1, 102));
+    }
+}
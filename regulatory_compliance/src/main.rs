@@ -1,56 +1,12 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 extern crate num_bigint;
 extern crate num_traits;
 
 use num_bigint::{BigUint, ToBigUint};
 use num_traits::{One, Zero};
-
-//
-// Safe 64-bit arithmetic
-//
-fn safe_add_u64(a: u64, b: u64) -> u64 {
-    a.checked_add(b).unwrap_or(u64::MAX)
-}
-
-fn safe_sub_u64(a: u64, b: u64) -> u64 {
-    a.checked_sub(b).unwrap_or(0)
-}
-
-fn safe_mul_u64(a: u64, b: u64) -> u64 {
-    a.checked_mul(b).unwrap_or(u64::MAX)
-}
-
-fn safe_div_u64(a: u64, b: u64) -> u64 {
-    if b == 0 {
-        0
-    } else {
-        a / b
-    }
-}
-
-//
-// Safe 32-bit arithmetic
-//
-fn safe_add_u32(a: u32, b: u32) -> u32 {
-    a.checked_add(b).unwrap_or(u32::MAX)
-}
-
-fn safe_sub_u32(a: u32, b: u32) -> u32 {
-    a.checked_sub(b).unwrap_or(0)
-}
-
-fn safe_mul_u32(a: u32, b: u32) -> u32 {
-    a.checked_mul(b).unwrap_or(u32::MAX)
-}
-
-fn safe_div_u32(a: u32, b: u32) -> u32 {
-    if b == 0 {
-        0
-    } else {
-        a / b
-    }
-}
+use safe_math::{safe_div_u32, safe_div_u64};
+use wasm_utils::fold_biguint_digits;
 
 //
 // Check if a 32-bit bitmask satisfies certain regulatory flags.
@@ -133,17 +89,12 @@ fn baseline_compliance_check(
 }
 
 //
-// Combine a BigUint into a 64-bit result by XORing the lower 64 bits
+// Combine a BigUint into a 64-bit result by XORing its folded limbs
 // and the popcount of some data. We'll incorporate bit manipulations
 // to demonstrate complexity.
 //
-fn combine_biguint_xor(big_val: &BigUint, pop_u32: u32) -> u64 {
-    let lower_64_array = big_val.to_u64_digits();
-    let lower_64 = if !lower_64_array.is_empty() {
-        lower_64_array[0]
-    } else {
-        0
-    };
+fn combine_biguint_xor(big_val: &BigUint, pop_u32: u32, legacy_digest: bool) -> u64 {
+    let lower_64 = fold_biguint_digits(big_val, legacy_digest);
 
     // For extra complexity, rotate left by pop_u32 mod 64
     let rotate_bits = (pop_u32 % 64) as u32;
@@ -164,6 +115,7 @@ fn partial_fallback_compliance(
     compliance_flags: u32,
     regulatory_rate: u32,
     attempts: u32,
+    legacy_digest: bool,
 ) -> u64 {
     if attempts == 0 {
         return 0;
@@ -180,8 +132,8 @@ fn partial_fallback_compliance(
         // Possibly valid fallback scenario, let's do bit manip on compliance_flags
         let transformed_flags = transform_compliance_flags(compliance_flags);
         let pop_flags = compliance_popcount(transformed_flags);
-        let combined_offset = combine_biguint_xor(&offset_big, pop_flags);
-        let combined_base = combine_biguint_xor(&baseline_big, pop_flags / 2);
+        let combined_offset = combine_biguint_xor(&offset_big, pop_flags, legacy_digest);
+        let combined_base = combine_biguint_xor(&baseline_big, pop_flags / 2, legacy_digest);
 
         // Combine partial fallback results
         return combine_results_64(&[
@@ -202,8 +154,8 @@ fn partial_fallback_compliance(
     if offset_big_2 > threshold_big {
         let transformed_flags = transform_compliance_flags(compliance_flags);
         let pop_flags = compliance_popcount(transformed_flags);
-        let combined_offset = combine_biguint_xor(&offset_big_2, pop_flags);
-        let combined_base = combine_biguint_xor(&baseline_big_2, pop_flags / 2);
+        let combined_offset = combine_biguint_xor(&offset_big_2, pop_flags, legacy_digest);
+        let combined_base = combine_biguint_xor(&baseline_big_2, pop_flags / 2, legacy_digest);
 
         return combine_results_64(&[
             combined_offset,
@@ -221,6 +173,7 @@ fn partial_fallback_compliance(
         compliance_flags,
         regulatory_rate,
         attempts - 1,
+        legacy_digest,
     )
 }
 
@@ -235,13 +188,16 @@ fn combine_results_64(values: &[u64]) -> u64 {
     result
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub fn main(
     measured_emissions: u64,
     carbon_credits: u64,
     compliance_flags: u32,
     regulatory_rate: u32,
+    legacy_digest: u32, // nonzero digests only the lowest 64 bits of each BigUint
 ) -> u64 {
+    let legacy_digest = legacy_digest != 0;
+
     // Step 1: Check a basic bitmask for compliance flags. Let's define some required bits, e.g. 0b1011
     let required_mask = 0b1011;
     let has_required_flags = check_regulatory_flags(compliance_flags, required_mask);
@@ -254,6 +210,7 @@ pub fn main(
             compliance_flags,
             regulatory_rate,
             3,
+            legacy_digest,
         );
     }
 
@@ -271,11 +228,11 @@ pub fn main(
     let pop_flags = compliance_popcount(transformed_flags);
 
     // Step 6: Combine big carbon offset + popcount => partial result
-    let combined_offset_val = combine_biguint_xor(&offset_big, pop_flags);
+    let combined_offset_val = combine_biguint_xor(&offset_big, pop_flags, legacy_digest);
 
     // Step 7: Combine baseline check + partial pop => partial result
     let half_pop = safe_div_u32(pop_flags, 2);
-    let combined_baseline_val = combine_biguint_xor(&baseline_big, half_pop);
+    let combined_baseline_val = combine_biguint_xor(&baseline_big, half_pop, legacy_digest);
 
     // Step 8: Final XOR combination
     combine_results_64(&[
@@ -286,3 +243,35 @@ pub fn main(
         (transformed_flags as u64),
     ])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limbs_above_64_bits_change_the_digest() {
+        let low = BigUint::from(0x1234_5678_9abc_def0u64);
+        let high = &low + (BigUint::one() << 64);
+        assert_eq!(
+            combine_biguint_xor(&low, 7, true),
+            combine_biguint_xor(&high, 7, true)
+        );
+        assert_ne!(
+            combine_biguint_xor(&low, 7, false),
+            combine_biguint_xor(&high, 7, false)
+        );
+    }
+
+    #[test]
+    fn legacy_digest_matches_on_single_limb_inputs() {
+        // Small enough that every BigUint main builds fits in one limb
+        assert_eq!(
+            main(1_000, 2_000, 0xFF, 3, 0),
+            main(1_000, 2_000, 0xFF, 3, 1)
+        );
+        assert_ne!(
+            main(u64::MAX, u64::MAX, 0xFF, 3, 0),
+            main(u64::MAX, u64::MAX, 0xFF, 3, 1)
+        );
+    }
+}
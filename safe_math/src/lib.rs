@@ -0,0 +1,38 @@
+//! Saturating arithmetic shared by the modules: sums and products clamp to the type's
+//! maximum, differences to zero, and a division by zero gives zero.
+
+#![no_std]
+
+// Safe operations for u64
+pub fn safe_add_u64(a: u64, b: u64) -> u64 {
+    a.saturating_add(b)
+}
+
+pub fn safe_sub_u64(a: u64, b: u64) -> u64 {
+    a.saturating_sub(b)
+}
+
+pub fn safe_mul_u64(a: u64, b: u64) -> u64 {
+    a.saturating_mul(b)
+}
+
+pub fn safe_div_u64(a: u64, b: u64) -> u64 {
+    a.checked_div(b).unwrap_or(0)
+}
+
+// Safe operations for u32
+pub fn safe_add_u32(a: u32, b: u32) -> u32 {
+    a.saturating_add(b)
+}
+
+pub fn safe_sub_u32(a: u32, b: u32) -> u32 {
+    a.saturating_sub(b)
+}
+
+pub fn safe_mul_u32(a: u32, b: u32) -> u32 {
+    a.saturating_mul(b)
+}
+
+pub fn safe_div_u32(a: u32, b: u32) -> u32 {
+    a.checked_div(b).unwrap_or(0)
+}
@@ -1,101 +1,578 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 extern crate num_bigint;
 extern crate num_traits;
 
 use num_bigint::{BigUint, ToBigUint};
 use num_traits::{One, Zero};
+use safe_math::{safe_add_u64, safe_div_u64, safe_mul_u64, safe_sub_u32, safe_sub_u64};
+use wasm_utils::{fold_biguint_digits, read_array, write_bytes, DIGIT_FOLD_MULTIPLIER};
+
+// Error codes returned by the exports in place of a combined result.
+const STATUS_INVALID_INPUT: u64 = u64::MAX;
+const STATUS_AUDIT_EXPIRED: u64 = u64::MAX - 1;
+const STATUS_QUORUM_NOT_MET: u64 = u64::MAX - 2;
+
+// `audit_report` writes a fixed 48-byte little-endian report:
+//   0: normalized score (u32)      4: risk tier (u8)        5: status flags (u8)
+//   6: coverage percentage (u16)   8: complexity, low 64 bits (u64)
+//  16: gas used (u64)             24: findings penalty, low 64 bits (u64)
+//  32: digest (u64)               40: status flags, high byte (u8)
+//  41: reserved, zero
+// The two status flags bytes have one bit per status in REPORT_STATUS_FLAGS, in order.
+const AUDIT_REPORT_SIZE: usize = 48;
+
+// Each of up to 16 auditors signs off with one bit of the signature word; higher bits are ignored.
+const AUDITOR_SIGNATURE_MASK: u32 = 0xFFFF;
+
+// Status bits OR-ed into the combined result, just below the normalized score.
+const STATUS_AUDIT_FAILED: u64 = 1 << 50;
+const STATUS_PARTIAL_COVERAGE: u64 = 1 << 49;
+const STATUS_GAS_WARNING: u64 = 1 << 48;
+const STATUS_GAS_CRITICAL: u64 = 1 << 47;
+const STATUS_GAS_LIMIT_EXCEEDED: u64 = 1 << 46;
+
+// `main` reports the risk tier in the top three bits and the combined result in the rest.
+// When asked to normalize, the 10 bits under the tier carry the normalized score instead.
+const RISK_TIER_SHIFT: u32 = 61;
+const RESULT_MASK: u64 = (1 << RISK_TIER_SHIFT) - 1;
+const NORMALIZED_SCORE_SHIFT: u32 = 51;
+const NORMALIZED_SCORE_MASK: u64 = 0x3FF << NORMALIZED_SCORE_SHIFT;
+
+// The packed result also carries the 0-100 confidence in the score in bits 39-45. Below the
+// threshold the risk tier is widened up by one.
+const CONFIDENCE_SHIFT: u32 = 39;
+const CONFIDENCE_MASK: u64 = 0x7F << CONFIDENCE_SHIFT;
+const LOW_CONFIDENCE: u32 = 40;
+
+// Set when branch coverage lags too far behind line coverage, a sign the tests only walk the
+// happy paths. Branch coverage must reach this share of line coverage.
+const STATUS_BRANCH_COVERAGE_LAGS: u64 = 1 << 38;
+const MIN_BRANCH_TO_LINE_PCT: u32 = 70;
+
+// Storage slot access: writing more than HOT_SLOT_WRITES_PER_SLOT times per slot on average
+// contends for hot slots, adding writes^2 / slots times the weight to the complexity. Reading
+// more than READ_HEAVY_RATIO times as often as writing takes a small discount off instead.
+// Accesses reported without any slots are suspicious, flagged and scored as if on a single slot.
+const HOT_SLOT_WRITES_PER_SLOT: u64 = 4;
+const HOT_SLOT_PENALTY_WEIGHT: u64 = 100;
+const READ_HEAVY_RATIO: u64 = 50;
+const READ_HEAVY_DISCOUNT_PCT: u32 = 5;
+const STATUS_STORAGE_SUSPICIOUS: u64 = 1 << 37;
+
+// Set when a fully audited score falls short of the caller's minimum score and the audit went
+// to the fallback on that account rather than for its coverage.
+const STATUS_BELOW_MIN_SCORE: u64 = 1 << 36;
+
+// Set when the exclusion mask takes out bits the required mask asked for, so a pass can't come
+// from excluding whatever wasn't covered without saying so.
+const STATUS_EXCLUSIONS_AFFECT_REQUIREMENTS: u64 = 1 << 35;
+
+// Every status bit above. Digests are cleared there before the statuses go in, so a clean audit
+// reads back with none of them set.
+const STATUS_BITS: u64 = STATUS_AUDIT_FAILED
+    | STATUS_PARTIAL_COVERAGE
+    | STATUS_GAS_WARNING
+    | STATUS_GAS_CRITICAL
+    | STATUS_GAS_LIMIT_EXCEEDED
+    | STATUS_BRANCH_COVERAGE_LAGS
+    | STATUS_STORAGE_SUSPICIOUS
+    | STATUS_BELOW_MIN_SCORE
+    | STATUS_EXCLUSIONS_AFFECT_REQUIREMENTS;
+
+const REPORT_STATUS_FLAGS: [u64; 9] = [
+    STATUS_AUDIT_FAILED,
+    STATUS_PARTIAL_COVERAGE,
+    STATUS_GAS_WARNING,
+    STATUS_GAS_CRITICAL,
+    STATUS_GAS_LIMIT_EXCEEDED,
+    STATUS_BRANCH_COVERAGE_LAGS,
+    STATUS_STORAGE_SUSPICIOUS,
+    STATUS_BELOW_MIN_SCORE,
+    STATUS_EXCLUSIONS_AFFECT_REQUIREMENTS,
+];
+
+// `main_multi` reads contracts as 16-byte little-endian records (coverage u32, gas u64,
+// function count u32) and reports the passing count in the top byte, the worst normalized
+// score in the 10 bits below it and a digest of every contract's result in the rest.
+const CONTRACT_RECORD_SIZE: u32 = 16;
+const MULTI_WORST_SCORE_SHIFT: u32 = 46;
+const MULTI_DIGEST_MASK: u64 = (1 << MULTI_WORST_SCORE_SHIFT) - 1;
+
+// Normalized scores run from 0 up to this, which also stands for "no reference to compare with".
+const MAX_NORMALIZED_SCORE: u32 = 1000;
+
+// Risk tiers run low, guarded, elevated, high, critical. The score alone moves an audit up one
+// tier per bound it reaches; weak coverage or heavy findings push the tier up regardless.
+const RISK_LOW: u8 = 0;
+const RISK_GUARDED: u8 = 1;
+const RISK_HIGH: u8 = 3;
+const RISK_CRITICAL: u8 = 4;
+const RISK_SCORE_BOUNDS: [u64; 3] = [
+    1_000_000_000_000,
+    1_000_000_000_000_000,
+    1_000_000_000_000_000_000,
+];
+
+// Coverage requirement modes, chosen by the low byte of the mode word: every required bit, at
+// least one required bit from each nibble of the mask, or at least n required bits with n taken
+// from the rest of the mode word. Unknown modes fall back to every required bit.
+const COVERAGE_MODE_ALL: u32 = 0;
+const COVERAGE_MODE_ANY_PER_NIBBLE: u32 = 1;
+const COVERAGE_MODE_AT_LEAST_N: u32 = 2;
+
+// Coverage at or above the first percentage audits normally; down to the second the
+// score is halved, and below it the audit goes to the fallback.
+const FULL_COVERAGE_PCT: u32 = 80;
+const PARTIAL_COVERAGE_PCT: u32 = 50;
+
+// Function counts are packed by visibility, 16 bits each: external in the lowest field, then
+// public, internal and private. Each visibility weighs on audit effort by its weight here.
+const VISIBILITY_WEIGHTS: [u32; 4] = [5, 4, 2, 1];
+const PRIVATE_FUNCTIONS_SHIFT: u32 = 48;
+
+// Cyclomatic complexity above these bounds doubles, then triples, the gas term of the complexity.
+const CYCLOMATIC_DOUBLE_ABOVE: u32 = 50;
+const CYCLOMATIC_TRIPLE_ABOVE: u32 = 200;
+
+// `compare_audits` result layout: score delta in bits 0-1, coverage bits lost in bits 2-7,
+// gas regression percentage in bits 8-17 and a digest of both scores in the rest.
+// A lower score means a less complex, less risky contract, so it counts as an improvement.
+const AUDIT_DELTA_EQUAL: u64 = 0;
+const AUDIT_DELTA_IMPROVED: u64 = 1;
+const AUDIT_DELTA_WORSE: u64 = 2;
+const MAX_GAS_REGRESSION_PCT: u64 = 1023;
+
+// `gas_diff` result layout: functions that regressed in the top byte, the worst regression in
+// basis points in the 16 bits below it, the sign of the total gas delta as an AUDIT_DELTA_* value
+// in bits 38-39 and a digest of the per-function deltas in the rest. A function regresses when
+// its gas grows by more than 10%; a new function, with no baseline gas, regresses when it uses
+// more than the mean of the functions the baseline does have, and counts as the worst regression.
+const GAS_REGRESSION_THRESHOLD_PCT: u128 = 10;
+const MAX_GAS_REGRESSION_BPS: u128 = 0xFFFF;
+const GAS_DIFF_WORST_SHIFT: u32 = 40;
+const GAS_DIFF_DELTA_SHIFT: u32 = 38;
+const GAS_DIFF_DIGEST_MASK: u64 = (1 << GAS_DIFF_DELTA_SHIFT) - 1;
+
+// `partial_fallback_audit` has five relaxation strategies to try, and reports which attempt
+// succeeded in bits 56-59 of its result.
+const FALLBACK_STRATEGIES: u32 = 5;
+const FALLBACK_ATTEMPT_SHIFT: u32 = 56;
+const FALLBACK_ATTEMPT_MASK: u64 = 0xF << FALLBACK_ATTEMPT_SHIFT;
+
+// Gas use above these shares of the block gas limit raises a warning, then a critical warning
+// that doubles the complexity's gas term. Gas use above the whole limit fails the audit.
+const GAS_WARNING_PCT: u64 = 50;
+const GAS_CRITICAL_PCT: u64 = 90;
+
+// An audit's score decays by 1% per 1000 blocks, and the audit expires outright at the end
+// of the decay.
+const DECAY_BLOCKS_PER_BPS: u64 = 10;
+const AUDIT_EXPIRY_BLOCKS: u64 = 100_000;
+
+// Complexity formula weights, packed as: gas exponent in bits 0-1, coverage offset in bits 2-9,
+// function count offset in bits 10-17 and cyclomatic exponent in bits 18-19. Exponents run 1-3,
+// with 0 read as 1. The default encoding is the original formula: gas^3, +1, +7 and cyclomatic^2.
+const DEFAULT_COMPLEXITY_WEIGHTS: u64 = 3 | 1 << 2 | 7 << 10 | 2 << 18;
+
+// Dangerous code patterns flagged by the host, each with the complexity penalty it carries once
+// the path using it is covered. A flagged pattern whose bit isn't covered sends the audit to the
+// fallback with one attempt fewer. Bits not listed here are ignored.
+const DANGER_DELEGATECALL: u32 = 1 << 0;
+const DANGER_SELFDESTRUCT: u32 = 1 << 1;
+const DANGER_CALL_BEFORE_STATE_WRITE: u32 = 1 << 2;
+const DANGER_TX_ORIGIN_AUTH: u32 = 1 << 3;
+const DANGER_UNCHECKED_CALL: u32 = 1 << 4;
+const DANGER_PATTERNS: [(u32, u64); 5] = [
+    (DANGER_DELEGATECALL, 5_000),
+    (DANGER_SELFDESTRUCT, 10_000),
+    (DANGER_CALL_BEFORE_STATE_WRITE, 8_000),
+    (DANGER_TX_ORIGIN_AUTH, 2_000),
+    (DANGER_UNCHECKED_CALL, 3_000),
+];
+
+// Opcode entropy is measured in milli-bits over at most 256 opcode buckets, so it tops out at
+// 8000. Entropy below that discounts the complexity linearly, by up to 20% for code that repeats
+// a single opcode. Hosts without a histogram pass OPCODE_ENTROPY_NOT_MEASURED for no discount,
+// which lies outside the measured range so a measured entropy of 0 stays 0.
+const OPCODE_BUCKETS: u32 = 256;
+const OPCODE_ENTROPY_NOT_MEASURED: u64 = u64::MAX;
+const MAX_OPCODE_ENTROPY: u64 = 8000;
+const ENTROPY_DISCOUNT_SCALE: u64 = 5 * MAX_OPCODE_ENTROPY;
+
+// 1000 * log2(i) for i in 0..256, rounded, with log2(0) taken as 0.
+const LOG2_MILLI: [u32; 256] = [
+    0, 0, 1000, 1585, 2000, 2322, 2585, 2807, 3000, 3170, 3322, 3459, 3585, 3700, 3807, 3907, 4000,
+    4087, 4170, 4248, 4322, 4392, 4459, 4524, 4585, 4644, 4700, 4755, 4807, 4858, 4907, 4954, 5000,
+    5044, 5087, 5129, 5170, 5209, 5248, 5285, 5322, 5358, 5392, 5426, 5459, 5492, 5524, 5555, 5585,
+    5615, 5644, 5672, 5700, 5728, 5755, 5781, 5807, 5833, 5858, 5883, 5907, 5931, 5954, 5977, 6000,
+    6022, 6044, 6066, 6087, 6109, 6129, 6150, 6170, 6190, 6209, 6229, 6248, 6267, 6285, 6304, 6322,
+    6340, 6358, 6375, 6392, 6409, 6426, 6443, 6459, 6476, 6492, 6508, 6524, 6539, 6555, 6570, 6585,
+    6600, 6615, 6629, 6644, 6658, 6672, 6687, 6700, 6714, 6728, 6741, 6755, 6768, 6781, 6794, 6807,
+    6820, 6833, 6845, 6858, 6870, 6883, 6895, 6907, 6919, 6931, 6943, 6954, 6966, 6977, 6989, 7000,
+    7011, 7022, 7033, 7044, 7055, 7066, 7077, 7087, 7098, 7109, 7119, 7129, 7140, 7150, 7160, 7170,
+    7180, 7190, 7200, 7209, 7219, 7229, 7238, 7248, 7257, 7267, 7276, 7285, 7295, 7304, 7313, 7322,
+    7331, 7340, 7349, 7358, 7366, 7375, 7384, 7392, 7401, 7409, 7418, 7426, 7435, 7443, 7451, 7459,
+    7468, 7476, 7484, 7492, 7500, 7508, 7516, 7524, 7531, 7539, 7547, 7555, 7562, 7570, 7577, 7585,
+    7592, 7600, 7607, 7615, 7622, 7629, 7637, 7644, 7651, 7658, 7665, 7672, 7679, 7687, 7693, 7700,
+    7707, 7714, 7721, 7728, 7735, 7741, 7748, 7755, 7762, 7768, 7775, 7781, 7788, 7794, 7801, 7807,
+    7814, 7820, 7827, 7833, 7839, 7845, 7852, 7858, 7864, 7870, 7877, 7883, 7889, 7895, 7901, 7907,
+    7913, 7919, 7925, 7931, 7937, 7943, 7948, 7954, 7960, 7966, 7972, 7977, 7983, 7989, 7994,
+];
+
+// Findings word layout: four 8-bit counts, critical in the top byte down to low in the bottom one.
+// Each finding adds its severity weight to the complexity and takes it off the score.
+const FINDING_WEIGHT_CRITICAL: u32 = 1000;
+const FINDING_WEIGHT_HIGH: u32 = 250;
+const FINDING_WEIGHT_MEDIUM: u32 = 50;
+const FINDING_WEIGHT_LOW: u32 = 5;
 
 //
-// Safe 64-bit arithmetic with extended logic
+// Extended bitmask-based checks for permissions and coverage tracking
 //
-fn safe_add_u64(a: u64, b: u64) -> u64 {
-    a.checked_add(b).unwrap_or_else(|| {
-        // Return MAX only if overflow happens
-        u64::MAX
-    })
+fn check_minimum_coverage(coverage_flags: u32, required_mask: u32) -> bool {
+    // coverage_flags must contain all bits from required_mask
+    (coverage_flags & required_mask) == required_mask
 }
 
-fn safe_sub_u64(a: u64, b: u64) -> u64 {
-    a.checked_sub(b).unwrap_or_else(|| {
-        // Avoid underflow, return zero
-        0
-    })
+// Share of the low `total_paths` code paths that are covered, in percent.
+// Bits above `total_paths` are ignored, and there are at most 32 paths.
+fn coverage_percentage(coverage_flags: u32, total_paths: u32) -> u32 {
+    let total_paths = total_paths.min(32);
+    if total_paths == 0 {
+        return 0;
+    }
+    let path_mask = u32::MAX >> (32 - total_paths);
+    (coverage_flags & path_mask).count_ones() * 100 / total_paths
 }
 
-fn safe_sub_u32(a: u32, b: u32) -> u32 {
-    a.checked_sub(b).unwrap_or_else(|| {
-        // Avoid underflow, return zero
-        0
-    })
+// Share of the required bits that are covered, in percent. Nothing required is full coverage.
+fn required_coverage_percentage(coverage_flags: u32, required_mask: u32) -> u32 {
+    let required = required_mask.count_ones();
+    if required == 0 {
+        return 100;
+    }
+    (coverage_flags & required_mask).count_ones() * 100 / required
 }
 
-fn safe_mul_u64(a: u64, b: u64) -> u64 {
-    a.checked_mul(b).unwrap_or_else(|| {
-        // Return MAX only if overflow happens
-        u64::MAX
-    })
+// Check the required coverage under the given requirement mode. An empty required mask always
+// passes.
+fn check_coverage_mode(coverage_flags: u32, required_mask: u32, mode: u32) -> bool {
+    match mode & 0xFF {
+        COVERAGE_MODE_ANY_PER_NIBBLE => (0..8).all(|nibble| {
+            let group = required_mask & (0xF << (nibble * 4));
+            group == 0 || coverage_flags & group != 0
+        }),
+        COVERAGE_MODE_AT_LEAST_N => {
+            required_mask == 0 || (coverage_flags & required_mask).count_ones() >= mode >> 8
+        }
+        _ => check_minimum_coverage(coverage_flags, required_mask),
+    }
 }
 
-fn safe_div_u64(a: u64, b: u64) -> u64 {
-    if b == 0 {
-        // Safe division: return zero for division by zero
-        0
-    } else {
-        a / b
+fn check_full_coverage(coverage_flags: u32) -> bool {
+    // Ensure the coverage_flags are set to full coverage (all bits set)
+    coverage_flags == u32::MAX
+}
+
+//
+// Whether `total_gas_used` is above `pct` percent of the block gas limit. Both sides are scaled
+// up rather than divided down, so even a limit of u64::MAX compares exactly.
+//
+fn exceeds_gas_share(total_gas_used: u64, block_gas_limit: u64, pct: u64) -> bool {
+    BigUint::from(total_gas_used) * 100u32 > BigUint::from(block_gas_limit) * pct
+}
+
+//
+// Audit effort of the packed function counts, weighted by visibility.
+//
+fn weighted_function_count(function_counts: u64) -> u32 {
+    let mut weighted = 0u32;
+    for (i, &weight) in VISIBILITY_WEIGHTS.iter().enumerate() {
+        let count = ((function_counts >> (i * 16)) & 0xFFFF) as u32;
+        weighted = weighted.saturating_add(count.saturating_mul(weight));
+    }
+    weighted
+}
+
+fn total_function_count(function_counts: u64) -> u32 {
+    (0..4)
+        .map(|i| ((function_counts >> (i * 16)) & 0xFFFF) as u32)
+        .sum()
+}
+
+// Pack a plain function count as private functions, so its weighted count is the count itself.
+fn private_functions(function_count: u32) -> u64 {
+    (function_count.min(0xFFFF) as u64) << PRIVATE_FUNCTIONS_SHIFT
+}
+
+// Remove up to `dropped` functions from the packed counts, externals first.
+fn drop_functions(function_counts: u64, dropped: u32) -> u64 {
+    let mut remaining = dropped as u64;
+    let mut counts = function_counts;
+    for i in 0..4 {
+        let shift = i * 16;
+        let taken = ((counts >> shift) & 0xFFFF).min(remaining);
+        counts -= taken << shift;
+        remaining -= taken;
+    }
+    counts
+}
+
+// Take the functions excluded from the audit, one per exclusion mask bit, off the function count.
+// Once anything is excluded, at least one function is left.
+fn exclude_functions(function_count: u32, exclusion_mask: u32) -> u32 {
+    if exclusion_mask == 0 {
+        return function_count;
     }
+    safe_sub_u32(function_count, exclusion_mask.count_ones()).max(1)
 }
 
 //
-// Extended bitmask-based checks for permissions and coverage tracking
+// Cyclomatic complexity of the contract's control-flow graph, approximated from its branch and
+// function counts as edges - nodes + 2 * components = branches - functions + 2 * functions.
+// Never reported below the number of functions.
 //
-fn check_minimum_coverage(coverage_flags: u32, required_mask: u32) -> bool {
-    // coverage_flags must contain all bits from required_mask
-    (coverage_flags & required_mask) == required_mask
+fn compute_cyclomatic(branch_count: u32, function_count: u32) -> u32 {
+    let components = function_count.saturating_mul(2);
+    let cyclomatic = safe_sub_u32(branch_count, function_count).saturating_add(components);
+    cyclomatic.max(function_count)
 }
 
-fn check_full_coverage(coverage_flags: u32) -> bool {
-    // Ensure the coverage_flags are set to full coverage (all bits set)
-    coverage_flags == u32::MAX
+//
+// How a contract uses its storage slots, see HOT_SLOT_WRITES_PER_SLOT.
+//
+struct StorageScore {
+    penalty: BigUint, // hot-slot contention, added to the complexity
+    read_heavy: bool, // earns the read-heavy discount
+    suspicious: bool, // accesses without any slots
+}
+
+impl StorageScore {
+    fn neutral() -> Self {
+        StorageScore {
+            penalty: BigUint::zero(),
+            read_heavy: false,
+            suspicious: false,
+        }
+    }
+
+    // Mixed into the result digest; a neutral score leaves it as is.
+    fn digest(&self) -> u64 {
+        fold_biguint_digits(&self.penalty, false) ^ self.read_heavy as u64
+    }
+}
+
+fn compute_storage_score(reads: u32, writes: u32, unique_slots: u32) -> StorageScore {
+    if reads == 0 && writes == 0 {
+        return StorageScore::neutral();
+    }
+
+    let suspicious = unique_slots == 0;
+    let slots = unique_slots.max(1) as u64;
+    let writes_big = BigUint::from(writes);
+    let penalty = if writes as u64 > HOT_SLOT_WRITES_PER_SLOT * slots {
+        &writes_big * &writes_big * HOT_SLOT_PENALTY_WEIGHT / slots
+    } else {
+        BigUint::zero()
+    };
+
+    StorageScore {
+        penalty,
+        read_heavy: reads as u64 > READ_HEAVY_RATIO * writes as u64,
+        suspicious,
+    }
 }
 
 //
 // Compute a more complex complexity metric with a combination of coverage, gas usage, and function counts.
 //
+#[allow(clippy::too_many_arguments)]
 fn compute_audit_complexity(
     coverage_flags: u32,
     total_gas_used: u64,
     function_count: u32,
-    extra_factor: u32,
+    cyclomatic: u32,
+    weights: u64,
+    opcode_entropy: u64,
+    gas_pressure: u32, // multiplies the gas term, 1 unless the block gas limit is close
+    storage: &StorageScore,
 ) -> BigUint {
     let coverage_big = coverage_flags.to_biguint().unwrap_or(BigUint::zero());
     let gas_big = total_gas_used.to_biguint().unwrap_or(BigUint::zero());
     let fnc_big = function_count.to_biguint().unwrap_or(BigUint::zero());
-    let extra_big = extra_factor.to_biguint().unwrap_or(BigUint::zero());
+    let extra_big = cyclomatic.to_biguint().unwrap_or(BigUint::zero());
+
+    // Unpack the formula weights
+    let gas_exponent = ((weights & 0x3) as u32).max(1);
+    let coverage_offset = ((weights >> 2) & 0xFF) as u32;
+    let fn_offset = ((weights >> 10) & 0xFF) as u32;
+    let extra_exponent = (((weights >> 18) & 0x3) as u32).max(1);
+
+    // Enhanced complexity formula (shown with the default weights):
+    // complexity = ((coverage_flags + 1) * total_gas_used^3 * gas_weight * (function_count + 7)) + (cyclomatic^2)
+    // where gas_weight is 1, 2 or 3 depending on the cyclomatic complexity, times the gas pressure
+    let gas_weight: u32 = if cyclomatic > CYCLOMATIC_TRIPLE_ABOVE {
+        3
+    } else if cyclomatic > CYCLOMATIC_DOUBLE_ABOVE {
+        2
+    } else {
+        1
+    };
+    let gas_term = gas_big.pow(gas_exponent) * gas_weight * gas_pressure; // weighted gas^3
+    let coverage_adjusted = &coverage_big + coverage_offset;
+    let fn_count_adjusted = &fnc_big + fn_offset;
+
+    let intermediate = &coverage_adjusted * &gas_term * &fn_count_adjusted;
+    let extra_adjusted = extra_big.pow(extra_exponent); // cyclomatic^2
+    let complexity_value = intermediate + extra_adjusted + &storage.penalty;
+
+    // Repetitive code (low opcode entropy) is easier to review, and so is read-heavy code
+    let missing_entropy = if opcode_entropy == OPCODE_ENTROPY_NOT_MEASURED {
+        0
+    } else {
+        MAX_OPCODE_ENTROPY - opcode_entropy.min(MAX_OPCODE_ENTROPY)
+    };
+    let complexity_value =
+        complexity_value * (ENTROPY_DISCOUNT_SCALE - missing_entropy) / ENTROPY_DISCOUNT_SCALE;
+    if storage.read_heavy {
+        complexity_value * (100 - READ_HEAVY_DISCOUNT_PCT) / 100u32
+    } else {
+        complexity_value
+    }
+}
+
+//
+// 1000 * log2(value), exact for table entries and using the top 8 significant bits above them.
+//
+fn log2_milli(value: u64) -> u64 {
+    if value < 256 {
+        return LOG2_MILLI[value as usize] as u64;
+    }
+    let shift = 56 - value.leading_zeros() as u64;
+    shift * 1000 + LOG2_MILLI[(value >> shift) as usize] as u64
+}
+
+//
+// Shannon entropy of an opcode histogram in milli-bits, using
+// H = log2(total) - sum(count * log2(count)) / total. An all-zero histogram and a single
+// repeated opcode both have no entropy.
+//
+fn compute_opcode_entropy(histogram: &[u32]) -> u64 {
+    let total: u64 = histogram.iter().map(|&c| c as u64).sum();
+    if total == 0 {
+        return 0;
+    }
+
+    let weighted: u64 = histogram
+        .iter()
+        .map(|&c| c as u64 * log2_milli(c as u64))
+        .sum();
+    safe_sub_u64(log2_milli(total), weighted / total)
+}
+
+//
+// Severity-weighted penalty for the audit findings packed into `findings`.
+//
+fn compute_findings_penalty(findings: u32) -> BigUint {
+    let weights = [
+        FINDING_WEIGHT_LOW,
+        FINDING_WEIGHT_MEDIUM,
+        FINDING_WEIGHT_HIGH,
+        FINDING_WEIGHT_CRITICAL,
+    ];
+
+    let mut penalty = BigUint::zero();
+    for (i, &weight) in weights.iter().enumerate() {
+        let count = (findings >> (i * 8)) & 0xFF;
+        penalty += BigUint::from(count) * weight;
+    }
+    penalty
+}
+
+fn has_critical_findings(findings: u32) -> bool {
+    findings >> 24 != 0
+}
+
+fn total_findings(findings: u32) -> u32 {
+    findings
+        .to_le_bytes()
+        .iter()
+        .map(|&count| count as u32)
+        .sum()
+}
+
+//
+// Confidence in the audit score, 0-100: higher coverage and more findings reviewed per function
+// both raise it.
+// confidence = min(100, coverage_pct * 7 / 10 + min(30, findings * 10 / max(functions, 1)))
+//
+fn score_confidence(coverage_pct: u32, function_count: u32, findings_total: u32) -> u32 {
+    let review_depth = (findings_total.saturating_mul(10) / function_count.max(1)).min(30);
+    (coverage_pct.saturating_mul(7) / 10 + review_depth).min(100)
+}
+
+//
+// Dangerous patterns flagged in `danger_flags` whose code paths aren't covered.
+//
+fn uncovered_danger(danger_flags: u32, coverage_flags: u32) -> u32 {
+    let known = DANGER_PATTERNS.iter().fold(0, |mask, &(bit, _)| mask | bit);
+    danger_flags & known & !coverage_flags
+}
+
+//
+// Sum of the penalties for the dangerous patterns flagged in `danger_flags`.
+//
+fn compute_danger_penalty(danger_flags: u32) -> BigUint {
+    let mut penalty = BigUint::zero();
+    for &(bit, weight) in DANGER_PATTERNS.iter() {
+        if danger_flags & bit != 0 {
+            penalty += weight;
+        }
+    }
+    penalty
+}
 
-    // Enhanced complexity formula:
-    // complexity = ((coverage_flags + 1) * total_gas_used^3 * (function_count + 7)) + (extra_factor^2)
-    let gas_cubed = &gas_big * &gas_big * &gas_big; // total_gas_used^3
-    let coverage_adjusted = &coverage_big + 1u32;
-    let fn_count_adjusted = &fnc_big + 7u32;
+//
+// Classify an audit into a risk tier:
+// - the score alone places it between RISK_LOW and RISK_HIGH using RISK_SCORE_BOUNDS
+// - coverage short of FULL_COVERAGE_PCT makes it at least RISK_GUARDED, and short of
+//   PARTIAL_COVERAGE_PCT at least RISK_HIGH
+// - a findings penalty worth a critical finding makes it RISK_CRITICAL
+//
+fn classify_risk(score: &BigUint, findings_penalty: &BigUint, coverage_pct: u32) -> u8 {
+    if *findings_penalty >= BigUint::from(FINDING_WEIGHT_CRITICAL) {
+        return RISK_CRITICAL;
+    }
 
-    let intermediate = &coverage_adjusted * &gas_cubed * &fn_count_adjusted;
-    let extra_adjusted = extra_big.pow(2); // extra_factor^2
-    let complexity_value = intermediate + extra_adjusted;
+    let mut tier = RISK_LOW;
+    for &bound in RISK_SCORE_BOUNDS.iter() {
+        if *score >= BigUint::from(bound) {
+            tier += 1;
+        }
+    }
 
-    complexity_value
+    if coverage_pct < PARTIAL_COVERAGE_PCT {
+        tier = tier.max(RISK_HIGH);
+    } else if coverage_pct < FULL_COVERAGE_PCT {
+        tier = tier.max(RISK_GUARDED);
+    }
+    tier
 }
 
 //
-// Calculate an advanced audit score with dynamic scaling using different thresholds.
+// Calculate an advanced audit score with dynamic scaling. `denominator_padding` is added to the
+// divisor on top of the coverage flags and function count, which keeps it nonzero and damps the
+// score of small contracts.
 //
 fn compute_audit_score(
     complexity_value: &BigUint,
     coverage_flags: u32,
     function_count: u32,
-    threshold: u64,
+    denominator_padding: u64,
 ) -> BigUint {
-    let denominator_val = (coverage_flags as u64 + function_count as u64 + threshold) as u64;
+    let denominator_val =
+        (coverage_flags as u64 + function_count as u64 + denominator_padding) as u64;
     let denominator = denominator_val.to_biguint().unwrap_or(BigUint::one());
 
     // Dynamic score scaling:
@@ -106,6 +583,43 @@ fn compute_audit_score(
     complexity_value / denominator
 }
 
+// Whether a score falls short of the minimum acceptable score. A minimum of 0 never does.
+fn below_min_score(score: &BigUint, min_score: u64) -> bool {
+    *score < BigUint::from(min_score)
+}
+
+//
+// Map a score onto 0-1000 relative to a reference complexity, saturating at 1000.
+// The score is scaled up before dividing so small ratios don't truncate to zero.
+//
+fn normalize_score(score: &BigUint, reference: &BigUint) -> u32 {
+    if reference.is_zero() {
+        return MAX_NORMALIZED_SCORE;
+    }
+
+    let scaled = score * MAX_NORMALIZED_SCORE / reference;
+    if scaled >= BigUint::from(MAX_NORMALIZED_SCORE) {
+        MAX_NORMALIZED_SCORE
+    } else {
+        scaled.to_u32_digits().first().copied().unwrap_or(0)
+    }
+}
+
+//
+// Share of an audit's score lost to its age, in bps.
+//
+fn audit_decay_bps(blocks_since_audit: u64) -> u64 {
+    (blocks_since_audit / DECAY_BLOCKS_PER_BPS).min(10_000)
+}
+
+fn decay_score(score: &BigUint, blocks_since_audit: u64) -> BigUint {
+    score * (10_000 - audit_decay_bps(blocks_since_audit)) / 10_000u32
+}
+
+fn decay_normalized_score(normalized: u32, blocks_since_audit: u64) -> u32 {
+    (normalized as u64 * (10_000 - audit_decay_bps(blocks_since_audit)) / 10_000) as u32
+}
+
 //
 // Perform complex bit manipulation operations on BigUint and other inputs.
 //
@@ -114,10 +628,10 @@ fn combine_biguint_with_bitops(
     coverage_flags: u32,
     total_gas_used: u64,
     function_count: u32,
+    legacy_digest: bool,
 ) -> u64 {
-    // Convert BigUint to array of u64 digits, focus on the lower 64 bits
-    let digits = big_val.to_u64_digits();
-    let lower_64 = if digits.is_empty() { 0 } else { digits[0] };
+    // Fold the BigUint down to 64 bits
+    let lower_64 = fold_biguint_digits(big_val, legacy_digest);
 
     // Perform various bitwise operations and arithmetic
     let div_result = safe_div_u64(lower_64, 5); // Divide by 5 for variety
@@ -138,43 +652,75 @@ fn combine_biguint_with_bitops(
 }
 
 //
-// Fallback logic with retry and more complex operations if conditions are not met.
+// Fallback logic: retry the audit on progressively relaxed inputs, one strategy per attempt,
+// each applied on top of the ones before it:
+// 1. shift coverage right by one bit
+// 2. shift coverage right by two more bits
+// 3. count the low nibble of the required mask as covered
+// 4. cut gas usage to an eighth
+// 5. drop three functions, each taking the highest required path still uncovered with it
+// The first attempt that meets the required coverage, and fits in a block when there's a block
+// gas limit, is scored, its digest weighted by `multiplier` times the attempt number, and the
+// attempt number is reported in bits 56-59. Returns 0 if no attempt succeeds.
 //
-fn partial_fallback_audit(
-    coverage_flags: u32,
-    total_gas_used: u64,
-    function_count: u32,
-    required_mask: u32,
-    attempts: u32,
-    multiplier: u64,
-) -> u64 {
-    if attempts == 0 {
-        return 0;
-    }
+fn partial_fallback_audit(inputs: &AuditInputs, attempts: u32, multiplier: u64) -> u64 {
+    let mut required_mask = inputs.required_coverage_mask;
+    let mut coverage = inputs.coverage_flags;
+    let mut gas = inputs.total_gas_used;
+    let mut functions = inputs.function_counts;
 
-    // Simulate a more aggressive fallback with multiplication and division
-    let halved_coverage = coverage_flags >> 2; // reduce coverage by shifting right
-    let quarter_gas = safe_div_u64(total_gas_used, 8); // reduce gas usage significantly
-    let reduced_fn_count = safe_sub_u32(function_count, 3);
+    for attempt in 1..=attempts.min(FALLBACK_STRATEGIES) {
+        match attempt {
+            1 => coverage >>= 1,
+            2 => coverage >>= 2,
+            3 => coverage |= required_mask & 0xF,
+            4 => gas = safe_div_u64(gas, 8),
+            _ => {
+                let before = total_function_count(functions);
+                functions = drop_functions(functions, 3);
+                for _ in total_function_count(functions)..before {
+                    let uncovered = required_mask & !coverage;
+                    if uncovered != 0 {
+                        required_mask &= !(1 << (31 - uncovered.leading_zeros()));
+                    }
+                }
+            }
+        }
+        let fn_count = exclude_functions(weighted_function_count(functions), inputs.exclusion_mask);
 
-    // Check if the fallback meets the required coverage
-    if check_minimum_coverage(halved_coverage, required_mask) {
-        let comp_big = compute_audit_complexity(halved_coverage, quarter_gas, reduced_fn_count, 2);
-        let score_big = compute_audit_score(&comp_big, halved_coverage, reduced_fn_count, 1);
-        let combined_result =
-            combine_biguint_with_bitops(&score_big, halved_coverage, quarter_gas, reduced_fn_count);
-        return combined_result * multiplier;
-    } else {
-        // Recurse with reduced parameters
-        return partial_fallback_audit(
-            halved_coverage,
-            quarter_gas,
-            reduced_fn_count,
-            required_mask,
-            attempts - 1,
-            multiplier,
-        );
+        // Check if this attempt meets the required coverage within the block gas limit
+        let fits_block =
+            inputs.block_gas_limit == 0 || !exceeds_gas_share(gas, inputs.block_gas_limit, 100);
+        if fits_block && check_coverage_mode(coverage, required_mask, inputs.coverage_mode) {
+            let cyclomatic = compute_cyclomatic(inputs.branch_count, fn_count);
+            let comp_big = compute_audit_complexity(
+                coverage,
+                gas,
+                fn_count,
+                cyclomatic,
+                inputs.complexity_weights,
+                inputs.opcode_entropy,
+                1,
+                &compute_storage_score(
+                    inputs.storage_reads,
+                    inputs.storage_writes,
+                    inputs.unique_slots,
+                ),
+            );
+            let score_big = compute_audit_score(&comp_big, coverage, fn_count, 1);
+            let combined_result = combine_biguint_with_bitops(
+                &score_big,
+                coverage,
+                gas,
+                fn_count,
+                inputs.legacy_digest != 0,
+            )
+            .wrapping_mul(multiplier.wrapping_mul(attempt as u64));
+            return (combined_result & !FALLBACK_ATTEMPT_MASK)
+                | (attempt as u64) << FALLBACK_ATTEMPT_SHIFT;
+        }
     }
+    0
 }
 
 // Final result combination with XOR and additional logic
@@ -186,43 +732,2011 @@ fn combine_results_64(values: &[u64]) -> u64 {
     out ^ u64::MAX // XOR with MAX for added complexity
 }
 
-#[no_mangle]
-pub fn main(
-    coverage_flags: u32,         // bitmask of covered code paths
+//
+// Everything `main` audits a single contract on.
+//
+#[derive(Clone, Copy)]
+struct AuditInputs {
+    coverage_flags: u32,         // bitmask of covered branches
     total_gas_used: u64,         // total gas used in contract execution
-    function_count: u32,         // how many functions in the contract
+    function_counts: u64,        // external/public/internal/private counts, 16 bits each
     required_coverage_mask: u32, // bits we require to be covered
-) -> u64 {
-    // Step 1: Check if coverage is sufficient
-    let coverage_ok = check_minimum_coverage(coverage_flags, required_coverage_mask);
-    if !coverage_ok {
-        // Partial fallback attempts if coverage is insufficient
-        return partial_fallback_audit(
+    findings: u32,               // critical/high/medium/low finding counts, 8 bits each
+    total_paths: u32,            // code paths in the contract, 0 to use the required mask
+    branch_count: u32,           // conditional branches across all functions
+    legacy_output: u32,          // nonzero returns the combined result without the risk tier
+    complexity_weights: u64,     // packed formula weights, see DEFAULT_COMPLEXITY_WEIGHTS
+    danger_flags: u32,           // dangerous patterns in use, see DANGER_PATTERNS
+    opcode_entropy: u64,         // milli-bits, OPCODE_ENTROPY_NOT_MEASURED if not measured
+    reference_complexity: u64,   // complexity that normalizes to a score of 1000
+    normalize: u32,              // nonzero digests and reports the normalized score
+    block_gas_limit: u64,        // gas available per block, 0 to skip the gas limit checks
+    coverage_mode: u32,          // how the required mask is checked, see COVERAGE_MODE_ALL
+    blocks_since_audit: u64,     // age of the audit, it expires after AUDIT_EXPIRY_BLOCKS
+    legacy_digest: u32,          // nonzero digests only the lowest 64 bits of the score
+    line_flags: u32,             // bitmask of covered lines, over the same paths
+    auditor_signatures: u32,     // one bit per auditor who signed off, up to 16 auditors
+    quorum: u32,                 // signatures required, 0 for no requirement
+    storage_reads: u32,          // storage slot reads during execution
+    storage_writes: u32,         // storage slot writes during execution
+    unique_slots: u32,           // distinct storage slots accessed
+    min_score: u64,              // lower scores go to the fallback, 0 to accept any score
+    exclusion_mask: u32,         // paths of functions left out of the audit, like view getters
+}
+
+impl AuditInputs {
+    // Audit on coverage, gas and function count alone, with every optional feature left neutral.
+    // The functions are counted as private, so they weigh in at exactly their number.
+    fn basic(
+        coverage_flags: u32,
+        total_gas_used: u64,
+        function_count: u32,
+        required_coverage_mask: u32,
+    ) -> Self {
+        AuditInputs {
             coverage_flags,
             total_gas_used,
-            function_count,
+            function_counts: private_functions(function_count),
             required_coverage_mask,
-            5,
-            2,
-        );
+            findings: 0,
+            total_paths: 0,
+            branch_count: 0,
+            legacy_output: 0,
+            complexity_weights: DEFAULT_COMPLEXITY_WEIGHTS,
+            danger_flags: 0,
+            opcode_entropy: OPCODE_ENTROPY_NOT_MEASURED,
+            reference_complexity: 0,
+            normalize: 0,
+            block_gas_limit: 0,
+            coverage_mode: COVERAGE_MODE_ALL,
+            blocks_since_audit: 0,
+            legacy_digest: 0,
+            line_flags: coverage_flags,
+            auditor_signatures: 0,
+            quorum: 0,
+            storage_reads: 0,
+            storage_writes: 0,
+            unique_slots: 0,
+            min_score: 0,
+            exclusion_mask: 0,
+        }
+    }
+}
+
+struct AuditOutcome {
+    result: u64,
+    passed: bool,          // audited on the main path rather than through the fallback
+    normalized_score: u32, // MAX_NORMALIZED_SCORE when the audit fell back
+    tier: u8,
+    status: u64,         // the status bits set along the way
+    coverage_pct: u32,   // branch coverage
+    complexity: BigUint, // zero when the audit fell back
+}
+
+impl AuditOutcome {
+    // An audit turned away before it started, reporting just an error code.
+    fn rejected(code: u64) -> Self {
+        AuditOutcome {
+            result: code,
+            passed: false,
+            normalized_score: MAX_NORMALIZED_SCORE,
+            tier: RISK_CRITICAL,
+            status: 0,
+            coverage_pct: 0,
+            complexity: BigUint::zero(),
+        }
     }
+}
 
-    // Step 2: Compute the audit complexity with an extra factor
-    let complexity_val =
-        compute_audit_complexity(coverage_flags, total_gas_used, function_count, 3);
+//
+// The single-contract audit pipeline behind `main` and the batch and profile exports.
+//
+fn audit_contract(inputs: &AuditInputs) -> AuditOutcome {
+    let AuditInputs {
+        coverage_flags,
+        total_gas_used,
+        function_counts,
+        required_coverage_mask,
+        findings,
+        total_paths,
+        branch_count,
+        legacy_output,
+        complexity_weights,
+        danger_flags,
+        opcode_entropy,
+        reference_complexity,
+        normalize,
+        block_gas_limit,
+        coverage_mode,
+        blocks_since_audit,
+        legacy_digest,
+        line_flags,
+        auditor_signatures,
+        quorum,
+        storage_reads,
+        storage_writes,
+        unique_slots,
+        min_score,
+        exclusion_mask,
+    } = *inputs;
 
-    // Step 3: Derive a more advanced "audit score"
-    let audit_score = compute_audit_score(&complexity_val, coverage_flags, function_count, 10);
+    // Excluded functions are audited as if their paths and the functions themselves weren't there
+    let exclusions_affect_requirements = exclusion_mask & required_coverage_mask != 0;
+    let inputs = &AuditInputs {
+        coverage_flags: coverage_flags & !exclusion_mask,
+        required_coverage_mask: required_coverage_mask & !exclusion_mask,
+        line_flags: line_flags & !exclusion_mask,
+        ..*inputs
+    };
+    let AuditInputs {
+        coverage_flags,
+        required_coverage_mask,
+        line_flags,
+        ..
+    } = *inputs;
+    let function_count =
+        exclude_functions(weighted_function_count(function_counts), exclusion_mask);
 
-    // Step 4: Combine the results with bitwise operations and additional logic
-    let final_val =
-        combine_biguint_with_bitops(&audit_score, coverage_flags, total_gas_used, function_count);
+    // Without enough sign-offs there's nothing to audit yet
+    let signers = auditor_signatures & AUDITOR_SIGNATURE_MASK;
+    if signers.count_ones() < quorum {
+        return AuditOutcome::rejected(STATUS_QUORUM_NOT_MET);
+    }
 
-    // Step 5: Final combination using XOR and logic
-    combine_results_64(&[
+    // Stale audits aren't worth rescuing through the fallback
+    if blocks_since_audit >= AUDIT_EXPIRY_BLOCKS {
+        return AuditOutcome::rejected(STATUS_AUDIT_EXPIRED);
+    }
+
+    // Any critical finding fails the audit whatever the score turns out to be
+    let mut status = if has_critical_findings(findings) {
+        STATUS_AUDIT_FAILED
+    } else {
+        0
+    };
+    let findings_penalty = compute_findings_penalty(findings);
+    if exclusions_affect_requirements {
+        status |= STATUS_EXCLUSIONS_AFFECT_REQUIREMENTS;
+    }
+
+    // Gas close to the block gas limit warns and weighs on the complexity; gas over it fails
+    let mut gas_pressure = 1;
+    let mut over_gas_limit = false;
+    if block_gas_limit != 0 {
+        if exceeds_gas_share(total_gas_used, block_gas_limit, GAS_WARNING_PCT) {
+            status |= STATUS_GAS_WARNING;
+        }
+        if exceeds_gas_share(total_gas_used, block_gas_limit, GAS_CRITICAL_PCT) {
+            status |= STATUS_GAS_CRITICAL;
+            gas_pressure = 2;
+        }
+        if exceeds_gas_share(total_gas_used, block_gas_limit, 100) {
+            status |= STATUS_AUDIT_FAILED | STATUS_GAS_LIMIT_EXCEEDED;
+            over_gas_limit = true;
+        }
+    }
+
+    // Storage accesses with nowhere to go point at misreported counts
+    let storage = compute_storage_score(storage_reads, storage_writes, unique_slots);
+    if storage.suspicious {
+        status |= STATUS_STORAGE_SUSPICIOUS;
+    }
+
+    // Step 1: Check if branch coverage is sufficient. Every mode has to meet its requirement, and
+    // the all-of mode still needs every required bit. Without a path count, the all-of mode is
+    // graded by the share of required bits covered, while a relaxed requirement is met or not.
+    let relaxed = matches!(
+        coverage_mode & 0xFF,
+        COVERAGE_MODE_ANY_PER_NIBBLE | COVERAGE_MODE_AT_LEAST_N
+    );
+    let requirement_met =
+        check_coverage_mode(coverage_flags, required_coverage_mask, coverage_mode);
+    let coverage_pct = if total_paths != 0 {
+        coverage_percentage(coverage_flags, total_paths)
+    } else if !relaxed {
+        required_coverage_percentage(coverage_flags, required_coverage_mask)
+    } else if requirement_met {
+        100
+    } else {
+        0
+    };
+
+    // Branch coverage has to keep up with line coverage
+    let line_pct = if total_paths != 0 {
+        coverage_percentage(line_flags, total_paths)
+    } else {
+        required_coverage_percentage(line_flags, required_coverage_mask)
+    };
+    let branches_lag = coverage_pct * 100 < line_pct * MIN_BRANCH_TO_LINE_PCT;
+    if branches_lag {
+        status |= STATUS_BRANCH_COVERAGE_LAGS;
+    }
+
+    // The risk tier and score confidence are packed into the top bits unless the caller wants
+    // the old unpacked result. A low-confidence score can't vouch for the tier, so it's widened.
+    let confidence = score_confidence(
+        coverage_pct,
+        total_function_count(function_counts),
+        total_findings(findings),
+    );
+    let widen = |tier: u8| {
+        if confidence < LOW_CONFIDENCE {
+            (tier + 1).min(RISK_CRITICAL)
+        } else {
+            tier
+        }
+    };
+    let pack = |result: u64, tier: u8| {
+        if legacy_output != 0 {
+            return result;
+        }
+        ((tier as u64) << RISK_TIER_SHIFT)
+            | (confidence as u64) << CONFIDENCE_SHIFT
+            | (result & RESULT_MASK & !CONFIDENCE_MASK)
+    };
+
+    // Partial fallback attempts if coverage is insufficient or the contract can't fit in a
+    // block, one fewer for untested dangerous code
+    let danger_uncovered = uncovered_danger(danger_flags, coverage_flags) != 0;
+    let fall_back = |status: u64| {
+        let attempts = if danger_uncovered { 4 } else { 5 };
+        let fallback = partial_fallback_audit(inputs, attempts, 2) ^ signers as u64;
+        let tier = widen(classify_risk(
+            &BigUint::zero(),
+            &findings_penalty,
+            coverage_pct,
+        ));
+        AuditOutcome {
+            result: pack(fallback & !STATUS_BITS | status, tier),
+            passed: false,
+            normalized_score: MAX_NORMALIZED_SCORE,
+            tier,
+            status,
+            coverage_pct,
+            complexity: BigUint::zero(),
+        }
+    };
+    if !requirement_met
+        || over_gas_limit
+        || danger_uncovered
+        || branches_lag
+        || coverage_pct < PARTIAL_COVERAGE_PCT
+    {
+        return fall_back(status);
+    }
+
+    // Step 2: Compute the audit complexity from the control flow and the paths covered by both
+    // lines and branches, weighed down by the findings and any dangerous patterns in use
+    let cyclomatic = compute_cyclomatic(branch_count, function_count);
+    let complexity_val = compute_audit_complexity(
+        coverage_flags & line_flags,
+        total_gas_used,
+        function_count,
+        cyclomatic,
+        complexity_weights,
+        opcode_entropy,
+        gas_pressure,
+        &storage,
+    ) + &findings_penalty
+        + compute_danger_penalty(danger_flags);
+
+    // Step 3: Derive a more advanced "audit score", less the findings penalty
+    let raw_score = compute_audit_score(&complexity_val, coverage_flags, function_count, 10);
+    let mut audit_score = if raw_score > findings_penalty {
+        raw_score - &findings_penalty
+    } else {
+        BigUint::zero()
+    };
+
+    // Partially covered contracts only get half the score
+    if coverage_pct < FULL_COVERAGE_PCT {
+        audit_score >>= 1;
+        status |= STATUS_PARTIAL_COVERAGE;
+    }
+
+    // A score short of the caller's minimum goes to the fallback as well
+    if below_min_score(&audit_score, min_score) {
+        return fall_back(status | STATUS_BELOW_MIN_SCORE);
+    }
+
+    // Step 4: Combine the results with bitwise operations and additional logic,
+    // working from the normalized score if asked to, decayed by the audit's age
+    let mut normalized = normalize_score(&audit_score, &BigUint::from(reference_complexity));
+    let digest_score = if normalize != 0 {
+        normalized = decay_normalized_score(normalized, blocks_since_audit);
+        BigUint::from(normalized)
+    } else {
+        decay_score(&audit_score, blocks_since_audit)
+    };
+    let final_val = combine_biguint_with_bitops(
+        &digest_score,
+        coverage_flags,
+        total_gas_used,
+        function_count,
+        legacy_digest != 0,
+    );
+
+    // Step 5: Final combination using XOR and logic, tagged with the risk tier and signed off
+    // by the auditors
+    let mut combined = combine_results_64(&[
         final_val,
         total_gas_used,
         coverage_flags as u64,
         function_count as u64,
-    ])
+        signers as u64,
+        storage.digest(),
+    ]);
+    if normalize != 0 {
+        combined =
+            (combined & !NORMALIZED_SCORE_MASK) | (normalized as u64) << NORMALIZED_SCORE_SHIFT;
+    }
+    let tier = widen(classify_risk(&audit_score, &findings_penalty, coverage_pct));
+    AuditOutcome {
+        result: pack(combined & !STATUS_BITS | status, tier),
+        passed: true,
+        normalized_score: normalized,
+        tier,
+        status,
+        coverage_pct,
+        complexity: complexity_val,
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
+#[allow(clippy::too_many_arguments)]
+pub fn main(
+    coverage_flags: u32,         // bitmask of covered branches
+    total_gas_used: u64,         // total gas used in contract execution
+    function_counts: u64,        // external/public/internal/private counts, 16 bits each
+    required_coverage_mask: u32, // bits we require to be covered
+    findings: u32,               // critical/high/medium/low finding counts, 8 bits each
+    total_paths: u32,            // code paths in the contract, 0 to use the required mask
+    branch_count: u32,           // conditional branches across all functions
+    legacy_output: u32,          // nonzero returns the combined result without the risk tier
+    complexity_weights: u64,     // packed formula weights, see DEFAULT_COMPLEXITY_WEIGHTS
+    danger_flags: u32,           // dangerous patterns in use, see DANGER_PATTERNS
+    opcode_entropy: u64,         // milli-bits, OPCODE_ENTROPY_NOT_MEASURED if not measured
+    reference_complexity: u64,   // complexity that normalizes to a score of 1000
+    normalize: u32,              // nonzero digests and reports the normalized score
+    block_gas_limit: u64,        // gas available per block, 0 to skip the gas limit checks
+    coverage_mode: u32,          // how the required mask is checked, see COVERAGE_MODE_ALL
+    blocks_since_audit: u64,     // age of the audit, it expires after AUDIT_EXPIRY_BLOCKS
+    legacy_digest: u32,          // nonzero digests only the lowest 64 bits of the score
+    line_flags: u32,             // bitmask of covered lines, over the same paths
+    auditor_signatures: u32,     // one bit per auditor who signed off, up to 16 auditors
+    quorum: u32,                 // signatures required, 0 for no requirement
+    storage_reads: u32,          // storage slot reads during execution
+    storage_writes: u32,         // storage slot writes during execution
+    unique_slots: u32,           // distinct storage slots accessed
+    min_score: u64,              // lower scores go to the fallback, 0 to accept any score
+    exclusion_mask: u32,         // paths of functions left out of the audit, like view getters
+) -> u64 {
+    audit_contract(&AuditInputs {
+        coverage_flags,
+        total_gas_used,
+        function_counts,
+        required_coverage_mask,
+        findings,
+        total_paths,
+        branch_count,
+        legacy_output,
+        complexity_weights,
+        danger_flags,
+        opcode_entropy,
+        reference_complexity,
+        normalize,
+        block_gas_limit,
+        coverage_mode,
+        blocks_since_audit,
+        legacy_digest,
+        line_flags,
+        auditor_signatures,
+        quorum,
+        storage_reads,
+        storage_writes,
+        unique_slots,
+        min_score,
+        exclusion_mask,
+    })
+    .result
+}
+
+/// # Safety
+///
+/// `contracts_ptr` must be 0 or point to `count` readable contract records.
+#[no_mangle]
+pub unsafe fn main_multi(
+    contracts_ptr: u32, // count packed contract records, see CONTRACT_RECORD_SIZE
+    count: u32,         // how many contracts to audit
+    required_mask: u32, // bits every contract must cover
+) -> u64 {
+    if count == 0 {
+        return STATUS_INVALID_INPUT;
+    }
+    match count
+        .checked_mul(CONTRACT_RECORD_SIZE)
+        .and_then(|len| read_array::<u8>(contracts_ptr, len))
+    {
+        Some(records) => audit_batch(&records, required_mask),
+        None => STATUS_INVALID_INPUT,
+    }
+}
+
+//
+// Audit each packed contract record in turn and summarize the batch for `main_multi`.
+//
+fn audit_batch(records: &[u8], required_mask: u32) -> u64 {
+    let mut passed = 0u64;
+    let mut worst_score = 0u32;
+    let mut digest = 0u64;
+    for record in records.chunks_exact(CONTRACT_RECORD_SIZE as usize) {
+        let coverage_flags = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        let mut gas = [0u8; 8];
+        gas.copy_from_slice(&record[4..12]);
+        let function_count = u32::from_le_bytes([record[12], record[13], record[14], record[15]]);
+
+        // Scores are normalized against the bound of the high risk tier
+        let mut inputs = AuditInputs::basic(
+            coverage_flags,
+            u64::from_le_bytes(gas),
+            function_count,
+            required_mask,
+        );
+        inputs.reference_complexity = RISK_SCORE_BOUNDS[RISK_SCORE_BOUNDS.len() - 1];
+        let outcome = audit_contract(&inputs);
+
+        if outcome.passed {
+            passed += 1;
+        }
+        worst_score = worst_score.max(outcome.normalized_score);
+        digest ^= outcome.result;
+    }
+
+    passed.min(0xFF) << 56
+        | (worst_score as u64) << MULTI_WORST_SCORE_SHIFT
+        | (digest & MULTI_DIGEST_MASK)
+}
+
+// Risk tier for a plain u64 score and findings penalty, so hosts can check the tier
+// boundaries without running the whole audit.
+#[no_mangle]
+pub fn risk_tier(score: u64, findings_penalty: u64, coverage_pct: u32) -> u32 {
+    classify_risk(
+        &BigUint::from(score),
+        &BigUint::from(findings_penalty),
+        coverage_pct,
+    ) as u32
+}
+
+//
+// Summary of a per-function gas profile.
+//
+struct GasProfile {
+    total: u64,
+    max: u64,
+    mean: u64,
+    concentration_bps: u64, // share of the total spent in the heaviest function
+    hotspots: u64,          // functions using more than ten times the mean
+}
+
+fn analyze_gas_profile(gas: &[u64]) -> Option<GasProfile> {
+    if gas.is_empty() {
+        return None;
+    }
+
+    let total = gas.iter().fold(0u64, |acc, &g| safe_add_u64(acc, g));
+    let max = gas.iter().copied().max().unwrap_or(0);
+    let mean = safe_div_u64(total, gas.len() as u64);
+    let concentration = BigUint::from(max) * 10_000u32 / BigUint::from(total.max(1));
+    let concentration_bps = concentration.to_u64_digits().first().copied().unwrap_or(0);
+    let hotspot_floor = safe_mul_u64(mean, 10);
+    let hotspots = gas.iter().filter(|&&g| g > hotspot_floor).count() as u64;
+
+    Some(GasProfile {
+        total,
+        max,
+        mean,
+        concentration_bps,
+        hotspots,
+    })
+}
+
+/// # Safety
+///
+/// `gas_ptr` must be 0 or point to `fn_count` readable gas figures.
+#[no_mangle]
+pub unsafe fn audit_with_profile(
+    gas_ptr: u32,        // fn_count little-endian u64 gas figures, one per function
+    fn_count: u32,       // how many functions the profile covers
+    coverage_flags: u32, // bitmask of covered code paths
+    required_mask: u32,  // bits we require to be covered
+) -> u64 {
+    let Some(profile) = read_array::<u64>(gas_ptr, fn_count)
+        .as_deref()
+        .and_then(analyze_gas_profile)
+    else {
+        return STATUS_INVALID_INPUT;
+    };
+
+    // Audit the profile's total exactly as `main` would audit it
+    let audit = audit_contract(&AuditInputs::basic(
+        coverage_flags,
+        profile.total,
+        fn_count,
+        required_mask,
+    ))
+    .result;
+
+    combine_results_64(&[
+        audit,
+        profile.max,
+        profile.mean,
+        profile.concentration_bps << 32,
+        profile.hotspots << 48,
+    ])
+}
+
+// Score a single snapshot the way `main` scores a fully covered contract with no findings.
+fn snapshot_score(coverage_flags: u32, total_gas_used: u64, function_count: u32) -> BigUint {
+    let cyclomatic = compute_cyclomatic(0, function_count);
+    let complexity = compute_audit_complexity(
+        coverage_flags,
+        total_gas_used,
+        function_count,
+        cyclomatic,
+        DEFAULT_COMPLEXITY_WEIGHTS,
+        OPCODE_ENTROPY_NOT_MEASURED,
+        1,
+        &StorageScore::neutral(),
+    );
+    compute_audit_score(&complexity, coverage_flags, function_count, 10)
+}
+
+// How much more gas the new snapshot uses than the old one, in percent of the old figure.
+fn gas_regression_pct(old_gas: u64, new_gas: u64) -> u64 {
+    if new_gas <= old_gas {
+        return 0;
+    }
+    if old_gas == 0 {
+        return MAX_GAS_REGRESSION_PCT;
+    }
+
+    let pct = BigUint::from(new_gas - old_gas) * 100u32 / BigUint::from(old_gas);
+    if pct > BigUint::from(MAX_GAS_REGRESSION_PCT) {
+        MAX_GAS_REGRESSION_PCT
+    } else {
+        pct.to_u64_digits().first().copied().unwrap_or(0)
+    }
+}
+
+#[no_mangle]
+pub fn compare_audits(
+    old_coverage: u32,
+    old_gas: u64,
+    old_fn_count: u32,
+    new_coverage: u32,
+    new_gas: u64,
+    new_fn_count: u32,
+    required_mask: u32, // losing any of these bits always counts as worse
+) -> u64 {
+    // Step 1: Score both snapshots
+    let old_score = snapshot_score(old_coverage, old_gas, old_fn_count);
+    let new_score = snapshot_score(new_coverage, new_gas, new_fn_count);
+
+    // Step 2: Classify the change, with lost required coverage overriding the scores
+    let lost_coverage = old_coverage & !new_coverage;
+    let delta = if lost_coverage & required_mask != 0 || new_score > old_score {
+        AUDIT_DELTA_WORSE
+    } else if new_score < old_score {
+        AUDIT_DELTA_IMPROVED
+    } else {
+        AUDIT_DELTA_EQUAL
+    };
+
+    // Step 3: Digest both scores
+    let old_digest =
+        combine_biguint_with_bitops(&old_score, old_coverage, old_gas, old_fn_count, false);
+    let new_digest =
+        combine_biguint_with_bitops(&new_score, new_coverage, new_gas, new_fn_count, false);
+    let digest = old_digest ^ new_digest.rotate_left(23);
+
+    // Step 4: Pack the report
+    delta
+        | (lost_coverage.count_ones() as u64) << 2
+        | gas_regression_pct(old_gas, new_gas) << 8
+        | digest << 18
+}
+
+//
+// Compare per-function gas between a baseline and a candidate build, see `gas_diff`.
+// Everything is summed in 128 bits, so the thresholds compare exactly.
+//
+fn compute_gas_diff(baseline: &[u64], candidate: &[u64]) -> u64 {
+    let (mut baseline_total, mut baseline_present) = (0u128, 0u128);
+    for &gas in baseline.iter().filter(|&&gas| gas != 0) {
+        baseline_total += gas as u128;
+        baseline_present += 1;
+    }
+
+    let mut regressed = 0u64;
+    let mut worst_bps = 0u128;
+    let mut candidate_total = 0u128;
+    let mut digest = 0u64;
+    for (&old, &new) in baseline.iter().zip(candidate) {
+        let (old_gas, new_gas) = (old as u128, new as u128);
+        candidate_total += new_gas;
+        digest = digest.rotate_left(7) ^ new.wrapping_sub(old).wrapping_mul(DIGIT_FOLD_MULTIPLIER);
+
+        let bps = if old == 0 {
+            if new_gas * baseline_present <= baseline_total {
+                continue;
+            }
+            MAX_GAS_REGRESSION_BPS
+        } else {
+            if (new_gas.saturating_sub(old_gas)) * 100 <= old_gas * GAS_REGRESSION_THRESHOLD_PCT {
+                continue;
+            }
+            ((new_gas - old_gas) * 10_000 / old_gas).min(MAX_GAS_REGRESSION_BPS)
+        };
+        regressed += 1;
+        worst_bps = worst_bps.max(bps);
+    }
+
+    let delta = if candidate_total > baseline_total {
+        AUDIT_DELTA_WORSE
+    } else if candidate_total < baseline_total {
+        AUDIT_DELTA_IMPROVED
+    } else {
+        AUDIT_DELTA_EQUAL
+    };
+
+    regressed.min(0xFF) << 56
+        | (worst_bps as u64) << GAS_DIFF_WORST_SHIFT
+        | delta << GAS_DIFF_DELTA_SHIFT
+        | (digest & GAS_DIFF_DIGEST_MASK)
+}
+
+/// # Safety
+///
+/// Both pointers must be 0 or point to `fn_count` readable gas figures.
+#[no_mangle]
+pub unsafe fn gas_diff(
+    baseline_ptr: u32,  // fn_count little-endian u64 gas figures of the baseline build
+    candidate_ptr: u32, // the candidate's gas for the same functions, in the same order
+    fn_count: u32,
+) -> u64 {
+    if fn_count == 0 {
+        return compute_gas_diff(&[], &[]);
+    }
+    match (
+        read_array::<u64>(baseline_ptr, fn_count),
+        read_array::<u64>(candidate_ptr, fn_count),
+    ) {
+        (Some(baseline), Some(candidate)) => compute_gas_diff(&baseline, &candidate),
+        _ => STATUS_INVALID_INPUT,
+    }
+}
+
+/// # Safety
+///
+/// `hist_ptr` must be 0 or point to `bucket_count` readable counts, at most 256.
+#[no_mangle]
+pub unsafe fn opcode_entropy(
+    hist_ptr: u32,     // bucket_count little-endian u32 opcode counts
+    bucket_count: u32, // buckets in the histogram, at most 256 are read
+) -> u64 {
+    match read_array::<u32>(hist_ptr, bucket_count.min(OPCODE_BUCKETS)) {
+        Some(histogram) => compute_opcode_entropy(&histogram),
+        None => STATUS_INVALID_INPUT,
+    }
+}
+
+//
+// Lay out the report `audit_report` writes, see AUDIT_REPORT_SIZE.
+//
+fn build_audit_report(inputs: &AuditInputs) -> [u8; AUDIT_REPORT_SIZE] {
+    let outcome = audit_contract(inputs);
+    let low_64 = |value: &BigUint| value.to_u64_digits().first().copied().unwrap_or(0);
+
+    // Optional features the caller didn't ask for report as zero
+    let normalized = if inputs.normalize != 0 {
+        outcome.normalized_score
+    } else {
+        0
+    };
+    let findings_penalty = low_64(&compute_findings_penalty(inputs.findings));
+    let mut flags = 0u16;
+    for (bit, &status) in REPORT_STATUS_FLAGS.iter().enumerate() {
+        if outcome.status & status != 0 {
+            flags |= 1 << bit;
+        }
+    }
+
+    let mut report = [0u8; AUDIT_REPORT_SIZE];
+    report[0..4].copy_from_slice(&normalized.to_le_bytes());
+    report[4] = outcome.tier;
+    report[5] = flags as u8;
+    report[6..8].copy_from_slice(&(outcome.coverage_pct as u16).to_le_bytes());
+    report[8..16].copy_from_slice(&low_64(&outcome.complexity).to_le_bytes());
+    report[16..24].copy_from_slice(&inputs.total_gas_used.to_le_bytes());
+    report[24..32].copy_from_slice(&findings_penalty.to_le_bytes());
+    report[32..40].copy_from_slice(&outcome.result.to_le_bytes());
+    report[40] = (flags >> 8) as u8;
+    report
+}
+
+/// # Safety
+///
+/// `out_ptr` must be 0 or point to AUDIT_REPORT_SIZE writable bytes.
+#[no_mangle]
+pub unsafe fn audit_report(
+    coverage_flags: u32, // bitmask of covered branches
+    total_gas_used: u64, // total gas used in contract execution
+    function_count: u32, // how many functions in the contract
+    required_mask: u32,  // bits we require to be covered
+    out_ptr: u32,        // where to write the AUDIT_REPORT_SIZE-byte report
+) -> u32 {
+    let inputs = AuditInputs::basic(
+        coverage_flags,
+        total_gas_used,
+        function_count,
+        required_mask,
+    );
+    let report = build_audit_report(&inputs);
+    if write_bytes(out_ptr, &report) {
+        AUDIT_REPORT_SIZE as u32
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_audit_decodes_to_no_status() {
+        for seed in 1..200u64 {
+            let gas = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (seed % 40);
+            let inputs = AuditInputs::basic(0xFFFF, gas, 1 + seed as u32 % 30, 0xFF);
+            let outcome = audit_contract(&inputs);
+            assert!(outcome.passed);
+            assert_eq!(outcome.status, 0);
+            assert_eq!(outcome.result & STATUS_BITS, 0, "gas {gas}");
+        }
+    }
+
+    #[test]
+    fn all_of_mode_needs_every_required_bit() {
+        // 31 of 32 paths covered grades as full coverage, but required bit 3 is missing
+        let mut inputs = AuditInputs::basic(!0b1000, 5_000, 4, 0b1111);
+        inputs.total_paths = 32;
+        assert!(!audit_contract(&inputs).passed);
+
+        inputs.coverage_flags = u32::MAX;
+        inputs.line_flags = u32::MAX;
+        assert!(audit_contract(&inputs).passed);
+
+        // The relaxed modes still accept the same gap
+        inputs.coverage_flags = !0b1000;
+        inputs.line_flags = !0b1000;
+        inputs.coverage_mode = COVERAGE_MODE_ANY_PER_NIBBLE;
+        assert!(audit_contract(&inputs).passed);
+        inputs.coverage_mode = COVERAGE_MODE_AT_LEAST_N | 3 << 8;
+        assert!(audit_contract(&inputs).passed);
+    }
+
+    fn report_flags(report: &[u8; AUDIT_REPORT_SIZE]) -> u16 {
+        u16::from(report[5]) | u16::from(report[40]) << 8
+    }
+
+    #[test]
+    fn audit_report_reads_back() {
+        let mut inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        inputs.normalize = 1;
+        inputs.findings = 2 << 8;
+        let outcome = audit_contract(&inputs);
+        let report = build_audit_report(&inputs);
+        let u64_at = |at: usize| u64::from_le_bytes(report[at..at + 8].try_into().unwrap());
+
+        assert_eq!(
+            u32::from_le_bytes(report[0..4].try_into().unwrap()),
+            outcome.normalized_score
+        );
+        assert_eq!(report[4], outcome.tier);
+        assert_eq!(report_flags(&report), 0);
+        assert_eq!(
+            u16::from_le_bytes(report[6..8].try_into().unwrap()),
+            outcome.coverage_pct as u16
+        );
+        assert_eq!(u64_at(8), outcome.complexity.to_u64_digits()[0]);
+        assert_eq!(u64_at(16), 120_000);
+        assert_eq!(
+            u64_at(24),
+            compute_findings_penalty(inputs.findings).to_u64_digits()[0]
+        );
+        assert_eq!(u64_at(32), outcome.result);
+        assert!(report[41..].iter().all(|&byte| byte == 0));
+        // Safety: a null out_ptr is never written to
+        assert_eq!(unsafe { audit_report(0xFFFF, 120_000, 6, 0xFF, 0) }, 0);
+    }
+
+    #[test]
+    fn audit_report_carries_every_status() {
+        // Excluding a required path takes the ninth flag, in the high byte
+        let mut inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        inputs.exclusion_mask = 0b1;
+        let report = build_audit_report(&inputs);
+        assert_eq!(report_flags(&report), 1 << 8);
+
+        inputs.exclusion_mask = 0;
+        inputs.block_gas_limit = 200_000;
+        let report = build_audit_report(&inputs);
+        assert_eq!(report_flags(&report), 0b100);
+        let status = audit_contract(&inputs).status;
+        for (bit, &flag) in REPORT_STATUS_FLAGS.iter().enumerate() {
+            assert_eq!(report_flags(&report) >> bit & 1 != 0, status & flag != 0);
+        }
+    }
+
+    #[test]
+    fn unmeasured_entropy_takes_no_discount() {
+        let storage = StorageScore::neutral();
+        let complexity = |entropy| {
+            compute_audit_complexity(
+                0xFF,
+                5_000,
+                4,
+                6,
+                DEFAULT_COMPLEXITY_WEIGHTS,
+                entropy,
+                1,
+                &storage,
+            )
+        };
+        assert_eq!(
+            complexity(OPCODE_ENTROPY_NOT_MEASURED),
+            complexity(MAX_OPCODE_ENTROPY)
+        );
+        // A single repeated opcode measures no entropy and takes the whole 20% off
+        assert_eq!(compute_opcode_entropy(&[42]), 0);
+        assert_eq!(compute_opcode_entropy(&[0, 42, 0]), 0);
+        assert_eq!(complexity(0), complexity(MAX_OPCODE_ENTROPY) * 4u32 / 5u32);
+        assert!(complexity(0) < complexity(1));
+    }
+
+    #[test]
+    fn uniform_histogram_has_full_entropy() {
+        assert_eq!(compute_opcode_entropy(&[1; 256]), MAX_OPCODE_ENTROPY);
+        assert_eq!(compute_opcode_entropy(&[1_000; 256]), MAX_OPCODE_ENTROPY);
+        assert_eq!(compute_opcode_entropy(&[1, 1]), 1000);
+    }
+
+    #[test]
+    fn all_zero_histogram_has_no_entropy() {
+        assert_eq!(compute_opcode_entropy(&[]), 0);
+        assert_eq!(compute_opcode_entropy(&[0; 256]), 0);
+        assert_ne!(
+            compute_opcode_entropy(&[0; 256]),
+            OPCODE_ENTROPY_NOT_MEASURED
+        );
+    }
+
+    #[test]
+    fn mixed_histogram_entropy_is_pinned() {
+        // Probabilities 1/2, 1/4, 1/8, 1/8: 1.75 bits
+        assert_eq!(compute_opcode_entropy(&[4, 2, 1, 1]), 1750);
+        assert_eq!(compute_opcode_entropy(&[400, 0, 200, 100, 100]), 1750);
+    }
+
+    /// `basic` inputs covering the lowest `covered` of `total_paths` paths.
+    fn graded_inputs(covered: u32, total_paths: u32) -> AuditInputs {
+        let mut inputs = AuditInputs::basic(u32::MAX >> (32 - covered), 5_000, 4, 0b1);
+        inputs.total_paths = total_paths;
+        inputs
+    }
+
+    #[test]
+    fn coverage_grades_at_the_80_percent_boundary() {
+        assert_eq!(coverage_percentage(u32::MAX >> 13, 24), 79);
+        assert_eq!(coverage_percentage(u32::MAX >> 12, 25), 80);
+        assert_eq!(coverage_percentage(u32::MAX >> 6, 32), 81);
+
+        let below = audit_contract(&graded_inputs(19, 24));
+        assert!(below.passed);
+        assert_eq!(below.status, STATUS_PARTIAL_COVERAGE);
+        for (covered, total_paths) in [(20, 25), (26, 32)] {
+            let outcome = audit_contract(&graded_inputs(covered, total_paths));
+            assert!(outcome.passed);
+            assert_eq!(outcome.status, 0);
+        }
+    }
+
+    #[test]
+    fn zero_paths_grade_by_the_required_mask() {
+        assert_eq!(coverage_percentage(u32::MAX, 0), 0);
+        assert_eq!(required_coverage_percentage(0, 0), 100);
+        assert_eq!(required_coverage_percentage(0b11, 0b1111), 50);
+        // Half the required bits covered, with no path count, isn't enough in all-of mode
+        let mut inputs = AuditInputs::basic(0b11, 5_000, 4, 0b1111);
+        inputs.coverage_mode = COVERAGE_MODE_AT_LEAST_N | 2 << 8;
+        let outcome = audit_contract(&inputs);
+        assert!(outcome.passed);
+        assert_eq!(outcome.coverage_pct, 100);
+        inputs.coverage_mode = COVERAGE_MODE_ALL;
+        assert!(!audit_contract(&inputs).passed);
+    }
+
+    #[test]
+    fn bits_past_the_path_count_are_ignored() {
+        assert_eq!(coverage_percentage(0xFFFF_FC00 | 0xFF, 10), 80);
+        assert_eq!(coverage_percentage(u32::MAX, 40), 100);
+        let mut inputs = graded_inputs(20, 25);
+        inputs.coverage_flags |= 0xFE00_0000;
+        inputs.line_flags = inputs.coverage_flags;
+        assert_eq!(audit_contract(&inputs).coverage_pct, 80);
+    }
+
+    #[test]
+    fn uniform_profile_has_no_hotspots() {
+        let profile = analyze_gas_profile(&[100, 100, 100, 100]).unwrap();
+        assert_eq!(
+            (
+                profile.total,
+                profile.max,
+                profile.mean,
+                profile.concentration_bps,
+                profile.hotspots
+            ),
+            (400, 100, 100, 2500, 0)
+        );
+        assert!(analyze_gas_profile(&[]).is_none());
+        assert_eq!(analyze_gas_profile(&[0, 0]).unwrap().concentration_bps, 0);
+        // Safety: a null gas_ptr is never read
+        assert_eq!(
+            unsafe { audit_with_profile(0, 3, 0, 0) },
+            STATUS_INVALID_INPUT
+        );
+    }
+
+    #[test]
+    fn dominant_function_is_a_hotspot() {
+        let mut gas = vec![1; 20];
+        gas.push(1000);
+        let profile = analyze_gas_profile(&gas).unwrap();
+        assert_eq!(profile.total, 1020);
+        assert_eq!(profile.mean, 48);
+        assert_eq!(profile.concentration_bps, 9803);
+        assert_eq!(profile.hotspots, 1);
+    }
+
+    #[test]
+    fn profile_total_audits_like_main() {
+        let profile = analyze_gas_profile(&[30_000, 50_000, 40_000]).unwrap();
+        let audit = audit_contract(&AuditInputs::basic(0xFFFF, profile.total, 3, 0xFF)).result;
+        let direct = main(
+            0xFFFF,
+            120_000,
+            private_functions(3),
+            0xFF,
+            0,
+            0,
+            0,
+            0,
+            DEFAULT_COMPLEXITY_WEIGHTS,
+            0,
+            OPCODE_ENTROPY_NOT_MEASURED,
+            0,
+            0,
+            0,
+            COVERAGE_MODE_ALL,
+            0,
+            0,
+            0xFFFF,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        );
+        assert_eq!(audit, direct);
+    }
+
+    /// Complexity with the default weights and nothing but the cyclomatic count varying.
+    fn cyclomatic_complexity(cyclomatic: u32) -> BigUint {
+        compute_audit_complexity(
+            1,
+            1000,
+            1,
+            cyclomatic,
+            DEFAULT_COMPLEXITY_WEIGHTS,
+            OPCODE_ENTROPY_NOT_MEASURED,
+            1,
+            &StorageScore::neutral(),
+        )
+    }
+
+    #[test]
+    fn cyclomatic_is_pinned() {
+        assert_eq!(compute_cyclomatic(30, 10), 40);
+        assert_eq!(compute_cyclomatic(0, 0), 0);
+        // (1 + 1) * 1000^3 * (1 + 7) + 50^2
+        let gas_term = BigUint::from(1000u32).pow(3) * 16u32;
+        assert_eq!(cyclomatic_complexity(50), &gas_term + 2500u32);
+    }
+
+    #[test]
+    fn cyclomatic_thresholds_raise_the_gas_weight() {
+        let gas_term = BigUint::from(1000u32).pow(3) * 16u32;
+        assert_eq!(
+            cyclomatic_complexity(CYCLOMATIC_DOUBLE_ABOVE + 1),
+            &gas_term * 2u32 + 51u32 * 51u32
+        );
+        assert_eq!(
+            cyclomatic_complexity(CYCLOMATIC_TRIPLE_ABOVE),
+            &gas_term * 2u32 + 200u32 * 200u32
+        );
+        assert_eq!(
+            cyclomatic_complexity(CYCLOMATIC_TRIPLE_ABOVE + 1),
+            &gas_term * 3u32 + 201u32 * 201u32
+        );
+    }
+
+    #[test]
+    fn fewer_branches_than_functions_floor_at_the_function_count() {
+        assert_eq!(compute_cyclomatic(3, 10), 20);
+        assert_eq!(compute_cyclomatic(0, 7), 14);
+        assert_eq!(compute_cyclomatic(u32::MAX, 10), u32::MAX);
+        assert_eq!(compute_cyclomatic(0, u32::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn each_risk_tier_is_pinned() {
+        assert_eq!(risk_tier(0, 0, 100), RISK_LOW as u32);
+        assert_eq!(risk_tier(RISK_SCORE_BOUNDS[0], 0, 100), 1);
+        assert_eq!(risk_tier(RISK_SCORE_BOUNDS[1], 0, 100), 2);
+        assert_eq!(risk_tier(u64::MAX, 0, 100), RISK_HIGH as u32);
+        assert_eq!(risk_tier(0, 1000, 100), RISK_CRITICAL as u32);
+        // Weak coverage raises the tier whatever the score
+        assert_eq!(risk_tier(0, 0, 79), RISK_GUARDED as u32);
+        assert_eq!(risk_tier(0, 0, 49), RISK_HIGH as u32);
+
+        let mut critical = AuditInputs::basic(0xFFFF, 1_000, 4, 0xFF);
+        critical.findings = 1 << 24;
+        for (inputs, tier, result) in [
+            (
+                AuditInputs::basic(0xFFFF, 1_000, 4, 0xFF),
+                0,
+                0x1ff8230263ef65d7,
+            ),
+            (
+                AuditInputs::basic(0xFFFF, 10_000, 4, 0xFF),
+                1,
+                0x3ff823069dd64952,
+            ),
+            (
+                AuditInputs::basic(0xFFFF, 100_000, 4, 0xFF),
+                2,
+                0x5fb0230062cbf29b,
+            ),
+            (
+                AuditInputs::basic(0xFFFF, 1_000_000, 4, 0xFF),
+                3,
+                0x70382306a29bbdd8,
+            ),
+            (critical, 4, 0x9ffc240263ef6c7f),
+        ] {
+            let outcome = audit_contract(&inputs);
+            assert_eq!(outcome.tier, tier);
+            assert_eq!(outcome.result, result);
+        }
+    }
+
+    #[test]
+    fn risk_tier_packing_round_trips() {
+        for gas in [1_000, 100_000, 1_000_000] {
+            let inputs = AuditInputs::basic(0xFFFF, gas, 4, 0xFF);
+            let outcome = audit_contract(&inputs);
+            assert_eq!(outcome.result >> RISK_TIER_SHIFT, u64::from(outcome.tier));
+            assert_eq!(
+                (outcome.result & CONFIDENCE_MASK) >> CONFIDENCE_SHIFT,
+                u64::from(score_confidence(100, 4, 0))
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_output_leaves_the_result_unpacked() {
+        let packed = AuditInputs::basic(0xFFFF, 1_000_000, 4, 0xFF);
+        let legacy = AuditInputs {
+            legacy_output: 1,
+            ..packed
+        };
+        let (packed, legacy) = (
+            audit_contract(&packed).result,
+            audit_contract(&legacy).result,
+        );
+        assert_ne!(packed, legacy);
+        let payload = RESULT_MASK & !CONFIDENCE_MASK;
+        assert_eq!(packed & payload, legacy & payload);
+    }
+
+    /// `compare_audits`' score delta, coverage bits lost and gas regression percentage.
+    fn audit_delta(comparison: u64) -> (u64, u64, u64) {
+        (
+            comparison & 0b11,
+            comparison >> 2 & 0x3F,
+            comparison >> 8 & 0x3FF,
+        )
+    }
+
+    #[test]
+    fn identical_runs_compare_equal() {
+        let comparison = compare_audits(0xFF, 1000, 10, 0xFF, 1000, 10, 0x0F);
+        assert_eq!(audit_delta(comparison), (AUDIT_DELTA_EQUAL, 0, 0));
+    }
+
+    #[test]
+    fn gas_only_regression() {
+        let comparison = compare_audits(0xFF, 1000, 10, 0xFF, 1500, 10, 0x0F);
+        assert_eq!(audit_delta(comparison), (AUDIT_DELTA_WORSE, 0, 50));
+        // New gas on a zero baseline, or past the field, saturates
+        assert_eq!(gas_regression_pct(0, 5), MAX_GAS_REGRESSION_PCT);
+        assert_eq!(gas_regression_pct(1, u64::MAX), MAX_GAS_REGRESSION_PCT);
+    }
+
+    #[test]
+    fn losing_a_required_bit_is_worse() {
+        // Less gas would be an improvement, but required bit 0 was lost
+        let comparison = compare_audits(0xFF, 1000, 10, 0xFE, 900, 10, 0x01);
+        assert_eq!(audit_delta(comparison), (AUDIT_DELTA_WORSE, 1, 0));
+    }
+
+    #[test]
+    fn less_gas_is_an_improvement() {
+        let comparison = compare_audits(0xFF, 1000, 10, 0xFF, 900, 10, 0x0F);
+        assert_eq!(audit_delta(comparison), (AUDIT_DELTA_IMPROVED, 0, 0));
+    }
+
+    /// Complexity of a fixed contract under the given formula weights.
+    fn weighted_complexity(weights: u64) -> BigUint {
+        compute_audit_complexity(
+            0xFF,
+            5_000,
+            4,
+            6,
+            weights,
+            OPCODE_ENTROPY_NOT_MEASURED,
+            1,
+            &StorageScore::neutral(),
+        )
+    }
+
+    #[test]
+    fn default_weights_are_the_original_formula() {
+        // (0xFF + 1) * 5000^3 * (4 + 7) + 6^2
+        let original = BigUint::from(256u32) * BigUint::from(5_000u32).pow(3) * 11u32 + 36u32;
+        assert_eq!(weighted_complexity(DEFAULT_COMPLEXITY_WEIGHTS), original);
+        let inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        assert_eq!(audit_contract(&inputs).result, 0x5f68_2304_ac96_dbc6);
+    }
+
+    #[test]
+    fn zero_exponents_read_as_one() {
+        let offsets = 1 << 2 | 7 << 10;
+        assert_eq!(
+            weighted_complexity(offsets),
+            weighted_complexity(offsets | 1 | 1 << 18)
+        );
+    }
+
+    #[test]
+    fn gas_exponent_scales_the_gas_term() {
+        let linear = weighted_complexity(1 | 1 << 2 | 7 << 10 | 1 << 18);
+        let cubic = weighted_complexity(DEFAULT_COMPLEXITY_WEIGHTS);
+        // 256 * 5000 * 11 + 6 against 256 * 5000^3 * 11 + 36
+        assert_eq!(linear, BigUint::from(256u32 * 5_000 * 11 + 6));
+        assert_eq!(
+            (cubic - 36u32) / (linear - 6u32),
+            BigUint::from(25_000_000u32)
+        );
+    }
+
+    #[test]
+    fn uncovered_danger_forces_the_fallback() {
+        // Bit 4 isn't covered
+        let mut inputs = AuditInputs::basic(0xFFEF, 120_000, 6, 0xF);
+        inputs.danger_flags = DANGER_UNCHECKED_CALL;
+        assert_eq!(
+            uncovered_danger(inputs.danger_flags, 0xFFEF),
+            DANGER_UNCHECKED_CALL
+        );
+        let outcome = audit_contract(&inputs);
+        assert!(!outcome.passed);
+        // Scored by the fallback, with one attempt fewer
+        let payload = RESULT_MASK & !CONFIDENCE_MASK & !STATUS_BITS;
+        assert_eq!(
+            outcome.result & payload,
+            partial_fallback_audit(&inputs, 4, 2) & payload
+        );
+    }
+
+    #[test]
+    fn covered_danger_adds_its_penalties() {
+        let flags = DANGER_DELEGATECALL | DANGER_SELFDESTRUCT | DANGER_TX_ORIGIN_AUTH;
+        assert_eq!(
+            compute_danger_penalty(flags),
+            BigUint::from(5_000u32 + 10_000 + 2_000)
+        );
+        // Bits past the known patterns are ignored
+        assert_eq!(compute_danger_penalty(1 << 20), BigUint::zero());
+        assert_eq!(uncovered_danger(1 << 20, 0), 0);
+
+        let clean = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        let dangerous = AuditInputs {
+            danger_flags: flags,
+            ..clean
+        };
+        let (clean, dangerous) = (audit_contract(&clean), audit_contract(&dangerous));
+        assert!(dangerous.passed);
+        assert_eq!(
+            dangerous.complexity,
+            clean.complexity + BigUint::from(17_000u32)
+        );
+    }
+
+    #[test]
+    fn no_danger_keeps_the_audit_as_it_was() {
+        let inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        assert_eq!(audit_contract(&inputs).result, 0x5f68_2304_ac96_dbc6);
+        assert_eq!(compute_danger_penalty(0), BigUint::zero());
+    }
+
+    #[test]
+    fn score_at_the_reference_normalizes_to_1000() {
+        let reference = BigUint::from(123_456_789u32);
+        assert_eq!(
+            normalize_score(&reference, &reference),
+            MAX_NORMALIZED_SCORE
+        );
+        // Past the reference it saturates
+        assert_eq!(
+            normalize_score(&(&reference * 5u32), &reference),
+            MAX_NORMALIZED_SCORE
+        );
+
+        let mut inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        inputs.normalize = 1;
+        inputs.reference_complexity = 1;
+        let outcome = audit_contract(&inputs);
+        assert_eq!(outcome.normalized_score, MAX_NORMALIZED_SCORE);
+        assert_eq!(
+            (outcome.result & NORMALIZED_SCORE_MASK) >> NORMALIZED_SCORE_SHIFT,
+            u64::from(MAX_NORMALIZED_SCORE)
+        );
+    }
+
+    #[test]
+    fn half_the_reference_normalizes_to_500() {
+        let reference = BigUint::from(1_000_000u32);
+        assert_eq!(normalize_score(&BigUint::from(500_000u32), &reference), 500);
+        // Scaled up before dividing, so small ratios survive
+        assert_eq!(normalize_score(&BigUint::from(1u32), &reference), 0);
+        assert_eq!(normalize_score(&BigUint::from(1_000u32), &reference), 1);
+    }
+
+    #[test]
+    fn zero_reference_is_the_sentinel() {
+        assert_eq!(
+            normalize_score(&BigUint::from(7u32), &BigUint::zero()),
+            MAX_NORMALIZED_SCORE
+        );
+        let mut inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        inputs.normalize = 1;
+        assert_eq!(
+            audit_contract(&inputs).normalized_score,
+            MAX_NORMALIZED_SCORE
+        );
+    }
+
+    /// A `main_multi` contract record, see CONTRACT_RECORD_SIZE.
+    fn contract_record(coverage_flags: u32, total_gas_used: u64, function_count: u32) -> Vec<u8> {
+        let mut record = coverage_flags.to_le_bytes().to_vec();
+        record.extend_from_slice(&total_gas_used.to_le_bytes());
+        record.extend_from_slice(&function_count.to_le_bytes());
+        record
+    }
+
+    /// The audit `audit_batch` runs on one contract.
+    fn batch_outcome(
+        coverage_flags: u32,
+        total_gas_used: u64,
+        function_count: u32,
+    ) -> AuditOutcome {
+        let mut inputs = AuditInputs::basic(coverage_flags, total_gas_used, function_count, 0xFF);
+        inputs.reference_complexity = RISK_SCORE_BOUNDS[2];
+        audit_contract(&inputs)
+    }
+
+    #[test]
+    fn single_contract_batch_matches_main() {
+        let outcome = batch_outcome(0xFFFF, 120_000, 6);
+        let direct = main(
+            0xFFFF,
+            120_000,
+            private_functions(6),
+            0xFF,
+            0,
+            0,
+            0,
+            0,
+            DEFAULT_COMPLEXITY_WEIGHTS,
+            0,
+            OPCODE_ENTROPY_NOT_MEASURED,
+            RISK_SCORE_BOUNDS[2],
+            0,
+            0,
+            COVERAGE_MODE_ALL,
+            0,
+            0,
+            0xFFFF,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        );
+        assert_eq!(outcome.result, direct);
+        let batch = audit_batch(&contract_record(0xFFFF, 120_000, 6), 0xFF);
+        assert_eq!(batch >> 56, 1);
+        assert_eq!(
+            batch >> MULTI_WORST_SCORE_SHIFT & 0x3FF,
+            u64::from(outcome.normalized_score)
+        );
+        assert_eq!(batch & MULTI_DIGEST_MASK, direct & MULTI_DIGEST_MASK);
+    }
+
+    #[test]
+    fn batch_reports_the_worst_score() {
+        let contracts = [
+            (0xFFFF, 120_000, 6),
+            (0xFFFF, 50_000_000, 3),
+            (0x1, 1_000, 2),
+        ];
+        let mut records = Vec::new();
+        let mut digest = 0;
+        for (coverage_flags, gas, function_count) in contracts {
+            records.extend(contract_record(coverage_flags, gas, function_count));
+            digest ^= batch_outcome(coverage_flags, gas, function_count).result;
+        }
+        let worst = batch_outcome(0xFFFF, 50_000_000, 3).normalized_score;
+        assert!(worst > batch_outcome(0xFFFF, 120_000, 6).normalized_score);
+        // The third contract misses the required bits and falls back, scoring the maximum
+        assert!(!batch_outcome(0x1, 1_000, 2).passed);
+        let batch = audit_batch(&records, 0xFF);
+        assert_eq!(batch >> 56, 2);
+        assert_eq!(
+            batch >> MULTI_WORST_SCORE_SHIFT & 0x3FF,
+            u64::from(MAX_NORMALIZED_SCORE)
+        );
+        assert_eq!(batch & MULTI_DIGEST_MASK, digest & MULTI_DIGEST_MASK);
+    }
+
+    #[test]
+    fn batch_rejects_bad_input() {
+        // Safety: nothing is read from a null pointer or for no contracts
+        unsafe {
+            assert_eq!(main_multi(0, 3, 0xFF), STATUS_INVALID_INPUT);
+            assert_eq!(main_multi(64, 0, 0xFF), STATUS_INVALID_INPUT);
+            assert_eq!(main_multi(0, u32::MAX, 0xFF), STATUS_INVALID_INPUT);
+        }
+    }
+
+    #[test]
+    fn gas_limit_statuses_at_each_boundary() {
+        let gas_statuses = STATUS_GAS_WARNING | STATUS_GAS_CRITICAL | STATUS_GAS_LIMIT_EXCEEDED;
+        for (pct, status) in [
+            (49, 0),
+            (50, 0),
+            (51, STATUS_GAS_WARNING),
+            (89, STATUS_GAS_WARNING),
+            (90, STATUS_GAS_WARNING),
+            (99, STATUS_GAS_WARNING | STATUS_GAS_CRITICAL),
+            (100, STATUS_GAS_WARNING | STATUS_GAS_CRITICAL),
+            (101, gas_statuses | STATUS_AUDIT_FAILED),
+        ] {
+            let mut inputs = AuditInputs::basic(0xFFFF, pct * 1_000, 6, 0xFF);
+            inputs.block_gas_limit = 100_000;
+            let outcome = audit_contract(&inputs);
+            assert_eq!(
+                outcome.status & (gas_statuses | STATUS_AUDIT_FAILED),
+                status,
+                "{pct}%"
+            );
+            // Past the limit the audit goes to the fallback
+            assert_eq!(outcome.passed, pct <= 100);
+        }
+        // Scaled up rather than divided down, so a u64::MAX limit compares exactly
+        assert!(!exceeds_gas_share(u64::MAX, u64::MAX, 100));
+        assert!(exceeds_gas_share(u64::MAX / 2 + 1, u64::MAX, 50));
+    }
+
+    #[test]
+    fn critical_gas_doubles_the_gas_term() {
+        // 6 private functions give a cyclomatic count of 12, which the doubling leaves alone
+        let complexity = |pct: u64| {
+            let mut inputs = AuditInputs::basic(0xFFFF, pct * 1_000, 6, 0xFF);
+            inputs.block_gas_limit = 100_000;
+            audit_contract(&inputs).complexity - 144u32
+        };
+        let gas_term = |pct: u64| {
+            let mut inputs = AuditInputs::basic(0xFFFF, pct * 1_000, 6, 0xFF);
+            inputs.block_gas_limit = 0;
+            audit_contract(&inputs).complexity - 144u32
+        };
+        assert_eq!(complexity(89), gas_term(89));
+        assert_eq!(complexity(90), gas_term(90));
+        assert_eq!(complexity(99), gas_term(99) * 2u32);
+        assert_eq!(complexity(100), gas_term(100) * 2u32);
+    }
+
+    /// The attempt number `partial_fallback_audit` reported, 0 if every attempt failed.
+    fn fallback_attempt(result: u64) -> u64 {
+        (result & FALLBACK_ATTEMPT_MASK) >> FALLBACK_ATTEMPT_SHIFT
+    }
+
+    #[test]
+    fn first_attempt_fallback_is_pinned() {
+        let inputs = AuditInputs::basic(0xFFFF, 120_000, 6, 0xF);
+        let weighted = partial_fallback_audit(&inputs, 5, 2);
+        let unweighted = partial_fallback_audit(&inputs, 5, 1);
+        assert_eq!(fallback_attempt(weighted), 1);
+        assert_eq!(fallback_attempt(unweighted), 1);
+        assert_eq!(weighted & !FALLBACK_ATTEMPT_MASK, 0x0020_c644_3c09_5972);
+        assert_eq!(unweighted & !FALLBACK_ATTEMPT_MASK, 0x0090_6322_1e04_acb9);
+    }
+
+    #[test]
+    fn or_with_required_nibble_succeeds_at_attempt_three() {
+        let inputs = AuditInputs::basic(0, 120_000, 6, 0xF);
+        assert_eq!(fallback_attempt(partial_fallback_audit(&inputs, 5, 2)), 3);
+        assert_eq!(partial_fallback_audit(&inputs, 2, 2), 0);
+    }
+
+    #[test]
+    fn reduced_gas_succeeds_at_attempt_four() {
+        let inputs = AuditInputs {
+            block_gas_limit: 200_000,
+            ..AuditInputs::basic(0xFFFF, 1_000_000, 6, 0xF)
+        };
+        assert_eq!(fallback_attempt(partial_fallback_audit(&inputs, 5, 2)), 4);
+        assert_eq!(partial_fallback_audit(&inputs, 3, 2), 0);
+    }
+
+    #[test]
+    fn shifting_two_more_bits_succeeds_at_attempt_two() {
+        // Bit 3 lands on the required bit 0 after three shifts, not after one
+        let inputs = AuditInputs::basic(0b1000, 120_000, 6, 0b1);
+        assert_eq!(fallback_attempt(partial_fallback_audit(&inputs, 5, 2)), 2);
+        assert_eq!(partial_fallback_audit(&inputs, 1, 2), 0);
+    }
+
+    #[test]
+    fn dropping_functions_succeeds_at_attempt_five() {
+        // Three required paths above the low nibble go with the three dropped functions
+        let inputs = AuditInputs::basic(0, 120_000, 6, 0x700);
+        assert_eq!(fallback_attempt(partial_fallback_audit(&inputs, 5, 2)), 5);
+        assert_eq!(partial_fallback_audit(&inputs, 4, 2), 0);
+        // A fourth missing path is one more than the dropped functions can take
+        let inputs = AuditInputs::basic(0, 120_000, 6, 0xF0);
+        assert_eq!(partial_fallback_audit(&inputs, 5, 2), 0);
+        // And with fewer functions than that, fewer paths go
+        let inputs = AuditInputs::basic(0, 120_000, 2, 0x700);
+        assert_eq!(partial_fallback_audit(&inputs, 5, 2), 0);
+        let inputs = AuditInputs::basic(0, 120_000, 2, 0x600);
+        assert_eq!(fallback_attempt(partial_fallback_audit(&inputs, 5, 2)), 5);
+    }
+
+    /// `audit_contract` on a fully covered contract audited `blocks_since_audit` blocks ago.
+    fn aged_audit(blocks_since_audit: u64, normalize: u32) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            blocks_since_audit,
+            normalize,
+            ..AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF)
+        })
+    }
+
+    #[test]
+    fn fresh_audits_do_not_decay() {
+        assert_eq!(audit_decay_bps(0), 0);
+        assert_eq!(aged_audit(0, 0).result, 0x5f68_2304_ac96_dbc6);
+        assert_eq!(aged_audit(0, 1).normalized_score, 1000);
+    }
+
+    #[test]
+    fn ten_thousand_blocks_decay_ten_percent() {
+        assert_eq!(audit_decay_bps(10_000), 1_000);
+        let normalized = aged_audit(10_000, 1);
+        assert_eq!(normalized.normalized_score, 900);
+        assert_eq!(normalized.result, 0x5c20_2307_ffff_07bc);
+        assert_eq!(aged_audit(10_000, 0).result, 0x5f78_2307_412e_4146);
+    }
+
+    #[test]
+    fn audits_expire_at_the_end_of_the_decay() {
+        let last_block = aged_audit(AUDIT_EXPIRY_BLOCKS - 1, 1);
+        assert_ne!(last_block.result, STATUS_AUDIT_EXPIRED);
+        assert_eq!(last_block.normalized_score, 0);
+        assert_eq!(
+            aged_audit(AUDIT_EXPIRY_BLOCKS, 1).result,
+            STATUS_AUDIT_EXPIRED
+        );
+
+        // Expired audits aren't rescued even when they would otherwise fall back
+        let stale = AuditInputs {
+            blocks_since_audit: AUDIT_EXPIRY_BLOCKS,
+            ..AuditInputs::basic(0, 120_000, 6, 0xFF)
+        };
+        let outcome = audit_contract(&stale);
+        assert_eq!(outcome.result, STATUS_AUDIT_EXPIRED);
+        assert!(!outcome.passed);
+    }
+
+    #[test]
+    fn biguint_scores_decay_exactly() {
+        let score = BigUint::from(u64::MAX) * BigUint::from(u64::MAX);
+        assert_eq!(decay_score(&score, 0), score);
+        assert_eq!(decay_score(&score, 10_000), &score * 9_000u32 / 10_000u32);
+        assert_eq!(decay_score(&score, 25_500), &score * 7_450u32 / 10_000u32);
+        assert!(decay_score(&score, 10_000) > BigUint::from(u64::MAX));
+        assert_eq!(decay_normalized_score(1000, 25_500), 745);
+    }
+
+    #[test]
+    fn limbs_above_64_bits_change_the_digest() {
+        let low = BigUint::from(0x1234_5678_9abc_def0u64);
+        let high = &low + (BigUint::one() << 64);
+        assert_eq!(
+            fold_biguint_digits(&low, true),
+            fold_biguint_digits(&high, true)
+        );
+        assert_ne!(
+            fold_biguint_digits(&low, false),
+            fold_biguint_digits(&high, false)
+        );
+
+        let combine =
+            |v: &BigUint, legacy| combine_biguint_with_bitops(v, 0xFFFF, 120_000, 6, legacy);
+        assert_eq!(combine(&low, true), combine(&high, true));
+        assert_ne!(combine(&low, false), combine(&high, false));
+    }
+
+    #[test]
+    fn single_limb_values_fold_the_same_in_both_modes() {
+        for value in [0u64, 1, 0xFFFF, u64::MAX] {
+            let big = BigUint::from(value);
+            assert_eq!(fold_biguint_digits(&big, false), value);
+            assert_eq!(fold_biguint_digits(&big, true), value);
+        }
+    }
+
+    /// `audit_contract` on a fully covered contract with the packed `function_counts`.
+    fn fleet_audit(function_counts: u64) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            function_counts,
+            ..AuditInputs::basic(0xFFFF, 120_000, 0, 0xFF)
+        })
+    }
+
+    #[test]
+    fn visibility_weights_the_function_count() {
+        let external = 6u64;
+        let internal = 6u64 << 32;
+        assert_eq!(weighted_function_count(external), 30);
+        assert_eq!(weighted_function_count(internal), 12);
+        assert_eq!(
+            total_function_count(external),
+            total_function_count(internal)
+        );
+
+        let external_audit = fleet_audit(external);
+        let internal_audit = fleet_audit(internal);
+        assert!(external_audit.complexity > internal_audit.complexity);
+        assert_ne!(external_audit.result, internal_audit.result);
+    }
+
+    #[test]
+    fn weighted_count_saturates() {
+        assert_eq!(weighted_function_count(u64::MAX), 0xFFFF * 12);
+        assert_eq!(weighted_function_count(0xFFFF), 0xFFFF * 5);
+        assert_eq!(weighted_function_count(private_functions(u32::MAX)), 0xFFFF);
+    }
+
+    #[test]
+    fn contracts_without_externals_are_audited() {
+        let outcome = fleet_audit(3 << 16 | 2 << 32 | 1 << 48);
+        assert!(outcome.passed);
+        assert_eq!(weighted_function_count(3 << 16 | 2 << 32 | 1 << 48), 17);
+    }
+
+    #[test]
+    fn dropping_functions_takes_externals_first() {
+        let counts = 2 | 4 << 16 | 4 << 32;
+        assert_eq!(drop_functions(counts, 1), 1 | 4 << 16 | 4 << 32);
+        assert_eq!(drop_functions(counts, 3), 3 << 16 | 4 << 32);
+        assert_eq!(drop_functions(counts, 20), 0);
+    }
+
+    #[test]
+    fn confidence_caps_at_100() {
+        assert_eq!(score_confidence(100, 1, 50), 100);
+        assert_eq!(score_confidence(100, 6, 0), 70);
+        assert_eq!(score_confidence(u32::MAX, u32::MAX, u32::MAX), 100);
+    }
+
+    #[test]
+    fn zero_coverage_rests_on_the_findings() {
+        assert_eq!(score_confidence(0, 6, 0), 0);
+        assert_eq!(score_confidence(0, 6, 3), 5);
+        assert_eq!(score_confidence(0, 1, 100), 30);
+    }
+
+    #[test]
+    fn zero_functions_do_not_divide_by_zero() {
+        assert_eq!(score_confidence(50, 0, 2), 55);
+        assert_eq!(score_confidence(50, 0, 0), 35);
+    }
+
+    /// `audit_contract` on a contract with half of its 16 paths covered and `findings`.
+    fn half_covered(findings: u32) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            total_paths: 16,
+            findings,
+            ..AuditInputs::basic(0xFF, 120_000, 6, 0xF)
+        })
+    }
+
+    #[test]
+    fn low_confidence_widens_the_tier() {
+        let confidence =
+            |outcome: &AuditOutcome| (outcome.result & CONFIDENCE_MASK) >> CONFIDENCE_SHIFT;
+
+        // 35% confidence pushes the tier up a step, a few low findings reviewed bring it back
+        let unreviewed = half_covered(0);
+        let reviewed = half_covered(5);
+        assert!(unreviewed.passed && reviewed.passed);
+        assert_eq!(confidence(&unreviewed), 35);
+        assert_eq!(confidence(&reviewed), 43);
+        assert_eq!(unreviewed.tier, RISK_HIGH);
+        assert_eq!(reviewed.tier, RISK_HIGH - 1);
+        assert_eq!(unreviewed.result >> RISK_TIER_SHIFT, RISK_HIGH as u64);
+    }
+
+    /// `audit_contract` over 16 paths with separate branch and line coverage.
+    fn line_and_branch(branch_flags: u32, line_flags: u32) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            total_paths: 16,
+            line_flags,
+            ..AuditInputs::basic(branch_flags, 120_000, 6, 0xF)
+        })
+    }
+
+    #[test]
+    fn equal_line_and_branch_masks_keep_the_original_result() {
+        let inputs = AuditInputs {
+            line_flags: 0xFFFF,
+            ..AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF)
+        };
+        assert_eq!(audit_contract(&inputs).result, 0x5f68_2304_ac96_dbc6);
+        assert_eq!(
+            line_and_branch(0xFFFF, 0xFFFF).status & STATUS_BRANCH_COVERAGE_LAGS,
+            0
+        );
+    }
+
+    #[test]
+    fn branches_lagging_lines_fall_back() {
+        let lagging = line_and_branch(0xFF, 0xFFFF);
+        assert!(!lagging.passed);
+        assert_ne!(lagging.status & STATUS_BRANCH_COVERAGE_LAGS, 0);
+
+        // 75% of the lines' 100% is within the 70% allowance
+        let close = line_and_branch(0xFFF, 0xFFFF);
+        assert!(close.passed);
+        assert_eq!(close.status & STATUS_BRANCH_COVERAGE_LAGS, 0);
+    }
+
+    #[test]
+    fn branches_ahead_of_lines_pass() {
+        let ahead = line_and_branch(0xFFFF, 0xFF);
+        assert!(ahead.passed);
+        assert_eq!(ahead.status & STATUS_BRANCH_COVERAGE_LAGS, 0);
+        // Only paths covered both ways count towards the complexity
+        assert_eq!(ahead.complexity, line_and_branch(0xFF, 0xFF).complexity);
+    }
+
+    #[test]
+    fn zero_line_coverage_never_lags() {
+        let outcome = line_and_branch(0xFFFF, 0);
+        assert!(outcome.passed);
+        assert_eq!(outcome.status & STATUS_BRANCH_COVERAGE_LAGS, 0);
+    }
+
+    /// `audit_contract` on a fully covered contract signed by `auditor_signatures` against `quorum`.
+    fn signed_audit(auditor_signatures: u32, quorum: u32) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            auditor_signatures,
+            quorum,
+            ..AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF)
+        })
+    }
+
+    #[test]
+    fn quorum_boundary() {
+        assert_eq!(signed_audit(0b111, 4).result, STATUS_QUORUM_NOT_MET);
+        let met = signed_audit(0b1111, 4);
+        assert_ne!(met.result, STATUS_QUORUM_NOT_MET);
+        assert!(met.passed);
+    }
+
+    #[test]
+    fn signatures_past_16_auditors_are_ignored() {
+        assert_eq!(signed_audit(0xFFFF_0000, 1).result, STATUS_QUORUM_NOT_MET);
+        assert_eq!(
+            signed_audit(0xFFFF_0001, 1).result,
+            signed_audit(0x0001, 1).result
+        );
+    }
+
+    #[test]
+    fn zero_quorum_needs_no_signatures() {
+        let unsigned = signed_audit(0, 0);
+        assert!(unsigned.passed);
+        assert_eq!(unsigned.result, 0x5f68_2304_ac96_dbc6);
+    }
+
+    #[test]
+    fn signer_set_changes_the_digest() {
+        let first = signed_audit(0b0011, 2);
+        let second = signed_audit(0b1100, 2);
+        assert!(first.passed && second.passed);
+        assert_ne!(first.result, second.result);
+    }
+
+    /// Regressed count, worst regression in bps and total delta of a `compute_gas_diff` result.
+    fn gas_diff_fields(result: u64) -> (u64, u64, u64) {
+        (
+            result >> 56,
+            (result >> GAS_DIFF_WORST_SHIFT) & 0xFFFF,
+            (result >> GAS_DIFF_DELTA_SHIFT) & 0b11,
+        )
+    }
+
+    #[test]
+    fn regressions_start_past_ten_percent() {
+        let at_threshold = compute_gas_diff(&[1_000, 500], &[1_100, 500]);
+        assert_eq!(gas_diff_fields(at_threshold), (0, 0, AUDIT_DELTA_WORSE));
+
+        let past_threshold = compute_gas_diff(&[1_000, 500], &[1_101, 500]);
+        assert_eq!(
+            gas_diff_fields(past_threshold),
+            (1, 1_010, AUDIT_DELTA_WORSE)
+        );
+    }
+
+    #[test]
+    fn new_functions_regress_above_the_baseline_mean() {
+        let at_mean = compute_gas_diff(&[1_000, 3_000, 0], &[1_000, 3_000, 2_000]);
+        assert_eq!(gas_diff_fields(at_mean).0, 0);
+
+        let above_mean = compute_gas_diff(&[1_000, 3_000, 0], &[1_000, 3_000, 2_001]);
+        assert_eq!(
+            gas_diff_fields(above_mean),
+            (1, MAX_GAS_REGRESSION_BPS as u64, AUDIT_DELTA_WORSE)
+        );
+    }
+
+    #[test]
+    fn all_improved_functions_report_no_regressions() {
+        let result = compute_gas_diff(&[1_000, 2_000, 3_000], &[900, 1_500, 3_000]);
+        assert_eq!(gas_diff_fields(result), (0, 0, AUDIT_DELTA_IMPROVED));
+        assert_ne!(result & GAS_DIFF_DIGEST_MASK, 0);
+    }
+
+    #[test]
+    fn no_functions_diff_to_zero() {
+        assert_eq!(compute_gas_diff(&[], &[]), 0);
+        // Safety: null pointers are never read
+        unsafe {
+            assert_eq!(gas_diff(0, 0, 0), 0);
+            assert_eq!(gas_diff(0, 0, 1), STATUS_INVALID_INPUT);
+        }
+    }
+
+    /// `audit_contract` on a fully covered contract with the given storage accesses.
+    fn storage_audit(storage_reads: u32, storage_writes: u32, unique_slots: u32) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            storage_reads,
+            storage_writes,
+            unique_slots,
+            ..AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF)
+        })
+    }
+
+    #[test]
+    fn hot_slots_add_their_penalty() {
+        assert_eq!(compute_storage_score(0, 40, 10).penalty, BigUint::zero());
+        assert_eq!(
+            compute_storage_score(0, 50, 10).penalty,
+            BigUint::from(25_000u32)
+        );
+
+        let base = storage_audit(0, 0, 0).complexity;
+        let hot = storage_audit(0, 50, 10).complexity;
+        assert_eq!(hot - base, BigUint::from(25_000u32));
+    }
+
+    #[test]
+    fn read_heavy_contracts_earn_the_discount() {
+        assert!(!compute_storage_score(50, 1, 1).read_heavy);
+        assert!(compute_storage_score(51, 1, 1).read_heavy);
+
+        let base = storage_audit(0, 0, 0);
+        let read_heavy = storage_audit(51, 1, 1);
+        assert_eq!(read_heavy.complexity, &base.complexity * 95u32 / 100u32);
+        assert_ne!(read_heavy.result, base.result);
+    }
+
+    #[test]
+    fn accesses_without_slots_are_suspicious() {
+        let score = compute_storage_score(10, 10, 0);
+        assert!(score.suspicious);
+        assert_eq!(score.penalty, BigUint::from(10_000u32));
+        assert_ne!(
+            storage_audit(10, 10, 0).status & STATUS_STORAGE_SUSPICIOUS,
+            0
+        );
+    }
+
+    #[test]
+    fn no_accesses_score_neutral() {
+        let score = compute_storage_score(0, 0, 0);
+        assert!(!score.suspicious && !score.read_heavy);
+        assert_eq!(score.digest(), 0);
+        let outcome = storage_audit(0, 0, 0);
+        assert_eq!(outcome.status & STATUS_STORAGE_SUSPICIOUS, 0);
+        assert_eq!(outcome.result, 0x5f68_2304_ac96_dbc6);
+    }
+
+    /// The audit score of `AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF)`.
+    const BASIC_AUDIT_SCORE: u64 = 22_458_859_574_987_414;
+
+    /// `audit_contract` on a fully covered contract held to `min_score`.
+    fn gated_audit(min_score: u64) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            min_score,
+            ..AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF)
+        })
+    }
+
+    #[test]
+    fn scores_at_the_minimum_pass() {
+        let at_minimum = gated_audit(BASIC_AUDIT_SCORE);
+        assert!(at_minimum.passed);
+        assert_eq!(at_minimum.status & STATUS_BELOW_MIN_SCORE, 0);
+        assert_eq!(at_minimum.result, 0x5f68_2304_ac96_dbc6);
+    }
+
+    #[test]
+    fn scores_below_the_minimum_fall_back() {
+        let below = gated_audit(BASIC_AUDIT_SCORE + 1);
+        assert!(!below.passed);
+        assert_ne!(below.status & STATUS_BELOW_MIN_SCORE, 0);
+        assert_ne!(below.result & STATUS_BELOW_MIN_SCORE, 0);
+    }
+
+    #[test]
+    fn zero_minimum_never_gates() {
+        assert!(!below_min_score(&BigUint::zero(), 0));
+        assert_eq!(gated_audit(0).result, 0x5f68_2304_ac96_dbc6);
+    }
+
+    #[test]
+    fn coverage_failures_keep_their_own_status() {
+        let uncovered = audit_contract(&AuditInputs::basic(0, 120_000, 6, 0xFF));
+        assert!(!uncovered.passed);
+        assert_eq!(uncovered.status & STATUS_BELOW_MIN_SCORE, 0);
+        assert_ne!(
+            uncovered.result & STATUS_BITS,
+            gated_audit(BASIC_AUDIT_SCORE + 1).result & STATUS_BITS
+        );
+    }
+
+    /// `audit_contract` on a fully covered contract with `findings`, normalized against
+    /// `reference_complexity`.
+    fn audit_with_findings(
+        findings: u32,
+        inputs: AuditInputs,
+        reference_complexity: u64,
+    ) -> AuditOutcome {
+        audit_contract(&AuditInputs {
+            findings,
+            normalize: 1,
+            reference_complexity,
+            ..inputs
+        })
+    }
+
+    #[test]
+    fn no_findings_leave_the_audit_as_it_was() {
+        assert_eq!(compute_findings_penalty(0), BigUint::zero());
+        let outcome = audit_contract(&AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF));
+        assert!(outcome.passed);
+        assert_eq!(outcome.status, 0);
+        assert_eq!(outcome.result, 0x5f68_2304_ac96_dbc6);
+        assert_eq!(
+            outcome.complexity,
+            compute_audit_complexity(
+                0xFFFF,
+                120_000,
+                6,
+                compute_cyclomatic(0, 6),
+                DEFAULT_COMPLEXITY_WEIGHTS,
+                OPCODE_ENTROPY_NOT_MEASURED,
+                1,
+                &compute_storage_score(0, 0, 0),
+            )
+        );
+    }
+
+    #[test]
+    fn each_severity_has_its_weight() {
+        assert_eq!(compute_findings_penalty(1), BigUint::from(5u32));
+        assert_eq!(compute_findings_penalty(1 << 8), BigUint::from(50u32));
+        assert_eq!(compute_findings_penalty(1 << 16), BigUint::from(250u32));
+        assert_eq!(compute_findings_penalty(1 << 24), BigUint::from(1000u32));
+        assert_eq!(
+            compute_findings_penalty(0x0101_0101),
+            BigUint::from(1305u32)
+        );
+    }
+
+    #[test]
+    fn maxed_out_criticals_saturate_the_score() {
+        assert_eq!(
+            compute_findings_penalty(0xFF << 24),
+            BigUint::from(255_000u32)
+        );
+        assert_eq!(
+            compute_findings_penalty(u32::MAX),
+            BigUint::from(255u32 * 1305)
+        );
+        // A tiny contract's score is far below the penalty, so it bottoms out at 0
+        let tiny = AuditInputs::basic(0x1, 1, 1, 0x1);
+        assert_eq!(audit_with_findings(0, tiny, 1).normalized_score, 1000);
+        let failed = audit_with_findings(0xFF << 24, tiny, 1);
+        assert!(failed.passed);
+        assert_eq!(failed.normalized_score, 0);
+        assert_eq!(failed.complexity, BigUint::from(255_020u32));
+        assert_ne!(failed.status & STATUS_AUDIT_FAILED, 0);
+    }
+
+    #[test]
+    fn any_critical_fails_a_perfect_audit() {
+        let basic = AuditInputs::basic(0xFFFF, 120_000, 6, 0xFF);
+        let clean = audit_with_findings(0, basic, 1);
+        let critical = audit_with_findings(1 << 24, basic, 1);
+        assert_eq!(clean.normalized_score, MAX_NORMALIZED_SCORE);
+        assert_eq!(critical.normalized_score, MAX_NORMALIZED_SCORE);
+        assert_eq!(clean.status & STATUS_AUDIT_FAILED, 0);
+        assert_eq!(critical.status, STATUS_AUDIT_FAILED);
+        assert_ne!(critical.result & STATUS_AUDIT_FAILED, 0);
+        // A high finding weighs on the score but doesn't fail the audit by itself
+        let high = audit_with_findings(0xFF << 16, basic, 1);
+        assert_eq!(high.status & STATUS_AUDIT_FAILED, 0);
+    }
 }
@@ -1,46 +1,151 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
-// Safe operations for u64
-fn safe_add_u64(a: u64, b: u64) -> u64 {
-    a.checked_add(b).unwrap_or(u64::MAX)
-}
+extern crate num_bigint;
+extern crate num_traits;
 
-fn safe_sub_u64(a: u64, b: u64) -> u64 {
-    a.checked_sub(b).unwrap_or(0)
-}
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use safe_math::{safe_div_u64, safe_mul_u64, safe_sub_u64};
+use wasm_utils::{read_bytes, write_bytes};
 
-fn safe_mul_u64(a: u64, b: u64) -> u64 {
-    a.checked_mul(b).unwrap_or(u64::MAX)
-}
+/// Mode bits for `main`. At most one of the operations DECRYPT, SIGN, VERIFY, KEYGEN and
+/// SELF_TEST may be set, `main` encrypts if none is; see `is_valid_mode` for the combinations
+/// it rejects with REASON_INVALID_MODE.
+/// - CRT (bit 0) encrypts through the CRT instead, so the host can compare it with the plain
+///   encryption. With DECRYPT it decrypts through the CRT, and with SELF_TEST it checks the
+///   CRT decryption as well.
+/// - DECRYPT (bit 1) treats the message as a ciphertext and decrypts it.
+/// - SIGN (bit 2) signs the message with the private exponent.
+/// - VERIFY (bit 3) checks the signature passed in `q_candidate` against the public key, with
+///   `p_candidate` holding the modulus n.
+/// - PADDED (bit 4) pads the message before encrypting, with the nonce in `message_hi`, and
+///   strips the padding after decrypting (see `pad_message`).
+/// - LADDER (bit 5) decrypts and signs with `mod_exp_ladder` instead of `mod_exp`, so the host
+///   can compare traces.
+/// - BIG (bit 6) works in BigUint on a 128-bit message, with `message_hi` as its high limb,
+///   and returns the low 64 bits of the result. Only DECRYPT may go with it.
+/// - KEYGEN (bit 7) generates a key pair from the seed in `p_candidate` with primes of
+///   `q_candidate` bits (see `generate_keypair`) and encrypts the message under it, ignoring `e`.
+/// - LAMBDA (bit 8) derives the private exponent from the Carmichael totient instead of Euler's.
+/// - AUTO_EXPONENT (bit 9) with `e` of 0 encrypts under the exponent `select_public_exponent`
+///   picks and reports it at AUTO_EXPONENT_SHIFT.
+/// - SELF_TEST (bit 10) round-trips the message through the key instead (see `self_test`).
+/// - SHARED_PRIME (bit 11), only with KEYGEN, checks the generated modulus against a prior one
+///   passed in `message_hi` instead of encrypting (see `detect_shared_prime`).
+const MODE_CRT: u64 = 1 << 0;
+const MODE_DECRYPT: u64 = 1 << 1;
+const MODE_SIGN: u64 = 1 << 2;
+const MODE_VERIFY: u64 = 1 << 3;
+const MODE_PADDED: u64 = 1 << 4;
+const MODE_LADDER: u64 = 1 << 5;
+const MODE_BIG: u64 = 1 << 6;
+const MODE_KEYGEN: u64 = 1 << 7;
+const MODE_LAMBDA: u64 = 1 << 8;
+const MODE_AUTO_EXPONENT: u64 = 1 << 9;
+const MODE_SELF_TEST: u64 = 1 << 10;
+const MODE_SHARED_PRIME: u64 = 1 << 11;
+const MODE_ALL: u64 = (1 << 12) - 1;
+const MODE_OPERATIONS: u64 = MODE_DECRYPT | MODE_SIGN | MODE_VERIFY | MODE_KEYGEN | MODE_SELF_TEST;
 
-fn safe_div_u64(a: u64, b: u64) -> u64 {
-    if b == 0 {
-        0
-    } else {
-        a / b
-    }
-}
+/// Verification result: 1 in the lowest bit for a good signature, and one of the
+/// statuses below in the nibble at VERIFY_STATUS_SHIFT.
+const VERIFY_STATUS_SHIFT: u32 = 4;
+const VERIFY_OK: u64 = 0;
+const VERIFY_BAD_MODULUS: u64 = 1;
+const VERIFY_SIGNATURE_OUT_OF_RANGE: u64 = 2;
+const VERIFY_MISMATCH: u64 = 3;
 
-// Safe operations for i64
-fn safe_add_i64(a: i64, b: i64) -> i64 {
-    a.checked_add(b).unwrap_or(i64::MAX)
-}
+/// Result of `self_test`: PASSED alone, or the bit of the first stage that failed. Checking
+/// the primes covers every key shape the key check rejects besides e sharing a factor with phi.
+const SELF_TEST_PASSED: u64 = 1;
+const SELF_TEST_BAD_PRIMES: u64 = 1 << 1;
+const SELF_TEST_E_NOT_COPRIME: u64 = 1 << 2;
+// 1 << 3 flagged a missing inverse of e, which the key check already rules out
+const SELF_TEST_ENCRYPT_FAILED: u64 = 1 << 4;
+const SELF_TEST_ROUND_TRIP_MISMATCH: u64 = 1 << 5;
 
-fn safe_sub_i64(a: i64, b: i64) -> i64 {
-    a.checked_sub(b).unwrap_or(i64::MIN)
-}
+/// Result of `detect_shared_prime`: one of the statuses in the top two bits, and for
+/// COMPROMISED the shared prime in the bits below, or 0 if it doesn't fit them.
+const SHARED_PRIME_STATUS_SHIFT: u32 = 62;
+const SHARED_PRIME_CLEAN: u64 = 0;
+const SHARED_PRIME_COMPROMISED: u64 = 1;
+const SHARED_PRIME_IDENTICAL: u64 = 2;
+const SHARED_PRIME_BAD_MODULUS: u64 = 3;
 
-fn safe_mul_i64(a: i64, b: i64) -> i64 {
-    a.checked_mul(b).unwrap_or(i64::MAX)
-}
+/// Returned by decryption for a ciphertext that isn't below the modulus, which no encryption
+/// under the key could have produced.
+const ERR_CIPHERTEXT_OUT_OF_RANGE: u64 = u64::MAX;
 
-fn safe_div_i64(a: i64, b: i64) -> i64 {
-    if b == 0 {
-        0
-    } else {
-        a / b
-    }
-}
+/// Why `main` couldn't encrypt, reported in the top byte of its result in place of a ciphertext.
+/// Ciphertexts go in the low 56 bits with a zero top byte, so they only read unambiguously
+/// for moduli below 2^56.
+const REASON_SHIFT: u32 = 56;
+const REASON_P_COMPOSITE: u64 = 1;
+const REASON_Q_COMPOSITE: u64 = 2;
+const REASON_E_NOT_COPRIME: u64 = 3;
+const REASON_P_EQUALS_Q: u64 = 4;
+const REASON_MESSAGE_TOO_LARGE: u64 = 5;
+const REASON_FERMAT_WEAK: u64 = 6;
+const REASON_PRIME_TOO_SMALL: u64 = 7;
+const REASON_MODULUS_OVERFLOW: u64 = 8;
+const REASON_INVALID_MODE: u64 = 9;
+const REASON_ZERO_MODULUS: u64 = 10;
+const REASON_MODULUS_TOO_WIDE: u64 = 11;
+
+/// Returned by padded encryption when the message, nonce and marker don't fit under n,
+/// and by padded decryption when the marker isn't where padding put it.
+const ERR_PADDING_DOES_NOT_FIT: u64 = u64::MAX - 1;
+const ERR_BAD_PADDING: u64 = u64::MAX - 2;
+
+/// Padded messages carry the 32-bit message in the low bits, a 16-bit nonce above it and
+/// this marker byte just below the top bit of n, with zeros in between.
+const PADDING_MARKER: u64 = 0x7A;
+const PADDING_NONCE_SHIFT: u32 = 32;
+const PADDING_MARKER_MIN_SHIFT: u32 = 48;
+
+/// Key generation draws primes of 16 to 31 bits, giving up after this many candidates in all.
+/// Around one odd 31-bit number in eleven is prime, so running out takes an absurdly bad seed.
+const KEYGEN_MIN_BITS: u32 = 16;
+const KEYGEN_MAX_BITS: u32 = 31;
+const KEYGEN_MAX_CANDIDATES: u32 = 10_000;
+
+/// Blinded decryption draws blinding factors until one is coprime to n, giving up after this
+/// many. n only has the factors p and q, so nearly every draw is.
+const BLINDING_MAX_CANDIDATES: u32 = 64;
+
+/// Public exponent selection tries these first, then odd numbers upward from the last one,
+/// giving up after PUBLIC_EXPONENT_MAX_CANDIDATES in all.
+const PUBLIC_EXPONENT_CANDIDATES: [u64; 5] = [3, 5, 17, 257, 65537];
+const PUBLIC_EXPONENT_MAX_CANDIDATES: u64 = 1000;
+
+/// With a selected exponent `main` reports it in bits 32-55, between the reason byte
+/// and a ciphertext that then has to fit in 32 bits. Moduli of 2^32 and up are turned
+/// away with REASON_MODULUS_TOO_WIDE.
+const AUTO_EXPONENT_SHIFT: u32 = 32;
+
+/// Block encryption output: the plaintext length as a little-endian u64, then one 8-byte
+/// little-endian ciphertext per block. Plaintext blocks take as many whole bytes as fit below
+/// n's top bit, so each is strictly smaller than n; the last one may be shorter, and the length
+/// prefix says how much of it is real.
+const BLOCK_LENGTH_PREFIX: usize = 8;
+const CIPHERTEXT_BLOCK_SIZE: usize = 8;
+
+/// `encrypt_with_modulus` output: the ciphertext, n and a REASON_* status (0 on success), each
+/// a little-endian u64. A key that fails the check leaves n 0, any other failure the ciphertext.
+const ENCRYPTION_RECORD_SIZE: usize = 24;
+
+/// `rsa_big` output: the full 128-bit result of the BigUint path as two little-endian u64s,
+/// low limb first. p and q are u64s, so the result is always below 2^128.
+const BIG_RESULT_SIZE: usize = 16;
+
+/// How many odd numbers below a composite p or q the fallback tries before giving up.
+const FALLBACK_SEARCH_WINDOW: u64 = 1000;
+
+/// Miller-Rabin with these witnesses is exact for every u64.
+const MILLER_RABIN_WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Check primes by trial division instead of Miller-Rabin, to compare the two.
+const USE_TRIAL_DIVISION: bool = false;
 
 /// Basic check if a candidate is prime-ish using trial division
 /// (Not robust for real crypto, but okay for demonstration).
@@ -60,6 +165,91 @@ fn is_prime_like(candidate: u64) -> bool {
     true
 }
 
+/// Deterministic Miller-Rabin: write candidate - 1 = d * 2^s with d odd, then every witness a
+/// must see a^d = 1 or a^(d * 2^r) = -1 for some r < s (mod candidate).
+fn is_prime_miller_rabin(candidate: u64) -> bool {
+    if candidate < 2 {
+        return false;
+    }
+    for &p in MILLER_RABIN_WITNESSES.iter() {
+        if candidate.is_multiple_of(p) {
+            return candidate == p;
+        }
+    }
+
+    let s = (candidate - 1).trailing_zeros();
+    let d = (candidate - 1) >> s;
+    'witness: for &a in MILLER_RABIN_WITNESSES.iter() {
+        let mut x = mod_exp(a, d, candidate);
+        if x == 1 || x == candidate - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mulmod_u64(x, x, candidate);
+            if x == candidate - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+/// Primality check used by the key checks.
+fn is_prime(candidate: u64) -> bool {
+    if USE_TRIAL_DIVISION {
+        is_prime_like(candidate)
+    } else {
+        is_prime_miller_rabin(candidate)
+    }
+}
+
+/// Integer square root, floor(sqrt(value)), exact for every u64. Newton's method from an
+/// estimate above the root only ever steps down, and stops at the floor.
+fn isqrt_u64(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+    let mut x = 1u64 << (64 - value.leading_zeros()).div_ceil(2);
+    loop {
+        let next = (x + value / x) / 2;
+        if next >= x {
+            return x;
+        }
+        x = next;
+    }
+}
+
+/// Integer fourth root, floor(value^(1/4)). Taking floors at both square roots loses
+/// nothing, since floor(sqrt(floor(sqrt(v)))) == floor(sqrt(sqrt(v))).
+fn iroot4_u64(value: u64) -> u64 {
+    isqrt_u64(isqrt_u64(value))
+}
+
+/// Integer k-th root, floor(value^(1/k)) for k >= 1, by binary search between 0 and
+/// 2^ceil(bits/k), which is above the root; a power that overflows is too big.
+fn iroot_u64(value: u64, k: u32) -> u64 {
+    if k <= 1 || value < 2 {
+        return value;
+    }
+    let (mut low, mut high) = (0u64, 1u64 << (64 - value.leading_zeros()).div_ceil(k));
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        match mid.checked_pow(k) {
+            Some(power) if power <= value => low = mid,
+            _ => high = mid - 1,
+        }
+    }
+    low
+}
+
+/// Whether p and q are close enough for Fermat's method to factor n = p*q at once:
+/// |p - q| < 2 * n^(1/4), with the fourth root rounded down.
+fn check_fermat_weakness(p: u64, q: u64) -> bool {
+    let n = safe_mul_u64(p, q);
+    p.abs_diff(q) < safe_mul_u64(2, iroot4_u64(n))
+}
+
 /// Compute gcd(a, b) using Euclid's algorithm, with divisions sprinkled in.
 fn gcd_u64(mut a: u64, mut b: u64) -> u64 {
     while b != 0 {
@@ -70,80 +260,497 @@ fn gcd_u64(mut a: u64, mut b: u64) -> u64 {
     a
 }
 
+/// a * b mod modulus, widened to 128 bits so the product can't overflow.
+fn mulmod_u64(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 * b as u128) % modulus as u128) as u64
+}
+
 /// Modular exponentiation: base^exp mod modulus, featuring many divisions
-/// This is just a standard "square-and-multiply" with safe operations.
+/// This is just a standard "square-and-multiply" with widened multiplications.
 fn mod_exp(base: u64, exp: u64, modulus: u64) -> u64 {
     if modulus == 0 {
         return 0;
     }
-    let mut result = 1u64;
+    let mut result = 1 % modulus;
     let mut current = base % modulus;
     let mut e = exp;
 
     while e > 0 {
         if e & 1 == 1 {
-            let tmp = safe_mul_u64(result, current);
-            result = tmp % modulus;
+            result = mulmod_u64(result, current, modulus);
         }
-        let sq = safe_mul_u64(current, current);
-        current = sq % modulus;
+        current = mulmod_u64(current, current, modulus);
         e >>= 1;
     }
     result
 }
 
-/// Compute toy RSA modulus n = p*q, totient = (p-1)*(q-1), then do encryption of message^e mod n
-/// Return 0 if invalid. We'll keep the logic flexible and add complexity.
-fn toy_rsa_encrypt(p: u64, q: u64, e: u64, message: u64) -> u64 {
-    if !is_prime_like(p) || !is_prime_like(q) {
+/// Modular exponentiation by a Montgomery ladder: walks all 64 exponent bits from the top,
+/// doing one multiply and one square per bit whatever its value. The bit picks which of the
+/// two registers gets which result by swapping them under a mask rather than by branching.
+/// Agrees with `mod_exp` on every input.
+fn mod_exp_ladder(base: u64, exp: u64, modulus: u64) -> u64 {
+    if modulus == 0 {
         return 0;
     }
-    let n = safe_mul_u64(p, q);
+    let mut r0 = 1 % modulus;
+    let mut r1 = base % modulus;
+
+    for i in (0..u64::BITS).rev() {
+        // All ones when the bit is set, so the swap below either exchanges r0 and r1 or is a no-op
+        let mask = 0u64.wrapping_sub((exp >> i) & 1);
+        let swap = (r0 ^ r1) & mask;
+        r0 ^= swap;
+        r1 ^= swap;
+
+        r1 = mulmod_u64(r0, r1, modulus);
+        r0 = mulmod_u64(r0, r0, modulus);
 
-    // Totient is (p-1)*(q-1) (basic RSA assumption)
+        let swap = (r0 ^ r1) & mask;
+        r0 ^= swap;
+        r1 ^= swap;
+    }
+    r0
+}
+
+/// Which exponentiation the private-key operations use: square-and-multiply, which only
+/// multiplies on set exponent bits, or the Montgomery ladder, which does the same work
+/// on every bit.
+#[derive(Clone, Copy, PartialEq)]
+enum Exponentiation {
+    SquareAndMultiply,
+    Ladder,
+}
+
+impl Exponentiation {
+    fn mod_exp(self, base: u64, exp: u64, modulus: u64) -> u64 {
+        match self {
+            Exponentiation::SquareAndMultiply => mod_exp(base, exp, modulus),
+            Exponentiation::Ladder => mod_exp_ladder(base, exp, modulus),
+        }
+    }
+}
+
+/// Which totient a key's private exponent is taken modulo: Euler's phi(n) = (p-1)*(q-1) or
+/// Carmichael's lambda(n) = lcm(p-1, q-1). Both have the same prime factors, so they accept
+/// exactly the same public exponents, but lambda gives a smaller d.
+#[derive(Clone, Copy, PartialEq)]
+enum Totient {
+    Euler,
+    Carmichael,
+}
+
+/// Carmichael's lambda(n) = lcm(p-1, q-1) for n = p*q, dividing before multiplying so
+/// the lcm only overflows when it doesn't fit a u64 at all.
+fn carmichael_lambda(p: u64, q: u64) -> u64 {
     let phi_p = safe_sub_u64(p, 1);
     let phi_q = safe_sub_u64(q, 1);
-    let phi = safe_mul_u64(phi_p, phi_q);
+    let g = gcd_u64(phi_p, phi_q);
+    safe_mul_u64(safe_div_u64(phi_p, g), phi_q)
+}
+
+/// Compute toy RSA modulus n = p*q and the chosen totient for a key,
+/// or the REASON_* code for why p, q and e don't make a valid key.
+fn toy_rsa_key(p: u64, q: u64, e: u64, totient: Totient) -> Result<(u64, u64), u64> {
+    if !is_prime(p) {
+        return Err(REASON_P_COMPOSITE);
+    }
+    if !is_prime(q) {
+        return Err(REASON_Q_COMPOSITE);
+    }
+    // n = p^2 has totient p*(p-1), not (p-1)^2
+    if p == q {
+        return Err(REASON_P_EQUALS_Q);
+    }
+    // A factor of 2 makes n even, which gives its factorization away
+    if p < 3 || q < 3 {
+        return Err(REASON_PRIME_TOO_SMALL);
+    }
+    // Check before multiplying, a saturated n would be a modulus with unknown factors
+    if p > u64::MAX / q {
+        return Err(REASON_MODULUS_OVERFLOW);
+    }
+    let n = p * q;
+
+    // Totient is (p-1)*(q-1) (basic RSA assumption), or its lcm form
+    let phi = if totient == Totient::Carmichael {
+        carmichael_lambda(p, q)
+    } else {
+        let phi_p = safe_sub_u64(p, 1);
+        let phi_q = safe_sub_u64(q, 1);
+        safe_mul_u64(phi_p, phi_q)
+    };
 
     // Ensure gcd(e, phi) = 1 for valid keys
     if gcd_u64(e, phi) != 1 {
-        return 0;
+        return Err(REASON_E_NOT_COPRIME);
+    }
+    Ok((n, phi))
+}
+
+/// Encrypt message^e mod n under the toy RSA key.
+/// Return the REASON_* code if the key is invalid or Fermat-weak, or the message isn't below n.
+fn toy_rsa_encrypt(p: u64, q: u64, e: u64, message: u64) -> Result<u64, u64> {
+    let (n, _) = toy_rsa_key(p, q, e, Totient::Euler)?;
+    if check_fermat_weakness(p, q) {
+        return Err(REASON_FERMAT_WEAK);
+    }
+    if message >= n {
+        return Err(REASON_MESSAGE_TOO_LARGE);
     }
 
     // Attempt encryption
-    mod_exp(message, e, n)
+    Ok(mod_exp(message, e, n))
 }
 
-/// Partial fallback attempt if prime checks fail or gcd checks fail
-/// For demonstration, we try to slightly tweak p or q by dividing them in half,
-/// and re-check if that yields a workable scenario.
-fn partial_fallback(
-    p_candidate: u64,
-    q_candidate: u64,
+/// Pack an encryption outcome the way `main` reports it, see REASON_SHIFT.
+fn encode_encryption(outcome: Result<u64, u64>) -> u64 {
+    match outcome {
+        Ok(ciphertext) => ciphertext,
+        Err(reason) => reason << REASON_SHIFT,
+    }
+}
+
+/// Decrypt ciphertext^d mod n, with the private exponent d = e^-1 mod phi derived from the key.
+/// Return 0 if the key is invalid, and ERR_CIPHERTEXT_OUT_OF_RANGE if ciphertext >= n.
+fn toy_rsa_decrypt(
+    p: u64,
+    q: u64,
+    e: u64,
+    ciphertext: u64,
+    totient: Totient,
+    exponentiation: Exponentiation,
+) -> u64 {
+    let Ok((n, phi)) = toy_rsa_key(p, q, e, totient) else {
+        return 0;
+    };
+    if ciphertext >= n {
+        return ERR_CIPHERTEXT_OUT_OF_RANGE;
+    }
+
+    let d = mod_inverse_u64_via_u128(e, phi);
+    if d == 0 {
+        return 0;
+    }
+    exponentiation.mod_exp(ciphertext, d, n)
+}
+
+/// Sign message^d mod n with the private exponent d = e^-1 mod phi, reducing the
+/// message mod n first. Return 0 if the key is invalid.
+#[no_mangle]
+pub fn toy_rsa_sign(p: u64, q: u64, e: u64, message: u64) -> u64 {
+    toy_rsa_sign_with(
+        p,
+        q,
+        e,
+        message,
+        Totient::Euler,
+        Exponentiation::SquareAndMultiply,
+    )
+}
+
+fn toy_rsa_sign_with(
+    p: u64,
+    q: u64,
     e: u64,
     message: u64,
-    attempts: u64,
+    totient: Totient,
+    exponentiation: Exponentiation,
 ) -> u64 {
-    if attempts == 0 {
+    let Ok((n, phi)) = toy_rsa_key(p, q, e, totient) else {
+        return 0;
+    };
+
+    let d = mod_inverse_u64_via_u128(e, phi);
+    if d == 0 {
+        return 0;
+    }
+    exponentiation.mod_exp(message % n, d, n)
+}
+
+/// Check signature^e mod n == message mod n using only the public key (n, e).
+/// Returns 1 or 0 with a status nibble, see VERIFY_STATUS_SHIFT.
+#[no_mangle]
+pub fn toy_rsa_verify(n: u64, e: u64, message: u64, signature: u64) -> u64 {
+    let status = if n < 2 {
+        VERIFY_BAD_MODULUS
+    } else if signature >= n {
+        VERIFY_SIGNATURE_OUT_OF_RANGE
+    } else if mod_exp(signature, e, n) != message % n {
+        VERIFY_MISMATCH
+    } else {
+        VERIFY_OK
+    };
+    (status == VERIFY_OK) as u64 | status << VERIFY_STATUS_SHIFT
+}
+
+/// Starting state of a xorshift64* generator for a seed. xorshift gets stuck on zero,
+/// so that seed starts from a fixed odd state instead.
+fn xorshift64_state(seed: u64) -> u64 {
+    if seed == 0 {
+        0x9E37_79B9_7F4A_7C15
+    } else {
+        seed
+    }
+}
+
+/// Next value of a xorshift64* generator. The state must never be zero.
+fn xorshift64_star(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Deterministically generate a toy key (p, q, e) from a seed: draw odd candidates with exactly
+/// `bit_size` bits until two primes far enough apart turn up, then take the smallest exponent from
+/// PUBLIC_EXPONENT_CANDIDATES coprime to phi (drawing a new q if there isn't one).
+/// Return None if `bit_size` is out of range or KEYGEN_MAX_CANDIDATES run out.
+fn generate_keypair(seed: u64, bit_size: u32) -> Option<(u64, u64, u64)> {
+    if !(KEYGEN_MIN_BITS..=KEYGEN_MAX_BITS).contains(&bit_size) {
+        return None;
+    }
+    let mut state = xorshift64_state(seed);
+    let top_bit = 1u64 << (bit_size - 1);
+
+    let mut p = 0u64;
+    for _ in 0..KEYGEN_MAX_CANDIDATES {
+        let candidate = (xorshift64_star(&mut state) >> (64 - bit_size)) | top_bit | 1;
+        if !is_prime(candidate) {
+            continue;
+        }
+        if p == 0 {
+            p = candidate;
+            continue;
+        }
+        if candidate == p || check_fermat_weakness(p, candidate) {
+            continue;
+        }
+
+        let phi = safe_mul_u64(p - 1, candidate - 1);
+        if let Some(&e) = PUBLIC_EXPONENT_CANDIDATES
+            .iter()
+            .find(|&&e| gcd_u64(e, phi) == 1)
+        {
+            return Some((p, candidate, e));
+        }
+    }
+    None
+}
+
+/// Primes of the key generated from `seed`, p in the high 32 bits and q in the low ones,
+/// or 0 if generation fails.
+#[no_mangle]
+pub fn keypair_primes(seed: u64, bit_size: u32) -> u64 {
+    match generate_keypair(seed, bit_size) {
+        Some((p, q, _)) => p << 32 | q,
+        None => 0,
+    }
+}
+
+/// Public exponent of the key generated from `seed`, or 0 if generation fails.
+#[no_mangle]
+pub fn keypair_exponent(seed: u64, bit_size: u32) -> u64 {
+    match generate_keypair(seed, bit_size) {
+        Some((_, _, e)) => e,
+        None => 0,
+    }
+}
+
+/// Check two moduli, each passed as a high and low limb, for a common prime factor.
+/// Any gcd above 1 factors both of them, see SHARED_PRIME_STATUS_SHIFT.
+#[no_mangle]
+pub fn detect_shared_prime(n1_hi: u64, n1_lo: u64, n2_hi: u64, n2_lo: u64) -> u64 {
+    let n1 = u128::from(n1_hi) << 64 | u128::from(n1_lo);
+    let n2 = u128::from(n2_hi) << 64 | u128::from(n2_lo);
+    shared_prime(n1, n2)
+}
+
+fn shared_prime(n1: u128, n2: u128) -> u64 {
+    // gcd(n, n) is n itself, which factors nothing
+    let status = if n1 < 2 || n2 < 2 {
+        SHARED_PRIME_BAD_MODULUS
+    } else if n1 == n2 {
+        SHARED_PRIME_IDENTICAL
+    } else {
+        let g = gcd_u128(n1, n2);
+        if g == 1 {
+            SHARED_PRIME_CLEAN
+        } else {
+            let prime = u64::try_from(g)
+                .ok()
+                .filter(|&g| g >> SHARED_PRIME_STATUS_SHIFT == 0);
+            return SHARED_PRIME_COMPROMISED << SHARED_PRIME_STATUS_SHIFT | prime.unwrap_or(0);
+        }
+    };
+    status << SHARED_PRIME_STATUS_SHIFT
+}
+
+/// Decrypt with blinding: draw r coprime to n from a xorshift64* generator seeded by
+/// `blind_seed`, decrypt c * r^e instead of c, then multiply the result by r^-1 to unblind it.
+/// Returns exactly what `toy_rsa_decrypt` does, or 0 if no blinding factor turns up.
+#[no_mangle]
+pub fn blinded_decrypt(p: u64, q: u64, e: u64, ciphertext: u64, blind_seed: u64) -> u64 {
+    let Ok((n, phi)) = toy_rsa_key(p, q, e, Totient::Euler) else {
+        return 0;
+    };
+    if ciphertext >= n {
+        return ERR_CIPHERTEXT_OUT_OF_RANGE;
+    }
+    let d = mod_inverse_u64_via_u128(e, phi);
+    if d == 0 {
+        return 0;
+    }
+
+    // Candidates sharing a factor with n can't be unblinded, so move on to the next one
+    let mut state = xorshift64_state(blind_seed);
+    for _ in 0..BLINDING_MAX_CANDIDATES {
+        let r = xorshift64_star(&mut state) % n;
+        let r_inv = mod_inverse_u64_via_u128(r, n);
+        if r_inv == 0 {
+            continue;
+        }
+
+        let blinded = mulmod_u64(ciphertext, mod_exp(r, e, n), n);
+        return mulmod_u64(mod_exp(blinded, d, n), r_inv, n);
+    }
+    0
+}
+
+/// The first public exponent coprime to phi, see PUBLIC_EXPONENT_CANDIDATES,
+/// or 0 if none of the candidates is.
+fn select_public_exponent(phi: u64) -> u64 {
+    let last = PUBLIC_EXPONENT_CANDIDATES[PUBLIC_EXPONENT_CANDIDATES.len() - 1];
+    let odd_numbers = (1..).map(|i| last + 2 * i);
+    PUBLIC_EXPONENT_CANDIDATES
+        .iter()
+        .copied()
+        .chain(odd_numbers)
+        .take(PUBLIC_EXPONENT_MAX_CANDIDATES as usize)
+        .find(|&e| gcd_u64(e, phi) == 1)
+        .unwrap_or(0)
+}
+
+/// Encrypt under a selected public exponent, packed with it, see AUTO_EXPONENT_SHIFT.
+fn toy_rsa_encrypt_auto_exponent(p: u64, q: u64, message: u64) -> u64 {
+    let phi = safe_mul_u64(safe_sub_u64(p, 1), safe_sub_u64(q, 1));
+    // With no exponent found, e = 0 fails the key check just as it should
+    let e = select_public_exponent(phi);
+    match toy_rsa_encrypt(p, q, e, message) {
+        // Only a modulus below 2^32 keeps every ciphertext clear of the exponent
+        Ok(_) if p * q > u32::MAX as u64 => encode_encryption(Err(REASON_MODULUS_TOO_WIDE)),
+        Ok(ciphertext) => e << AUTO_EXPONENT_SHIFT | ciphertext,
+        Err(reason) => encode_encryption(Err(reason)),
+    }
+}
+
+/// Where the padding marker goes under modulus n: its top bit just below n's top bit.
+fn padding_marker_shift(n: u64) -> u32 {
+    (64 - n.leading_zeros()).saturating_sub(9)
+}
+
+/// Pad a 32-bit message with a 16-bit nonce and the marker byte, see PADDING_MARKER.
+/// The padded value stays below n's top bit, so it's always below n. Return None if the
+/// message or nonce is too wide, or n too small to fit the marker above them.
+fn pad_message(message: u64, nonce: u64, n: u64) -> Option<u64> {
+    let shift = padding_marker_shift(n);
+    if message > u32::MAX as u64 || nonce > u16::MAX as u64 || shift < PADDING_MARKER_MIN_SHIFT {
+        return None;
+    }
+    Some(PADDING_MARKER << shift | nonce << PADDING_NONCE_SHIFT | message)
+}
+
+/// Strip the padding `pad_message` added, or None if the marker or the zeros around
+/// it aren't there.
+fn unpad_message(padded: u64, n: u64) -> Option<u64> {
+    let shift = padding_marker_shift(n);
+    if shift < PADDING_MARKER_MIN_SHIFT || padded >> shift != PADDING_MARKER {
+        return None;
+    }
+    let between = padded & ((1 << shift) - 1);
+    if between >> PADDING_MARKER_MIN_SHIFT != 0 {
+        return None;
+    }
+    Some(padded & u32::MAX as u64)
+}
+
+/// Encrypt the padded message. Return 0 if the key is invalid and ERR_PADDING_DOES_NOT_FIT
+/// if the padding doesn't fit under n.
+fn toy_rsa_encrypt_padded(p: u64, q: u64, e: u64, message: u64, nonce: u64) -> u64 {
+    let Ok((n, _)) = toy_rsa_key(p, q, e, Totient::Euler) else {
+        return 0;
+    };
+    match pad_message(message, nonce, n) {
+        Some(padded) => mod_exp(padded, e, n),
+        None => ERR_PADDING_DOES_NOT_FIT,
+    }
+}
+
+/// Whether message^e < n, so encryption never reduces mod n and the ciphertext is a plain
+/// power anyone can undo with an integer e-th root. That's also the last step of the
+/// small-exponent broadcast attack, once the CRT has joined e ciphertexts of one message.
+/// Returns 1 if so, 0 otherwise or for e of 0. Padded messages sit above 2^48 and are
+/// never flagged for e > 1.
+#[no_mangle]
+pub fn check_broadcast_risk(e: u64, message: u64, n: u64) -> u64 {
+    if e == 0 || n < 2 {
         return 0;
     }
+    // Every root past the 63rd of a u64 is 1, so clamping e changes nothing
+    let root = iroot_u64(n - 1, u32::try_from(e).unwrap_or(u32::MAX));
+    (message <= root) as u64
+}
 
-    let p_half = safe_div_u64(p_candidate, 2);
-    let q_half = safe_div_u64(q_candidate, 2);
+/// Whether a failed encryption is worth retrying with tweaked primes: only a composite
+/// p or q might be fixed that way.
+fn is_retryable(reason: u64) -> bool {
+    reason == REASON_P_COMPOSITE || reason == REASON_Q_COMPOSITE
+}
 
-    let encrypted_half = toy_rsa_encrypt(p_half, q_half, e, message);
-    if encrypted_half != 0 {
-        return encrypted_half;
+/// The largest prime below `candidate`, trying at most `budget` odd numbers on the way down,
+/// or None if there's none in that window (2 doesn't count, no key may use it).
+fn next_prime_below(candidate: u64, budget: u64) -> Option<u64> {
+    let start = if candidate.is_multiple_of(2) {
+        candidate.checked_sub(1)?
     } else {
-        // Try recursively with fewer attempts
-        partial_fallback(p_half, q_half, e, message, attempts - 1)
+        candidate.checked_sub(2)?
+    };
+    (0..budget)
+        .map_while(|i| start.checked_sub(2 * i).filter(|&odd| odd >= 3))
+        .find(|&odd| is_prime(odd))
+}
+
+/// Partial fallback attempt if prime checks fail
+/// Replaces whichever of p or q the key check found composite with the next prime below it,
+/// searching at most `budget` candidates each, and re-checks. Gives up on any other failure.
+fn partial_fallback(
+    p_candidate: u64,
+    q_candidate: u64,
+    e: u64,
+    message: u64,
+    budget: u64,
+) -> Result<u64, u64> {
+    let mut p = p_candidate;
+    let mut q = q_candidate;
+
+    // Each prime is only replaced once, the replacement passes the check it failed
+    loop {
+        match toy_rsa_encrypt(p, q, e, message) {
+            Err(REASON_P_COMPOSITE) => {
+                p = next_prime_below(p, budget).ok_or(REASON_P_COMPOSITE)?;
+            }
+            Err(REASON_Q_COMPOSITE) => {
+                q = next_prime_below(q, budget).ok_or(REASON_Q_COMPOSITE)?;
+            }
+            outcome => return outcome,
+        }
     }
 }
 
 /// Attempt Chinese Remainder Theorem (CRT) version of encryption
 /// If use_crt = 1, do a toy encryption using CRT approach for demonstration.
 fn toy_rsa_encrypt_crt(p: u64, q: u64, e: u64, message: u64) -> u64 {
-    if !is_prime_like(p) || !is_prime_like(q) {
+    if !is_prime(p) || !is_prime(q) {
         return 0;
     }
     let n = safe_mul_u64(p, q);
@@ -153,82 +760,1958 @@ fn toy_rsa_encrypt_crt(p: u64, q: u64, e: u64, message: u64) -> u64 {
     // Combine using naive CRT approach:
     // M = q*(q_inv mod p)*p_enc + p*(p_inv mod q)*q_enc  (mod n)
     // For demonstration, let's do simpler manipulations with divisions:
-    let q_inv_mod_p = mod_inverse(q, p);
-    let p_inv_mod_q = mod_inverse(p, q);
+    let q_inv_mod_p = mod_inverse_u64_via_u128(q, p);
+    let p_inv_mod_q = mod_inverse_u64_via_u128(p, q);
     if q_inv_mod_p == 0 || p_inv_mod_q == 0 {
         return 0;
     }
 
-    let term1 = safe_mul_u64(q, q_inv_mod_p) % n;
-    let partial1 = safe_mul_u64(term1, p_enc) % n;
+    let term1 = mulmod_u64(q, q_inv_mod_p, n);
+    let partial1 = mulmod_u64(term1, p_enc, n);
+
+    let term2 = mulmod_u64(p, p_inv_mod_q, n);
+    let partial2 = mulmod_u64(term2, q_enc, n);
+
+    ((partial1 as u128 + partial2 as u128) % n as u128) as u64
+}
+
+/// Decrypt through the CRT: with d reduced to dp = d mod (p-1) and dq = d mod (q-1),
+/// take m1 = c^dp mod p and m2 = c^dq mod q, then recombine with Garner's formula
+/// m = m2 + q * ((m1 - m2) * q^-1 mod p). Gives exactly what `toy_rsa_decrypt` does,
+/// including its errors; the key check rejects p == q, where the CRT doesn't apply.
+fn toy_rsa_decrypt_crt(
+    p: u64,
+    q: u64,
+    e: u64,
+    ciphertext: u64,
+    totient: Totient,
+    exponentiation: Exponentiation,
+) -> u64 {
+    let Ok((n, phi)) = toy_rsa_key(p, q, e, totient) else {
+        return 0;
+    };
+    if ciphertext >= n {
+        return ERR_CIPHERTEXT_OUT_OF_RANGE;
+    }
+
+    let d = mod_inverse_u64_via_u128(e, phi);
+    let q_inv_mod_p = mod_inverse_u64_via_u128(q, p);
+    if d == 0 || q_inv_mod_p == 0 {
+        return 0;
+    }
+
+    let half_decrypt = |prime: u64| exponentiation.mod_exp(ciphertext, d % (prime - 1), prime);
+    let m1 = half_decrypt(p);
+    let m2 = half_decrypt(q);
+
+    // Garner's recombination, keeping m1 - m2 non-negative mod p
+    let diff = (m1 + p - m2 % p) % p;
+    let h = mulmod_u64(diff, q_inv_mod_p, p);
+    m2 + h * q
+}
+
+/// Run the whole pipeline on one key: check it, derive d, encrypt the message and decrypt
+/// it back. Returns SELF_TEST_PASSED if the original message comes out, see SELF_TEST_*.
+#[no_mangle]
+pub fn self_test(p: u64, q: u64, e: u64, message: u64) -> u64 {
+    self_test_with(
+        p,
+        q,
+        e,
+        message,
+        false,
+        Totient::Euler,
+        Exponentiation::SquareAndMultiply,
+    )
+}
+
+/// `self_test`, also requiring the CRT decryption to recover the message if `use_crt` is set.
+fn self_test_with(
+    p: u64,
+    q: u64,
+    e: u64,
+    message: u64,
+    use_crt: bool,
+    totient: Totient,
+    exponentiation: Exponentiation,
+) -> u64 {
+    self_test_decrypting(p, q, e, message, use_crt, totient, |ciphertext, crt| {
+        if crt {
+            toy_rsa_decrypt_crt(p, q, e, ciphertext, totient, exponentiation)
+        } else {
+            toy_rsa_decrypt(p, q, e, ciphertext, totient, exponentiation)
+        }
+    })
+}
 
-    let term2 = safe_mul_u64(p, p_inv_mod_q) % n;
-    let partial2 = safe_mul_u64(term2, q_enc) % n;
+/// `self_test_with` around any decryption, given the ciphertext and whether to take the CRT
+/// path, so the round trip check can be pointed at a broken one.
+fn self_test_decrypting(
+    p: u64,
+    q: u64,
+    e: u64,
+    message: u64,
+    use_crt: bool,
+    totient: Totient,
+    decrypt: impl Fn(u64, bool) -> u64,
+) -> u64 {
+    match toy_rsa_key(p, q, e, totient) {
+        Ok(_) => {}
+        Err(REASON_E_NOT_COPRIME) => return SELF_TEST_E_NOT_COPRIME,
+        Err(_) => return SELF_TEST_BAD_PRIMES,
+    }
+    let Ok(ciphertext) = toy_rsa_encrypt(p, q, e, message) else {
+        return SELF_TEST_ENCRYPT_FAILED;
+    };
 
-    safe_add_u64(partial1, partial2) % n
+    let crt_matches = !use_crt || decrypt(ciphertext, true) == message;
+    if decrypt(ciphertext, false) != message || !crt_matches {
+        return SELF_TEST_ROUND_TRIP_MISMATCH;
+    }
+    SELF_TEST_PASSED
 }
 
 /// Compute modular inverse using Extended Euclidean Algorithm
 /// Return 0 if inverse doesn't exist (which also showcases divisions).
-fn mod_inverse(a: u64, m: u64) -> u64 {
+fn mod_inverse_u64_via_u128(a: u64, m: u64) -> u64 {
     // Extended Euclid: find x,y s.t. a*x + m*y = gcd(a,m)
     // If gcd(a,m) = 1, then a*x ≡ 1 (mod m)
-    if m == 0 {
+    if m == 0 || gcd_u128(a.into(), m.into()) != 1 {
         return 0;
     }
-    let (g, x) = extended_gcd(a as i64, m as i64);
-    if g != 1 {
-        0
-    } else {
-        let mut inv = x % (m as i64);
-        if inv < 0 {
-            inv = safe_add_i64(inv, m as i64);
-        }
-        inv as u64
+    let (_, x, _, x_negative) = extended_gcd_u128(a.into(), m.into());
+    let x = (x % u128::from(m)) as u64;
+    let inv = if x_negative && x != 0 { m - x } else { x };
+
+    // Only hand back a value that really is the inverse
+    if mulmod_u64(a, inv, m) != 1 {
+        return 0;
     }
+    inv
 }
 
-/// Extended Euclidean Algorithm returning gcd(a, b), and coefficient x for 'a*x + b*y = gcd(a,b)'
-/// This is done with signed operations (and divisions).
-fn extended_gcd(a: i64, b: i64) -> (i64, i64) {
-    if b == 0 {
-        return (a, 1);
+/// Compute gcd(a, b) using Euclid's algorithm, iteratively.
+fn gcd_u128(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
     }
-    let (g, x1) = extended_gcd(b, a % b);
-    // The "y" is not needed for just the inverse. We'll just store x part.
-    let x = safe_sub_i64(0, safe_div_i64(a, b)) * x1;
-    (g, x1 - x)
+    a
 }
 
-/// Combine results with XOR (similar pattern to previous code).
-fn combine_results(results: &[u64]) -> u64 {
-    let mut out = 0;
-    for &r in results {
-        out ^= r;
+/// Extended Euclidean Algorithm returning gcd(a, b) and coefficients x, y with
+/// a*x + b*y = gcd(a,b), as magnitudes plus whether x is the negative one (y then
+/// has the other sign). The coefficients alternate in sign from one step to the next,
+/// so their magnitudes only ever add up and stay bounded by the inputs.
+fn extended_gcd_u128(a: u128, b: u128) -> (u128, u128, u128, bool) {
+    let (mut old_r, mut r) = (a, b);
+    let (mut old_x, mut x) = (1u128, 0u128);
+    let (mut old_y, mut y) = (0u128, 1u128);
+    let mut x_negative = false;
+
+    while r != 0 {
+        let quotient = old_r / r;
+        (old_r, r) = (r, old_r - quotient * r);
+        (old_x, x) = (x, old_x + quotient * x);
+        (old_y, y) = (y, old_y + quotient * y);
+        x_negative = !x_negative;
     }
-    out
+    (old_r, old_x, old_y, x_negative)
 }
 
-#[no_mangle]
-pub fn main(p_candidate: u64, q_candidate: u64, e: u64, message: u64, use_crt: u64) -> u64 {
-    // Step 1: Try standard toy RSA encryption
-    let encrypted = toy_rsa_encrypt(p_candidate, q_candidate, e, message);
-    if encrypted == 0 {
-        // Step 2: Attempt partial fallback logic if standard encryption fails
-        let fallback_encrypted = partial_fallback(p_candidate, q_candidate, e, message, 3);
-        if fallback_encrypted == 0 {
-            return 0;
+/// Modular exponentiation on BigUint: base^exp mod modulus by square-and-multiply,
+/// walking the exponent bits from the lowest up.
+fn mod_exp_big(base: &BigUint, exp: &BigUint, modulus: &BigUint) -> BigUint {
+    if modulus.is_zero() {
+        return BigUint::zero();
+    }
+    let mut result = BigUint::one() % modulus;
+    let mut current = base % modulus;
+
+    for i in 0..exp.bits() {
+        if exp.bit(i) {
+            result = result * &current % modulus;
         }
-        return fallback_encrypted;
+        current = &current * &current % modulus;
     }
+    result
+}
 
-    // Step 3: If user set use_crt == 1, optionally compute CRT-based encryption for demonstration
-    let crt_encrypted = if use_crt == 1 {
-        toy_rsa_encrypt_crt(p_candidate, q_candidate, e, message)
-    } else {
-        0
-    };
+/// Compute gcd(a, b) on BigUint using Euclid's algorithm.
+fn gcd_big(a: &BigUint, b: &BigUint) -> BigUint {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    while !b.is_zero() {
+        let temp = &a % &b;
+        a = b;
+        b = temp;
+    }
+    a
+}
+
+/// Modular inverse on BigUint by the Extended Euclidean Algorithm, keeping the
+/// coefficient reduced mod m so it never goes negative. Return 0 if there's no inverse.
+fn mod_inverse_big(a: &BigUint, m: &BigUint) -> BigUint {
+    if m.is_zero() {
+        return BigUint::zero();
+    }
+    let (mut old_r, mut r) = (a % m, m.clone());
+    let (mut old_s, mut s) = (BigUint::one(), BigUint::zero());
+    while !r.is_zero() {
+        let quotient = &old_r / &r;
+        let next_r = &old_r - &quotient * &r;
+        old_r = std::mem::replace(&mut r, next_r);
+        // old_s - quotient * s, mod m
+        let next_s = (&old_s + m - &quotient * &s % m) % m;
+        old_s = std::mem::replace(&mut s, next_s);
+    }
+    if !old_r.is_one() {
+        return BigUint::zero();
+    }
+    old_s % m
+}
 
-    // Step 4: Combine results
-    combine_results(&[encrypted, crt_encrypted])
+/// BigUint version of `toy_rsa_key`: n and phi can't saturate however large p and q get,
+/// so there's no REASON_MODULUS_OVERFLOW. Every other check is the same.
+fn toy_rsa_key_big(p: u64, q: u64, e: u64) -> Result<(BigUint, BigUint), u64> {
+    if !is_prime(p) {
+        return Err(REASON_P_COMPOSITE);
+    }
+    if !is_prime(q) {
+        return Err(REASON_Q_COMPOSITE);
+    }
+    if p == q {
+        return Err(REASON_P_EQUALS_Q);
+    }
+    if p < 3 || q < 3 {
+        return Err(REASON_PRIME_TOO_SMALL);
+    }
+    let (p, q, e) = (BigUint::from(p), BigUint::from(q), BigUint::from(e));
+    let n = &p * &q;
+    let phi = (p - 1u32) * (q - 1u32);
+    if !gcd_big(&e, &phi).is_one() {
+        return Err(REASON_E_NOT_COPRIME);
+    }
+    Ok((n, phi))
+}
+
+/// `check_fermat_weakness` on the full product, which can be past 64 bits here.
+fn check_fermat_weakness_big(p: u64, q: u64) -> bool {
+    let n = BigUint::from(p) * BigUint::from(q);
+    BigUint::from(p.abs_diff(q)) < n.nth_root(4) * 2u32
+}
+
+/// Encrypt message^e mod n entirely in BigUint, for moduli past 64 bits.
+/// Return the REASON_* code like `toy_rsa_encrypt` does.
+fn toy_rsa_encrypt_big(p: u64, q: u64, e: u64, message: &BigUint) -> Result<BigUint, u64> {
+    let (n, _) = toy_rsa_key_big(p, q, e)?;
+    if check_fermat_weakness_big(p, q) {
+        return Err(REASON_FERMAT_WEAK);
+    }
+    if *message >= n {
+        return Err(REASON_MESSAGE_TOO_LARGE);
+    }
+    Ok(mod_exp_big(message, &BigUint::from(e), &n))
+}
+
+/// Decrypt ciphertext^d mod n entirely in BigUint. Return the REASON_* code if the key is
+/// invalid, and REASON_MESSAGE_TOO_LARGE if the ciphertext isn't below n, like `rsa_decrypt`.
+fn toy_rsa_decrypt_big(p: u64, q: u64, e: u64, ciphertext: &BigUint) -> Result<BigUint, u64> {
+    let (n, phi) = toy_rsa_key_big(p, q, e)?;
+    if *ciphertext >= n {
+        return Err(REASON_MESSAGE_TOO_LARGE);
+    }
+    let d = mod_inverse_big(&BigUint::from(e), &phi);
+    Ok(mod_exp_big(ciphertext, &d, &n))
+}
+
+/// Plaintext bytes per block under modulus n: whole bytes below n's top bit, 0 if n is too
+/// small to hold even one byte.
+fn plaintext_block_size(n: u64) -> usize {
+    let bits = 64 - n.leading_zeros() as usize;
+    bits.saturating_sub(1) / 8
+}
+
+/// Encrypt a byte string block by block with exponent e, see BLOCK_LENGTH_PREFIX.
+fn encrypt_block_bytes(n: u64, e: u64, message: &[u8]) -> Option<Vec<u8>> {
+    let block_size = plaintext_block_size(n);
+    if block_size == 0 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(
+        BLOCK_LENGTH_PREFIX + message.len().div_ceil(block_size) * CIPHERTEXT_BLOCK_SIZE,
+    );
+    out.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    for block in message.chunks(block_size) {
+        let mut value = [0u8; 8];
+        value[..block.len()].copy_from_slice(block);
+        let encrypted = mod_exp(u64::from_le_bytes(value), e, n);
+        out.extend_from_slice(&encrypted.to_le_bytes());
+    }
+    Some(out)
+}
+
+/// Undo `encrypt_block_bytes` with the private exponent d. None if the data isn't
+/// laid out the way encryption writes it.
+fn decrypt_block_bytes(n: u64, d: u64, data: &[u8]) -> Option<Vec<u8>> {
+    let block_size = plaintext_block_size(n);
+    if block_size == 0 || data.len() < BLOCK_LENGTH_PREFIX {
+        return None;
+    }
+    let (prefix, blocks) = data.split_at(BLOCK_LENGTH_PREFIX);
+    let len = usize::try_from(u64::from_le_bytes(prefix.try_into().ok()?)).ok()?;
+    if blocks.len()
+        != len
+            .div_ceil(block_size)
+            .checked_mul(CIPHERTEXT_BLOCK_SIZE)?
+    {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(len);
+    for block in blocks.chunks(CIPHERTEXT_BLOCK_SIZE) {
+        let ciphertext = u64::from_le_bytes(block.try_into().ok()?);
+        if ciphertext >= n {
+            return None;
+        }
+        let value = mod_exp(ciphertext, d, n).to_le_bytes();
+        let take = block_size.min(len - out.len());
+        out.extend_from_slice(&value[..take]);
+    }
+    Some(out)
+}
+
+/// The modulus `encrypt_blocks` encrypts under, or the REASON_* code for a key that
+/// `toy_rsa_encrypt` would refuse too.
+fn block_encryption_modulus(p: u64, q: u64, e: u64) -> Result<u64, u64> {
+    let (n, _) = toy_rsa_key(p, q, e, Totient::Euler)?;
+    if check_fermat_weakness(p, q) {
+        return Err(REASON_FERMAT_WEAK);
+    }
+    Ok(n)
+}
+
+/// Encrypt `msg_len` bytes at `msg_ptr` block by block and write the result to `out_ptr`,
+/// which needs room for 8 bytes plus 8 per block. Returns the bytes written, or 0 if the key
+/// is invalid or Fermat-weak, n is below 256 (under a byte per block) or memory is out of range.
+///
+/// # Safety
+///
+/// `msg_ptr` must be 0 or point to `msg_len` readable bytes, and `out_ptr` to room for the
+/// whole result, see `wasm_utils::write_bytes`.
+#[no_mangle]
+pub unsafe fn encrypt_blocks(
+    p: u64,
+    q: u64,
+    e: u64,
+    msg_ptr: u32,
+    msg_len: u32,
+    out_ptr: u32,
+) -> u32 {
+    let Ok(n) = block_encryption_modulus(p, q, e) else {
+        return 0;
+    };
+    let message = if msg_len == 0 {
+        Vec::new()
+    } else {
+        match read_bytes(msg_ptr, msg_len as usize) {
+            Some(message) => message,
+            None => return 0,
+        }
+    };
+
+    match encrypt_block_bytes(n, e, &message) {
+        Some(out) if write_bytes(out_ptr, &out) => out.len() as u32,
+        _ => 0,
+    }
+}
+
+/// Decrypt `ct_len` bytes written by `encrypt_blocks` and write the plaintext to `out_ptr`.
+/// Returns the plaintext length, or 0 on any error.
+///
+/// # Safety
+///
+/// `ct_ptr` must be 0 or point to `ct_len` readable bytes, and `out_ptr` to room for the
+/// plaintext.
+#[no_mangle]
+pub unsafe fn decrypt_blocks(
+    p: u64,
+    q: u64,
+    e: u64,
+    ct_ptr: u32,
+    ct_len: u32,
+    out_ptr: u32,
+) -> u32 {
+    let Ok((n, phi)) = toy_rsa_key(p, q, e, Totient::Euler) else {
+        return 0;
+    };
+    let d = mod_inverse_u64_via_u128(e, phi);
+    let Some(data) = read_bytes(ct_ptr, ct_len as usize) else {
+        return 0;
+    };
+
+    match decrypt_block_bytes(n, d, &data) {
+        Some(out) if out.is_empty() || write_bytes(out_ptr, &out) => out.len() as u32,
+        _ => 0,
+    }
+}
+
+/// Encrypt like `main` without the fallback or CRT, and write the ciphertext together with
+/// the modulus it's under to `out_ptr`, see ENCRYPTION_RECORD_SIZE. Returns the bytes
+/// written, or 0 if memory is out of range.
+///
+/// # Safety
+///
+/// `out_ptr` must be 0 or point to ENCRYPTION_RECORD_SIZE writable bytes.
+#[no_mangle]
+pub unsafe fn encrypt_with_modulus(p: u64, q: u64, e: u64, message: u64, out_ptr: u32) -> u32 {
+    let record = build_encryption_record(p, q, e, message);
+    if write_bytes(out_ptr, &record) {
+        record.len() as u32
+    } else {
+        0
+    }
+}
+
+fn build_encryption_record(p: u64, q: u64, e: u64, message: u64) -> [u8; ENCRYPTION_RECORD_SIZE] {
+    let n = match toy_rsa_key(p, q, e, Totient::Euler) {
+        Ok((n, _)) => n,
+        Err(_) => 0,
+    };
+    let (ciphertext, status) = match toy_rsa_encrypt(p, q, e, message) {
+        Ok(ciphertext) => (ciphertext, 0),
+        Err(reason) => (0, reason),
+    };
+
+    let mut record = [0u8; ENCRYPTION_RECORD_SIZE];
+    record[..8].copy_from_slice(&ciphertext.to_le_bytes());
+    record[8..16].copy_from_slice(&n.to_le_bytes());
+    record[16..].copy_from_slice(&status.to_le_bytes());
+    record
+}
+
+/// MODE_BIG with the whole result: encrypt (or decrypt, with `decrypt` nonzero) the 128-bit
+/// message and write the result to `out_ptr`, see BIG_RESULT_SIZE, with a failure in the low
+/// limb like `main` reports it. Returns the bytes written, or 0 if memory is out of range.
+///
+/// # Safety
+///
+/// `out_ptr` must be 0 or point to BIG_RESULT_SIZE writable bytes.
+#[no_mangle]
+pub unsafe fn rsa_big(
+    p: u64,
+    q: u64,
+    e: u64,
+    message: u64,
+    message_hi: u64,
+    decrypt: u32,
+    out_ptr: u32,
+) -> u32 {
+    let [low, high] = big_result(p, q, e, message, message_hi, decrypt != 0)
+        .unwrap_or_else(|reason| [encode_encryption(Err(reason)), 0]);
+    let mut record = [0u8; BIG_RESULT_SIZE];
+    record[..8].copy_from_slice(&low.to_le_bytes());
+    record[8..].copy_from_slice(&high.to_le_bytes());
+    if write_bytes(out_ptr, &record) {
+        record.len() as u32
+    } else {
+        0
+    }
+}
+
+/// Encrypt under the exact key, without the fallback or CRT. Failures are reported like
+/// `main` does, see REASON_SHIFT.
+#[no_mangle]
+pub fn rsa_encrypt(p: u64, q: u64, e: u64, message: u64) -> u64 {
+    encode_encryption(toy_rsa_encrypt(p, q, e, message))
+}
+
+/// Decrypt under the exact key, reporting failures like `rsa_encrypt`: a ciphertext that
+/// isn't below n as REASON_MESSAGE_TOO_LARGE.
+#[no_mangle]
+pub fn rsa_decrypt(p: u64, q: u64, e: u64, ciphertext: u64) -> u64 {
+    let outcome = toy_rsa_key(p, q, e, Totient::Euler).and_then(|(n, _)| {
+        if ciphertext >= n {
+            return Err(REASON_MESSAGE_TOO_LARGE);
+        }
+        Ok(toy_rsa_decrypt(
+            p,
+            q,
+            e,
+            ciphertext,
+            Totient::Euler,
+            Exponentiation::SquareAndMultiply,
+        ))
+    });
+    encode_encryption(outcome)
+}
+
+/// Sign the message reduced mod n, reporting an invalid key like `rsa_encrypt`.
+#[no_mangle]
+pub fn rsa_sign(p: u64, q: u64, e: u64, message: u64) -> u64 {
+    let outcome = toy_rsa_key(p, q, e, Totient::Euler).map(|_| toy_rsa_sign(p, q, e, message));
+    encode_encryption(outcome)
+}
+
+/// base^exp mod modulus, reporting a modulus of 0 like `rsa_encrypt`.
+#[no_mangle]
+pub fn rsa_modexp(base: u64, exp: u64, modulus: u64) -> u64 {
+    if modulus == 0 {
+        return encode_encryption(Err(REASON_ZERO_MODULUS));
+    }
+    mod_exp(base, exp, modulus)
+}
+
+/// Whether `main` can honour every bit of `mode`: no unknown bits, at most one operation,
+/// nothing but DECRYPT alongside BIG, and SHARED_PRIME only with KEYGEN.
+fn is_valid_mode(mode: u64) -> bool {
+    let big_extras = mode & !(MODE_BIG | MODE_DECRYPT);
+    mode & !MODE_ALL == 0
+        && (mode & MODE_OPERATIONS).count_ones() <= 1
+        && (mode & MODE_BIG == 0 || big_extras == 0)
+        && (mode & MODE_SHARED_PRIME == 0 || mode & MODE_KEYGEN != 0)
+}
+
+fn totient_for(mode: u64) -> Totient {
+    if mode & MODE_LAMBDA != 0 {
+        Totient::Carmichael
+    } else {
+        Totient::Euler
+    }
+}
+
+fn exponentiation_for(mode: u64) -> Exponentiation {
+    if mode & MODE_LADDER != 0 {
+        Exponentiation::Ladder
+    } else {
+        Exponentiation::SquareAndMultiply
+    }
+}
+
+/// MODE_BIG: encrypt or decrypt the 128-bit message, see `big_result`. Failures are
+/// reported like `main` does, see REASON_SHIFT.
+fn big_pipeline(p: u64, q: u64, e: u64, message: u64, message_hi: u64, mode: u64) -> u64 {
+    encode_encryption(
+        big_result(p, q, e, message, message_hi, mode & MODE_DECRYPT != 0).map(|[low, _]| low),
+    )
+}
+
+/// Encrypt or decrypt the 128-bit message in BigUint, returning the result's two limbs,
+/// low limb first, or the REASON_* code.
+fn big_result(
+    p: u64,
+    q: u64,
+    e: u64,
+    message: u64,
+    message_hi: u64,
+    decrypt: bool,
+) -> Result<[u64; 2], u64> {
+    let message = BigUint::from(message_hi) << 64 | BigUint::from(message);
+    let result = if decrypt {
+        toy_rsa_decrypt_big(p, q, e, &message)
+    } else {
+        toy_rsa_encrypt_big(p, q, e, &message)
+    }?;
+    let mut limbs = result.iter_u64_digits();
+    Ok([limbs.next().unwrap_or(0), limbs.next().unwrap_or(0)])
+}
+
+/// MODE_KEYGEN: encrypt under a generated key, or check it for a prime shared with
+/// `prior_modulus`. A generated key is valid by construction, so there's no fallback.
+fn keygen_pipeline(seed: u64, bit_size: u64, message: u64, prior_modulus: u64, mode: u64) -> u64 {
+    match generate_keypair(seed, u32::try_from(bit_size).unwrap_or(0)) {
+        Some((p, q, _)) if mode & MODE_SHARED_PRIME != 0 => {
+            shared_prime(u128::from(p * q), u128::from(prior_modulus))
+        }
+        Some((p, q, e)) => encode_encryption(toy_rsa_encrypt(p, q, e, message)),
+        None => 0,
+    }
+}
+
+/// MODE_DECRYPT, through the CRT and stripping padding as the mode says. Decryption needs
+/// the exact key, so there's nothing for the fallback to tweak.
+fn decrypt_pipeline(p: u64, q: u64, e: u64, ciphertext: u64, mode: u64) -> u64 {
+    let totient = totient_for(mode);
+    let exponentiation = exponentiation_for(mode);
+    let plaintext = if mode & MODE_CRT != 0 {
+        toy_rsa_decrypt_crt(p, q, e, ciphertext, totient, exponentiation)
+    } else {
+        toy_rsa_decrypt(p, q, e, ciphertext, totient, exponentiation)
+    };
+    // Padding is never 0, so 0 still means the key was invalid
+    if mode & MODE_PADDED == 0 || plaintext == 0 || plaintext == ERR_CIPHERTEXT_OUT_OF_RANGE {
+        return plaintext;
+    }
+    let n = safe_mul_u64(p, q);
+    unpad_message(plaintext, n).unwrap_or(ERR_BAD_PADDING)
+}
+
+/// Encryption when no operation bit is set, with the exponent chosen, padding or the CRT
+/// as the mode says.
+fn encrypt_pipeline(p: u64, q: u64, e: u64, message: u64, nonce: u64, mode: u64) -> u64 {
+    // The fallback's tweaked primes would need an exponent of their own
+    if mode & MODE_AUTO_EXPONENT != 0 && e == 0 {
+        return toy_rsa_encrypt_auto_exponent(p, q, message);
+    }
+
+    // Padding is laid out for the exact key, so it can't go through the fallback
+    if mode & MODE_PADDED != 0 {
+        return toy_rsa_encrypt_padded(p, q, e, message, nonce);
+    }
+
+    // Step 1: Try standard toy RSA encryption
+    let encrypted = match toy_rsa_encrypt(p, q, e, message) {
+        Ok(encrypted) => encrypted,
+        Err(reason) if is_retryable(reason) => {
+            // Step 2: Attempt partial fallback logic if a prime check fails, reporting why the
+            // original key failed if the fallback can't encrypt either
+            let fallback = partial_fallback(p, q, e, message, FALLBACK_SEARCH_WINDOW);
+            return encode_encryption(fallback.map_err(|_| reason));
+        }
+        Err(reason) => return encode_encryption(Err(reason)),
+    };
+
+    // Step 3: If user set MODE_CRT, hand back the CRT-based encryption of the same key instead
+    if mode & MODE_CRT != 0 {
+        return toy_rsa_encrypt_crt(p, q, e, message);
+    }
+    encrypted
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub fn main(
+    p_candidate: u64,
+    q_candidate: u64,
+    e: u64,
+    message: u64,
+    mode: u64,
+    // High limb of the message in MODE_BIG, nonce in MODE_PADDED,
+    // prior modulus in MODE_SHARED_PRIME
+    message_hi: u64,
+) -> u64 {
+    if !is_valid_mode(mode) {
+        return encode_encryption(Err(REASON_INVALID_MODE));
+    }
+    if mode & MODE_BIG != 0 {
+        return big_pipeline(p_candidate, q_candidate, e, message, message_hi, mode);
+    }
+
+    let (p, q) = (p_candidate, q_candidate);
+    match mode & MODE_OPERATIONS {
+        MODE_KEYGEN => keygen_pipeline(p, q, message, message_hi, mode),
+        MODE_SELF_TEST => {
+            let use_crt = mode & MODE_CRT != 0;
+            let (totient, exponentiation) = (totient_for(mode), exponentiation_for(mode));
+            self_test_with(p, q, e, message, use_crt, totient, exponentiation)
+        }
+        // Signatures are checked against the public key alone
+        MODE_VERIFY => toy_rsa_verify(p, e, message, q),
+        MODE_SIGN => toy_rsa_sign_with(
+            p,
+            q,
+            e,
+            message,
+            totient_for(mode),
+            exponentiation_for(mode),
+        ),
+        MODE_DECRYPT => decrypt_pipeline(p, q, e, message, mode),
+        _ => encrypt_pipeline(p, q, e, message, message_hi, mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_totients_round_trip() {
+        let (p, q, e, message) = (11, 101, 3, 65);
+        let ciphertext = toy_rsa_encrypt(p, q, e, message).unwrap();
+        for totient in [Totient::Euler, Totient::Carmichael] {
+            for exponentiation in [Exponentiation::SquareAndMultiply, Exponentiation::Ladder] {
+                assert_eq!(
+                    toy_rsa_decrypt(p, q, e, ciphertext, totient, exponentiation),
+                    message
+                );
+                let signature = toy_rsa_sign_with(p, q, e, message, totient, exponentiation);
+                assert_eq!(mod_exp(signature, e, p * q), message);
+            }
+        }
+    }
+
+    #[test]
+    fn lambda_gives_the_smaller_private_exponent() {
+        let (p, q, e) = (11, 101, 3);
+        assert_eq!(carmichael_lambda(p, q), 100);
+        let (_, phi) = toy_rsa_key(p, q, e, Totient::Euler).unwrap();
+        let (_, lambda) = toy_rsa_key(p, q, e, Totient::Carmichael).unwrap();
+        assert_eq!((phi, lambda), (1000, 100));
+        let d_phi = mod_inverse_u64_via_u128(e, phi);
+        let d_lambda = mod_inverse_u64_via_u128(e, lambda);
+        assert!(d_lambda <= d_phi);
+        assert_eq!(d_lambda, d_phi % lambda);
+    }
+
+    #[test]
+    fn lambda_accepts_the_same_exponents_as_phi() {
+        // lambda divides phi and has the same prime factors, so gcd(e, phi) > 1 exactly when
+        // gcd(e, lambda) > 1: no key is valid under lambda but rejected under phi
+        let (p, q) = (11, 101);
+        for e in 2..200 {
+            assert_eq!(
+                toy_rsa_key(p, q, e, Totient::Euler).is_ok(),
+                toy_rsa_key(p, q, e, Totient::Carmichael).is_ok(),
+                "e = {e}"
+            );
+        }
+        assert_eq!(
+            toy_rsa_key(p, q, 5, Totient::Carmichael),
+            Err(REASON_E_NOT_COPRIME)
+        );
+    }
+
+    #[test]
+    fn big_path_keeps_the_high_limb() {
+        // Primes past 2^48 and 1.25 * 2^47 give a 96-bit modulus, far enough apart that
+        // Fermat's method doesn't factor it
+        let p = (1u64 << 48..).find(|&c| is_prime(c)).unwrap();
+        let q = (5u64 << 45..).find(|&c| is_prime(c)).unwrap();
+        let e = 65537;
+        let n = BigUint::from(p) * BigUint::from(q);
+        assert_eq!(n.bits(), 96);
+        assert!(!check_fermat_weakness_big(p, q));
+
+        let message = [0x1234_5678_9ABC_DEF0, 0x42];
+        let ciphertext = big_result(p, q, e, message[0], message[1], false).unwrap();
+        assert_ne!(ciphertext[1], 0);
+        let expected = mod_exp_big(
+            &(BigUint::from(message[1]) << 64 | BigUint::from(message[0])),
+            &BigUint::from(e),
+            &n,
+        );
+        assert_eq!(
+            BigUint::from(ciphertext[1]) << 64 | BigUint::from(ciphertext[0]),
+            expected
+        );
+        assert_eq!(
+            big_result(p, q, e, ciphertext[0], ciphertext[1], true),
+            Ok(message)
+        );
+        assert_eq!(
+            big_pipeline(p, q, e, message[0], message[1], MODE_BIG),
+            ciphertext[0]
+        );
+        // Safety: a null out_ptr is never written to
+        assert_eq!(unsafe { rsa_big(p, q, e, message[0], message[1], 0, 0) }, 0);
+    }
+
+    #[test]
+    fn big_path_rejects_messages_past_the_modulus() {
+        let (p, q, e) = (1009, 65537, 17);
+        let n = p * q;
+        assert!(big_result(p, q, e, n - 1, 0, false).is_ok());
+        for message in [[n, 0], [0, 1]] {
+            assert_eq!(
+                big_result(p, q, e, message[0], message[1], false),
+                Err(REASON_MESSAGE_TOO_LARGE)
+            );
+            assert_eq!(
+                big_result(p, q, e, message[0], message[1], true),
+                Err(REASON_MESSAGE_TOO_LARGE)
+            );
+        }
+        assert_eq!(
+            big_pipeline(p, q, e, n, 0, MODE_BIG),
+            toy_rsa_encrypt(p, q, e, n).unwrap_err() << REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn big_path_checks_the_key_like_the_u64_path() {
+        // Every rejection the u64 path makes for these keys, besides the overflow the BigUint
+        // path doesn't have
+        let keys = [
+            (1008, 65537, 17),
+            (1009, 65536, 17),
+            (1009, 1009, 17),
+            (2, 65537, 17),
+            (1009, 65537, 3),
+            (1009, 1013, 5),
+        ];
+        for (p, q, e) in keys {
+            let reason = toy_rsa_encrypt(p, q, e, 42).unwrap_err();
+            assert_eq!(
+                big_result(p, q, e, 42, 0, false),
+                Err(reason),
+                "{p} {q} {e}"
+            );
+            assert_eq!(
+                big_pipeline(p, q, e, 42, 0, MODE_BIG),
+                reason << REASON_SHIFT
+            );
+        }
+        assert_eq!(toy_rsa_encrypt(1009, 1013, 5, 42), Err(REASON_FERMAT_WEAK));
+        assert_eq!(toy_rsa_key_big(2, 65537, 17), Err(REASON_PRIME_TOO_SMALL));
+        // The primes' product past 2^64 still gets checked
+        let p = (1u64 << 40..).find(|&c| is_prime(c)).unwrap();
+        let q = (p + 2..).find(|&c| is_prime(c)).unwrap();
+        assert_eq!(
+            big_result(p, q, 65537, 42, 0, false),
+            Err(REASON_FERMAT_WEAK)
+        );
+    }
+
+    #[test]
+    fn auto_exponent_round_trips_below_2_32() {
+        let (p, q) = (11, 101);
+        let result = toy_rsa_encrypt_auto_exponent(p, q, 65);
+        let e = result >> AUTO_EXPONENT_SHIFT;
+        assert_eq!(e, 3);
+        let ciphertext = result & u32::MAX as u64;
+        let decrypted = toy_rsa_decrypt(
+            p,
+            q,
+            e,
+            ciphertext,
+            Totient::Euler,
+            Exponentiation::SquareAndMultiply,
+        );
+        assert_eq!(decrypted, 65);
+    }
+
+    #[test]
+    fn auto_exponent_rejects_wide_moduli() {
+        // n = 65537 * 100003 is past 2^32
+        assert_eq!(
+            toy_rsa_encrypt_auto_exponent(65537, 100003, 12345),
+            REASON_MODULUS_TOO_WIDE << REASON_SHIFT
+        );
+        // Just under 2^32 still fits
+        let (p, q) = (40009, 107347);
+        assert!(p * q <= u32::MAX as u64);
+        let result = toy_rsa_encrypt_auto_exponent(p, q, 1 << 31);
+        assert_eq!(result >> REASON_SHIFT, 0);
+    }
+
+    /// `toy_rsa_decrypt` with the textbook totient and square-and-multiply.
+    fn decrypt(p: u64, q: u64, e: u64, ciphertext: u64) -> u64 {
+        toy_rsa_decrypt(
+            p,
+            q,
+            e,
+            ciphertext,
+            Totient::Euler,
+            Exponentiation::SquareAndMultiply,
+        )
+    }
+
+    #[test]
+    fn decrypt_inverts_encrypt() {
+        for (p, q, e) in [
+            (11, 101, 3),
+            (17, 1009, 5),
+            (1009, 65537, 17),
+            (4_294_967_291, 1_000_003, 65537),
+        ] {
+            let n = p * q;
+            for message in [0, 1, 2, 65 % n, n / 2, n - 1] {
+                let ciphertext = toy_rsa_encrypt(p, q, e, message).unwrap();
+                assert_eq!(
+                    decrypt(p, q, e, ciphertext),
+                    message,
+                    "{p} {q} {e} {message}"
+                );
+                assert_eq!(main(p, q, e, ciphertext, MODE_DECRYPT, 0), message);
+            }
+        }
+    }
+
+    #[test]
+    fn decrypt_rejects_ciphertexts_past_the_modulus() {
+        let (p, q, e) = (11, 101, 3);
+        assert_eq!(decrypt(p, q, e, p * q), ERR_CIPHERTEXT_OUT_OF_RANGE);
+        assert_eq!(decrypt(p, q, e, u64::MAX), ERR_CIPHERTEXT_OUT_OF_RANGE);
+        assert_ne!(decrypt(p, q, e, p * q - 1), ERR_CIPHERTEXT_OUT_OF_RANGE);
+    }
+
+    #[test]
+    fn decrypt_checks_the_key_like_encrypt() {
+        // Composite p, and an exponent sharing a factor with phi = 1000
+        assert_eq!(decrypt(12, 101, 3, 5), 0);
+        assert_eq!(decrypt(11, 101, 5, 5), 0);
+        assert_eq!(toy_rsa_encrypt(11, 101, 5, 5), Err(REASON_E_NOT_COPRIME));
+    }
+
+    #[test]
+    fn miller_rabin_agrees_with_trial_division() {
+        for candidate in 0..100_000 {
+            assert_eq!(
+                is_prime_miller_rabin(candidate),
+                is_prime_like(candidate),
+                "{candidate}"
+            );
+        }
+    }
+
+    #[test]
+    fn miller_rabin_classifies_known_numbers() {
+        for prime in [
+            65_537,
+            1_000_000_007,
+            4_294_967_291,
+            (1 << 61) - 1,
+            18_446_744_073_709_551_557,
+        ] {
+            assert!(is_prime_miller_rabin(prime), "{prime}");
+        }
+        for carmichael in [561, 41_041, 825_265, 321_197_185] {
+            assert!(!is_prime_miller_rabin(carmichael), "{carmichael}");
+        }
+        // Strong pseudoprime to every prime base up to 23, caught by the larger witnesses
+        assert!(!is_prime_miller_rabin(3_825_123_056_546_413_051));
+    }
+
+    #[test]
+    fn miller_rabin_near_2_62() {
+        let base = 1u64 << 62;
+        let primes: Vec<u64> = (base - 200..base)
+            .filter(|&c| is_prime_miller_rabin(c))
+            .collect();
+        // 2^62 - 57 and 2^62 - 87 are the largest primes below 2^62
+        assert_eq!(primes.last(), Some(&(base - 57)));
+        assert!(primes.contains(&(base - 87)));
+        for &prime in &primes {
+            assert_eq!(mod_exp(2, prime - 1, prime), 1);
+        }
+        assert!(!is_prime_miller_rabin(4_294_967_291 * 1_000_003));
+    }
+
+    #[test]
+    fn mod_inverse_is_always_an_inverse() {
+        let mut state = xorshift64_state(0x5EED);
+        let mut checked = 0;
+        while checked < 5_000 {
+            let m = xorshift64_star(&mut state) >> (xorshift64_star(&mut state) % 63);
+            let a = xorshift64_star(&mut state);
+            if m < 2 || gcd_u64(a, m) != 1 {
+                assert!(m < 2 || mod_inverse_u64_via_u128(a, m) == 0);
+                continue;
+            }
+            let inv = mod_inverse_u64_via_u128(a, m);
+            assert!(inv < m);
+            assert_eq!(mulmod_u64(a, inv, m), 1, "a = {a}, m = {m}");
+            checked += 1;
+        }
+    }
+
+    #[test]
+    fn mod_inverse_regressions() {
+        // The old recurrence gave 336, 384 and 414640773971820 here, none of them inverses
+        assert_eq!(mod_inverse_u64_via_u128(3, 1000), 667);
+        assert_eq!(mod_inverse_u64_via_u128(17, 3120), 2753);
+        assert_eq!(
+            mod_inverse_u64_via_u128(65537, 4_294_975_879_934_580),
+            592_634_189_576_093
+        );
+        let (g, x, y, x_negative) = extended_gcd_u128(3, 1000);
+        assert_eq!((g, x, y, x_negative), (1, 333, 1, true));
+        assert_eq!(mod_inverse_u64_via_u128(4, 1000), 0);
+        assert_eq!(mod_inverse_u64_via_u128(3, 0), 0);
+    }
+
+    #[test]
+    fn signatures_verify_with_the_public_key() {
+        let (p, q, e) = (1009, 65537, 17);
+        let n = p * q;
+        let signature = toy_rsa_sign(p, q, e, 123_456);
+        assert_eq!(toy_rsa_verify(n, e, 123_456, signature), 1);
+        assert_eq!(main(n, signature, e, 123_456, MODE_VERIFY, 0), 1);
+        assert_eq!(main(p, q, e, 123_456, MODE_SIGN, 0), signature);
+        // Messages are signed mod n
+        assert_eq!(toy_rsa_sign(p, q, e, 123_456 + n), signature);
+    }
+
+    #[test]
+    fn flipped_signature_bits_fail_verification() {
+        let (p, q, e) = (1009, 65537, 17);
+        let n = p * q;
+        let signature = toy_rsa_sign(p, q, e, 123_456);
+        let mismatch = VERIFY_MISMATCH << VERIFY_STATUS_SHIFT;
+        assert_eq!(toy_rsa_verify(n, e, 123_456, signature ^ 1), mismatch);
+        assert_eq!(
+            toy_rsa_verify(n, e, 123_456, signature | 1 << 40),
+            VERIFY_SIGNATURE_OUT_OF_RANGE << VERIFY_STATUS_SHIFT
+        );
+        assert_eq!(
+            toy_rsa_verify(1, e, 123_456, 0),
+            VERIFY_BAD_MODULUS << VERIFY_STATUS_SHIFT
+        );
+    }
+
+    #[test]
+    fn signatures_do_not_verify_other_messages() {
+        let (p, q, e) = (1009, 65537, 17);
+        let signature = toy_rsa_sign(p, q, e, 123_456);
+        assert_eq!(
+            toy_rsa_verify(p * q, e, 123_457, signature),
+            VERIFY_MISMATCH << VERIFY_STATUS_SHIFT
+        );
+    }
+
+    #[test]
+    fn signing_needs_an_exponent_coprime_to_phi() {
+        // phi = 10 * 100 shares the factor 5
+        assert_eq!(toy_rsa_sign(11, 101, 5, 42), 0);
+        assert_ne!(toy_rsa_sign(11, 101, 3, 42), 0);
+    }
+
+    #[test]
+    fn same_seed_same_keys() {
+        for seed in [0, 1, 0xDEAD_BEEF, u64::MAX] {
+            let key = generate_keypair(seed, 24);
+            assert!(key.is_some());
+            assert_eq!(generate_keypair(seed, 24), key);
+            let (p, q, e) = key.unwrap();
+            assert_eq!(keypair_primes(seed, 24), p << 32 | q);
+            assert_eq!(keypair_exponent(seed, 24), e);
+        }
+        assert_ne!(generate_keypair(1, 24), generate_keypair(2, 24));
+    }
+
+    #[test]
+    fn generated_keys_are_valid() {
+        for bit_size in KEYGEN_MIN_BITS..=KEYGEN_MAX_BITS {
+            let (p, q, e) = generate_keypair(7, bit_size).unwrap();
+            for prime in [p, q] {
+                assert!(is_prime(prime));
+                assert_eq!(64 - prime.leading_zeros(), bit_size);
+            }
+            assert_ne!(p, q);
+            assert!(!check_fermat_weakness(p, q));
+            let smallest = PUBLIC_EXPONENT_CANDIDATES
+                .iter()
+                .find(|&&e| gcd_u64(e, (p - 1) * (q - 1)) == 1);
+            assert_eq!(Some(&e), smallest);
+        }
+        assert_eq!(generate_keypair(7, KEYGEN_MIN_BITS - 1), None);
+        assert_eq!(generate_keypair(7, KEYGEN_MAX_BITS + 1), None);
+        assert_eq!(keypair_primes(7, 0), 0);
+    }
+
+    #[test]
+    fn narrow_keys_terminate_within_the_candidate_budget() {
+        // 16-bit primes sit close together, so Fermat-weak pairs turn many candidates away;
+        // KEYGEN_MAX_CANDIDATES still bounds the draws
+        for seed in 0..200 {
+            let (p, q, _) = generate_keypair(seed, KEYGEN_MIN_BITS).unwrap();
+            assert!(!check_fermat_weakness(p, q));
+        }
+    }
+
+    #[test]
+    fn keygen_mode_encrypts_under_the_generated_key() {
+        let (p, q, e) = generate_keypair(42, 20).unwrap();
+        let ciphertext = main(42, 20, 0, 1234, MODE_KEYGEN, 0);
+        assert_eq!(Ok(ciphertext), toy_rsa_encrypt(p, q, e, 1234));
+        assert_eq!(main(42, 99, 0, 1234, MODE_KEYGEN, 0), 0);
+    }
+
+    #[test]
+    fn crt_decrypt_matches_plain_decrypt() {
+        let mut state = xorshift64_state(1389);
+        for (p, q, e) in [
+            (11, 101, 3),
+            (1009, 65537, 17),
+            (4_294_967_291, 1_000_003, 65537),
+        ] {
+            let n = p * q;
+            for _ in 0..500 {
+                let ciphertext = xorshift64_star(&mut state) % n;
+                for totient in [Totient::Euler, Totient::Carmichael] {
+                    assert_eq!(
+                        toy_rsa_decrypt_crt(p, q, e, ciphertext, totient, Exponentiation::Ladder),
+                        toy_rsa_decrypt(p, q, e, ciphertext, totient, Exponentiation::Ladder),
+                        "{p} {q} {ciphertext}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crt_decrypt_handles_ciphertexts_divisible_by_a_prime() {
+        let (p, q, e) = (1009, 65537, 17);
+        for ciphertext in [0, p, 5 * p, q, 7 * q] {
+            let expected = decrypt(p, q, e, ciphertext);
+            let crt = toy_rsa_decrypt_crt(
+                p,
+                q,
+                e,
+                ciphertext,
+                Totient::Euler,
+                Exponentiation::SquareAndMultiply,
+            );
+            assert_eq!(crt, expected);
+            assert_eq!(toy_rsa_encrypt(p, q, e, crt), Ok(ciphertext));
+        }
+    }
+
+    #[test]
+    fn crt_decrypt_rejects_equal_primes_and_wide_ciphertexts() {
+        let crt = |p, q, ciphertext| {
+            toy_rsa_decrypt_crt(p, q, 3, ciphertext, Totient::Euler, Exponentiation::Ladder)
+        };
+        assert_eq!(crt(101, 101, 5), 0);
+        assert_eq!(
+            toy_rsa_key(101, 101, 3, Totient::Euler),
+            Err(REASON_P_EQUALS_Q)
+        );
+        assert_eq!(crt(11, 101, 1111), ERR_CIPHERTEXT_OUT_OF_RANGE);
+    }
+
+    #[test]
+    fn crt_mode_decrypts_end_to_end() {
+        let (p, q, e) = (1009, 65537, 17);
+        let ciphertext = toy_rsa_encrypt(p, q, e, 987_654).unwrap();
+        assert_eq!(
+            main(p, q, e, ciphertext, MODE_DECRYPT | MODE_CRT, 0),
+            987_654
+        );
+        assert_eq!(
+            main(
+                p,
+                q,
+                e,
+                ciphertext,
+                MODE_DECRYPT | MODE_CRT | MODE_LAMBDA | MODE_LADDER,
+                0
+            ),
+            987_654
+        );
+    }
+
+    /// Modulus and private exponent of the (1009, 65537, 17) key, with 3-byte blocks.
+    fn block_key() -> (u64, u64, u64) {
+        let (n, phi) = toy_rsa_key(1009, 65537, 17, Totient::Euler).unwrap();
+        (n, 17, mod_inverse_u64_via_u128(17, phi))
+    }
+
+    #[test]
+    fn empty_messages_are_just_the_length_prefix() {
+        let (n, e, d) = block_key();
+        let encrypted = encrypt_block_bytes(n, e, &[]).unwrap();
+        assert_eq!(encrypted, [0; BLOCK_LENGTH_PREFIX]);
+        assert_eq!(decrypt_block_bytes(n, d, &encrypted), Some(Vec::new()));
+    }
+
+    #[test]
+    fn one_block_message() {
+        let (n, e, d) = block_key();
+        assert_eq!(plaintext_block_size(n), 3);
+        let encrypted = encrypt_block_bytes(n, e, b"abc").unwrap();
+        assert_eq!(encrypted.len(), BLOCK_LENGTH_PREFIX + CIPHERTEXT_BLOCK_SIZE);
+        assert_eq!(encrypted[..BLOCK_LENGTH_PREFIX], 3u64.to_le_bytes());
+        let block = u64::from_le_bytes(encrypted[BLOCK_LENGTH_PREFIX..].try_into().unwrap());
+        assert_eq!(block, mod_exp(u64::from_le_bytes(*b"abc\0\0\0\0\0"), e, n));
+        assert_eq!(decrypt_block_bytes(n, d, &encrypted).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn ragged_multi_block_message_round_trips() {
+        let (n, e, d) = block_key();
+        let message = b"a longer message with a ragged tail";
+        assert_ne!(message.len() % 3, 0);
+        let encrypted = encrypt_block_bytes(n, e, message).unwrap();
+        assert_eq!(
+            encrypted.len(),
+            BLOCK_LENGTH_PREFIX + message.len().div_ceil(3) * CIPHERTEXT_BLOCK_SIZE
+        );
+        assert_eq!(decrypt_block_bytes(n, d, &encrypted).unwrap(), message);
+    }
+
+    #[test]
+    fn block_round_trips_and_rejects_bad_layouts() {
+        let (n, e, d) = block_key();
+        for len in 0..=20 {
+            let message: Vec<u8> = (0..len).map(|i| (i * 37 + 255) as u8).collect();
+            let encrypted = encrypt_block_bytes(n, e, &message).unwrap();
+            assert_eq!(decrypt_block_bytes(n, d, &encrypted).unwrap(), message);
+        }
+        let encrypted = encrypt_block_bytes(n, e, b"abcd").unwrap();
+        assert_eq!(
+            decrypt_block_bytes(n, d, &encrypted[..encrypted.len() - 1]),
+            None
+        );
+        assert_eq!(decrypt_block_bytes(n, d, &encrypted[..4]), None);
+        // A modulus below 256 can't hold a whole byte per block
+        assert_eq!(encrypt_block_bytes(11 * 13, 7, b"a"), None);
+        // Safety: null pointers are never read or written
+        assert_eq!(unsafe { encrypt_blocks(12, 101, 3, 0, 0, 0) }, 0);
+    }
+
+    #[test]
+    fn block_encryption_refuses_fermat_weak_keys() {
+        assert_eq!(block_encryption_modulus(11, 101, 3), Ok(1111));
+        assert_eq!(
+            block_encryption_modulus(12, 101, 3),
+            Err(REASON_P_COMPOSITE)
+        );
+        assert_eq!(
+            block_encryption_modulus(1009, 1013, 5),
+            toy_rsa_encrypt(1009, 1013, 5, 42).map(|_| 1009 * 1013)
+        );
+        assert_eq!(
+            block_encryption_modulus(1009, 1013, 5),
+            Err(REASON_FERMAT_WEAK)
+        );
+    }
+
+    /// A key with n past 2^57, wide enough for the padding.
+    const PADDED_KEY: (u64, u64, u64) = (4_294_967_291, 1_000_000_007, 65537);
+
+    #[test]
+    fn padded_round_trip_depends_on_the_nonce() {
+        let (p, q, e) = PADDED_KEY;
+        let n = p * q;
+        let mut ciphertexts = Vec::new();
+        for nonce in [1, 2, 0xBEEF, 0xFFFF] {
+            let ciphertext = toy_rsa_encrypt_padded(p, q, e, 42, nonce);
+            assert!(ciphertext < n);
+            assert_eq!(main(p, q, e, 42, MODE_PADDED, nonce), ciphertext);
+            assert_eq!(main(p, q, e, ciphertext, MODE_DECRYPT | MODE_PADDED, 0), 42);
+            ciphertexts.push(ciphertext);
+        }
+        ciphertexts.dedup();
+        assert_eq!(ciphertexts.len(), 4);
+
+        // Even 0 and 1 come out as proper ciphertexts
+        for message in [0, 1] {
+            let ciphertext = toy_rsa_encrypt_padded(p, q, e, message, 7);
+            assert!(ciphertext > 1);
+            assert_eq!(
+                main(p, q, e, ciphertext, MODE_DECRYPT | MODE_PADDED, 0),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn padding_must_fit_under_the_modulus() {
+        assert_eq!(
+            toy_rsa_encrypt_padded(1009, 65537, 17, 42, 1),
+            ERR_PADDING_DOES_NOT_FIT
+        );
+        let (p, q, e) = PADDED_KEY;
+        assert_eq!(pad_message(1 << 32, 1, p * q), None);
+        assert_eq!(pad_message(42, 1 << 16, p * q), None);
+        assert_eq!(
+            toy_rsa_encrypt_padded(p, q, e, u32::MAX as u64 + 1, 1),
+            ERR_PADDING_DOES_NOT_FIT
+        );
+    }
+
+    #[test]
+    fn tampered_padding_is_rejected() {
+        let (p, q, e) = PADDED_KEY;
+        let n = p * q;
+        let padded = pad_message(42, 9, n).unwrap();
+        assert_eq!(unpad_message(padded, n), Some(42));
+        assert_eq!(
+            unpad_message(padded ^ 1 << padding_marker_shift(n), n),
+            None
+        );
+        assert_eq!(
+            unpad_message(padded | 1 << PADDING_MARKER_MIN_SHIFT, n),
+            None
+        );
+
+        let ciphertext = toy_rsa_encrypt_padded(p, q, e, 42, 9);
+        assert_eq!(
+            main(p, q, e, ciphertext ^ 1, MODE_DECRYPT | MODE_PADDED, 0),
+            ERR_BAD_PADDING
+        );
+    }
+
+    #[test]
+    fn each_key_failure_has_its_reason() {
+        let reason = |outcome: Result<u64, u64>| encode_encryption(outcome) >> REASON_SHIFT;
+        assert_eq!(reason(toy_rsa_encrypt(1009, 65537, 17, 42)), 0);
+        assert_eq!(
+            reason(toy_rsa_encrypt(1010, 65537, 17, 42)),
+            REASON_P_COMPOSITE
+        );
+        assert_eq!(
+            reason(toy_rsa_encrypt(1009, 65541, 17, 42)),
+            REASON_Q_COMPOSITE
+        );
+        assert_eq!(
+            reason(toy_rsa_encrypt(11, 101, 5, 42)),
+            REASON_E_NOT_COPRIME
+        );
+        assert_eq!(reason(toy_rsa_encrypt(101, 101, 3, 42)), REASON_P_EQUALS_Q);
+        assert_eq!(
+            reason(toy_rsa_encrypt(11, 101, 3, 1111)),
+            REASON_MESSAGE_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn zero_ciphertext_is_not_an_error() {
+        assert_eq!(toy_rsa_encrypt(1009, 65537, 17, 0), Ok(0));
+        assert_eq!(main(1009, 65537, 17, 0, 0, 0), 0);
+    }
+
+    #[test]
+    fn fallback_only_retries_composite_primes() {
+        // 1010 is replaced by 1009, the largest prime below it
+        assert!(is_retryable(REASON_P_COMPOSITE) && is_retryable(REASON_Q_COMPOSITE));
+        assert_eq!(
+            main(1010, 65537, 17, 42, 0, 0),
+            toy_rsa_encrypt(1009, 65537, 17, 42).unwrap()
+        );
+        // 65541 = 3 * 21847 is replaced by 65539
+        assert_eq!(
+            partial_fallback(1009, 65541, 17, 42, 10),
+            toy_rsa_encrypt(1009, 65539, 17, 42)
+        );
+
+        for reason in [
+            REASON_E_NOT_COPRIME,
+            REASON_P_EQUALS_Q,
+            REASON_MESSAGE_TOO_LARGE,
+        ] {
+            assert!(!is_retryable(reason));
+        }
+        assert_eq!(
+            main(11, 101, 5, 42, 0, 0),
+            REASON_E_NOT_COPRIME << REASON_SHIFT
+        );
+        assert_eq!(
+            main(101, 101, 3, 42, 0, 0),
+            REASON_P_EQUALS_Q << REASON_SHIFT
+        );
+        // A fallback that can't find a prime reports the original reason
+        assert_eq!(
+            partial_fallback(1010, 65537, 17, 42, 0),
+            Err(REASON_P_COMPOSITE)
+        );
+    }
+
+    #[test]
+    fn blinding_never_changes_the_plaintext() {
+        for (p, q, e) in [
+            (11, 101, 3),
+            (1009, 65537, 17),
+            (4_294_967_291, 1_000_003, 65537),
+        ] {
+            let n = p * q;
+            for seed in 0..200 {
+                let ciphertext = (seed * 0x9E37_79B9) % n;
+                assert_eq!(
+                    blinded_decrypt(p, q, e, ciphertext, seed),
+                    decrypt(p, q, e, ciphertext),
+                    "{p} {q} {seed}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn blinding_skips_factors_that_share_a_prime_with_n() {
+        let (p, q, e) = (11, 101, 3);
+        let n = p * q;
+        let shares_a_factor = |seed| {
+            let mut state = xorshift64_state(seed);
+            gcd_u64(xorshift64_star(&mut state) % n, n) != 1
+        };
+        let seed = (0..).find(|&seed| shares_a_factor(seed)).unwrap();
+        let ciphertext = toy_rsa_encrypt(p, q, e, 65).unwrap();
+        assert_eq!(blinded_decrypt(p, q, e, ciphertext, seed), 65);
+    }
+
+    #[test]
+    fn blinded_decrypt_keeps_the_plain_errors() {
+        assert_eq!(
+            blinded_decrypt(11, 101, 3, 1111, 1),
+            ERR_CIPHERTEXT_OUT_OF_RANGE
+        );
+        assert_eq!(blinded_decrypt(12, 101, 3, 5, 1), 0);
+    }
+
+    #[test]
+    fn integer_roots_are_exact() {
+        assert_eq!(isqrt_u64(1 << 62), 1 << 31);
+        assert_eq!(isqrt_u64(u64::MAX), u32::MAX as u64);
+        for root in [1u64, 2, 3, 255, 65_535, 1 << 31, u32::MAX as u64] {
+            let square = root * root;
+            assert_eq!(isqrt_u64(square), root);
+            assert_eq!(isqrt_u64(square - 1), root - 1);
+            assert_eq!(isqrt_u64(square + 1), root);
+        }
+        assert_eq!(iroot4_u64(1 << 60), 1 << 15);
+        for root in [2u64, 3, 1000, 65_535] {
+            let fourth = root.pow(4);
+            assert_eq!(iroot4_u64(fourth), root);
+            assert_eq!(iroot4_u64(fourth - 1), root - 1);
+            assert_eq!(iroot_u64(fourth, 4), root);
+            assert_eq!(iroot_u64(fourth - 1, 4), root - 1);
+        }
+        assert_eq!(iroot_u64(u64::MAX, 3), 2_642_245);
+    }
+
+    #[test]
+    fn close_primes_are_fermat_weak() {
+        let p = 1_000_003;
+        let q = (p + 2..).find(|&c| is_prime(c)).unwrap();
+        assert!(check_fermat_weakness(p, q));
+        assert_eq!(toy_rsa_encrypt(p, q, 65537, 42), Err(REASON_FERMAT_WEAK));
+        assert!(!check_fermat_weakness(1009, 65537));
+        assert!(toy_rsa_encrypt(1009, 65537, 17, 42).is_ok());
+    }
+
+    #[test]
+    fn fermat_boundary_is_consistent() {
+        // Around p = 10007 the threshold 2 * n^(1/4) is about 200
+        let p = 10_007;
+        let mut seen = [false; 2];
+        for q in p + 150..p + 250 {
+            let threshold = 2 * iroot4_u64(p * q);
+            let weak = check_fermat_weakness(p, q);
+            assert_eq!(weak, q - p < threshold, "q = {q}");
+            assert_eq!(weak, check_fermat_weakness(q, p));
+            seen[weak as usize] = true;
+        }
+        assert_eq!(seen, [true, true]);
+    }
+
+    #[test]
+    fn ladder_agrees_with_square_and_multiply() {
+        let mut state = xorshift64_state(1397);
+        for _ in 0..20_000 {
+            let base = xorshift64_star(&mut state);
+            let exp = xorshift64_star(&mut state) >> (xorshift64_star(&mut state) % 64);
+            let modulus = xorshift64_star(&mut state) >> (xorshift64_star(&mut state) % 64);
+            assert_eq!(
+                mod_exp_ladder(base, exp, modulus),
+                mod_exp(base, exp, modulus),
+                "{base} {exp} {modulus}"
+            );
+        }
+        for modulus in [0, 1, 2, u64::MAX] {
+            for exp in [0, 1, u64::MAX] {
+                assert_eq!(mod_exp_ladder(3, exp, modulus), mod_exp(3, exp, modulus));
+            }
+        }
+    }
+
+    #[test]
+    fn ladder_mode_switches_the_private_key_paths() {
+        let (p, q, e) = (1009, 65537, 17);
+        let ciphertext = toy_rsa_encrypt(p, q, e, 4242).unwrap();
+        assert_eq!(
+            main(p, q, e, ciphertext, MODE_DECRYPT | MODE_LADDER, 0),
+            4242
+        );
+        assert_eq!(
+            main(p, q, e, 4242, MODE_SIGN | MODE_LADDER, 0),
+            main(p, q, e, 4242, MODE_SIGN, 0)
+        );
+        assert_eq!(
+            toy_rsa_sign_with(p, q, e, 4242, Totient::Euler, Exponentiation::Ladder),
+            toy_rsa_sign(p, q, e, 4242)
+        );
+    }
+
+    #[test]
+    fn degenerate_key_shapes_are_rejected() {
+        assert_eq!(
+            toy_rsa_key(101, 101, 3, Totient::Euler),
+            Err(REASON_P_EQUALS_Q)
+        );
+        assert_eq!(
+            toy_rsa_key(2, 101, 3, Totient::Euler),
+            Err(REASON_PRIME_TOO_SMALL)
+        );
+        assert_eq!(
+            toy_rsa_key(101, 2, 3, Totient::Euler),
+            Err(REASON_PRIME_TOO_SMALL)
+        );
+        assert_eq!(
+            toy_rsa_key(4_294_967_311, 4_294_967_357, 65537, Totient::Euler),
+            Err(REASON_MODULUS_OVERFLOW)
+        );
+        assert!(toy_rsa_key(4_294_967_291, 4_294_967_279, 65537, Totient::Euler).is_ok());
+        assert_eq!(
+            toy_rsa_encrypt(11, 101, 3, 1111),
+            Err(REASON_MESSAGE_TOO_LARGE)
+        );
+        assert_eq!(toy_rsa_encrypt(11, 101, 3, 1110).map(|_| ()), Ok(()));
+    }
+
+    #[test]
+    fn fallback_cannot_land_on_equal_primes() {
+        // Replacing the composite 1010 lands on 1009, the other prime
+        assert_eq!(
+            partial_fallback(1010, 1009, 3, 42, 10),
+            Err(REASON_P_EQUALS_Q)
+        );
+        assert_eq!(
+            main(1010, 1009, 3, 42, 0, 0),
+            REASON_P_COMPOSITE << REASON_SHIFT
+        );
+        // Nor on 2, the only prime below 4
+        assert_eq!(next_prime_below(4, 10), Some(3));
+        assert_eq!(next_prime_below(3, 10), None);
+    }
+
+    #[test]
+    fn self_test_passes_on_a_good_key() {
+        assert_eq!(self_test(1009, 65537, 17, 4242), SELF_TEST_PASSED);
+        for mode in [0, MODE_CRT, MODE_CRT | MODE_LAMBDA | MODE_LADDER] {
+            assert_eq!(
+                main(1009, 65537, 17, 4242, MODE_SELF_TEST | mode, 0),
+                SELF_TEST_PASSED
+            );
+        }
+    }
+
+    #[test]
+    fn self_test_reports_the_failing_stage() {
+        assert_eq!(self_test(1010, 65537, 17, 4242), SELF_TEST_BAD_PRIMES);
+        assert_eq!(self_test(101, 101, 3, 42), SELF_TEST_BAD_PRIMES);
+        assert_eq!(self_test(11, 101, 5, 42), SELF_TEST_E_NOT_COPRIME);
+        assert_eq!(self_test(11, 101, 3, 1111), SELF_TEST_ENCRYPT_FAILED);
+        let p = 1_000_003;
+        let q = (p + 2..).find(|&c| is_prime(c)).unwrap();
+        assert_eq!(self_test(p, q, 65537, 42), SELF_TEST_ENCRYPT_FAILED);
+    }
+
+    #[test]
+    fn broken_decryption_fails_the_round_trip() {
+        let (p, q, e, message) = (1009, 65537, 17, 4242);
+        let status = |use_crt, broken_crt: bool, broken_plain: bool| {
+            self_test_decrypting(
+                p,
+                q,
+                e,
+                message,
+                use_crt,
+                Totient::Euler,
+                |ciphertext, crt| {
+                    let decrypted = toy_rsa_decrypt_crt(
+                        p,
+                        q,
+                        e,
+                        ciphertext,
+                        Totient::Euler,
+                        Exponentiation::Ladder,
+                    );
+                    if (crt && broken_crt) || (!crt && broken_plain) {
+                        decrypted + 1
+                    } else {
+                        decrypted
+                    }
+                },
+            )
+        };
+        assert_eq!(status(true, false, false), SELF_TEST_PASSED);
+        assert_eq!(status(false, false, true), SELF_TEST_ROUND_TRIP_MISMATCH);
+        assert_eq!(status(true, true, false), SELF_TEST_ROUND_TRIP_MISMATCH);
+        // The CRT path is only consulted when asked for
+        assert_eq!(status(false, true, false), SELF_TEST_PASSED);
+    }
+
+    #[test]
+    fn valid_keys_round_trip_on_every_path() {
+        for seed in 0..50 {
+            let (p, q, e) = generate_keypair(seed, 20).unwrap();
+            for use_crt in [false, true] {
+                let status = self_test_with(
+                    p,
+                    q,
+                    e,
+                    seed * 7919 % (p * q),
+                    use_crt,
+                    Totient::Carmichael,
+                    Exponentiation::Ladder,
+                );
+                assert_eq!(status, SELF_TEST_PASSED, "seed {seed}");
+            }
+        }
+    }
+
+    /// The signed recursion `mod_inverse` used before the u128 helpers, kept to compare against.
+    fn legacy_mod_inverse(a: u64, m: u64) -> u64 {
+        fn extended_gcd(a: i64, b: i64) -> (i64, i64) {
+            if b == 0 {
+                return (a, 1);
+            }
+            let (g, x1) = extended_gcd(b, a % b);
+            let x = -(a / b) * x1;
+            (g, x1 - x)
+        }
+        let (g, x) = extended_gcd(a as i64, m as i64);
+        if g != 1 {
+            return 0;
+        }
+        x.rem_euclid(m as i64) as u64
+    }
+
+    #[test]
+    fn u128_inverse_over_random_pairs() {
+        let mut state = xorshift64_state(1400);
+        for _ in 0..5_000 {
+            let m = xorshift64_star(&mut state) | 1 << 63;
+            let a = xorshift64_star(&mut state) % m;
+            let inv = mod_inverse_u64_via_u128(a, m);
+            if gcd_u128(a.into(), m.into()) == 1 {
+                assert_eq!(u128::from(a) * u128::from(inv) % u128::from(m), 1);
+            } else {
+                assert_eq!(inv, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn u128_inverse_near_the_top_of_u64() {
+        for m in [u64::MAX, u64::MAX - 1, u64::MAX - 58] {
+            for a in [2, 3, 65537, u64::MAX - 2, m - 1] {
+                let inv = mod_inverse_u64_via_u128(a, m);
+                if gcd_u64(a, m) == 1 {
+                    assert_eq!(
+                        u128::from(a) * u128::from(inv) % u128::from(m),
+                        1,
+                        "{a} {m}"
+                    );
+                } else {
+                    assert_eq!(inv, 0);
+                }
+            }
+        }
+        let (g, x, y, x_negative) = extended_gcd_u128(u64::MAX as u128, 2);
+        assert_eq!((g, x, y, x_negative), (1, 1, (u64::MAX / 2) as u128, false));
+    }
+
+    #[test]
+    fn u128_inverse_agrees_where_the_old_one_was_right() {
+        let mut agreed = 0;
+        for m in 2..300u64 {
+            for a in 1..m {
+                let old = legacy_mod_inverse(a, m);
+                if old != 0 && a * old % m == 1 {
+                    assert_eq!(mod_inverse_u64_via_u128(a, m), old, "{a} {m}");
+                    agreed += 1;
+                }
+            }
+        }
+        assert!(agreed > 0);
+    }
+
+    /// Status of a `shared_prime` result, see SHARED_PRIME_STATUS_SHIFT.
+    fn shared_prime_status(result: u64) -> u64 {
+        result >> SHARED_PRIME_STATUS_SHIFT
+    }
+
+    #[test]
+    fn shared_prime_factors_both_keys() {
+        let (p, q1, q2) = (1009u64, 65537u64, 100_003u64);
+        let result = detect_shared_prime(0, p * q1, 0, p * q2);
+        assert_eq!(shared_prime_status(result), SHARED_PRIME_COMPROMISED);
+        assert_eq!(result & ((1 << SHARED_PRIME_STATUS_SHIFT) - 1), p);
+
+        // The toy moduli fit in one limb, but the gcd runs over the full 128 bits
+        let wide = detect_shared_prime(1, 0, 0, 1 << 20);
+        assert_eq!(
+            wide,
+            SHARED_PRIME_COMPROMISED << SHARED_PRIME_STATUS_SHIFT | 1 << 20
+        );
+    }
+
+    #[test]
+    fn independent_keys_are_clean() {
+        assert_eq!(detect_shared_prime(0, 1009 * 65537, 0, 11 * 101), 0);
+        assert_eq!(shared_prime_status(0), SHARED_PRIME_CLEAN);
+    }
+
+    #[test]
+    fn identical_and_degenerate_moduli_have_their_own_status() {
+        let n = 1009 * 65537;
+        assert_eq!(
+            shared_prime_status(detect_shared_prime(0, n, 0, n)),
+            SHARED_PRIME_IDENTICAL
+        );
+        assert_eq!(
+            shared_prime_status(detect_shared_prime(0, 1, 0, n)),
+            SHARED_PRIME_BAD_MODULUS
+        );
+    }
+
+    #[test]
+    fn keygen_mode_checks_against_a_prior_modulus() {
+        let (p, q, _) = generate_keypair(42, 20).unwrap();
+        let mode = MODE_KEYGEN | MODE_SHARED_PRIME;
+        let result = main(42, 20, 0, 0, mode, p * 65537);
+        assert_eq!(
+            result,
+            SHARED_PRIME_COMPROMISED << SHARED_PRIME_STATUS_SHIFT | p
+        );
+        assert_eq!(main(42, 20, 0, 0, mode, 11 * 101), 0);
+        assert_eq!(
+            shared_prime_status(main(42, 20, 0, 0, mode, p * q)),
+            SHARED_PRIME_IDENTICAL
+        );
+    }
+
+    /// The ciphertext, modulus and status words of an encryption record.
+    fn record_words(record: &[u8; ENCRYPTION_RECORD_SIZE]) -> [u64; 3] {
+        let word = |i: usize| u64::from_le_bytes(record[i * 8..i * 8 + 8].try_into().unwrap());
+        [word(0), word(1), word(2)]
+    }
+
+    #[test]
+    fn encryption_record_verifies_against_its_own_modulus() {
+        let (p, q, e, message) = (1009, 65537, 17, 4242);
+        let [ciphertext, n, status] = record_words(&build_encryption_record(p, q, e, message));
+        assert_eq!(status, 0);
+        assert_eq!(n, p * q);
+        assert_eq!(mod_exp(message, e, n), ciphertext);
+        assert_eq!(main(p, q, e, message, 0, 0), ciphertext);
+    }
+
+    #[test]
+    fn encryption_record_carries_the_reason() {
+        let [ciphertext, n, status] = record_words(&build_encryption_record(11, 101, 3, 1111));
+        assert_eq!((ciphertext, n, status), (0, 1111, REASON_MESSAGE_TOO_LARGE));
+        let [_, n, status] = record_words(&build_encryption_record(12, 101, 3, 5));
+        assert_eq!((n, status), (0, REASON_P_COMPOSITE));
+        // Safety: a null out_ptr is never written to
+        assert_eq!(unsafe { encrypt_with_modulus(1009, 65537, 17, 4242, 0) }, 0);
+    }
+
+    #[test]
+    fn composite_p_one_above_a_prime_is_corrected() {
+        assert_eq!(next_prime_below(1010, FALLBACK_SEARCH_WINDOW), Some(1009));
+        assert_eq!(next_prime_below(1011, FALLBACK_SEARCH_WINDOW), Some(1009));
+        assert_eq!(
+            partial_fallback(1010, 65537, 17, 42, FALLBACK_SEARCH_WINDOW),
+            toy_rsa_encrypt(1009, 65537, 17, 42)
+        );
+    }
+
+    #[test]
+    fn both_composite_primes_are_corrected() {
+        // 1027 = 13 * 79 and 65541 = 3 * 21847 step down to 1021 and 65539
+        let expected = toy_rsa_encrypt(1021, 65539, 7, 42);
+        assert!(expected.is_ok());
+        assert_eq!(
+            partial_fallback(1027, 65541, 7, 42, FALLBACK_SEARCH_WINDOW),
+            expected
+        );
+        assert_eq!(main(1027, 65541, 7, 42, 0, 0), expected.unwrap());
+    }
+
+    #[test]
+    fn exhausted_window_reports_the_composite_prime() {
+        // The only odd number tried below 1028 is 1027, which isn't prime
+        assert_eq!(next_prime_below(1028, 1), None);
+        assert_eq!(
+            partial_fallback(1028, 65537, 17, 42, 1),
+            Err(REASON_P_COMPOSITE)
+        );
+        assert_eq!(
+            partial_fallback(1009, 65545, 17, 42, 0),
+            Err(REASON_Q_COMPOSITE)
+        );
+        // Nothing below 3 may be used
+        assert_eq!(next_prime_below(3, FALLBACK_SEARCH_WINDOW), None);
+        assert_eq!(
+            main(1, 65537, 17, 42, 0, 0),
+            REASON_P_COMPOSITE << REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn corrected_key_round_trips() {
+        let ciphertext = main(1010, 65541, 17, 4242, 0, 0);
+        assert_eq!(ciphertext >> REASON_SHIFT, 0);
+        assert_eq!(decrypt(1009, 65539, 17, ciphertext), 4242);
+    }
+
+    #[test]
+    fn crt_flag_values_keep_their_outputs() {
+        let (p, q, e, message) = (1009, 65537, 17, 4242);
+        let ciphertext = mod_exp(message, e, p * q);
+        assert_eq!(ciphertext, 0x41_6417);
+        assert_eq!(main(p, q, e, message, 0, 0), ciphertext);
+        // The CRT encryption comes back on its own and agrees with the plain one
+        assert_eq!(toy_rsa_encrypt_crt(p, q, e, message), ciphertext);
+        assert_eq!(main(p, q, e, message, MODE_CRT, 0), ciphertext);
+        assert_eq!(
+            main(p, q, e, message, MODE_CRT | MODE_LAMBDA | MODE_LADDER, 0),
+            ciphertext
+        );
+    }
+
+    #[test]
+    fn every_single_bit_mode_dispatches() {
+        let (p, q, e, message) = (1009, 65537, 17, 4242);
+        let ciphertext = mod_exp(message, e, p * q);
+        let plaintext = decrypt(p, q, e, message);
+        let cases = [
+            (MODE_CRT, ciphertext),
+            (MODE_DECRYPT, plaintext),
+            (MODE_SIGN, toy_rsa_sign(p, q, e, message)),
+            (MODE_VERIFY, toy_rsa_verify(p, e, message, q)),
+            (MODE_PADDED, ERR_PADDING_DOES_NOT_FIT),
+            (MODE_LADDER, ciphertext),
+            (MODE_BIG, ciphertext),
+            (MODE_KEYGEN, 0),
+            (MODE_LAMBDA, ciphertext),
+            (MODE_AUTO_EXPONENT, ciphertext),
+            (MODE_SELF_TEST, SELF_TEST_PASSED),
+            (MODE_SHARED_PRIME, REASON_INVALID_MODE << REASON_SHIFT),
+        ];
+        assert_eq!(cases.len() as u32, MODE_ALL.count_ones());
+        for (mode, expected) in cases {
+            assert_eq!(main(p, q, e, message, mode, 0), expected, "mode {mode:#x}");
+        }
+        assert_eq!(plaintext, 0x2ab_beca);
+        assert_eq!(toy_rsa_sign(p, q, e, message), plaintext);
+    }
+
+    #[test]
+    fn invalid_mode_combinations_are_rejected() {
+        let invalid = REASON_INVALID_MODE << REASON_SHIFT;
+        for mode in [
+            MODE_SIGN | MODE_VERIFY,
+            MODE_DECRYPT | MODE_SIGN,
+            MODE_KEYGEN | MODE_SELF_TEST,
+            MODE_BIG | MODE_CRT,
+            MODE_BIG | MODE_PADDED,
+            MODE_SHARED_PRIME,
+            MODE_ALL + 1,
+        ] {
+            assert!(!is_valid_mode(mode), "mode {mode:#x}");
+            assert_eq!(main(1009, 65537, 17, 4242, mode, 0), invalid);
+        }
+        assert!(is_valid_mode(MODE_BIG | MODE_DECRYPT));
+        assert!(is_valid_mode(
+            MODE_DECRYPT | MODE_CRT | MODE_PADDED | MODE_LADDER | MODE_LAMBDA
+        ));
+    }
+
+    #[test]
+    fn small_messages_under_e_3_are_flagged() {
+        let (p, q, _) = PADDED_KEY;
+        let n = p * q;
+        let root = iroot_u64(n - 1, 3);
+        assert_eq!(check_broadcast_risk(3, 42, n), 1);
+        assert_eq!(check_broadcast_risk(3, root, n), 1);
+        assert_eq!(check_broadcast_risk(3, root + 1, n), 0);
+        // Unreduced, so the cube root recovers the message
+        assert_eq!(mod_exp(root, 3, n), root.pow(3));
+        assert_eq!(check_broadcast_risk(0, 42, n), 0);
+        assert_eq!(check_broadcast_risk(3, 0, 1), 0);
+    }
+
+    #[test]
+    fn padded_messages_are_not_flagged() {
+        let (p, q, _) = PADDED_KEY;
+        let n = p * q;
+        for message in [0, 1, 42, 1 << 20] {
+            assert_eq!(check_broadcast_risk(3, message, n), 1);
+            let padded = pad_message(message, 1, n).unwrap();
+            assert_eq!(check_broadcast_risk(3, padded, n), 0);
+        }
+    }
+
+    #[test]
+    fn kth_roots_of_perfect_powers() {
+        for k in 2..=10u32 {
+            for root in [2u64, 3, 10, 77] {
+                let Some(power) = root.checked_pow(k) else {
+                    continue;
+                };
+                assert_eq!(iroot_u64(power, k), root, "{root}^{k}");
+                assert_eq!(iroot_u64(power - 1, k), root - 1, "{root}^{k} - 1");
+                assert_eq!(iroot_u64(power + 1, k), root, "{root}^{k} + 1");
+            }
+        }
+        assert_eq!(iroot_u64(u64::MAX, 64), 1);
+        assert_eq!(iroot_u64(u64::MAX, 63), 2);
+        assert_eq!(iroot_u64(12345, 1), 12345);
+    }
+
+    #[test]
+    fn rsa_encrypt_export() {
+        assert_eq!(rsa_encrypt(11, 101, 3, 65), 208);
+        assert_eq!(
+            rsa_encrypt(12, 101, 3, 65),
+            REASON_P_COMPOSITE << REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn rsa_decrypt_export() {
+        assert_eq!(rsa_decrypt(11, 101, 3, 208), 65);
+        assert_eq!(
+            rsa_decrypt(11, 101, 3, 1111),
+            REASON_MESSAGE_TOO_LARGE << REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn rsa_sign_export() {
+        // d = 3^-1 mod 1000 = 667
+        let signature = rsa_sign(11, 101, 3, 65);
+        assert_eq!(signature, mod_exp(65, 667, 1111));
+        assert_eq!(mod_exp(signature, 3, 1111), 65);
+        assert_eq!(
+            rsa_sign(11, 101, 5, 65),
+            REASON_E_NOT_COPRIME << REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn rsa_modexp_export() {
+        assert_eq!(rsa_modexp(4, 13, 497), 445);
+        assert_eq!(rsa_modexp(2, 10, 1000), 24);
+        assert_eq!(rsa_modexp(7, 0, 1), 0);
+        assert_eq!(rsa_modexp(4, 13, 0), REASON_ZERO_MODULUS << REASON_SHIFT);
+    }
+
+    #[test]
+    fn exponent_selection_skips_factors_of_phi() {
+        assert_eq!(select_public_exponent(4), 3);
+        // phi = 30 * 10 for p = 31, q = 11 has both 3 and 5 as factors
+        assert_eq!(select_public_exponent(300), 17);
+        let (p, q, message) = (31, 11, 42);
+        let result = main(p, q, 0, message, MODE_AUTO_EXPONENT, 0);
+        assert_eq!(result >> AUTO_EXPONENT_SHIFT, 17);
+        assert_eq!(result & u32::MAX as u64, mod_exp(message, 17, p * q));
+        assert_eq!(select_public_exponent(3 * 5 * 17), 257);
+        assert_eq!(select_public_exponent(3 * 5 * 17 * 257), 65537);
+    }
+
+    #[test]
+    fn exponent_selection_gives_up_after_the_candidate_cap() {
+        // Every candidate divides phi = 0, so only the cap ends the search
+        assert_eq!(select_public_exponent(0), 0);
+        // p = 1 has phi = 0; with no exponent the key check reports why
+        assert_eq!(
+            main(1, 101, 0, 5, MODE_AUTO_EXPONENT, 0),
+            REASON_P_COMPOSITE << REASON_SHIFT
+        );
+    }
+
+    #[test]
+    fn explicit_exponent_skips_selection() {
+        let (p, q, message) = (11, 101, 65);
+        assert_eq!(select_public_exponent(1000), 3);
+        let explicit = main(p, q, 7, message, MODE_AUTO_EXPONENT, 0);
+        assert_eq!(explicit, mod_exp(message, 7, p * q));
+        assert_eq!(explicit, main(p, q, 7, message, 0, 0));
+        assert_eq!(explicit >> AUTO_EXPONENT_SHIFT, 0);
+    }
 }
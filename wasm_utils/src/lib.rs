@@ -0,0 +1,92 @@
+//! Helpers shared by the modules beyond plain arithmetic: folding BigUint values into
+//! 64-bit digests, and copying data in and out of linear memory for the exports that take
+//! pointers.
+
+use num_bigint::BigUint;
+
+// Odd multiplier mixing each higher BigUint limb into a folded digest.
+pub const DIGIT_FOLD_MULTIPLIER: u64 = 0x9E37_79B9_7F4A_7C15;
+
+//
+// Fold every 64-bit limb of a BigUint into one word. The lowest limb passes through as is and
+// each higher one is mixed in after rotating the accumulator by an index-dependent amount, so
+// values differing in any limb fold differently. `legacy` keeps only the lowest limb.
+//
+pub fn fold_biguint_digits(big_val: &BigUint, legacy: bool) -> u64 {
+    let digits = big_val.to_u64_digits();
+    let mut folded = digits.first().copied().unwrap_or(0);
+    if legacy {
+        return folded;
+    }
+
+    for (i, &digit) in digits.iter().enumerate().skip(1) {
+        folded =
+            folded.rotate_left((i as u32 * 17) % 64) ^ digit.wrapping_mul(DIGIT_FOLD_MULTIPLIER);
+    }
+    folded
+}
+
+/// Size of linear memory in bytes. Native builds have no bound to check against.
+pub fn linear_memory_size() -> usize {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) * 65536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        usize::MAX
+    }
+}
+
+/// Whether `len` bytes from `ptr` are a non-null range inside linear memory.
+fn in_linear_memory(ptr: u32, len: usize) -> bool {
+    match (ptr as usize).checked_add(len) {
+        Some(end) => ptr != 0 && end <= linear_memory_size(),
+        None => false,
+    }
+}
+
+/// Copy `len` bytes out of linear memory, or None if the range runs past the end of it.
+///
+/// # Safety
+///
+/// A nonzero `ptr` must point to `len` readable bytes. On wasm32 every range inside linear
+/// memory is, natively nothing can be checked.
+pub unsafe fn read_bytes(ptr: u32, len: usize) -> Option<Vec<u8>> {
+    if !in_linear_memory(ptr, len) {
+        return None;
+    }
+    Some(core::slice::from_raw_parts(ptr as usize as *const u8, len).to_vec())
+}
+
+/// Copy `count` values out of linear memory, or None if the range runs past the end of it.
+/// The values needn't be aligned.
+///
+/// # Safety
+///
+/// As for `read_bytes`, over `count` values of `T`, and any bit pattern must be a valid `T`.
+pub unsafe fn read_array<T: Copy>(ptr: u32, count: u32) -> Option<Vec<T>> {
+    let byte_len = (count as usize).checked_mul(core::mem::size_of::<T>())?;
+    if !in_linear_memory(ptr, byte_len) {
+        return None;
+    }
+    let base = ptr as usize as *const T;
+    Some(
+        (0..count as usize)
+            .map(|i| base.add(i).read_unaligned())
+            .collect(),
+    )
+}
+
+/// Copy `bytes` into linear memory at `ptr`, or return false if they wouldn't fit.
+///
+/// # Safety
+///
+/// A nonzero `ptr` must point to `bytes.len()` writable bytes that nothing else is using.
+pub unsafe fn write_bytes(ptr: u32, bytes: &[u8]) -> bool {
+    if !in_linear_memory(ptr, bytes.len()) {
+        return false;
+    }
+    core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr as usize as *mut u8, bytes.len());
+    true
+}